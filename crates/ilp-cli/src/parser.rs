@@ -143,6 +143,24 @@ fn accounts_create<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("packets_per_minute_limit")
                 .long("packets-per-minute-limit")
                 .takes_value(true),
+            Arg::with_name("amount_burst_limit")
+                .long("amount-burst-limit")
+                .takes_value(true),
+            Arg::with_name("packets_burst_limit")
+                .long("packets-burst-limit")
+                .takes_value(true),
+            Arg::with_name("receive_max_per_connection")
+                .long("receive-max-per-connection")
+                .takes_value(true),
+            Arg::with_name("receive_max_per_period")
+                .long("receive-max-per-period")
+                .takes_value(true),
+            Arg::with_name("receive_period")
+                .long("receive-period")
+                .takes_value(true),
+            Arg::with_name("receive_min_per_packet")
+                .long("receive-min-per-packet")
+                .takes_value(true),
             Arg::with_name("settlement_engine_url")
                 .long("settlement-engine-url")
                 .takes_value(true),
@@ -214,6 +232,24 @@ fn accounts_update<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("packets_per_minute_limit")
                 .long("packets-per-minute-limit")
                 .takes_value(true),
+            Arg::with_name("amount_burst_limit")
+                .long("amount-burst-limit")
+                .takes_value(true),
+            Arg::with_name("packets_burst_limit")
+                .long("packets-burst-limit")
+                .takes_value(true),
+            Arg::with_name("receive_max_per_connection")
+                .long("receive-max-per-connection")
+                .takes_value(true),
+            Arg::with_name("receive_max_per_period")
+                .long("receive-max-per-period")
+                .takes_value(true),
+            Arg::with_name("receive_period")
+                .long("receive-period")
+                .takes_value(true),
+            Arg::with_name("receive_min_per_packet")
+                .long("receive-min-per-packet")
+                .takes_value(true),
             Arg::with_name("settlement_engine_url")
                 .long("settlement-engine-url")
                 .takes_value(true),
@@ -1,8 +1,11 @@
 use interledger::{
     ccp::CcpRoutingAccount,
+    rates::{HeldPairRate, HeldRate},
     service::{
         Account, IlpResult, IncomingRequest, IncomingService, OutgoingRequest, OutgoingService,
+        Username,
     },
+    service_util::CircuitState,
 };
 use metrics::{self, labels, recorder, Key};
 use std::time::Instant;
@@ -80,3 +83,52 @@ pub async fn outgoing_metrics<A: Account + CcpRoutingAccount>(
 
     result
 }
+
+/// Counts the exchange rates held back for moving too much within one poll
+pub fn held_exchange_rate(asset_code: &str, _held_rate: &HeldRate) {
+    recorder().increment_counter(
+        Key::from_name_and_labels(
+            "exchange_rates.held",
+            labels!("asset_code" => asset_code.to_string()),
+        ),
+        1,
+    );
+}
+
+/// Counts the pair rates held back for moving too much within one poll
+pub fn held_pair_rate(pair: &str, _held_rate: &HeldPairRate) {
+    recorder().increment_counter(
+        Key::from_name_and_labels(
+            "exchange_rates.held_pairs",
+            labels!("pair" => pair.to_string()),
+        ),
+        1,
+    );
+}
+
+/// Counts the state changes of the circuits to outgoing accounts, and records
+/// the current state of each circuit (0 closed, 1 half-open, 2 open)
+pub fn circuit_state_changed(username: &Username, state: CircuitState) {
+    recorder().increment_counter(
+        Key::from_name_and_labels(
+            "circuit_breaker.transitions",
+            labels!(
+                "account" => username.to_string(),
+                "state" => state.as_str(),
+            ),
+        ),
+        1,
+    );
+    let value = match state {
+        CircuitState::Closed => 0,
+        CircuitState::HalfOpen => 1,
+        CircuitState::Open => 2,
+    };
+    recorder().update_gauge(
+        Key::from_name_and_labels(
+            "circuit_breaker.state",
+            labels!("account" => username.to_string()),
+        ),
+        value,
+    );
+}
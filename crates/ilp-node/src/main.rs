@@ -154,15 +154,33 @@ fn cmdline_configuration<'b>(version: &'b str) -> clap::App<'static, 'b> {
         Arg::with_name("http_bind_address")
             .long("http_bind_address")
             .takes_value(true)
-            .help("IP address and port to listen for HTTP connections. This is used for both the API and ILP over HTTP packets. ILP over HTTP is a means to transfer ILP packets instead of BTP connections"),
+            .help("IP address and port to listen for HTTP connections. This is used for both the API and ILP over HTTP packets. ILP over HTTP is a means to transfer ILP packets instead of BTP connections. \
+                A Unix domain socket can be used instead by giving its path prefixed with `unix:` (for example, \"unix:/tmp/ilp-node.sock\")"),
         Arg::with_name("settlement_api_bind_address")
             .long("settlement_api_bind_address")
             .takes_value(true)
-            .help("IP address and port to listen for the Settlement Engine API"),
+            .help("IP address and port to listen for the Settlement Engine API. \
+                A Unix domain socket can be used instead by giving its path prefixed with `unix:` (for example, \"unix:/tmp/ilp-node-settlement.sock\")"),
+        Arg::with_name("ilp_over_tcp.bind_address")
+            .long("ilp_over_tcp.bind_address")
+            .takes_value(true)
+            .help("IP address and port to listen for ILP-over-TCP connections from peers. If this is not set, the node does not accept ILP-over-TCP connections."),
+        Arg::with_name("ilp_over_tcp.tls_identity")
+            .long("ilp_over_tcp.tls_identity")
+            .takes_value(true)
+            .help("Path to a PKCS #12 archive with the certificate and private key used to accept ILP-over-TCP connections over TLS."),
+        Arg::with_name("ilp_over_tcp.tls_identity_password")
+            .long("ilp_over_tcp.tls_identity_password")
+            .takes_value(true)
+            .help("Password of the ilp_over_tcp.tls_identity archive."),
         Arg::with_name("default_spsp_account")
             .long("default_spsp_account")
             .takes_value(true)
             .help("When SPSP payments are sent to the root domain, the payment pointer is resolved to <domain>/.well-known/pay. This value determines which account those payments will be sent to."),
+        Arg::with_name("spsp.include_asset")
+            .long("spsp.include_asset")
+            .takes_value(true)
+            .help("Whether SPSP responses include the asset code and scale of the receiving account. Payment Pointer paths and aliases can be configured via a config file or stdin."),
         Arg::with_name("route_broadcast_interval")
             .long("route_broadcast_interval")
             .takes_value(true)
@@ -176,6 +194,16 @@ fn cmdline_configuration<'b>(version: &'b str) -> clap::App<'static, 'b> {
             .long("exchange_rate.poll_interval")
             .default_value("60000") // also change ExchangeRateConfig::default_poll_interval
             .help("Interval, defined in milliseconds, on which the node will poll the exchange_rate.provider (if specified) for exchange rates."),
+        Arg::with_name("exchange_rate.quorum")
+            .long("exchange_rate.quorum")
+            .takes_value(true)
+            .help("Number of exchange rate providers which must return a rate for an asset for it to be updated. \
+                Defaults to a majority of the providers. Several providers can be configured via a config file or stdin."),
+        Arg::with_name("exchange_rate.max_age")
+            .long("exchange_rate.max_age")
+            .takes_value(true)
+            .help("Maximum age, defined in milliseconds, of an exchange rate polled from the providers. \
+                Rates which were not updated for longer are removed. If this is not set, rates which were not updated by the latest poll are removed."),
         Arg::with_name("exchange_rate.spread")
             .long("exchange_rate.spread")
             .default_value("0") // also change ExchangeRateConfig::default_spread
@@ -185,6 +213,22 @@ fn cmdline_configuration<'b>(version: &'b str) -> clap::App<'static, 'b> {
                 For example, take an incoming packet with an amount of 100. If the \
                 exchange rate is 1:0.5 and the spread is 0.01, the amount on the \
                     outgoing packet would be 198 (instead of 200 without the spread)."),
+        Arg::with_name("exchange_rate.max_change")
+            .long("exchange_rate.max_change")
+            .takes_value(true)
+            .help("Largest relative change, as a fraction, allowed for a polled exchange rate since the previous poll. \
+                Rates which move more keep their previous value until they return within bounds \
+                or are confirmed via the HTTP API. If this is not set, polled rates are always applied."),
+        Arg::with_name("fees.same_currency_free")
+            .long("fees.same_currency_free")
+            .takes_value(true)
+            .help("Whether packets between accounts of the same asset are forwarded without fees, \
+                including the exchange rate spread. \
+                The fee policies themselves can be configured via a config file or stdin."),
+        Arg::with_name("fees.fee_account")
+            .long("fees.fee_account")
+            .takes_value(true)
+            .help("Username of the account the fees charged on forwarded packets are credited to."),
         Arg::with_name("prometheus.bind_address")
             .long("prometheus.bind_address")
             .takes_value(true)
@@ -417,6 +461,7 @@ fn is_fd_tty(file_descriptor: c_int) -> bool {
 #[cfg(test)]
 mod tests {
     use super::{cmdline_configuration, load_configuration, BadConfig, InterledgerNode};
+    use crate::node::{BindAddress, IlpOverTcpConfig};
     use std::ffi::OsString;
     use std::io::Write;
 
@@ -449,6 +494,66 @@ mod tests {
         assert_eq!(expected, node);
     }
 
+    #[test]
+    fn loads_unix_socket_bind_addresses() {
+        let args = [
+            "ilp-node",
+            "--admin_auth_token",
+            "foobar",
+            "--secret_seed",
+            "8852500887504328225458511465394229327394647958135038836332350604",
+            "--http_bind_address",
+            "unix:/tmp/ilp-node.sock",
+            "--settlement_api_bind_address",
+            "127.0.0.1:3000",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        let app = cmdline_configuration("anything");
+        let additional = Option::<std::io::Empty>::None;
+
+        let node = load_configuration(app, args, additional).unwrap();
+
+        assert_eq!(
+            node.http_bind_address,
+            BindAddress::Unix("/tmp/ilp-node.sock".into())
+        );
+        assert_eq!(
+            node.settlement_api_bind_address,
+            BindAddress::Tcp(([127, 0, 0, 1], 3000).into())
+        );
+    }
+
+    #[test]
+    fn loads_ilp_over_tcp_config() {
+        let args = [
+            "ilp-node",
+            "--admin_auth_token",
+            "foobar",
+            "--secret_seed",
+            "8852500887504328225458511465394229327394647958135038836332350604",
+            "--ilp_over_tcp.bind_address",
+            "0.0.0.0:7772",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        let app = cmdline_configuration("anything");
+        let additional = Option::<std::io::Empty>::None;
+
+        let node = load_configuration(app, args, additional).unwrap();
+
+        assert_eq!(
+            node.ilp_over_tcp,
+            Some(IlpOverTcpConfig {
+                bind_address: ([0, 0, 0, 0], 7772).into(),
+                tls_identity: None,
+                tls_identity_password: None,
+            })
+        );
+    }
+
     static ADDITIONAL_SECRETS: &[(&str, &[u8])] = &[
         ("json", b"{ \"secret_seed\": \"8852500887504328225458511465394229327394647958135038836332350604\" }"),
         ("yaml", b"secret_seed: \"8852500887504328225458511465394229327394647958135038836332350604\"\n"),
@@ -16,7 +16,10 @@ cfg_if! {
             reload::Handle,
         };
         use crate::instrumentation::{
-            metrics::{incoming_metrics, outgoing_metrics},
+            metrics::{
+                circuit_state_changed, held_exchange_rate, held_pair_rate, incoming_metrics,
+                outgoing_metrics,
+            },
             prometheus::{serve_prometheus, PrometheusConfig},
             trace::{trace_forwarding, trace_incoming, trace_outgoing},
         };
@@ -33,33 +36,41 @@ use bytes::Bytes;
 use futures::TryFutureExt;
 use hex::FromHex;
 use interledger::{
-    api::{NodeApi, NodeStore},
-    btp::{btp_service_as_filter, connect_client, BtpOutgoingService, BtpStore},
+    api::{NodeApi, NodeStore, OutgoingPaymentStore, SpspConfig},
+    btp::{
+        btp_service_as_filter, connect_client, BtpOutgoingService, BtpStore, TcpOutgoingService,
+        TcpServer,
+    },
     ccp::{CcpRouteManagerBuilder, CcpRoutingAccount, CcpRoutingStore, RoutingRelation},
     errors::*,
     http::{HttpClientService, HttpServer as IlpOverHttpServer, HttpStore},
     ildcp::IldcpService,
     packet::Address,
     packet::{ErrorCode, RejectBuilder},
-    rates::{ExchangeRateFetcher, ExchangeRateStore},
+    rates::{
+        parse_pair, AcceptedRatesStore, ExchangeRateFetcher, ExchangeRateStore, PairRate,
+        RateAggregator, RateChangeGuard,
+    },
     router::{Router, RouterStore},
     service::{
         outgoing_service_fn, Account as AccountTrait, AccountStore, AddressStore, OutgoingRequest,
         Username,
     },
     service_util::{
-        BalanceStore, EchoService, ExchangeRateService, ExpiryShortenerService,
-        MaxPacketAmountService, RateLimitService, RateLimitStore, ValidatorService,
+        BalanceStore, CircuitBreaker, CircuitBreakerConfig, CircuitBreakerService, EchoService,
+        ExchangeRateService, ExpiryShortenerService, FeeConfig, FeeService, MaxPacketAmountService,
+        PendingEchoes, RateLimitConfig, RateLimitService, RateLimitStore, RateLimiter,
+        ValidatorService,
     },
     settlement::{
         api::{create_settlements_filter, SettlementMessageService},
         core::{
             idempotency::IdempotentStore,
-            types::{LeftoversStore, SettlementStore},
+            types::{LeftoversStore, SettlementAccount, SettlementStore},
         },
     },
     store::account::Account,
-    stream::{StreamNotificationsStore, StreamReceiverService},
+    stream::{InvoiceStore, ReceiptTotalsStore, StreamNotificationsStore, StreamReceiverService},
 };
 use num_bigint::BigUint;
 use once_cell::sync::Lazy;
@@ -67,8 +78,11 @@ use serde::{de::Error as DeserializeError, Deserialize, Deserializer};
 #[cfg(feature = "balance-tracking")]
 use std::num::NonZeroU32;
 use std::{
+    collections::HashMap,
     convert::TryFrom,
+    fmt,
     net::SocketAddr,
+    path::PathBuf,
     str::{self, FromStr},
     time::Duration,
 };
@@ -88,11 +102,11 @@ pub use interledger::rates::ExchangeRateProvider;
 
 static DEFAULT_ILP_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("local.host").unwrap());
 
-fn default_settlement_api_bind_address() -> SocketAddr {
-    SocketAddr::from(([127, 0, 0, 1], 7771))
+fn default_settlement_api_bind_address() -> BindAddress {
+    BindAddress::Tcp(SocketAddr::from(([127, 0, 0, 1], 7771)))
 }
-fn default_http_bind_address() -> SocketAddr {
-    SocketAddr::from(([127, 0, 0, 1], 7770))
+fn default_http_bind_address() -> BindAddress {
+    BindAddress::Tcp(SocketAddr::from(([127, 0, 0, 1], 7770)))
 }
 // We allow unreachable code on the below function because there must always be exactly one default
 // regardless of how many data sources the crate is compiled to support,
@@ -143,6 +157,91 @@ where
     }
 }
 
+/// Address on which one of the node's HTTP servers listens for connections.
+///
+/// Parsed either from an IP address and port (`127.0.0.1:7770`) or from the path of a Unix
+/// domain socket prefixed with `unix:` (`unix:/var/run/ilp-node/api.sock`).
+#[derive(Clone, PartialEq, Debug)]
+pub enum BindAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("Unix domain socket path cannot be empty".to_string());
+            }
+            Ok(BindAddress::Unix(PathBuf::from(path)))
+        } else {
+            SocketAddr::from_str(s)
+                .map(BindAddress::Tcp)
+                .map_err(|err| format!("Invalid socket address: {}", err))
+        }
+    }
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddress::Tcp(addr) => write!(f, "{}", addr),
+            BindAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for BindAddress {
+    fn from(addr: SocketAddr) -> Self {
+        BindAddress::Tcp(addr)
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddress {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        BindAddress::from_str(&String::deserialize(deserializer)?).map_err(DeserializeError::custom)
+    }
+}
+
+/// Spawns a warp server for the filter listening on the given address
+fn spawn_server<F>(filter: F, bind_address: &BindAddress) -> Result<(), ()>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+{
+    match bind_address {
+        BindAddress::Tcp(addr) => {
+            spawn(warp::serve(filter).bind(*addr));
+        }
+        #[cfg(unix)]
+        BindAddress::Unix(path) => {
+            use std::os::unix::fs::FileTypeExt;
+
+            // Remove the socket left behind by a previous run, but never any other kind of file
+            if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                if metadata.file_type().is_socket() {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+            let mut listener = tokio::net::UnixListener::bind(path).map_err(|err| {
+                error!(target: "interledger-node", "Unable to listen on {}: {}", bind_address, err)
+            })?;
+            spawn(async move { warp::serve(filter).run_incoming(listener.incoming()).await });
+        }
+        #[cfg(not(unix))]
+        BindAddress::Unix(_) => {
+            error!(target: "interledger-node", "Unix domain sockets are not supported on this platform: {}", bind_address);
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
 /// Configuration for calculating exchange rates between various pairs.
 #[derive(Deserialize, Clone, PartialEq, Debug)]
 pub struct ExchangeRateConfig {
@@ -157,10 +256,25 @@ pub struct ExchangeRateConfig {
     /// API to poll for exchange rates. Currently the supported options are:
     /// - [CoinCap](https://docs.coincap.io)
     /// - [CryptoCompare](https://cryptocompare.com) (note this requires an API key)
+    /// - Http: any HTTP endpoint returning JSON, with configurable paths to the rates
+    /// - File: a local JSON or YAML file, which is reloaded when it changes
     /// If this value is not set, the node will not poll for exchange rates and will
     /// instead use the rates configured via the HTTP API.
     #[serde(default)]
     pub provider: Option<ExchangeRateProvider>,
+    /// Additional APIs to poll for exchange rates, alongside `provider` if it is set.
+    /// The rate of each asset is the median of the rates returned by the providers.
+    #[serde(default)]
+    pub providers: Vec<ExchangeRateProvider>,
+    /// The number of providers which must return a rate for an asset for it to be updated.
+    /// Defaults to a majority of the configured providers.
+    #[serde(default)]
+    pub quorum: Option<usize>,
+    /// Maximum age, defined in milliseconds, of an exchange rate. Rates which were not
+    /// updated for longer, e.g. because too few providers returned them, are removed.
+    /// If this value is not set, rates which were not updated by the latest poll are removed.
+    #[serde(default)]
+    pub max_age: Option<u64>,
     /// Spread, as a fraction, to add on top of the exchange rate.
     /// This amount is kept as the node operator's profit, or may cover
     /// fluctuations in exchange rates.
@@ -169,6 +283,18 @@ pub struct ExchangeRateConfig {
     /// outgoing packet would be 198 (instead of 200 without the spread).
     #[serde(default)]
     pub spread: f64,
+    /// The largest relative change, as a fraction, allowed for a polled rate since
+    /// the previous poll. Rates which move more are held back, keeping the previous
+    /// rate, until they return within bounds or are confirmed via the HTTP API.
+    /// For example, with 0.1 a rate of 100 may move between 90 and 110 within one poll.
+    /// If this value is not set, polled rates are always applied.
+    #[serde(default)]
+    pub max_change: Option<f64>,
+    /// Direct rates between pairs of assets, keyed by `BASE/QUOTE` such as `XRP/EUR`.
+    /// Each is a single rate or a `bid` and an `ask`, in units of the quote asset per
+    /// unit of the base asset, and takes precedence over the rates of both assets in USD.
+    #[serde(default)]
+    pub pairs: HashMap<String, PairRate>,
 }
 
 impl Default for ExchangeRateConfig {
@@ -179,7 +305,12 @@ impl Default for ExchangeRateConfig {
             poll_interval: Self::default_poll_interval(),
             poll_failure_tolerance: Self::default_poll_failure_tolerance(),
             provider: Default::default(),
+            providers: Vec::new(),
+            quorum: None,
+            max_age: None,
             spread: Self::default_spread(),
+            max_change: None,
+            pairs: HashMap::new(),
         }
     }
 }
@@ -196,6 +327,20 @@ impl ExchangeRateConfig {
     }
 }
 
+/// Configuration for accepting ILP-over-TCP connections from peers.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct IlpOverTcpConfig {
+    /// IP address and port to listen for ILP-over-TCP connections
+    pub bind_address: SocketAddr,
+    /// Path to a PKCS #12 archive with the certificate and private key to use.
+    /// If this is not set, connections are accepted without TLS.
+    #[serde(default)]
+    pub tls_identity: Option<String>,
+    /// Password of the PKCS #12 archive
+    #[serde(default)]
+    pub tls_identity_password: Option<String>,
+}
+
 /// An all-in-one Interledger node that includes sender and receiver functionality,
 /// a connector, and a management API.
 /// Will connect to the database at the given URL; see the crate features defined in
@@ -221,24 +366,45 @@ pub struct InterledgerNode {
     /// Database prefix which can be used in case a db instance is shared by multiple nodes
     #[serde(default)]
     pub database_prefix: String,
-    /// IP address and port to listen for HTTP connections
-    /// This is used for both the API and ILP over HTTP packets
+    /// IP address and port, or `unix:` prefixed Unix domain socket path, to listen for HTTP
+    /// connections. This is used for both the API and ILP over HTTP packets
     #[serde(default = "default_http_bind_address")]
-    pub http_bind_address: SocketAddr,
-    /// IP address and port to listen for the Settlement Engine API
+    pub http_bind_address: BindAddress,
+    /// IP address and port, or `unix:` prefixed Unix domain socket path, to listen for the
+    /// Settlement Engine API
     #[serde(default = "default_settlement_api_bind_address")]
-    pub settlement_api_bind_address: SocketAddr,
+    pub settlement_api_bind_address: BindAddress,
+    /// Configuration for accepting ILP-over-TCP connections from peers.
+    /// Outgoing connections to accounts with a `btp+tcp` or `btp+tls` URL are made
+    /// regardless of this setting.
+    #[serde(default)]
+    pub ilp_over_tcp: Option<IlpOverTcpConfig>,
     /// When SPSP payments are sent to the root domain, the payment pointer is resolved
     /// to <domain>/.well-known/pay. This value determines which account those payments
     /// will be sent to.
     #[serde(default, deserialize_with = "deserialize_optional_username")]
     pub default_spsp_account: Option<Username>,
+    /// The Payment Pointer paths SPSP is served at, besides `/accounts/:username/spsp`
+    /// and `/.well-known/pay`, and the extra details included in SPSP responses
+    #[serde(default)]
+    pub spsp: SpspConfig,
     /// Interval, defined in milliseconds, on which the node will broadcast routing
     /// information to other nodes using CCP. Defaults to 30000ms (30 seconds).
     pub route_broadcast_interval: Option<u64>,
     #[serde(default)]
     /// Configuration for calculating exchange rates between various pairs.
     pub exchange_rate: ExchangeRateConfig,
+    /// Fees charged on forwarded packets, per account or destination prefix,
+    /// and the account they are credited to
+    #[serde(default)]
+    pub fees: FeeConfig,
+    /// How the rate limits of accounts are applied, and limits per destination prefix
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    /// When set, packets to outgoing accounts which keep failing are rejected right away
+    /// instead of waiting for the transport to time out, until the accounts recover
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
     /// Configuration for [Prometheus](https://prometheus.io) metrics collection.
     /// If this configuration is not provided, the node will not collect metrics.
     /// Needs the feature flag "monitoring" to be enabled
@@ -324,9 +490,13 @@ impl InterledgerNode {
             + BtpStore<Account = Account>
             + HttpStore<Account = Account>
             + StreamNotificationsStore<Account = Account>
+            + OutgoingPaymentStore
+            + InvoiceStore
+            + ReceiptTotalsStore
             + BalanceStore
             + SettlementStore<Account = Account>
             + ExchangeRateStore
+            + AcceptedRatesStore
             + BalanceStore
             + SettlementStore<Account = Account>
             + RouterStore<Account = Account>
@@ -346,15 +516,81 @@ impl InterledgerNode {
         );
 
         let secret_seed = Bytes::copy_from_slice(&self.secret_seed[..]);
-        let http_bind_address = self.http_bind_address;
-        let settlement_api_bind_address = self.settlement_api_bind_address;
+        let http_bind_address = self.http_bind_address.clone();
+        let settlement_api_bind_address = self.settlement_api_bind_address.clone();
+        let ilp_over_tcp = self.ilp_over_tcp.clone();
         let admin_auth_token = self.admin_auth_token.clone();
         let default_spsp_account = self.default_spsp_account.clone();
+        let spsp_config = self.spsp.clone();
         let route_broadcast_interval = self.route_broadcast_interval;
-        let exchange_rate_provider = self.exchange_rate.provider.clone();
+        let exchange_rate_providers: Vec<_> = self
+            .exchange_rate
+            .provider
+            .iter()
+            .chain(self.exchange_rate.providers.iter())
+            .cloned()
+            .collect();
+        let rate_aggregator = RateAggregator::new(
+            self.exchange_rate
+                .quorum
+                .unwrap_or(exchange_rate_providers.len() / 2 + 1),
+            self.exchange_rate.max_age.map(Duration::from_millis),
+        );
         let exchange_rate_poll_interval = self.exchange_rate.poll_interval;
         let exchange_rate_poll_failure_tolerance = self.exchange_rate.poll_failure_tolerance;
         let exchange_rate_spread = self.exchange_rate.spread;
+        let rate_change_guard = match self.exchange_rate.max_change {
+            Some(max_change) => match RateChangeGuard::new(max_change) {
+                Some(guard) => {
+                    #[cfg(feature = "monitoring")]
+                    let guard = guard
+                        .on_hold(held_exchange_rate)
+                        .on_hold_pair(held_pair_rate);
+                    // Keep comparing with the rates accepted before the node restarted
+                    let guard = match store
+                        .load_accepted_rates()
+                        .map_err(|err| {
+                            error!(target: "interledger-node", "Error loading accepted exchange rates: {}", err)
+                        })
+                        .await?
+                    {
+                        Some(accepted) => guard.with_accepted_rates(accepted),
+                        None => guard,
+                    };
+                    let store = store.clone();
+                    Some(guard.on_accept(move |accepted| {
+                        let store = store.clone();
+                        let accepted = accepted.clone();
+                        tokio::spawn(async move {
+                            if let Err(err) = store.save_accepted_rates(accepted).await {
+                                error!(target: "interledger-node", "Error saving accepted exchange rates: {}", err);
+                            }
+                        });
+                    }))
+                }
+                None => {
+                    error!(target: "interledger-node", "Invalid exchange rate max_change: {}", max_change);
+                    return Err(());
+                }
+            },
+            None => None,
+        };
+        if let Some(pair) = self
+            .exchange_rate
+            .pairs
+            .keys()
+            .find(|pair| parse_pair(pair).is_none())
+        {
+            error!(target: "interledger-node", "Invalid exchange rate pair: {}, expected BASE/QUOTE", pair);
+            return Err(());
+        }
+        let exchange_rate_pairs = self.exchange_rate.pairs.clone();
+        let fee_config = self.fees.clone();
+        let rate_limiter = RateLimiter::new(&self.rate_limits);
+        let circuit_breaker = self.circuit_breaker.as_ref().map(CircuitBreaker::new);
+        #[cfg(feature = "monitoring")]
+        let circuit_breaker =
+            circuit_breaker.map(|breaker| breaker.on_change(circuit_state_changed));
         #[cfg(feature = "google-pubsub")]
         let google_pubsub = self.google_pubsub.clone();
 
@@ -363,6 +599,42 @@ impl InterledgerNode {
             .map_err(|_| error!(target: "interledger-node", "Error getting accounts"))
             .await?;
 
+        let fee_account = match fee_config.fee_account {
+            Some(ref username) => {
+                let id = store
+                    .get_account_id_from_username(username)
+                    .map_err(|err| {
+                        error!(target: "interledger-node", "Error getting fee account {}: {}", username, err)
+                    })
+                    .await?;
+                let mut accounts = store
+                    .get_accounts(vec![id])
+                    .map_err(|err| {
+                        error!(target: "interledger-node", "Error getting fee account {}: {}", username, err)
+                    })
+                    .await?;
+                accounts.pop()
+            }
+            None => None,
+        };
+        if let Some(ref fee_account) = fee_account {
+            if fee_account.settlement_engine_details().is_some() {
+                error!(target: "interledger-node", "Fee account {} must not have a settlement engine configured", fee_account.username());
+                return Err(());
+            }
+        }
+        let fee_usernames = fee_config
+            .usernames()
+            .map_err(|err| error!(target: "interledger-node", "Invalid fee policy: {}", err))?;
+        for username in fee_usernames {
+            store
+                .get_account_id_from_username(&username)
+                .map_err(|err| {
+                    error!(target: "interledger-node", "Fee policy for account {} which cannot be loaded: {}", username, err)
+                })
+                .await?;
+        }
+
         let outgoing_service = outgoing_service_fn({
             let ilp_address = ilp_address.clone();
             move |request: OutgoingRequest<Account>| {
@@ -389,13 +661,22 @@ impl InterledgerNode {
             }
         });
 
+        // Accounts with btp+tcp or btp+tls URLs are handled by the ILP-over-TCP service,
+        // which is reconnected on demand when sending requests
+        let tcp_service = TcpOutgoingService::new(ilp_address.clone(), outgoing_service);
+        tcp_service.connect_accounts(btp_accounts.clone()).await;
+
         // Connect to all of the accounts that have outgoing ilp_over_btp_urls configured
         // but don't fail if we are unable to connect
         // TODO try reconnecting to those accounts later
-        let btp_client_service =
-            connect_client(ilp_address.clone(), btp_accounts, false, outgoing_service)
-                .map_err(|err| error!("{}", err))
-                .await?;
+        let btp_client_service = connect_client(
+            ilp_address.clone(),
+            btp_accounts,
+            false,
+            tcp_service.clone(),
+        )
+        .map_err(|err| error!("{}", err))
+        .await?;
         let btp_server_service =
             BtpOutgoingService::new(ilp_address.clone(), btp_client_service.clone());
         let btp_server_service_clone = btp_server_service.clone();
@@ -405,6 +686,9 @@ impl InterledgerNode {
         // service to others like the router and then call handle_incoming on it to set up the incoming handler
         let outgoing_service = btp_server_service.clone();
         let outgoing_service = HttpClientService::new(store.clone(), outgoing_service);
+        // The circuit breaker only sees the rejects of the peers and the transports
+        let outgoing_service =
+            CircuitBreakerService::new(store.clone(), circuit_breaker.clone(), outgoing_service);
 
         #[cfg(feature = "monitoring")]
         let outgoing_service = outgoing_service.wrap(outgoing_metrics);
@@ -431,7 +715,11 @@ impl InterledgerNode {
         };
 
         let outgoing_service =
-            ExchangeRateService::new(exchange_rate_spread, store.clone(), outgoing_service);
+            ExchangeRateService::new(exchange_rate_spread, store.clone(), outgoing_service)
+                .with_same_currency_free(fee_config.same_currency_free);
+        // Fees are deducted in the incoming account's asset, before the amount is converted
+        let outgoing_service =
+            FeeService::new(&fee_config, fee_account, store.clone(), outgoing_service);
 
         #[cfg(feature = "google-pubsub")]
         let outgoing_service =
@@ -467,12 +755,16 @@ impl InterledgerNode {
         }
 
         let incoming_service = ccp_builder.to_service();
-        let incoming_service = EchoService::new(store.clone(), incoming_service);
+        let pending_echoes = PendingEchoes::default();
+        let incoming_service = EchoService::new(store.clone(), incoming_service)
+            .with_pending_echoes(pending_echoes.clone());
         let incoming_service = SettlementMessageService::new(incoming_service);
         let incoming_service = IldcpService::new(incoming_service);
         let incoming_service = MaxPacketAmountService::new(store.clone(), incoming_service);
         let incoming_service = ValidatorService::incoming(store.clone(), incoming_service);
-        let incoming_service = RateLimitService::new(store.clone(), incoming_service);
+        let incoming_service = RateLimitService::new(store.clone(), incoming_service)
+            .with_limiter(rate_limiter.clone());
+        rate_limiter.spawn_sync(store.clone());
 
         // Add tracing to track the incoming request details
         #[cfg(feature = "monitoring")]
@@ -501,6 +793,11 @@ impl InterledgerNode {
             .await;
 
         btp_client_service
+            .handle_incoming(incoming_service_btp.clone())
+            .await;
+
+        tcp_service
+            .clone()
             .handle_incoming(incoming_service_btp)
             .await;
 
@@ -530,7 +827,18 @@ impl InterledgerNode {
         if let Some(username) = default_spsp_account {
             api.default_spsp_account(username);
         }
+        api.spsp_config(spsp_config);
         api.node_version(env!("CARGO_PKG_VERSION").to_string());
+        api.pending_echoes(pending_echoes);
+        if let Some(ref guard) = rate_change_guard {
+            api.rate_change_guard(guard.clone());
+        }
+        if !exchange_rate_providers.is_empty() {
+            api.rate_aggregator(rate_aggregator.clone());
+        }
+        if let Some(ref breaker) = circuit_breaker {
+            api.circuit_breaker(breaker.clone());
+        }
 
         cfg_if! {
             if #[cfg(feature = "monitoring")] {
@@ -622,20 +930,53 @@ impl InterledgerNode {
             .boxed();
 
         info!(target: "interledger-node", "Interledger.rs node HTTP API listening on: {}", http_bind_address);
-        spawn(warp::serve(api).bind(http_bind_address));
+        spawn_server(api, &http_bind_address)?;
 
         // Settlement API
         let settlement_api = create_settlements_filter(store.clone(), outgoing_service.clone());
         info!(target: "interledger-node", "Settlement API listening on: {}", settlement_api_bind_address);
-        spawn(warp::serve(settlement_api).bind(settlement_api_bind_address));
+        spawn_server(settlement_api, &settlement_api_bind_address)?;
+
+        // ILP-over-TCP server
+        if let Some(config) = ilp_over_tcp {
+            let mut server = TcpServer::new(tcp_service, store.clone());
+            if let Some(path) = config.tls_identity {
+                let identity = std::fs::read(&path).map_err(|err| {
+                    error!(target: "interledger-node", "Unable to read TLS identity from {}: {}", path, err)
+                })?;
+                let password = config.tls_identity_password.unwrap_or_default();
+                server = server
+                    .tls_identity(&identity, &password)
+                    .map_err(|err| error!(target: "interledger-node", "{}", err))?;
+            }
+            let bind_address = server
+                .bind(config.bind_address)
+                .map_err(|err| error!(target: "interledger-node", "{}", err))
+                .await?;
+            info!(target: "interledger-node", "ILP-over-TCP listening on: {}", bind_address);
+        }
 
         // Exchange Rate Polling
-        if let Some(provider) = exchange_rate_provider {
-            let exchange_rate_fetcher = ExchangeRateFetcher::new(
-                provider,
+        if !exchange_rate_pairs.is_empty() {
+            store.set_pair_rates(exchange_rate_pairs).map_err(
+                |err| error!(target: "interledger-node", "Error setting pair rates: {}", err),
+            )?;
+        }
+        let mut exchange_rate_providers = exchange_rate_providers.into_iter();
+        if let Some(provider) = exchange_rate_providers.next() {
+            let mut exchange_rate_fetcher = ExchangeRateFetcher::new(
+                provider.into_provider(),
                 exchange_rate_poll_failure_tolerance,
                 store.clone(),
-            );
+            )
+            .with_aggregator(rate_aggregator);
+            for provider in exchange_rate_providers {
+                exchange_rate_fetcher =
+                    exchange_rate_fetcher.with_provider(provider.into_provider());
+            }
+            if let Some(guard) = rate_change_guard {
+                exchange_rate_fetcher = exchange_rate_fetcher.with_rate_change_guard(guard);
+            }
             exchange_rate_fetcher
                 .spawn_interval(Duration::from_millis(exchange_rate_poll_interval));
         } else {
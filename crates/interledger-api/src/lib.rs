@@ -3,17 +3,23 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use interledger_btp::{BtpAccount, BtpOutgoingService};
 use interledger_ccp::CcpRoutingAccount;
-use interledger_errors::NodeStoreError;
+use interledger_errors::{NodeStoreError, OutgoingPaymentStoreError};
 use interledger_http::{HttpAccount, HttpStore};
 use interledger_packet::Address;
-use interledger_rates::ExchangeRateStore;
+use interledger_rates::{
+    ExchangeRate, ExchangeRateStore, PairRate, RateAggregator, RateChangeGuard,
+};
 use interledger_router::RouterStore;
 use interledger_service::{
     Account, AccountStore, AddressStore, IncomingService, OutgoingService, Username,
 };
-use interledger_service_util::BalanceStore;
+use interledger_service_util::{BalanceStore, CircuitBreaker, PendingEchoes};
 use interledger_settlement::core::types::{SettlementAccount, SettlementStore};
-use interledger_stream::StreamNotificationsStore;
+use interledger_stream::{
+    InvoiceStore, PaymentAmount, PaymentCheckpoint, SendMoneyOptions, StreamDelivery,
+    StreamNotificationsStore,
+};
+use percent_encoding::percent_decode_str;
 use secrecy::SecretString;
 use serde::{de, Deserialize, Serialize};
 use std::{boxed::*, collections::HashMap, fmt::Display, net::SocketAddr, str::FromStr};
@@ -57,17 +63,6 @@ where
     }
 }
 
-pub fn map_of_number_or_string<'de, D>(deserializer: D) -> Result<HashMap<String, f64>, D::Error>
-where
-    D: de::Deserializer<'de>,
-{
-    #[derive(Deserialize)]
-    struct Wrapper(#[serde(deserialize_with = "number_or_string")] f64);
-
-    let v = HashMap::<String, Wrapper>::deserialize(deserializer)?;
-    Ok(v.into_iter().map(|(k, Wrapper(v))| (k, v)).collect())
-}
-
 // TODO should the methods from this trait be split up and put into the
 // traits that are more specific to what they're doing?
 // One argument against doing that is that the NodeStore allows admin-only
@@ -140,10 +135,93 @@ pub trait NodeStore: Clone + Send + Sync + 'static {
     ) -> Result<Option<Url>, NodeStoreError>;
 }
 
+/// State of an outgoing payment
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutgoingPaymentState {
+    Sending,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Outgoing STREAM payment of an account, stored so that it can be resumed after a restart
+/// and so that retrying the request which started it does not pay twice
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingPayment {
+    pub id: Uuid,
+    /// The account sending the payment
+    pub account_id: Uuid,
+    /// Key given by the client, unique per account
+    pub idempotency_key: Option<String>,
+    /// Payment pointer or SPSP URL of the receiver
+    pub receiver: String,
+    /// Shared secret of the STREAM connection, from the receiver's SPSP response
+    pub shared_secret: Vec<u8>,
+    /// Target amount of the payment
+    pub amount: PaymentAmount,
+    /// Maximum acceptable slippage percentage below calculated minimum exchange rate
+    pub slippage: f64,
+    #[serde(default)]
+    pub options: SendMoneyOptions,
+    pub state: OutgoingPaymentState,
+    /// Amounts sent & delivered so far, and where the connection is at.
+    /// The receipt's `to` is the receiver's ILP address.
+    pub checkpoint: PaymentCheckpoint,
+    /// Why the payment failed
+    pub error: Option<String>,
+}
+
+impl OutgoingPayment {
+    /// Receipt with the amounts sent & delivered so far
+    pub fn receipt(&self) -> &StreamDelivery {
+        &self.checkpoint.receipt
+    }
+}
+
+/// Store for the outgoing payments sent through the API
+#[async_trait]
+pub trait OutgoingPaymentStore: Clone + Send + Sync + 'static {
+    /// Saves a new payment. If the account already has a payment with the same
+    /// idempotency key, nothing is saved and the existing payment is returned instead.
+    async fn insert_outgoing_payment(
+        &self,
+        payment: OutgoingPayment,
+    ) -> Result<OutgoingPayment, OutgoingPaymentStoreError>;
+
+    /// Saves the state and progress of an existing payment
+    async fn update_outgoing_payment(
+        &self,
+        payment: OutgoingPayment,
+    ) -> Result<(), OutgoingPaymentStoreError>;
+
+    /// Loads a payment by its id
+    async fn get_outgoing_payment(
+        &self,
+        id: Uuid,
+    ) -> Result<OutgoingPayment, OutgoingPaymentStoreError>;
+
+    /// Loads the payment the account started with the given idempotency key, if any
+    async fn get_outgoing_payment_by_idempotency_key(
+        &self,
+        account_id: Uuid,
+        idempotency_key: &str,
+    ) -> Result<Option<OutgoingPayment>, OutgoingPaymentStoreError>;
+
+    /// Loads the payments which are still sending, e.g. because they were interrupted by a restart
+    async fn get_unfinished_outgoing_payments(
+        &self,
+    ) -> Result<Vec<OutgoingPayment>, OutgoingPaymentStoreError>;
+}
+
+/// Rates may be given as JSON numbers or, to keep their full precision, as decimal strings
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ExchangeRates(
-    #[serde(deserialize_with = "map_of_number_or_string")] HashMap<String, f64>,
-);
+pub struct ExchangeRates(pub HashMap<String, ExchangeRate>);
+
+/// Direct rates between pairs of assets, keyed by `BASE/QUOTE`. Each rate is either
+/// a single rate or an object with a `bid` and an `ask`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairRates(pub HashMap<String, PairRate>);
 
 /// AccountSettings is a subset of the user parameters defined in
 /// AccountDetails. Its purpose is to allow a user to modify certain of their
@@ -256,18 +334,84 @@ pub struct AccountDetails {
     /// The limit of packets the account can send per minute
     #[serde(default, deserialize_with = "optional_number_or_string")]
     pub packets_per_minute_limit: Option<u32>,
+    /// The maximum amount the account can send at once (defaults to the amount per minute limit)
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub amount_burst_limit: Option<u64>,
+    /// The limit of packets the account can send at once (defaults to the packets per minute limit)
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub packets_burst_limit: Option<u32>,
+    /// The maximum amount the account accepts over a single STREAM connection
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub receive_max_per_connection: Option<u64>,
+    /// The maximum amount the account accepts over STREAM within each receive period
+    /// (counted in the node's memory, so it starts over on restart and is per node)
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub receive_max_per_period: Option<u64>,
+    /// The length of the receive period in seconds (defaults to a day)
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub receive_period: Option<u64>,
+    /// The minimum amount the account accepts in a single STREAM packet
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub receive_min_per_packet: Option<u64>,
     /// The account's settlement engine URL. If a global engine url is configured
     /// for the account's asset code,  that will be used instead (even if the account is
     /// configured with a specific one)
     pub settlement_engine_url: Option<String>,
 }
 
+fn usernames_by_alias<'de, D>(deserializer: D) -> Result<HashMap<String, Username>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    HashMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(alias, username)| {
+            let username = Username::from_str(&username).map_err(de::Error::custom)?;
+            Ok((alias, username))
+        })
+        .collect()
+}
+
+/// How the node serves SPSP queries besides `/accounts/:username/spsp`
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct SpspConfig {
+    /// Prefixes of the paths which resolve to the account with the username that follows
+    /// them, for example `$` makes the Payment Pointer `$example.com/$alice` resolve to the
+    /// account `alice`, and `~` does so for `$example.com/~alice`
+    #[serde(default)]
+    pub path_prefixes: Vec<String>,
+    /// Custom paths (without the leading slash), each resolving to the account with the given username
+    #[serde(default, deserialize_with = "usernames_by_alias")]
+    pub aliases: HashMap<String, Username>,
+    /// Whether SPSP responses include the asset code and scale of the receiving account
+    #[serde(default)]
+    pub include_asset: bool,
+}
+
+impl SpspConfig {
+    /// The username of the account the (percent-encoded) path segment resolves to
+    pub fn resolve_path(&self, segment: &str) -> Option<Username> {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        if let Some(username) = self.aliases.get(segment.as_ref()) {
+            return Some(username.clone());
+        }
+        self.path_prefixes.iter().find_map(|prefix| {
+            if segment.starts_with(prefix.as_str()) {
+                Username::from_str(&segment[prefix.len()..]).ok()
+            } else {
+                None
+            }
+        })
+    }
+}
+
 pub struct NodeApi<S, I, O, B, A: Account> {
     store: S,
     /// The admin's API token, used to make admin-only changes
     // TODO: Make this a SecretString
     admin_api_token: String,
     default_spsp_account: Option<Username>,
+    spsp_config: SpspConfig,
     incoming_handler: I,
     // The outgoing service is included so that the API can send outgoing
     // requests to specific accounts (namely ILDCP requests)
@@ -278,6 +422,12 @@ pub struct NodeApi<S, I, O, B, A: Account> {
     /// Server secret used to instantiate SPSP/Stream connections
     server_secret: Bytes,
     node_version: Option<String>,
+    /// Holds back polled exchange rates which moved too much, until they are confirmed
+    rate_change_guard: Option<RateChangeGuard>,
+    rate_aggregator: Option<RateAggregator>,
+    circuit_breaker: Option<CircuitBreaker>,
+    /// Shared with the node's echo service, which fulfills the responses to the pings
+    pending_echoes: PendingEchoes,
 }
 
 impl<S, I, O, B, A> NodeApi<S, I, O, B, A>
@@ -289,6 +439,8 @@ where
         + BalanceStore
         + SettlementStore<Account = A>
         + StreamNotificationsStore<Account = A>
+        + OutgoingPaymentStore
+        + InvoiceStore
         + RouterStore
         + ExchangeRateStore,
     I: IncomingService<A> + Clone + Send + Sync + 'static,
@@ -316,11 +468,16 @@ where
             store,
             admin_api_token,
             default_spsp_account: None,
+            spsp_config: SpspConfig::default(),
             incoming_handler,
             outgoing_handler,
             btp,
             server_secret,
             node_version: None,
+            rate_change_guard: None,
+            rate_aggregator: None,
+            circuit_breaker: None,
+            pending_echoes: PendingEchoes::default(),
         }
     }
 
@@ -332,6 +489,39 @@ where
         self
     }
 
+    /// Sets the Payment Pointer paths the node serves SPSP at and the extra details
+    /// included in the SPSP responses
+    pub fn spsp_config(&mut self, config: SpspConfig) -> &mut Self {
+        self.spsp_config = config;
+        self
+    }
+
+    /// Exposes the exchange rates held back by the guard, so that they can be
+    /// confirmed or dismissed
+    pub fn rate_change_guard(&mut self, guard: RateChangeGuard) -> &mut Self {
+        self.rate_change_guard = Some(guard);
+        self
+    }
+
+    /// Exposes where the polled exchange rates came from, with `GET /rates?provenance=true`
+    pub fn rate_aggregator(&mut self, aggregator: RateAggregator) -> &mut Self {
+        self.rate_aggregator = Some(aggregator);
+        self
+    }
+
+    /// Exposes the state of the circuits to the outgoing accounts, with `GET /circuits`
+    pub fn circuit_breaker(&mut self, breaker: CircuitBreaker) -> &mut Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// Lets the node's echo service fulfill the responses to the echo requests `POST /ping`
+    /// sends. Without it, only the unidirectional mode gets replies.
+    pub fn pending_echoes(&mut self, pending: PendingEchoes) -> &mut Self {
+        self.pending_echoes = pending;
+        self
+    }
+
     /// Sets the node version
     pub fn node_version(&mut self, version: String) -> &mut Self {
         self.node_version = Some(version);
@@ -341,17 +531,33 @@ where
     /// Returns a Warp Filter which exposes the accounts and admin APIs
     pub fn into_warp_filter(self) -> warp::filters::BoxedFilter<(impl warp::Reply,)> {
         routes::accounts_api(
-            self.server_secret,
+            self.server_secret.clone(),
             self.admin_api_token.clone(),
             self.default_spsp_account,
-            self.incoming_handler,
+            self.spsp_config.clone(),
+            self.incoming_handler.clone(),
             self.outgoing_handler,
             self.btp,
             self.store.clone(),
         )
         .or(routes::node_settings_api(
-            self.admin_api_token,
+            self.admin_api_token.clone(),
             self.node_version,
+            self.rate_change_guard,
+            self.rate_aggregator,
+            self.circuit_breaker,
+            self.store.clone(),
+        ))
+        .or(routes::ping_api(
+            self.admin_api_token,
+            self.incoming_handler,
+            self.pending_echoes,
+            self.store.clone(),
+        ))
+        // Payment Pointer paths may look like any other path, so they are matched last
+        .or(routes::payment_pointers_api(
+            self.server_secret,
+            self.spsp_config,
             self.store,
         ))
         .boxed()
@@ -441,4 +647,21 @@ mod tests {
         );
         assert!(settings.ilp_over_btp_url.is_none());
     }
+
+    #[test]
+    fn resolves_payment_pointer_paths() {
+        let config: SpspConfig = serde_json::from_value(json!({
+            "path_prefixes": ["$", "~"],
+            "aliases": { "shop": "alice" },
+        }))
+        .unwrap();
+        let alice = Username::from_str("alice").unwrap();
+        assert_eq!(config.resolve_path("$alice"), Some(alice.clone()));
+        assert_eq!(config.resolve_path("%24alice"), Some(alice.clone()));
+        assert_eq!(config.resolve_path("~alice"), Some(alice.clone()));
+        assert_eq!(config.resolve_path("shop"), Some(alice));
+        assert_eq!(config.resolve_path("alice"), None);
+        assert_eq!(config.resolve_path("$a"), None);
+        assert!(!config.include_asset);
+    }
 }
@@ -1,4 +1,9 @@
-use crate::{number_or_string, AccountDetails, AccountSettings, NodeStore};
+use super::payment_pointers::spsp_response;
+use crate::{
+    number_or_string, optional_number_or_string, AccountDetails, AccountSettings, NodeStore,
+    OutgoingPayment, OutgoingPaymentState, OutgoingPaymentStore, SpspConfig,
+};
+use async_trait::async_trait;
 use bytes::Bytes;
 use futures::{Future, FutureExt, StreamExt, TryFutureExt};
 use interledger_btp::{connect_to_service_account, BtpAccount, BtpOutgoingService};
@@ -15,16 +20,30 @@ use interledger_service::{
 };
 use interledger_service_util::BalanceStore;
 use interledger_settlement::core::{types::SettlementAccount, SettlementClient};
-use interledger_spsp::{pay, SpspResponder};
-use interledger_stream::{PaymentNotification, StreamNotificationsStore};
+use interledger_spsp::{query, quote};
+use interledger_stream::{
+    resume_payment, CongestionStrategy, Error as StreamError, Invoice, InvoiceStore, PaymentAmount,
+    PaymentCanceller, PaymentCheckpoint, PaymentCheckpointStore, PaymentNotification,
+    SendMoneyOptions, SourceAddressUpdater, StreamDataNotification, StreamDelivery,
+    StreamNotificationsStore,
+};
+use parking_lot::RwLock;
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::Debug;
-use tracing::{debug, error, trace};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::{debug, error, trace, warn};
 use uuid::Uuid;
-use warp::{self, reply::Json, Filter, Rejection};
+use warp::{
+    self,
+    http::{HeaderMap, StatusCode},
+    reply::Json,
+    Filter, Rejection,
+};
 
 pub const BEARER_TOKEN_START: usize = 7;
 
@@ -35,19 +54,239 @@ const fn get_default_max_slippage() -> f64 {
 #[derive(Deserialize, Debug)]
 struct SpspPayRequest {
     receiver: String,
-    #[serde(deserialize_with = "number_or_string")]
-    source_amount: u64,
+    /// Amount to send, in source units
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    source_amount: Option<u64>,
+    /// Amount the receiver should get, in destination units. Exclusive with `source_amount`.
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    destination_amount: Option<u64>,
     #[serde(
         deserialize_with = "number_or_string",
         default = "get_default_max_slippage"
     )]
     slippage: f64,
+    /// How the payment is split into packets, e.g. `{"strategy": "fixed_packet_size", "packet_amount": 100}`
+    #[serde(default)]
+    congestion_control: CongestionStrategy,
+    /// Respond as soon as the payment started, with a resource for following and cancelling it
+    #[serde(default, rename = "async")]
+    asynchronous: bool,
 }
 
+/// Status of an outgoing payment, as returned by `/accounts/:username/payments/:id`
+#[derive(Serialize, Debug, Clone)]
+struct PaymentStatus {
+    id: Uuid,
+    state: OutgoingPaymentState,
+    receipt: StreamDelivery,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<&OutgoingPayment> for PaymentStatus {
+    fn from(payment: &OutgoingPayment) -> Self {
+        PaymentStatus {
+            id: payment.id,
+            state: payment.state,
+            receipt: payment.receipt().clone(),
+            error: payment.error.clone(),
+        }
+    }
+}
+
+/// Payment being sent by this node
+struct RunningPayment {
+    /// The account sending the payment
+    account_id: Uuid,
+    canceller: PaymentCanceller,
+    /// Tells the recipient when the account's address changes
+    source_address: SourceAddressUpdater,
+    /// Changes to `true` once the payment finished and its final state was saved
+    finished: watch::Receiver<bool>,
+}
+
+/// Payments being sent by this node, by payment id
+type RunningPayments = Arc<RwLock<HashMap<Uuid, RunningPayment>>>;
+
+/// Saves the checkpoints of a payment as part of the stored payment
+struct OutgoingPaymentCheckpoints<S> {
+    store: S,
+    payment: OutgoingPayment,
+}
+
+#[async_trait]
+impl<S: OutgoingPaymentStore> PaymentCheckpointStore for OutgoingPaymentCheckpoints<S> {
+    async fn save_payment_checkpoint(
+        &self,
+        checkpoint: PaymentCheckpoint,
+    ) -> Result<(), OutgoingPaymentStoreError> {
+        let mut payment = self.payment.clone();
+        payment.checkpoint = checkpoint;
+        self.store.update_outgoing_payment(payment).await
+    }
+}
+
+/// Send a stored payment in the background, picking up where it left off,
+/// and save its progress to the store until it finishes
+fn spawn_outgoing_payment<I, A, S>(
+    incoming_handler: I,
+    account: A,
+    store: S,
+    mut payment: OutgoingPayment,
+    running: RunningPayments,
+) where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: OutgoingPaymentStore + ExchangeRateStore,
+{
+    let mut handle = resume_payment(
+        incoming_handler,
+        &account,
+        store.clone(),
+        payment.shared_secret.clone(),
+        payment.amount,
+        payment.slippage,
+        payment.options.clone(),
+        payment.checkpoint.clone(),
+        OutgoingPaymentCheckpoints {
+            store: store.clone(),
+            payment: payment.clone(),
+        },
+    );
+    let (finished_tx, finished_rx) = watch::channel(false);
+    running.write().insert(
+        payment.id,
+        RunningPayment {
+            account_id: payment.account_id,
+            canceller: handle.canceller(),
+            source_address: handle.source_address_updater(),
+            finished: finished_rx,
+        },
+    );
+
+    tokio::spawn(async move {
+        // The payment saves its checkpoints itself, before each packet goes out
+        while handle.next_progress().await.is_some() {}
+
+        payment.checkpoint = handle.checkpoint().await;
+        match handle.finish().await {
+            Ok(receipt) => {
+                debug!("Sent SPSP payment {}, receipt: {:?}", payment.id, receipt);
+                payment.state = OutgoingPaymentState::Completed;
+                payment.checkpoint.receipt = receipt;
+            }
+            Err(StreamError::Cancelled(receipt)) => {
+                debug!("Cancelled SPSP payment {}", payment.id);
+                payment.state = OutgoingPaymentState::Cancelled;
+                payment.checkpoint.receipt = *receipt;
+            }
+            Err(err) => {
+                error!("Error sending SPSP payment {}: {}", payment.id, err);
+                payment.state = OutgoingPaymentState::Failed;
+                payment.error = Some(err.to_string());
+            }
+        }
+        if let Err(err) = store.update_outgoing_payment(payment.clone()).await {
+            error!("Unable to save payment {}: {}", payment.id, err);
+        }
+
+        running.write().remove(&payment.id);
+        let _ = finished_tx.broadcast(true);
+    });
+}
+
+/// Resume the payments which were still sending when the node stopped. Their receivers are
+/// asked how much they got first, since the saved checkpoints may be older than that.
+async fn resume_outgoing_payments<I, A, S>(incoming_handler: I, store: S, running: RunningPayments)
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: OutgoingPaymentStore + AccountStore<Account = A> + ExchangeRateStore,
+{
+    let payments = match store.get_unfinished_outgoing_payments().await {
+        Ok(payments) => payments,
+        Err(err) => {
+            error!("Unable to load unfinished payments: {}", err);
+            return;
+        }
+    };
+    for mut payment in payments {
+        match store.get_accounts(vec![payment.account_id]).await {
+            Ok(mut accounts) => {
+                debug!("Resuming SPSP payment {}", payment.id);
+                spawn_outgoing_payment(
+                    incoming_handler.clone(),
+                    accounts.remove(0),
+                    store.clone(),
+                    payment,
+                    running.clone(),
+                );
+            }
+            Err(err) => {
+                error!("Unable to resume SPSP payment {}: {}", payment.id, err);
+                payment.state = OutgoingPaymentState::Failed;
+                payment.error = Some(err.to_string());
+                if let Err(err) = store.update_outgoing_payment(payment).await {
+                    error!("Unable to save payment: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Tell the recipients of the running payments about the current addresses of the
+/// accounts sending them, e.g. after our node got a new address from its parent
+async fn announce_source_addresses<A, S>(store: &S, running: &RunningPayments)
+where
+    A: Account,
+    S: AccountStore<Account = A>,
+{
+    let payments: Vec<(Uuid, SourceAddressUpdater)> = running
+        .read()
+        .values()
+        .map(|payment| (payment.account_id, payment.source_address.clone()))
+        .collect();
+    for (account_id, source_address) in payments {
+        match store.get_accounts(vec![account_id]).await {
+            Ok(mut accounts) => {
+                source_address
+                    .update(accounts.remove(0).ilp_address().clone())
+                    .await
+            }
+            Err(err) => warn!(
+                "Unable to load account {} to update its payments' address: {}",
+                account_id, err
+            ),
+        }
+    }
+}
+
+/// Wait until a running payment finished
+async fn wait_until_finished(mut finished: watch::Receiver<bool>) {
+    while let Some(false) = finished.recv().await {}
+}
+
+#[derive(Deserialize, Debug)]
+struct InvoiceRequest {
+    #[serde(deserialize_with = "number_or_string")]
+    amount: u64,
+    #[serde(default)]
+    metadata: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct SpspQuoteRequest {
+    receiver: String,
+    #[serde(deserialize_with = "number_or_string")]
+    source_amount: u64,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn accounts_api<I, O, S, A, B>(
     server_secret: Bytes,
     admin_api_token: String,
     default_spsp_account: Option<Username>,
+    spsp_config: SpspConfig,
     incoming_handler: I,
     outgoing_handler: O,
     btp: BtpOutgoingService<B, A>,
@@ -63,6 +302,8 @@ where
         + HttpStore<Account = A>
         + BalanceStore
         + StreamNotificationsStore<Account = A>
+        + OutgoingPaymentStore
+        + InvoiceStore
         + ExchangeRateStore
         + RouterStore,
     A: BtpAccount
@@ -75,6 +316,15 @@ where
         + Sync
         + 'static,
 {
+    // Resume the payments which were interrupted by a restart
+    let running_payments = RunningPayments::default();
+    tokio::spawn(resume_outgoing_payments(
+        incoming_handler.clone(),
+        store.clone(),
+        running_payments.clone(),
+    ));
+    let with_running_payments = warp::any().map(move || running_payments.clone());
+
     // TODO can we make any of the Filters const or put them in once_cell?
     let with_store = warp::any().map(move || store.clone());
     let with_incoming_handler = warp::any().map(move || incoming_handler.clone());
@@ -172,17 +422,27 @@ where
         .and(admin_only.clone())
         .and(deserialize_json()) // Why does warp::body::json not work?
         .and(with_store.clone())
-        .and_then(move |account_details: AccountDetails, store: S| {
-            let store_clone = store.clone();
-            let handler = outgoing_handler_clone.clone();
-            let btp = btp_clone.clone();
-            async move {
-                let account = store.insert_account(account_details.clone()).await?;
+        .and(with_running_payments.clone())
+        .and_then(
+            move |account_details: AccountDetails, store: S, running: RunningPayments| {
+                let store_clone = store.clone();
+                let handler = outgoing_handler_clone.clone();
+                let btp = btp_clone.clone();
+                async move {
+                    let account = store.insert_account(account_details.clone()).await?;
 
-                connect_to_external_services(handler, account.clone(), store_clone, btp).await?;
-                Ok::<Json, Rejection>(warp::reply::json(&account))
-            }
-        });
+                    connect_to_external_services(
+                        handler,
+                        account.clone(),
+                        store_clone,
+                        btp,
+                        running,
+                    )
+                    .await?;
+                    Ok::<Json, Rejection>(warp::reply::json(&account))
+                }
+            },
+        );
 
     // GET /accounts
     let get_accounts = warp::get()
@@ -205,23 +465,33 @@ where
         .and(admin_only.clone())
         .and(deserialize_json()) // warp::body::json() is not able to decode this!
         .and(with_store.clone())
-        .and_then(move |id: Uuid, account_details: AccountDetails, store: S| {
-            let outgoing_handler = outgoing_handler_clone.clone();
-            let btp = btp_clone.clone();
-            if account_details.ilp_over_btp_incoming_token.is_some() {
-                // if the BTP token was provided, assume that it's different
-                // from the existing one and drop the connection
-                // the saved websocket connection
-                // a new one will be initialized in the `connect_to_external_services` call
-                btp.close_connection(&id);
-            }
-            async move {
-                let account = store.update_account(id, account_details).await?;
-                connect_to_external_services(outgoing_handler, account.clone(), store, btp).await?;
+        .and(with_running_payments.clone())
+        .and_then(
+            move |id: Uuid, account_details: AccountDetails, store: S, running: RunningPayments| {
+                let outgoing_handler = outgoing_handler_clone.clone();
+                let btp = btp_clone.clone();
+                if account_details.ilp_over_btp_incoming_token.is_some() {
+                    // if the BTP token was provided, assume that it's different
+                    // from the existing one and drop the connection
+                    // the saved websocket connection
+                    // a new one will be initialized in the `connect_to_external_services` call
+                    btp.close_connection(&id);
+                }
+                async move {
+                    let account = store.update_account(id, account_details).await?;
+                    connect_to_external_services(
+                        outgoing_handler,
+                        account.clone(),
+                        store,
+                        btp,
+                        running,
+                    )
+                    .await?;
 
-                Ok::<Json, Rejection>(warp::reply::json(&account))
-            }
-        });
+                    Ok::<Json, Rejection>(warp::reply::json(&account))
+                }
+            },
+        );
 
     // GET /accounts/:username
     let get_account = warp::get()
@@ -289,34 +559,38 @@ where
         .and(warp::path::end())
         .and(deserialize_json())
         .and(with_store.clone())
-        .and_then(move |id: Uuid, settings: AccountSettings, store: S| {
-            let btp = btp.clone();
-            let outgoing_handler = outgoing_handler_clone.clone();
-            async move {
-                if settings.ilp_over_btp_incoming_token.is_some() {
-                    // if the BTP token was provided, assume that it's different
-                    // from the existing one and drop the connection
-                    // the saved websocket connection
-                    btp.close_connection(&id);
+        .and(with_running_payments.clone())
+        .and_then(
+            move |id: Uuid, settings: AccountSettings, store: S, running: RunningPayments| {
+                let btp = btp.clone();
+                let outgoing_handler = outgoing_handler_clone.clone();
+                async move {
+                    if settings.ilp_over_btp_incoming_token.is_some() {
+                        // if the BTP token was provided, assume that it's different
+                        // from the existing one and drop the connection
+                        // the saved websocket connection
+                        btp.close_connection(&id);
+                    }
+                    let modified_account = store.modify_account_settings(id, settings).await?;
+
+                    // Since the account was modified, we should also try to
+                    // connect to the new account:
+                    connect_to_external_services(
+                        outgoing_handler,
+                        modified_account.clone(),
+                        store,
+                        btp,
+                        running,
+                    )
+                    .await?;
+                    Ok::<Json, Rejection>(warp::reply::json(&modified_account))
                 }
-                let modified_account = store.modify_account_settings(id, settings).await?;
-
-                // Since the account was modified, we should also try to
-                // connect to the new account:
-                connect_to_external_services(
-                    outgoing_handler,
-                    modified_account.clone(),
-                    store,
-                    btp,
-                )
-                .await?;
-                Ok::<Json, Rejection>(warp::reply::json(&modified_account))
-            }
-        });
+            },
+        );
 
     // (Websocket) /accounts/:username/payments/incoming
     let incoming_payment_notifications = warp::path("accounts")
-        .and(admin_or_authorized_user_only)
+        .and(admin_or_authorized_user_only.clone())
         .and(warp::path("payments"))
         .and(warp::path("incoming"))
         .and(warp::path::end())
@@ -330,6 +604,24 @@ where
             })
         });
 
+    // (Websocket) /accounts/:username/data/incoming
+    let incoming_data_notifications = warp::path("accounts")
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("data"))
+        .and(warp::path("incoming"))
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(with_store.clone())
+        .map(|id: Uuid, ws: warp::ws::Ws, store: S| {
+            ws.on_upgrade(move |ws: warp::ws::WebSocket| {
+                let (ws_tx, ws_rx) = ws.split();
+                tokio::task::spawn(
+                    notify_user_data(ws_tx, id, store).map(|result| result.unwrap()),
+                );
+                consume_msg_drain(ws_rx)
+            })
+        });
+
     // (Websocket) /payments/incoming
     let all_payment_notifications = warp::path("payments")
         .and(admin_only)
@@ -345,59 +637,308 @@ where
             })
         });
 
-    // POST /accounts/:username/payments
+    // POST /accounts/:username/payments (optional idempotency-key header)
     let post_payments = warp::post()
         .and(warp::path("accounts"))
-        .and(authorized_user_only)
+        .and(authorized_user_only.clone())
         .and(warp::path("payments"))
         .and(warp::path::end())
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(deserialize_json())
+        .and(with_incoming_handler.clone())
+        .and(with_store.clone())
+        .and(with_running_payments.clone())
+        .and_then(
+            move |account: A,
+                  idempotency_key: Option<String>,
+                  pay_request: SpspPayRequest,
+                  incoming_handler: I,
+                  store: S,
+                  running: RunningPayments| {
+                async move {
+                    let amount = match (pay_request.source_amount, pay_request.destination_amount) {
+                        (Some(source_amount), None) => PaymentAmount::FixedSource(source_amount),
+                        (None, Some(destination_amount)) => {
+                            PaymentAmount::FixedDelivery(destination_amount)
+                        }
+                        _ => {
+                            return Err(Rejection::from(ApiError::bad_request().detail(
+                                "Exactly one of source_amount and destination_amount must be given",
+                            )))
+                        }
+                    };
+
+                    let existing = match &idempotency_key {
+                        Some(key) => {
+                            store
+                                .get_outgoing_payment_by_idempotency_key(account.id(), key)
+                                .await?
+                        }
+                        None => None,
+                    };
+                    let payment = match existing {
+                        Some(payment) => payment,
+                        None => {
+                            let spsp = query(&pay_request.receiver).await.map_err(|err| {
+                                let msg = format!("Error sending SPSP payment: {}", err);
+                                error!("{}", msg);
+                                Rejection::from(ApiError::internal_server_error().detail(msg))
+                            })?;
+                            let mut receipt = StreamDelivery::new(
+                                &account,
+                                spsp.destination_account().clone(),
+                                pay_request.source_amount.unwrap_or(0),
+                            );
+                            receipt.destination_amount = pay_request.destination_amount;
+                            let payment = OutgoingPayment {
+                                id: Uuid::new_v4(),
+                                account_id: account.id(),
+                                idempotency_key,
+                                receiver: pay_request.receiver.clone(),
+                                shared_secret: spsp.shared_secret().to_vec(),
+                                amount,
+                                slippage: pay_request.slippage,
+                                options: SendMoneyOptions {
+                                    congestion_control: pay_request.congestion_control,
+                                },
+                                state: OutgoingPaymentState::Sending,
+                                checkpoint: PaymentCheckpoint {
+                                    receipt,
+                                    next_sequence: 1,
+                                },
+                                error: None,
+                            };
+                            let id = payment.id;
+                            // Another request with the same idempotency key may have been first
+                            let payment = store.insert_outgoing_payment(payment).await?;
+                            if payment.id == id {
+                                debug!("Starting SPSP payment {}", id);
+                                spawn_outgoing_payment(
+                                    incoming_handler,
+                                    account,
+                                    store.clone(),
+                                    payment.clone(),
+                                    running.clone(),
+                                );
+                            }
+                            payment
+                        }
+                    };
+                    if payment.receiver != pay_request.receiver || payment.amount != amount {
+                        return Err(Rejection::from(ApiError::idempotency_conflict().detail(
+                            "A different payment was already sent with this idempotency key",
+                        )));
+                    }
+
+                    if pay_request.asynchronous {
+                        return Ok::<_, Rejection>(warp::reply::with_status(
+                            warp::reply::json(&PaymentStatus::from(&payment)),
+                            StatusCode::ACCEPTED,
+                        ));
+                    }
+
+                    let finished = running
+                        .read()
+                        .get(&payment.id)
+                        .map(|running| running.finished.clone());
+                    if let Some(finished) = finished {
+                        wait_until_finished(finished).await;
+                    }
+                    let payment = store.get_outgoing_payment(payment.id).await?;
+                    match payment.state {
+                        OutgoingPaymentState::Completed => Ok(warp::reply::with_status(
+                            warp::reply::json(&json!(payment.receipt())),
+                            StatusCode::OK,
+                        )),
+                        // The payment is about to be resumed after a restart
+                        OutgoingPaymentState::Sending => Ok(warp::reply::with_status(
+                            warp::reply::json(&PaymentStatus::from(&payment)),
+                            StatusCode::ACCEPTED,
+                        )),
+                        OutgoingPaymentState::Failed | OutgoingPaymentState::Cancelled => {
+                            let msg = format!(
+                                "Error sending SPSP payment: {}",
+                                payment
+                                    .error
+                                    .as_deref()
+                                    .unwrap_or("the payment was cancelled")
+                            );
+                            // TODO give a different error message depending on what type of error it is
+                            Err(Rejection::from(
+                                ApiError::internal_server_error().detail(msg),
+                            ))
+                        }
+                    }
+                }
+            },
+        );
+
+    // GET /accounts/:username/payments/:id
+    let get_payment = warp::get()
+        .and(warp::path("accounts"))
+        .and(authorized_user_only.clone())
+        .and(warp::path("payments"))
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path::end())
+        .and(with_store.clone())
+        .and_then(move |account: A, id: Uuid, store: S| async move {
+            let payment = store.get_outgoing_payment(id).await?;
+            if payment.account_id != account.id() {
+                return Err(Rejection::from(
+                    ApiError::not_found().detail("payment not found"),
+                ));
+            }
+            Ok::<Json, Rejection>(warp::reply::json(&PaymentStatus::from(&payment)))
+        });
+
+    // DELETE /accounts/:username/payments/:id
+    let delete_payment = warp::delete()
+        .and(warp::path("accounts"))
+        .and(authorized_user_only.clone())
+        .and(warp::path("payments"))
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path::end())
+        .and(with_store.clone())
+        .and(with_running_payments)
+        .and_then(
+            move |account: A, id: Uuid, store: S, running: RunningPayments| async move {
+                let payment = store.get_outgoing_payment(id).await?;
+                if payment.account_id != account.id() {
+                    return Err(Rejection::from(
+                        ApiError::not_found().detail("payment not found"),
+                    ));
+                }
+                // The payment stops once the packets in flight are done
+                if let Some(running) = running.read().get(&id) {
+                    debug!("Cancelling SPSP payment {}", id);
+                    running.canceller.cancel();
+                }
+                Ok::<Json, Rejection>(warp::reply::json(&PaymentStatus::from(&payment)))
+            },
+        );
+
+    // POST /accounts/:username/quotes
+    let post_quotes = warp::post()
+        .and(warp::path("accounts"))
+        .and(authorized_user_only)
+        .and(warp::path("quotes"))
+        .and(warp::path::end())
         .and(deserialize_json())
         .and(with_incoming_handler)
+        .and_then(
+            move |account: A, quote_request: SpspQuoteRequest, incoming_handler: I| async move {
+                let quote = quote(
+                    incoming_handler,
+                    account,
+                    &quote_request.receiver,
+                    quote_request.source_amount,
+                )
+                .map_err(|err| {
+                    let msg = format!("Error quoting SPSP payment: {}", err);
+                    error!("{}", msg);
+                    Rejection::from(ApiError::internal_server_error().detail(msg))
+                })
+                .await?;
+
+                debug!("Quoted SPSP payment: {:?}", quote);
+                Ok::<Json, Rejection>(warp::reply::json(&json!(quote)))
+            },
+        );
+
+    // POST /accounts/:username/invoices
+    let post_invoices = warp::post()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("invoices"))
+        .and(warp::path::end())
+        .and(deserialize_json())
+        .and(with_store.clone())
+        .and_then(
+            |account_id: Uuid, invoice_request: InvoiceRequest, store: S| async move {
+                let invoice =
+                    Invoice::new(account_id, invoice_request.amount, invoice_request.metadata);
+                store.insert_invoice(invoice.clone()).await?;
+                debug!("Created invoice {} for {}", invoice.id, invoice.amount);
+                Ok::<Json, Rejection>(warp::reply::json(&invoice))
+            },
+        );
+
+    // GET /accounts/:username/invoices/:id
+    let get_invoice = warp::get()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only)
+        .and(warp::path("invoices"))
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path::end())
+        .and(with_store.clone())
+        .and_then(|account_id: Uuid, id: Uuid, store: S| async move {
+            let invoice = store.get_invoice(id).await?;
+            if invoice.account_id != account_id {
+                return Err(Rejection::from(
+                    ApiError::not_found().detail("invoice not found"),
+                ));
+            }
+            Ok::<Json, Rejection>(warp::reply::json(&invoice))
+        });
+
+    // GET /accounts/:username/invoices/:id/spsp
+    // Connections generated here are tagged with the invoice ID, so the
+    // money received over them pays the invoice
+    let server_secret_clone = server_secret.clone();
+    let spsp_config_clone = spsp_config.clone();
+    let get_invoice_spsp = warp::get()
+        .and(warp::path("accounts"))
+        .and(account_username_to_id.clone())
+        .and(warp::path("invoices"))
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path("spsp"))
+        .and(warp::path::end())
+        .and(warp::header::headers_cloned())
         .and(with_store.clone())
         .and_then(
-            move |account: A, pay_request: SpspPayRequest, incoming_handler: I, store: S| {
+            move |account_id: Uuid, id: Uuid, headers: HeaderMap, store: S| {
+                let server_secret_clone = server_secret_clone.clone();
+                let spsp_config_clone = spsp_config_clone.clone();
                 async move {
-                    let receipt = pay(
-                        incoming_handler,
-                        account.clone(),
-                        store,
-                        &pay_request.receiver,
-                        pay_request.source_amount,
-                        pay_request.slippage,
+                    let invoice = store.get_invoice(id).await?;
+                    if invoice.account_id != account_id {
+                        return Err(Rejection::from(
+                            ApiError::not_found().detail("invoice not found"),
+                        ));
+                    }
+                    let accounts = store.get_accounts(vec![account_id]).await?;
+                    spsp_response(
+                        &accounts[0],
+                        server_secret_clone,
+                        &spsp_config_clone,
+                        &headers,
+                        Some(&invoice.connection_tag()),
                     )
-                    .map_err(|err| {
-                        let msg = format!("Error sending SPSP payment: {}", err);
-                        error!("{}", msg);
-                        // TODO give a different error message depending on what type of error it is
-                        Rejection::from(ApiError::internal_server_error().detail(msg))
-                    })
-                    .await?;
-
-                    debug!("Sent SPSP payment, receipt: {:?}", receipt);
-                    Ok::<Json, Rejection>(warp::reply::json(&json!(receipt)))
                 }
             },
         );
 
     // GET /accounts/:username/spsp
     let server_secret_clone = server_secret.clone();
+    let spsp_config_clone = spsp_config.clone();
     let get_spsp = warp::get()
         .and(warp::path("accounts"))
         .and(account_username_to_id)
         .and(warp::path("spsp"))
         .and(warp::path::end())
+        .and(warp::header::headers_cloned())
         .and(with_store.clone())
-        .and_then(move |id: Uuid, store: S| {
+        .and_then(move |id: Uuid, headers: HeaderMap, store: S| {
             let server_secret_clone = server_secret_clone.clone();
+            let spsp_config_clone = spsp_config_clone.clone();
             async move {
                 let accounts = store.get_accounts(vec![id]).await?;
-                // TODO return the response without instantiating an SpspResponder (use a simple fn)
-                Ok::<_, Rejection>(
-                    SpspResponder::new(
-                        accounts[0].ilp_address().clone(),
-                        server_secret_clone.clone(),
-                    )
-                    .generate_http_response(),
+                spsp_response(
+                    &accounts[0],
+                    server_secret_clone,
+                    &spsp_config_clone,
+                    &headers,
+                    None,
                 )
             }
         });
@@ -409,10 +950,12 @@ where
         .and(warp::path(".well-known"))
         .and(warp::path("pay"))
         .and(warp::path::end())
+        .and(warp::header::headers_cloned())
         .and(with_store)
-        .and_then(move |store: S| {
+        .and_then(move |headers: HeaderMap, store: S| {
             let default_spsp_account = default_spsp_account.clone();
             let server_secret_clone = server_secret.clone();
+            let spsp_config_clone = spsp_config.clone();
             async move {
                 if let Some(ref username) = default_spsp_account {
                     let id = store.get_account_id_from_username(&username).await?;
@@ -421,13 +964,12 @@ where
                     let mut accounts = store.get_accounts(vec![id]).await?;
 
                     let account = accounts.pop().unwrap();
-                    // TODO return the response without instantiating an SpspResponder (use a simple fn)
-                    Ok::<_, Rejection>(
-                        SpspResponder::new(
-                            account.ilp_address().clone(),
-                            server_secret_clone.clone(),
-                        )
-                        .generate_http_response(),
+                    spsp_response(
+                        &account,
+                        server_secret_clone,
+                        &spsp_config_clone,
+                        &headers,
+                        None,
                     )
                 } else {
                     Err(Rejection::from(
@@ -447,8 +989,15 @@ where
         .or(get_account_balance)
         .or(put_account_settings)
         .or(incoming_payment_notifications)
+        .or(incoming_data_notifications)
         .or(all_payment_notifications)
         .or(post_payments)
+        .or(get_payment)
+        .or(delete_payment)
+        .or(post_quotes)
+        .or(post_invoices)
+        .or(get_invoice)
+        .or(get_invoice_spsp)
 }
 
 async fn consume_msg_drain(mut ws_rx: futures::stream::SplitStream<warp::ws::WebSocket>) {
@@ -486,6 +1035,29 @@ fn notify_user(
         .then(futures::future::ok)
 }
 
+// Similar to notify_user, but forwards the data received over STREAM instead of payments
+fn notify_user_data(
+    ws_tx: futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>,
+    id: Uuid,
+    store: impl StreamNotificationsStore,
+) -> impl Future<Output = Result<(), ()>> {
+    let (tx, rx) = futures::channel::mpsc::unbounded::<StreamDataNotification>();
+    store.add_stream_data_subscription(id, tx);
+
+    let rx = rx.map(|notification: StreamDataNotification| {
+        let msg = warp::ws::Message::text(serde_json::to_string(&notification).unwrap());
+        Ok(msg)
+    });
+
+    rx.forward(ws_tx)
+        .map(|result| {
+            if let Err(e) = result {
+                eprintln!("websocket send error: {}", e);
+            }
+        })
+        .then(futures::future::ok)
+}
+
 // Similar to notify_user, but instead of associating an account Uuid with a sender,
 // it only assumes control of the store's all payment notification receiver; its messages
 // are published alongside account-specific notifications and the dedicated thread
@@ -513,11 +1085,18 @@ async fn get_address_from_parent_and_update_routes<O, A, S>(
     mut service: O,
     parent: A,
     store: S,
+    running: RunningPayments,
 ) -> Result<(), warp::Rejection>
 where
     O: OutgoingService<A> + Clone + Send + Sync + 'static,
     A: CcpRoutingAccount + Clone + Send + Sync + 'static,
-    S: NodeStore<Account = A> + AddressStore + Clone + Send + Sync + 'static,
+    S: NodeStore<Account = A>
+        + AccountStore<Account = A>
+        + AddressStore
+        + Clone
+        + Send
+        + Sync
+        + 'static,
 {
     debug!(
         "Getting ILP address from parent account: {} (id: {})",
@@ -565,6 +1144,8 @@ where
     store.set_default_route(parent.id()).await?;
     // Update our store's address
     store.set_ilp_address(ilp_address).await?;
+    // The accounts' addresses changed with it, so let the recipients of their payments know
+    announce_source_addresses(&store, &running).await;
 
     // Get the parent's routes for us
     debug!("Asking for routes from {:?}", parent.clone());
@@ -600,11 +1181,19 @@ async fn connect_to_external_services<O, A, S, B>(
     account: A,
     store: S,
     btp: BtpOutgoingService<B, A>,
+    running: RunningPayments,
 ) -> Result<A, warp::reject::Rejection>
 where
     O: OutgoingService<A> + Clone + Send + Sync + 'static,
     A: CcpRoutingAccount + BtpAccount + SettlementAccount + Clone + Send + Sync + 'static,
-    S: NodeStore<Account = A> + AddressStore + BalanceStore + Clone + Send + Sync + 'static,
+    S: NodeStore<Account = A>
+        + AccountStore<Account = A>
+        + AddressStore
+        + BalanceStore
+        + Clone
+        + Send
+        + Sync
+        + 'static,
     B: OutgoingService<A> + Clone + 'static,
 {
     // Try to connect to the account's BTP socket if they have
@@ -617,7 +1206,8 @@ where
     // If we added a parent, get the address assigned to us by
     // them and update all of our routes
     if account.routing_relation() == RoutingRelation::Parent {
-        get_address_from_parent_and_update_routes(service, account.clone(), store.clone()).await?;
+        get_address_from_parent_and_update_routes(service, account.clone(), store.clone(), running)
+            .await?;
     }
 
     // Register the account with the settlement engine
@@ -678,7 +1268,7 @@ where
 #[cfg(test)]
 mod tests {
     use crate::routes::test_helpers::*;
-    // TODO: Add test for GET /accounts/:username/spsp and /.well_known
+    // TODO: Add test for /.well_known
 
     #[tokio::test]
     async fn only_admin_can_create_account() {
@@ -824,4 +1414,111 @@ mod tests {
         .await;
         assert_eq!(resp.status().as_u16(), 401);
     }
+
+    #[tokio::test]
+    async fn only_user_can_request_quote() {
+        let quote_request: Option<serde_json::Value> = Some(serde_json::json!({
+            "receiver": "some_receiver",
+            "source_amount" : "10",
+        }));
+        let api = test_accounts_api();
+        let resp = api_call(
+            &api,
+            "POST",
+            "/accounts/alice/quotes",
+            "password",
+            quote_request.clone(),
+        )
+        .await;
+        // The receiver cannot be queried, but the request got through
+        assert_eq!(resp.status().as_u16(), 500);
+
+        let resp = api_call(
+            &api,
+            "POST",
+            "/accounts/alice/quotes",
+            "admin",
+            quote_request,
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn only_user_can_get_or_cancel_payment() {
+        let api = test_accounts_api();
+        let path = format!("/accounts/alice/payments/{}", uuid::Uuid::new_v4());
+        for method in &["GET", "DELETE"] {
+            // The payment does not exist, but the request got through
+            let resp = api_call(&api, method, &path, "password", None).await;
+            assert_eq!(resp.status().as_u16(), 404);
+
+            let resp = api_call(&api, method, &path, "admin", None).await;
+            assert_eq!(resp.status().as_u16(), 401);
+        }
+    }
+    #[tokio::test]
+    async fn creates_invoices() {
+        let api = test_accounts_api();
+        let invoice = serde_json::json!({ "amount": "1000", "metadata": { "order": "1234" } });
+        for auth in &["password", "admin"] {
+            let resp = api_call(
+                &api,
+                "POST",
+                "/accounts/alice/invoices",
+                auth,
+                Some(invoice.clone()),
+            )
+            .await;
+            assert_eq!(resp.status().as_u16(), 200);
+            let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+            assert_eq!(body["amount"], 1000);
+            assert_eq!(body["received"], 0);
+            assert_eq!(body["paid"], false);
+            assert_eq!(body["metadata"]["order"], "1234");
+        }
+
+        let resp = api_call(
+            &api,
+            "POST",
+            "/accounts/alice/invoices",
+            "wrong",
+            Some(invoice),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 401);
+
+        let path = format!("/accounts/alice/invoices/{}/spsp", uuid::Uuid::new_v4());
+        let resp = api_call(&api, "GET", &path, "", None).await;
+        assert_eq!(resp.status().as_u16(), 404);
+    }
+
+    #[tokio::test]
+    async fn serves_spsp_with_asset_details() {
+        let api = test_accounts_api();
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/accounts/alice/spsp")
+            .header("Accept", "application/spsp4+json")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "application/spsp4+json"
+        );
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(
+            body["asset"],
+            serde_json::json!({ "code": "XYZ", "scale": 9 })
+        );
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/accounts/alice/spsp")
+            .header("Accept", "text/html")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status().as_u16(), 406);
+    }
 }
@@ -1,8 +1,39 @@
 mod accounts;
 mod node_settings;
+mod payment_pointers;
+mod ping;
 
 pub use accounts::accounts_api;
 pub use node_settings::node_settings_api;
+pub use payment_pointers::payment_pointers_api;
+pub use ping::ping_api;
+
+use interledger_errors::ApiError;
+use secrecy::{ExposeSecret, SecretString};
+use warp::{Filter, Rejection};
+
+/// Rejects requests which are not authorized with the admin's API token
+pub(crate) fn admin_only(
+    admin_api_token: &str,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let admin_auth_header = format!("Bearer {}", admin_api_token);
+    warp::header::<SecretString>("authorization")
+        .and_then(move |authorization: SecretString| {
+            let admin_auth_header = admin_auth_header.clone();
+            async move {
+                if authorization.expose_secret() == &admin_auth_header {
+                    Ok::<(), Rejection>(())
+                } else {
+                    Err(Rejection::from(
+                        ApiError::unauthorized().detail("invalid admin auth token provided"),
+                    ))
+                }
+            }
+        })
+        // This call makes it so we do not pass on a () value on
+        // success to the next filter, it just gets rid of it
+        .untuple_one()
+}
 
 #[cfg(test)]
 pub mod test_helpers;
@@ -1,15 +1,18 @@
-use crate::{ExchangeRates, NodeStore};
+use super::admin_only;
+use crate::{ExchangeRates, NodeStore, PairRates};
 use bytes::Bytes;
 use futures::TryFutureExt;
 use interledger_errors::*;
 use interledger_http::{deserialize_json, HttpAccount};
 use interledger_packet::Address;
-use interledger_rates::ExchangeRateStore;
+use interledger_rates::{
+    parse_pair, ExchangeRate, ExchangeRateStore, RateAggregator, RateChangeGuard, RateSource,
+};
 use interledger_router::RouterStore;
 use interledger_service::{Account, AccountStore, AddressStore, Username};
+use interledger_service_util::CircuitBreaker;
 use interledger_settlement::core::{types::SettlementAccount, SettlementClient};
-use secrecy::{ExposeSecret, SecretString};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     str::{self, FromStr},
@@ -28,9 +31,29 @@ struct StatusResponse {
     version: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct RatesQuery {
+    #[serde(default)]
+    provenance: bool,
+}
+
+/// A rate of `GET /rates?provenance=true`. Rates which were not polled from
+/// providers, e.g. those set via the API, have no sources.
+#[derive(Serialize)]
+struct RateWithProvenance {
+    rate: ExchangeRate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sources: Option<Vec<RateSource>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fetched_at: Option<u64>,
+}
+
 pub fn node_settings_api<S, A>(
     admin_api_token: String,
     node_version: Option<String>,
+    rate_change_guard: Option<RateChangeGuard>,
+    rate_aggregator: Option<RateAggregator>,
+    circuit_breaker: Option<CircuitBreaker>,
     store: S,
 ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
 where
@@ -42,24 +65,9 @@ where
     A: Account + HttpAccount + Send + Sync + SettlementAccount + Serialize + 'static,
 {
     // Helper filters
-    let admin_auth_header = format!("Bearer {}", admin_api_token);
-    let admin_only = warp::header::<SecretString>("authorization")
-        .and_then(move |authorization: SecretString| {
-            let admin_auth_header = admin_auth_header.clone();
-            async move {
-                if authorization.expose_secret() == &admin_auth_header {
-                    Ok::<(), Rejection>(())
-                } else {
-                    Err(Rejection::from(
-                        ApiError::unauthorized().detail("invalid admin auth token provided"),
-                    ))
-                }
-            }
-        })
-        // This call makes it so we do not pass on a () value on
-        // success to the next filter, it just gets rid of it
-        .untuple_one();
+    let admin_only = admin_only(&admin_api_token);
     let with_store = warp::any().map(move || store.clone());
+    let with_rate_change_guard = warp::any().map(move || rate_change_guard.clone());
 
     // GET /
     let get_root = warp::get()
@@ -86,13 +94,216 @@ where
         });
 
     // GET /rates
+    // With ?provenance=true, each rate also has the providers it was aggregated from
     let get_rates = warp::get()
         .and(warp::path("rates"))
         .and(warp::path::end())
+        .and(warp::query::<RatesQuery>())
+        .and(with_store.clone())
+        .and_then(move |query: RatesQuery, store: S| {
+            let rate_aggregator = rate_aggregator.clone();
+            async move {
+                let rates = store.get_all_exchange_rates()?;
+                if !query.provenance {
+                    return Ok::<_, Rejection>(warp::reply::json(&rates));
+                }
+                let mut provenance = rate_aggregator
+                    .map(|aggregator| aggregator.provenance())
+                    .unwrap_or_default();
+                let rates: HashMap<String, RateWithProvenance> = rates
+                    .into_iter()
+                    .map(|(asset_code, rate)| {
+                        let provenance = provenance.remove(&asset_code);
+                        let rate = RateWithProvenance {
+                            rate,
+                            fetched_at: provenance.as_ref().map(|p| p.fetched_at),
+                            sources: provenance.map(|p| p.sources),
+                        };
+                        (asset_code, rate)
+                    })
+                    .collect();
+                Ok(warp::reply::json(&rates))
+            }
+        });
+
+    // PUT /rates/pairs
+    // Replaces the direct rates between pairs of assets
+    let put_pair_rates = warp::put()
+        .and(warp::path("rates"))
+        .and(warp::path("pairs"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(deserialize_json())
+        .and(with_store.clone())
+        .and_then(|pair_rates: PairRates, store: S| async move {
+            if let Some(pair) = pair_rates.0.keys().find(|pair| parse_pair(pair).is_none()) {
+                return Err(Rejection::from(
+                    ApiError::bad_request()
+                        .detail(format!("Invalid pair {}, expected BASE/QUOTE", pair)),
+                ));
+            }
+            store.set_pair_rates(pair_rates.0.clone())?;
+            Ok::<_, Rejection>(warp::reply::json(&pair_rates))
+        });
+
+    // GET /rates/pairs
+    // Response: Map of BASE/QUOTE -> bid and ask
+    let get_pair_rates = warp::get()
+        .and(warp::path("rates"))
+        .and(warp::path("pairs"))
+        .and(warp::path::end())
         .and(with_store.clone())
         .and_then(|store: S| async move {
-            let rates = store.get_all_exchange_rates()?;
-            Ok::<_, Rejection>(warp::reply::json(&rates))
+            let pair_rates = store.get_all_pair_rates()?;
+            Ok::<_, Rejection>(warp::reply::json(&pair_rates))
+        });
+
+    // GET /rates/held
+    // Response: Map of asset code -> polled rate held back because it moved too much
+    let get_held_rates = warp::get()
+        .and(warp::path("rates"))
+        .and(warp::path("held"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_rate_change_guard.clone())
+        .map(|guard: Option<RateChangeGuard>| {
+            let held_rates = guard.map(|guard| guard.held_rates()).unwrap_or_default();
+            warp::reply::json(&held_rates)
+        });
+
+    // PUT /rates/held/:asset_code
+    // Confirms the held rate, applying it
+    let confirm_held_rate = warp::put()
+        .and(warp::path("rates"))
+        .and(warp::path("held"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_rate_change_guard.clone())
+        .and(with_store.clone())
+        .and_then(
+            |asset_code: String, guard: Option<RateChangeGuard>, store: S| async move {
+                let not_held = || {
+                    Rejection::from(
+                        ApiError::not_found().detail(format!("No rate is held for {}", asset_code)),
+                    )
+                };
+                let rate = guard
+                    .ok_or_else(not_held)?
+                    .confirm(&asset_code, &store)?
+                    .ok_or_else(not_held)?;
+                let mut confirmed = HashMap::new();
+                confirmed.insert(asset_code.clone(), rate);
+                Ok::<_, Rejection>(warp::reply::json(&confirmed))
+            },
+        );
+
+    // DELETE /rates/held/:asset_code
+    // Dismisses the held rate, keeping the previous one
+    let dismiss_held_rate = warp::delete()
+        .and(warp::path("rates"))
+        .and(warp::path("held"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_rate_change_guard.clone())
+        .and_then(
+            |asset_code: String, guard: Option<RateChangeGuard>| async move {
+                let held_rate = guard
+                    .and_then(|guard| guard.dismiss(&asset_code))
+                    .ok_or_else(|| {
+                        Rejection::from(
+                            ApiError::not_found()
+                                .detail(format!("No rate is held for {}", asset_code)),
+                        )
+                    })?;
+                Ok::<_, Rejection>(warp::reply::json(&held_rate))
+            },
+        );
+
+    // GET /rates/held/pairs
+    // Response: Map of BASE/QUOTE -> polled pair rate held back because it moved too much
+    let get_held_pair_rates = warp::get()
+        .and(warp::path("rates"))
+        .and(warp::path("held"))
+        .and(warp::path("pairs"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_rate_change_guard.clone())
+        .map(|guard: Option<RateChangeGuard>| {
+            let held_pair_rates = guard
+                .map(|guard| guard.held_pair_rates())
+                .unwrap_or_default();
+            warp::reply::json(&held_pair_rates)
+        });
+
+    // PUT /rates/held/pairs/:base/:quote
+    // Confirms the held pair rate, applying it
+    let confirm_held_pair_rate = warp::put()
+        .and(warp::path("rates"))
+        .and(warp::path("held"))
+        .and(warp::path("pairs"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_rate_change_guard.clone())
+        .and(with_store.clone())
+        .and_then(
+            |base: String, quote: String, guard: Option<RateChangeGuard>, store: S| async move {
+                let pair = format!("{}/{}", base, quote);
+                let not_held = || {
+                    Rejection::from(
+                        ApiError::not_found().detail(format!("No rate is held for {}", pair)),
+                    )
+                };
+                let pair_rate = guard
+                    .ok_or_else(not_held)?
+                    .confirm_pair(&pair, &store)?
+                    .ok_or_else(not_held)?;
+                let mut confirmed = HashMap::new();
+                confirmed.insert(pair.clone(), pair_rate);
+                Ok::<_, Rejection>(warp::reply::json(&confirmed))
+            },
+        );
+
+    // DELETE /rates/held/pairs/:base/:quote
+    // Dismisses the held pair rate, keeping the previous one
+    let dismiss_held_pair_rate = warp::delete()
+        .and(warp::path("rates"))
+        .and(warp::path("held"))
+        .and(warp::path("pairs"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_rate_change_guard)
+        .and_then(
+            |base: String, quote: String, guard: Option<RateChangeGuard>| async move {
+                let pair = format!("{}/{}", base, quote);
+                let held_pair_rate = guard
+                    .and_then(|guard| guard.dismiss_pair(&pair))
+                    .ok_or_else(|| {
+                        Rejection::from(
+                            ApiError::not_found().detail(format!("No rate is held for {}", pair)),
+                        )
+                    })?;
+                Ok::<_, Rejection>(warp::reply::json(&held_pair_rate))
+            },
+        );
+
+    // GET /circuits
+    // Response: Map of account id -> state of the circuit to the account
+    let get_circuits = warp::get()
+        .and(warp::path("circuits"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .map(move || {
+            let circuits = circuit_breaker
+                .as_ref()
+                .map(|breaker| breaker.circuits())
+                .unwrap_or_default();
+            warp::reply::json(&circuits)
         });
 
     // GET /routes
@@ -222,6 +433,15 @@ where
     get_root
         .or(put_rates)
         .or(get_rates)
+        .or(put_pair_rates)
+        .or(get_pair_rates)
+        .or(get_held_rates)
+        .or(confirm_held_rate)
+        .or(dismiss_held_rate)
+        .or(get_held_pair_rates)
+        .or(confirm_held_pair_rate)
+        .or(dismiss_held_pair_rate)
+        .or(get_circuits)
         .or(get_routes)
         .or(put_static_routes)
         .or(put_static_route)
@@ -230,8 +450,17 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::routes::test_helpers::{api_call, test_node_settings_api};
+    use crate::routes::test_helpers::{
+        api_call, test_node_settings_api, test_node_settings_api_with_circuit_breaker,
+        test_node_settings_api_with_rates, TestAccount, TestStore, EXAMPLE_ADDRESS,
+    };
+    use interledger_packet::{ErrorCode, PrepareBuilder, RejectBuilder};
+    use interledger_rates::{ExchangeRate, PairRate, RateAggregator, RateChangeGuard};
+    use interledger_service::{outgoing_service_fn, OutgoingRequest, OutgoingService};
+    use interledger_service_util::{CircuitBreaker, CircuitBreakerConfig, CircuitBreakerService};
     use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
 
     #[tokio::test]
     async fn gets_status() {
@@ -255,6 +484,227 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn gets_rates_with_provenance() {
+        let aggregator = RateAggregator::default();
+        let polled = |rate: f64| -> HashMap<String, ExchangeRate> {
+            vec![("XYZ".to_owned(), ExchangeRate::from_f64(rate).unwrap())]
+                .into_iter()
+                .collect()
+        };
+        aggregator.aggregate(vec![
+            ("CoinCap".to_owned(), polled(1.0)),
+            ("CryptoCompare".to_owned(), polled(3.0)),
+        ]);
+        let api = test_node_settings_api_with_rates(None, Some(aggregator.clone()));
+
+        let resp = api_call(&api, "GET", "/rates?provenance=true", "", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let rates: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(rates["ABC"], json!({"rate": 1.0}));
+        assert_eq!(rates["XYZ"]["rate"], json!(2.0));
+        assert_eq!(
+            rates["XYZ"]["sources"],
+            json!([
+                {"provider": "CoinCap", "rate": 1.0},
+                {"provider": "CryptoCompare", "rate": 3.0},
+            ])
+        );
+        assert_eq!(
+            rates["XYZ"]["fetched_at"],
+            json!(aggregator.provenance()["XYZ"].fetched_at)
+        );
+
+        let resp = api_call(&api, "GET", "/rates?provenance=false", "", None).await;
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            json!({"XYZ": 2.0, "ABC": 1.0})
+        );
+    }
+
+    #[tokio::test]
+    async fn puts_exact_rates() {
+        let api = test_node_settings_api();
+        let rates = json!({"ABC": "1.00000000000000000001", "XYZ": 0.5, "DEF": "2"});
+        let resp = api_call(&api, "PUT", "/rates", "admin", Some(rates)).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            json!({"ABC": "1.00000000000000000001", "XYZ": 0.5, "DEF": 2.0})
+        );
+
+        let rates = json!({"ABC": "-1"});
+        let resp = api_call(&api, "PUT", "/rates", "admin", Some(rates)).await;
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
+    #[tokio::test]
+    async fn puts_and_gets_pair_rates() {
+        let api = test_node_settings_api();
+        let resp = api_call(&api, "GET", "/rates/pairs", "", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            json!({"ABC/XYZ": {"bid": 0.4, "ask": 0.5}})
+        );
+
+        let pair_rates = json!({"XRP/EUR": {"bid": "0.2", "ask": 0.21}, "ABC/XYZ": 0.5});
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/rates/pairs",
+            "wrong",
+            Some(pair_rates.clone()),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 401);
+        let resp = api_call(&api, "PUT", "/rates/pairs", "admin", Some(pair_rates)).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            json!({"XRP/EUR": {"bid": 0.2, "ask": 0.21}, "ABC/XYZ": {"bid": 0.5, "ask": 0.5}})
+        );
+
+        let pair_rates = json!({"XRP/EUR": {"bid": 0.3, "ask": 0.2}});
+        let resp = api_call(&api, "PUT", "/rates/pairs", "admin", Some(pair_rates)).await;
+        assert_eq!(resp.status().as_u16(), 400);
+        let pair_rates = json!({"XRP": 0.2});
+        let resp = api_call(&api, "PUT", "/rates/pairs", "admin", Some(pair_rates)).await;
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
+    #[tokio::test]
+    async fn confirms_and_dismisses_held_rates() {
+        let guard = RateChangeGuard::new(0.1).unwrap();
+        let rates = |rate: f64| -> HashMap<String, ExchangeRate> {
+            vec![
+                ("ABC".to_owned(), ExchangeRate::from_f64(rate).unwrap()),
+                ("XYZ".to_owned(), ExchangeRate::from_f64(rate).unwrap()),
+            ]
+            .into_iter()
+            .collect()
+        };
+        guard.check(&rates(1.0), rates(2.0));
+        let api = test_node_settings_api_with_rates(Some(guard), None);
+
+        let resp = api_call(&api, "GET", "/rates/held", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+        let resp = api_call(&api, "GET", "/rates/held", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let held: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(held["ABC"]["previous"], json!(1.0));
+        assert_eq!(held["ABC"]["proposed"], json!(2.0));
+        assert_eq!(held["ABC"]["change"], json!(1.0));
+
+        let resp = api_call(&api, "PUT", "/rates/held/ABC", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            json!({"ABC": 2.0})
+        );
+        let resp = api_call(&api, "PUT", "/rates/held/ABC", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 404);
+
+        let resp = api_call(&api, "DELETE", "/rates/held/XYZ", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let resp = api_call(&api, "GET", "/rates/held", "admin", None).await;
+        assert_eq!(resp.body(), &b"{}"[..]);
+
+        let api = test_node_settings_api();
+        let resp = api_call(&api, "PUT", "/rates/held/ABC", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 404);
+    }
+
+    #[tokio::test]
+    async fn confirms_and_dismisses_held_pair_rates() {
+        let guard = RateChangeGuard::new(0.1).unwrap();
+        // The store has a rate of 0.4/0.5 for ABC/XYZ
+        let polled = vec![(
+            "ABC/XYZ".to_owned(),
+            PairRate::mid(ExchangeRate::from_f64(1.0).unwrap()).unwrap(),
+        )]
+        .into_iter()
+        .collect();
+        guard
+            .apply_pairs(&TestStore, &Default::default(), polled)
+            .unwrap();
+        let api = test_node_settings_api_with_rates(Some(guard), None);
+
+        let resp = api_call(&api, "GET", "/rates/held/pairs", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+        let resp = api_call(&api, "GET", "/rates/held/pairs", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let held: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(held["ABC/XYZ"]["previous"], json!({"bid": 0.4, "ask": 0.5}));
+        assert_eq!(held["ABC/XYZ"]["proposed"], json!({"bid": 1.0, "ask": 1.0}));
+
+        let resp = api_call(&api, "DELETE", "/rates/held/pairs/XYZ/ABC", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 404);
+        let resp = api_call(&api, "PUT", "/rates/held/pairs/ABC/XYZ", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            json!({"ABC/XYZ": {"bid": 1.0, "ask": 1.0}})
+        );
+        let resp = api_call(&api, "GET", "/rates/held/pairs", "admin", None).await;
+        assert_eq!(resp.body(), &b"{}"[..]);
+    }
+
+    #[tokio::test]
+    async fn gets_circuits() {
+        let breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold: 1,
+            ..Default::default()
+        });
+        let mut service = CircuitBreakerService::new(
+            TestStore,
+            Some(breaker.clone()),
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::T01_PEER_UNREACHABLE,
+                    message: &[],
+                    triggered_by: Some(&EXAMPLE_ADDRESS),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        service
+            .send_request(OutgoingRequest {
+                from: TestAccount,
+                to: TestAccount,
+                original_amount: 100,
+                prepare: PrepareBuilder {
+                    destination: EXAMPLE_ADDRESS.clone(),
+                    amount: 100,
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    execution_condition: &[0; 32],
+                    data: &[],
+                }
+                .build(),
+            })
+            .await
+            .unwrap_err();
+        let api = test_node_settings_api_with_circuit_breaker(breaker);
+
+        let resp = api_call(&api, "GET", "/circuits", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+        let resp = api_call(&api, "GET", "/circuits", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let circuits: Value = serde_json::from_slice(resp.body()).unwrap();
+        let circuits = circuits.as_object().unwrap();
+        assert_eq!(circuits.len(), 1);
+        let circuit = circuits.values().next().unwrap();
+        assert_eq!(circuit["username"], json!("alice"));
+        assert_eq!(circuit["state"], json!("open"));
+        assert_eq!(circuit["consecutive_failures"], json!(1));
+        assert!(circuit["retry_after_ms"].as_u64().unwrap() <= 30_000);
+
+        let api = test_node_settings_api();
+        let resp = api_call(&api, "GET", "/circuits", "admin", None).await;
+        assert_eq!(resp.body(), &b"{}"[..]);
+    }
+
     #[tokio::test]
     async fn gets_routes() {
         let api = test_node_settings_api();
@@ -0,0 +1,109 @@
+use crate::SpspConfig;
+use bytes::Bytes;
+use interledger_errors::*;
+use interledger_service::{Account, AccountStore, Username};
+use interledger_spsp::{accepts_spsp, receipt_details_from_headers, SpspResponder};
+use warp::{
+    self,
+    http::{HeaderMap, Response},
+    hyper::Body,
+    Filter, Rejection,
+};
+
+/// Answers an SPSP query for the account. The connection is generated with the
+/// `connection_tag`, if one is given, so that the money received over it can be told apart.
+pub(crate) fn spsp_response<A: Account>(
+    account: &A,
+    server_secret: Bytes,
+    config: &SpspConfig,
+    headers: &HeaderMap,
+    connection_tag: Option<&str>,
+) -> Result<Response<Body>, Rejection> {
+    if !accepts_spsp(headers) {
+        return Err(Rejection::from(
+            ApiError::not_acceptable().detail("only application/spsp4+json responses are served"),
+        ));
+    }
+    let receipt_details = receipt_details_from_headers(headers)
+        .map_err(|err| ApiError::bad_request().detail(err.to_string()))?;
+    let mut responder = SpspResponder::new(account.ilp_address().clone(), server_secret);
+    if config.include_asset {
+        responder = responder.with_asset(account.asset_code().to_string(), account.asset_scale());
+    }
+    match connection_tag {
+        Some(connection_tag) => responder
+            .generate_http_response_with_tag(connection_tag, receipt_details.as_ref())
+            .map_err(|err| {
+                Rejection::from(ApiError::internal_server_error().detail(err.to_string()))
+            }),
+        None => Ok(responder.generate_http_response_with_receipts(receipt_details.as_ref())),
+    }
+}
+
+pub fn payment_pointers_api<S, A>(
+    server_secret: Bytes,
+    config: SpspConfig,
+    store: S,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    S: AccountStore<Account = A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    let with_store = warp::any().map(move || store.clone());
+    let resolve_config = config.clone();
+
+    // GET /:path
+    // Payment Pointers such as `$example.com/$alice` resolve to these paths,
+    // depending on the configured prefixes and aliases
+    warp::get()
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(move |segment: String| {
+            let username = resolve_config.resolve_path(&segment);
+            async move { username.ok_or_else(warp::reject::not_found) }
+        })
+        .and(warp::header::headers_cloned())
+        .and(with_store)
+        .and_then(move |username: Username, headers: HeaderMap, store: S| {
+            let server_secret = server_secret.clone();
+            let config = config.clone();
+            async move {
+                let id = store.get_account_id_from_username(&username).await?;
+                let accounts = store.get_accounts(vec![id]).await?;
+                spsp_response(&accounts[0], server_secret, &config, &headers, None)
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::test_helpers::*;
+
+    #[tokio::test]
+    async fn serves_spsp_at_payment_pointer_paths() {
+        let api = test_payment_pointers_api();
+        for path in &["/$alice", "/%24alice", "/shop"] {
+            let resp = api_call(&api, "GET", path, "", None).await;
+            assert_eq!(resp.status().as_u16(), 200, "{}", path);
+            let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+            assert!(body["destination_account"]
+                .as_str()
+                .unwrap()
+                .starts_with("example.alice."));
+            assert!(body.get("asset").is_none());
+        }
+
+        for path in &["/alice", "/~alice", "/$alice/spsp"] {
+            let resp = api_call(&api, "GET", path, "", None).await;
+            assert_eq!(resp.status().as_u16(), 404, "{}", path);
+        }
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/$alice")
+            .header("Accept", "text/html")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status().as_u16(), 406);
+    }
+}
@@ -0,0 +1,575 @@
+use super::admin_only;
+use crate::number_or_string;
+use interledger_errors::*;
+use interledger_http::deserialize_json;
+use interledger_packet::{Address, ErrorCode, Reject};
+use interledger_service::{
+    Account, AccountStore, AddressStore, IncomingRequest, IncomingService, Username,
+};
+use interledger_service_util::{
+    EchoRequestBuilder, PendingEchoes, UnidirectionalEchoRequestBuilder,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+use warp::{self, Filter, Rejection};
+
+/// The most echo requests a single ping sends
+const MAX_PING_COUNT: u32 = 100;
+/// How much the expiry of each traceroute probe exceeds the one of the previous probe
+const TRACEROUTE_EXPIRY_STEP: Duration = Duration::from_millis(100);
+/// The most hops a traceroute reports before it gives up on reaching the destination
+const MAX_TRACEROUTE_HOPS: usize = 32;
+/// The longest a ping or traceroute may take. No more echo requests are sent once the
+/// next one might not complete in time.
+const MAX_PING_DURATION: Duration = Duration::from_secs(60);
+
+const fn default_ping_count() -> u32 {
+    4
+}
+
+const fn default_ping_timeout() -> u64 {
+    10000
+}
+
+const fn default_echo_mode() -> EchoMode {
+    EchoMode::Bidirectional
+}
+
+/// How the destination replies to the echo requests
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum EchoMode {
+    /// The destination sends each request back to this node, as the Echo protocol specifies
+    Bidirectional,
+    /// The destination fulfills the requests itself. This is an extension of the
+    /// Echo protocol, which only nodes running this implementation support.
+    Unidirectional,
+}
+
+#[derive(Deserialize, Debug)]
+struct PingRequest {
+    /// The ILP address to send the echo requests to
+    destination: Address,
+    /// The account the echo requests are sent from
+    from: Username,
+    #[serde(default = "default_ping_count", deserialize_with = "number_or_string")]
+    count: u32,
+    /// How long to wait for each reply, in milliseconds
+    #[serde(
+        default = "default_ping_timeout",
+        deserialize_with = "number_or_string"
+    )]
+    timeout: u64,
+    #[serde(default = "default_echo_mode")]
+    mode: EchoMode,
+    /// Find the connectors on the path to the destination instead
+    #[serde(default)]
+    traceroute: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct PingError {
+    code: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    triggered_by: Option<Address>,
+}
+
+impl From<&Reject> for PingError {
+    fn from(reject: &Reject) -> Self {
+        PingError {
+            code: reject.code().to_string(),
+            message: String::from_utf8_lossy(reject.message()).to_string(),
+            triggered_by: reject.triggered_by(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct PingReply {
+    /// Round-trip time in milliseconds
+    rtt: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<PingError>,
+}
+
+#[derive(Serialize, Debug)]
+struct PingResponse {
+    destination: Address,
+    sent: usize,
+    received: usize,
+    /// Round-trip times of the fulfilled echo requests, in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<f64>,
+    replies: Vec<PingReply>,
+}
+
+#[derive(Serialize, Debug)]
+struct TracerouteHop {
+    address: Address,
+    /// Expiry of the first probe which expired at this hop, or reached the destination, in milliseconds
+    expiry: u64,
+    /// Round-trip time of that probe, in milliseconds
+    rtt: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct TracerouteResponse {
+    destination: Address,
+    reached: bool,
+    hops: Vec<TracerouteHop>,
+    /// Why the destination was not reached, if it wasn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<PingError>,
+}
+
+/// Sends echo requests from an account through the node's incoming pipeline
+struct EchoSender<I, A> {
+    incoming_handler: I,
+    from: A,
+    destination: Address,
+    mode: EchoMode,
+    /// The node's address, which the responses to bidirectional echo requests are sent to
+    source_address: Address,
+    /// Lets the node's echo service fulfill the responses
+    pending: PendingEchoes,
+}
+
+impl<I, A> EchoSender<I, A>
+where
+    I: IncomingService<A>,
+    A: Account,
+{
+    /// Sends an echo request, returning the round-trip time and the reject, if any
+    async fn send(&mut self, expiry: Duration) -> (Duration, Result<(), Reject>) {
+        let mut fulfillment = [0; 32];
+        SystemRandom::new()
+            .fill(&mut fulfillment)
+            .expect("Failed to securely generate a random fulfillment");
+        let expires_at = SystemTime::now() + expiry;
+        let (prepare, condition) = match self.mode {
+            EchoMode::Bidirectional => {
+                let condition = self.pending.insert(fulfillment);
+                let prepare = EchoRequestBuilder {
+                    amount: 0,
+                    expires_at,
+                    execution_condition: &condition,
+                    destination: &self.destination,
+                    source_address: &self.source_address,
+                }
+                .build();
+                (prepare, Some(condition))
+            }
+            EchoMode::Unidirectional => {
+                let prepare = UnidirectionalEchoRequestBuilder {
+                    expires_at,
+                    fulfillment: &fulfillment,
+                    destination: &self.destination,
+                }
+                .build();
+                (prepare, None)
+            }
+        };
+        let start = Instant::now();
+        let result = self
+            .incoming_handler
+            .handle_request(IncomingRequest {
+                from: self.from.clone(),
+                prepare,
+            })
+            .await;
+        if let Some(condition) = condition {
+            self.pending.remove(&condition);
+        }
+        (start.elapsed(), result.map(|_| ()))
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+async fn ping<I, A>(mut sender: EchoSender<I, A>, count: u32, timeout: Duration) -> PingResponse
+where
+    I: IncomingService<A>,
+    A: Account,
+{
+    let start = Instant::now();
+    let mut replies = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if start.elapsed() + timeout > MAX_PING_DURATION {
+            break;
+        }
+        let (rtt, result) = sender.send(timeout).await;
+        debug!(
+            "Echo request to {} took {:?}: {:?}",
+            sender.destination, rtt, result
+        );
+        replies.push(PingReply {
+            rtt: millis(rtt),
+            error: result.err().as_ref().map(PingError::from),
+        });
+    }
+
+    let rtts: Vec<f64> = replies
+        .iter()
+        .filter(|reply| reply.error.is_none())
+        .map(|reply| reply.rtt)
+        .collect();
+    let (min, avg, max) = if rtts.is_empty() {
+        (None, None, None)
+    } else {
+        (
+            Some(rtts.iter().cloned().fold(f64::INFINITY, f64::min)),
+            Some(rtts.iter().sum::<f64>() / rtts.len() as f64),
+            Some(rtts.iter().cloned().fold(f64::NEG_INFINITY, f64::max)),
+        )
+    };
+    PingResponse {
+        destination: sender.destination,
+        sent: replies.len(),
+        received: rtts.len(),
+        min,
+        avg,
+        max,
+        replies,
+    }
+}
+
+/// Sends echo requests with increasing expiries. Each hop on the path shortens the expiry,
+/// so the probes expire at hops further and further away, and the `triggered_by` of the
+/// timeout rejects identifies the hops. Gives up after `MAX_TRACEROUTE_HOPS` hops.
+async fn traceroute<I, A>(mut sender: EchoSender<I, A>, timeout: Duration) -> TracerouteResponse
+where
+    I: IncomingService<A>,
+    A: Account,
+{
+    let start = Instant::now();
+    let mut hops: Vec<TracerouteHop> = Vec::new();
+    let mut expiry = TRACEROUTE_EXPIRY_STEP;
+    let mut error = None;
+    let mut reached = false;
+    while expiry <= timeout && start.elapsed() + expiry <= MAX_PING_DURATION {
+        let (rtt, result) = sender.send(expiry).await;
+        let address = match result {
+            Ok(()) => {
+                reached = true;
+                sender.destination.clone()
+            }
+            Err(reject) => match reject.triggered_by() {
+                Some(address) if reject.code() == ErrorCode::R00_TRANSFER_TIMED_OUT => address,
+                _ => {
+                    error = Some(PingError::from(&reject));
+                    break;
+                }
+            },
+        };
+        if hops.last().map(|hop| &hop.address) != Some(&address) {
+            hops.push(TracerouteHop {
+                address,
+                expiry: expiry.as_millis() as u64,
+                rtt: millis(rtt),
+            });
+        }
+        if reached || hops.len() >= MAX_TRACEROUTE_HOPS {
+            break;
+        }
+        expiry += TRACEROUTE_EXPIRY_STEP;
+    }
+    TracerouteResponse {
+        destination: sender.destination,
+        reached,
+        hops,
+        error,
+    }
+}
+
+pub fn ping_api<I, S, A>(
+    admin_api_token: String,
+    incoming_handler: I,
+    pending: PendingEchoes,
+    store: S,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    S: AccountStore<Account = A> + AddressStore + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    let admin_only = admin_only(&admin_api_token);
+    let with_pending = warp::any().map(move || pending.clone());
+    let with_store = warp::any().map(move || store.clone());
+    let with_incoming_handler = warp::any().map(move || incoming_handler.clone());
+
+    // POST /ping
+    warp::post()
+        .and(warp::path("ping"))
+        .and(warp::path::end())
+        .and(admin_only)
+        .and(deserialize_json())
+        .and(with_store)
+        .and(with_incoming_handler)
+        .and(with_pending)
+        .and_then(
+            move |request: PingRequest,
+                  store: S,
+                  incoming_handler: I,
+                  pending: PendingEchoes| async move {
+                if request.count == 0 || request.count > MAX_PING_COUNT {
+                    return Err(Rejection::from(
+                        ApiError::bad_request()
+                            .detail(format!("count must be between 1 and {}", MAX_PING_COUNT)),
+                    ));
+                }
+                let id = store.get_account_id_from_username(&request.from).await?;
+                let mut accounts = store.get_accounts(vec![id]).await?;
+                let from = accounts.pop().unwrap();
+                let timeout = Duration::from_millis(request.timeout);
+                let sender = EchoSender {
+                    incoming_handler,
+                    from,
+                    destination: request.destination,
+                    mode: request.mode,
+                    source_address: store.get_ilp_address(),
+                    pending,
+                };
+                if request.traceroute {
+                    Ok(warp::reply::json(&traceroute(sender, timeout).await))
+                } else {
+                    Ok(warp::reply::json(&ping(sender, request.count, timeout).await))
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::test_helpers::*;
+    use async_trait::async_trait;
+    use interledger_packet::{ErrorCode, FulfillBuilder, RejectBuilder};
+    use interledger_service::{incoming_service_fn, IlpResult, IncomingRequest, IncomingService};
+    use interledger_service_util::{EchoPacket, EchoResponseBuilder, EchoService, PendingEchoes};
+    use serde_json::{json, Value};
+    use std::convert::TryFrom;
+    use std::time::{Duration, SystemTime};
+
+    /// The destination of bidirectional echo requests, which sends them back to the
+    /// node's echo service
+    #[derive(Clone)]
+    struct EchoingDestination {
+        pending: PendingEchoes,
+    }
+
+    #[async_trait]
+    impl IncomingService<TestAccount> for EchoingDestination {
+        async fn handle_request(&mut self, request: IncomingRequest<TestAccount>) -> IlpResult {
+            let source_address = match EchoPacket::try_from(request.prepare.data()).unwrap() {
+                EchoPacket::Request { source_address } => source_address,
+                packet => panic!("Unexpected echo packet: {:?}", packet),
+            };
+            let mut execution_condition = [0; 32];
+            execution_condition.copy_from_slice(request.prepare.execution_condition());
+            let prepare = EchoResponseBuilder {
+                amount: request.prepare.amount(),
+                expires_at: request.prepare.expires_at(),
+                execution_condition: &execution_condition,
+                destination: &source_address,
+            }
+            .build();
+            let unreachable = incoming_service_fn(|_| -> IlpResult {
+                panic!("the response should be fulfilled by the node")
+            });
+            EchoService::new(TestStore, unreachable)
+                .with_pending_echoes(self.pending.clone())
+                .handle_request(IncomingRequest {
+                    from: request.from,
+                    prepare,
+                })
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn pings_destination() {
+        let pending = PendingEchoes::default();
+        let api = test_ping_api(
+            EchoingDestination {
+                pending: pending.clone(),
+            },
+            pending,
+        );
+        let request = json!({ "destination": "example.bob", "from": "alice", "count": 3 });
+        let resp = api_call(&api, "POST", "/ping", "admin", Some(request.clone())).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["destination"], "example.bob");
+        assert_eq!(body["sent"], 3);
+        assert_eq!(body["received"], 3);
+        assert_eq!(body["replies"].as_array().unwrap().len(), 3);
+        let (min, avg, max) = (
+            body["min"].as_f64().unwrap(),
+            body["avg"].as_f64().unwrap(),
+            body["max"].as_f64().unwrap(),
+        );
+        assert!(min <= avg && avg <= max);
+
+        let resp = api_call(&api, "POST", "/ping", "wrong", Some(request)).await;
+        assert_eq!(resp.status().as_u16(), 401);
+
+        let request = json!({ "destination": "example.bob", "from": "alice", "count": 0 });
+        let resp = api_call(&api, "POST", "/ping", "admin", Some(request)).await;
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
+    #[tokio::test]
+    async fn pings_destination_in_unidirectional_mode() {
+        let api = test_ping_api(
+            incoming_service_fn(|request| {
+                assert_eq!(request.prepare.amount(), 0);
+                match EchoPacket::try_from(request.prepare.data()).unwrap() {
+                    EchoPacket::UnidirectionalRequest { fulfillment } => Ok(FulfillBuilder {
+                        fulfillment: &fulfillment,
+                        data: &[],
+                    }
+                    .build()),
+                    packet => panic!("Unexpected echo packet: {:?}", packet),
+                }
+            }),
+            PendingEchoes::default(),
+        );
+        let request = json!({
+            "destination": "example.bob",
+            "from": "alice",
+            "count": 2,
+            "mode": "unidirectional",
+        });
+        let resp = api_call(&api, "POST", "/ping", "admin", Some(request)).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["received"], 2);
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_echo_requests() {
+        let api = test_ping_api(
+            incoming_service_fn(|_request| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"no route",
+                    triggered_by: Some(&EXAMPLE_ADDRESS),
+                    data: &[],
+                }
+                .build())
+            }),
+            PendingEchoes::default(),
+        );
+        let request = json!({ "destination": "example.bob", "from": "alice", "count": 2 });
+        let resp = api_call(&api, "POST", "/ping", "admin", Some(request)).await;
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["sent"], 2);
+        assert_eq!(body["received"], 0);
+        assert!(body.get("avg").is_none());
+        assert_eq!(
+            body["replies"][0]["error"],
+            json!({ "code": "F02", "message": "no route", "triggered_by": "example.alice" })
+        );
+    }
+
+    #[tokio::test]
+    async fn traces_route_to_destination() {
+        // Each of the two connectors on the path takes 200ms off the expiry
+        let handler = incoming_service_fn(|request| {
+            let time_left = request
+                .prepare
+                .expires_at()
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            let hop = if time_left < Duration::from_millis(200) {
+                "example.connector"
+            } else if time_left < Duration::from_millis(400) {
+                "example.peer"
+            } else {
+                match EchoPacket::try_from(request.prepare.data()).unwrap() {
+                    EchoPacket::UnidirectionalRequest { fulfillment } => {
+                        return Ok(FulfillBuilder {
+                            fulfillment: &fulfillment,
+                            data: &[],
+                        }
+                        .build())
+                    }
+                    packet => panic!("Unexpected echo packet: {:?}", packet),
+                }
+            };
+            Err(RejectBuilder {
+                code: ErrorCode::R00_TRANSFER_TIMED_OUT,
+                message: &[],
+                triggered_by: Some(&hop.parse().unwrap()),
+                data: &[],
+            }
+            .build())
+        });
+        let api = test_ping_api(handler, PendingEchoes::default());
+        let request = json!({
+            "destination": "example.bob",
+            "from": "alice",
+            "traceroute": true,
+            "mode": "unidirectional",
+        });
+        let resp = api_call(&api, "POST", "/ping", "admin", Some(request)).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["reached"], true);
+        let hops: Vec<(&str, u64)> = body["hops"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hop| {
+                (
+                    hop["address"].as_str().unwrap(),
+                    hop["expiry"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            hops,
+            vec![
+                ("example.connector", 100),
+                ("example.peer", 300),
+                ("example.bob", 500)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn traceroute_gives_up_after_max_hops() {
+        // Every probe expires at a different, further away hop
+        let api = test_ping_api(
+            incoming_service_fn(|request| {
+                let time_left = request
+                    .prepare
+                    .expires_at()
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+                let hop = format!("example.hop{}", (time_left.as_millis() + 50) / 100);
+                Err(RejectBuilder {
+                    code: ErrorCode::R00_TRANSFER_TIMED_OUT,
+                    message: &[],
+                    triggered_by: Some(&hop.parse().unwrap()),
+                    data: &[],
+                }
+                .build())
+            }),
+            PendingEchoes::default(),
+        );
+        let request = json!({ "destination": "example.bob", "from": "alice", "traceroute": true });
+        let resp = api_call(&api, "POST", "/ping", "admin", Some(request)).await;
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["reached"], false);
+        assert_eq!(body["hops"].as_array().unwrap().len(), 32);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
-    routes::{accounts_api, node_settings_api},
-    AccountDetails, AccountSettings, NodeStore,
+    routes::{accounts_api, node_settings_api, payment_pointers_api, ping_api},
+    AccountDetails, AccountSettings, NodeStore, OutgoingPayment, OutgoingPaymentStore, SpspConfig,
 };
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -11,14 +11,19 @@ use interledger_ccp::{CcpRoutingAccount, RoutingRelation};
 use interledger_errors::*;
 use interledger_http::{HttpAccount, HttpStore};
 use interledger_packet::{Address, ErrorCode, FulfillBuilder, RejectBuilder};
-use interledger_rates::ExchangeRateStore;
+use interledger_rates::{
+    ExchangeRate, ExchangeRateStore, PairRate, RateAggregator, RateChangeGuard,
+};
 use interledger_router::RouterStore;
 use interledger_service::{
-    incoming_service_fn, outgoing_service_fn, Account, AccountStore, AddressStore, Username,
+    incoming_service_fn, outgoing_service_fn, Account, AccountStore, AddressStore, IncomingService,
+    Username,
 };
-use interledger_service_util::BalanceStore;
+use interledger_service_util::{BalanceStore, CircuitBreaker, PendingEchoes};
 use interledger_settlement::core::types::{SettlementAccount, SettlementEngineDetails};
-use interledger_stream::{PaymentNotification, StreamNotificationsStore};
+use interledger_stream::{
+    Invoice, InvoiceStore, PaymentNotification, StreamDataNotification, StreamNotificationsStore,
+};
 use once_cell::sync::Lazy;
 use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
@@ -56,7 +61,53 @@ where
 
 pub fn test_node_settings_api(
 ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    node_settings_api("admin".to_owned(), None, TestStore).recover(default_rejection_handler)
+    node_settings_api("admin".to_owned(), None, None, None, None, TestStore)
+        .recover(default_rejection_handler)
+}
+
+pub fn test_node_settings_api_with_rates(
+    guard: Option<RateChangeGuard>,
+    aggregator: Option<RateAggregator>,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    node_settings_api("admin".to_owned(), None, guard, aggregator, None, TestStore)
+        .recover(default_rejection_handler)
+}
+
+pub fn test_node_settings_api_with_circuit_breaker(
+    breaker: CircuitBreaker,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    node_settings_api(
+        "admin".to_owned(),
+        None,
+        None,
+        None,
+        Some(breaker),
+        TestStore,
+    )
+    .recover(default_rejection_handler)
+}
+
+pub fn test_payment_pointers_api(
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let config = SpspConfig {
+        path_prefixes: vec!["$".to_string()],
+        aliases: vec![("shop".to_string(), USERNAME.clone())]
+            .into_iter()
+            .collect(),
+        include_asset: false,
+    };
+    payment_pointers_api(Bytes::from(&[0; 32][..]), config, TestStore)
+        .recover(default_rejection_handler)
+}
+
+pub fn test_ping_api<I>(
+    incoming: I,
+    pending: PendingEchoes,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    I: IncomingService<TestAccount> + Clone + Send + Sync + 'static,
+{
+    ping_api("admin".to_owned(), incoming, pending, TestStore).recover(default_rejection_handler)
 }
 
 pub fn test_accounts_api(
@@ -83,9 +134,14 @@ pub fn test_accounts_api(
     );
     let store = TestStore;
     accounts_api(
-        Bytes::from("admin"),
+        Bytes::from(&[0; 32][..]),
         "admin".to_owned(),
         None,
+        SpspConfig {
+            path_prefixes: vec!["$".to_string()],
+            aliases: HashMap::new(),
+            include_asset: true,
+        },
         incoming,
         outgoing,
         btp,
@@ -100,7 +156,7 @@ pub fn test_accounts_api(
  */
 
 #[derive(Clone)]
-struct TestStore;
+pub struct TestStore;
 
 use serde_json::json;
 pub static USERNAME: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
@@ -198,21 +254,45 @@ impl ExchangeRateStore for TestStore {
     fn get_exchange_rates(
         &self,
         _asset_codes: &[&str],
-    ) -> Result<Vec<f64>, ExchangeRateStoreError> {
-        Ok(vec![1.0, 2.0])
+    ) -> Result<Vec<ExchangeRate>, ExchangeRateStoreError> {
+        Ok(vec![
+            ExchangeRate::from_f64(1.0).unwrap(),
+            ExchangeRate::from_f64(2.0).unwrap(),
+        ])
     }
 
     fn set_exchange_rates(
         &self,
-        _rates: HashMap<String, f64>,
+        _rates: HashMap<String, ExchangeRate>,
+    ) -> Result<(), ExchangeRateStoreError> {
+        Ok(())
+    }
+
+    fn get_all_exchange_rates(
+        &self,
+    ) -> Result<HashMap<String, ExchangeRate>, ExchangeRateStoreError> {
+        let mut ret = HashMap::new();
+        ret.insert("ABC".to_owned(), ExchangeRate::from_f64(1.0).unwrap());
+        ret.insert("XYZ".to_owned(), ExchangeRate::from_f64(2.0).unwrap());
+        Ok(ret)
+    }
+    fn set_pair_rates(
+        &self,
+        _rates: HashMap<String, PairRate>,
     ) -> Result<(), ExchangeRateStoreError> {
         Ok(())
     }
 
-    fn get_all_exchange_rates(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+    fn get_all_pair_rates(&self) -> Result<HashMap<String, PairRate>, ExchangeRateStoreError> {
         let mut ret = HashMap::new();
-        ret.insert("ABC".to_owned(), 1.0);
-        ret.insert("XYZ".to_owned(), 2.0);
+        ret.insert(
+            "ABC/XYZ".to_owned(),
+            PairRate::new(
+                ExchangeRate::from_f64(0.4).unwrap(),
+                ExchangeRate::from_f64(0.5).unwrap(),
+            )
+            .unwrap(),
+        );
         Ok(ret)
     }
 }
@@ -327,6 +407,83 @@ impl StreamNotificationsStore for TestStore {
     fn all_payment_subscription(&self) -> broadcast::Receiver<PaymentNotification> {
         unimplemented!()
     }
+
+    fn add_stream_data_subscription(
+        &self,
+        _id: Uuid,
+        _sender: UnboundedSender<StreamDataNotification>,
+    ) {
+        unimplemented!()
+    }
+
+    fn publish_stream_data_notification(&self, _id: Uuid, _notification: StreamDataNotification) {
+        unimplemented!()
+    }
+}
+
+#[async_trait]
+impl OutgoingPaymentStore for TestStore {
+    async fn insert_outgoing_payment(
+        &self,
+        payment: OutgoingPayment,
+    ) -> Result<OutgoingPayment, OutgoingPaymentStoreError> {
+        Ok(payment)
+    }
+
+    async fn update_outgoing_payment(
+        &self,
+        _payment: OutgoingPayment,
+    ) -> Result<(), OutgoingPaymentStoreError> {
+        Ok(())
+    }
+
+    async fn get_outgoing_payment(
+        &self,
+        id: Uuid,
+    ) -> Result<OutgoingPayment, OutgoingPaymentStoreError> {
+        Err(OutgoingPaymentStoreError::PaymentNotFound(id.to_string()))
+    }
+
+    async fn get_outgoing_payment_by_idempotency_key(
+        &self,
+        _account_id: Uuid,
+        _idempotency_key: &str,
+    ) -> Result<Option<OutgoingPayment>, OutgoingPaymentStoreError> {
+        Ok(None)
+    }
+
+    async fn get_unfinished_outgoing_payments(
+        &self,
+    ) -> Result<Vec<OutgoingPayment>, OutgoingPaymentStoreError> {
+        Ok(Vec::new())
+    }
+}
+
+#[async_trait]
+impl InvoiceStore for TestStore {
+    async fn insert_invoice(&self, _invoice: Invoice) -> Result<(), InvoiceStoreError> {
+        Ok(())
+    }
+
+    async fn get_invoice(&self, id: Uuid) -> Result<Invoice, InvoiceStoreError> {
+        Err(InvoiceStoreError::InvoiceNotFound(id.to_string()))
+    }
+
+    async fn reserve_invoice_payment(
+        &self,
+        id: Uuid,
+        _amount: u64,
+    ) -> Result<(bool, Invoice), InvoiceStoreError> {
+        Err(InvoiceStoreError::InvoiceNotFound(id.to_string()))
+    }
+
+    async fn release_invoice_payment(
+        &self,
+        id: Uuid,
+        _amount: u64,
+    ) -> Result<Invoice, InvoiceStoreError> {
+        Err(InvoiceStoreError::InvoiceNotFound(id.to_string()))
+    }
 }
 
 #[async_trait]
@@ -78,6 +78,10 @@ where
         .get_ilp_over_btp_url()
         .expect("Accounts must have BTP URLs")
         .clone();
+    if crate::is_tcp_url(&url) {
+        // These accounts are connected by the TcpOutgoingService instead
+        return Ok(());
+    }
     if url.scheme().starts_with("btp+") {
         // Re-parse the URL after stripping off the leading "btp+" prefix.
         // We cannot use set_scheme here because the URL specification
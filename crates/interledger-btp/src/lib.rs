@@ -5,6 +5,9 @@
 //!
 //! Because this protocol uses WebSockets, only one party needs to have a publicly-accessible HTTPS
 //! endpoint but both sides can send and receive ILP packets.
+//!
+//! Peers that can reach each other directly may instead use the lighter ILP-over-TCP transport
+//! by giving the account a `btp+tcp://` or `btp+tls://` URL (see `TcpOutgoingService`).
 
 use async_trait::async_trait;
 use interledger_service::{Account, Username};
@@ -15,11 +18,16 @@ mod errors;
 mod packet;
 mod server;
 mod service;
+mod tcp;
 mod wrapped_ws;
 
 pub use self::client::{connect_client, connect_to_service_account};
 pub use self::server::btp_service_as_filter; // This is consumed only by the node.
 pub use self::service::{BtpOutgoingService, BtpService};
+pub use self::tcp::{
+    connect_to_tcp_account, is_tcp_url, TcpClientError, TcpOutgoingService, TcpServer,
+    TcpServerError, TcpService,
+};
 
 use interledger_errors::BtpStoreError;
 
@@ -327,4 +335,147 @@ mod client_server {
 
         btp_service.close();
     }
+
+    #[tokio::test]
+    async fn tcp_client_server_test() {
+        let server_acc_id = Uuid::new_v4();
+        let server_store = TestStore {
+            accounts: Arc::new([TestAccount {
+                id: server_acc_id,
+                ilp_over_btp_incoming_token: Some("test_auth_token".to_string()),
+                ilp_over_btp_outgoing_token: None,
+                ilp_over_btp_url: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let tcp_service = TcpOutgoingService::new(
+            server_address.clone(),
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&server_address),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        tcp_service
+            .clone()
+            .handle_incoming(incoming_service_fn(|_| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: b"test data",
+                }
+                .build())
+            }))
+            .await;
+        let bind_addr = TcpServer::new(tcp_service.clone(), server_store)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_over_btp_url: Some(Url::parse(&format!("btp+tcp://alice@{}", bind_addr)).unwrap()),
+            ilp_over_btp_outgoing_token: Some("test_auth_token".to_string()),
+            ilp_over_btp_incoming_token: None,
+        };
+        let unauthorized_account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_over_btp_outgoing_token: Some("wrong_token".to_string()),
+            ..account.clone()
+        };
+        let addr = Address::from_str("example.address").unwrap();
+        let addr_clone = addr.clone();
+        let mut tcp_client = TcpOutgoingService::new(
+            addr.clone(),
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    data: &[],
+                    triggered_by: Some(&addr_clone),
+                }
+                .build())
+            }),
+        )
+        .handle_incoming(incoming_service_fn(move |_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                data: &[],
+                triggered_by: Some(&addr),
+            }
+            .build())
+        }))
+        .await;
+
+        let request = |to: &TestAccount| OutgoingRequest {
+            from: to.clone(),
+            to: to.clone(),
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 100,
+                execution_condition: &[0; 32],
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: b"test data",
+            }
+            .build(),
+        };
+
+        // A peer which accepts the TCP connection but never answers the auth frame
+        // does not hold up dialing the other accounts
+        let unresponsive = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unresponsive_account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_over_btp_url: Some(
+                Url::parse(&format!(
+                    "btp+tcp://alice@{}",
+                    unresponsive.local_addr().unwrap()
+                ))
+                .unwrap(),
+            ),
+            ..account.clone()
+        };
+        let mut tcp_client_clone = tcp_client.clone();
+        let unresponsive_request = request(&unresponsive_account);
+        tokio::spawn(async move {
+            let _ = tcp_client_clone.send_request(unresponsive_request).await;
+        });
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        // The client connects on the first request
+        let fulfill = tokio::time::timeout(
+            Duration::from_secs(5),
+            tcp_client.send_request(request(&account)),
+        )
+        .await
+        .expect("Dialing another account should not wait for the unresponsive peer")
+        .unwrap();
+        assert_eq!(fulfill.data(), b"test data");
+        assert!(tcp_service.is_connected(&server_acc_id));
+
+        let reject = tcp_client
+            .send_request(request(&unauthorized_account))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+
+        // Accounts without an ILP-over-TCP URL are passed to the next service
+        let btp_account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_over_btp_url: None,
+            ..account.clone()
+        };
+        let reject = tcp_client
+            .send_request(request(&btp_account))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F02_UNREACHABLE);
+
+        tcp_service.close();
+        tcp_client.close();
+    }
 }
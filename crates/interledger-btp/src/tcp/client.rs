@@ -0,0 +1,124 @@
+use super::frame::Frame;
+use super::service::TcpOutgoingService;
+use super::{framed, TCP_SCHEME, TLS_SCHEME};
+use crate::BtpAccount;
+use futures::{SinkExt, StreamExt};
+use interledger_service::{OutgoingService, Username};
+use rand::random;
+use secrecy::SecretString;
+use std::{str::FromStr, time::Duration};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tracing::{debug, trace};
+use url::Url;
+
+// Give up on the connection if the TCP (and TLS) handshake has not completed within this timeout
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Give up on the connection if the peer has not accepted our auth details within this timeout
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum TcpClientError {
+    #[error("Invalid ILP-over-TCP URL for account {0}: {1}")]
+    InvalidUrl(String, String),
+    #[error("Cannot connect to ILP-over-TCP url: {1} for account {0}. Got error {2}")]
+    CannotConnect(String, Url, String),
+    #[error("Peer did not accept the ILP-over-TCP auth details of account {0}")]
+    Unauthorized(String),
+}
+
+/// Connects to the account's `btp+tcp` or `btp+tls` URL and adds the connection to the service.
+/// This is done in the following steps:
+/// 1. Open a TCP connection to the host and port in the URL (and do a TLS handshake for `btp+tls`)
+/// 2. Send an auth frame with the username from the URL and the account's outgoing BTP token
+/// 3. If the peer responds with an auth response, add the connection to the service
+pub async fn connect_to_tcp_account<O, A>(
+    account: A,
+    service: TcpOutgoingService<O, A>,
+) -> Result<(), TcpClientError>
+where
+    O: OutgoingService<A> + Clone,
+    A: BtpAccount + Send + Sync + 'static,
+{
+    let url = account
+        .get_ilp_over_btp_url()
+        .expect("Accounts must have ILP-over-TCP URLs")
+        .clone();
+    let invalid_url = |reason: &str| {
+        TcpClientError::InvalidUrl(account.username().to_string(), reason.to_string())
+    };
+
+    let host = url.host_str().ok_or_else(|| invalid_url("missing host"))?;
+    let port = url.port().ok_or_else(|| invalid_url("missing port"))?;
+    // The username is the one of our account on the peer's node
+    let username = Username::from_str(url.username())
+        .map_err(|_| invalid_url("the URL must contain our username on the peer's node"))?;
+    let token = account
+        .get_ilp_over_btp_outgoing_token()
+        .map(|token| String::from_utf8_lossy(token).into_owned())
+        .unwrap_or_default();
+
+    let cannot_connect = |err: String| {
+        TcpClientError::CannotConnect(account.username().to_string(), url.clone(), err)
+    };
+    if url.scheme() != TCP_SCHEME && url.scheme() != TLS_SCHEME {
+        return Err(invalid_url("the scheme must be btp+tcp or btp+tls"));
+    }
+    debug!("Connecting to {}:{} over ILP-over-TCP", host, port);
+    let connect = async {
+        let stream = TcpStream::connect((host, port))
+            .await
+            .map_err(|err| cannot_connect(err.to_string()))?;
+        let _ = stream.set_nodelay(true);
+        if url.scheme() == TCP_SCHEME {
+            return Ok(framed(stream));
+        }
+        let connector =
+            native_tls::TlsConnector::new().map_err(|err| cannot_connect(err.to_string()))?;
+        let stream = tokio_tls::TlsConnector::from(connector)
+            .connect(host, stream)
+            .await
+            .map_err(|err| cannot_connect(err.to_string()))?;
+        Ok(framed(stream))
+    };
+    let mut connection = tokio::time::timeout(CONNECT_TIMEOUT, connect)
+        .await
+        .map_err(|_| cannot_connect("timed out".to_string()))??;
+
+    trace!(
+        "Connected to account {} (UID: {}) (URL: {}), sending auth frame",
+        account.username(),
+        account.id(),
+        url
+    );
+    let request_id = random();
+    let auth = Frame::Auth {
+        request_id,
+        username,
+        token: SecretString::new(token),
+    };
+    connection
+        .send(auth.to_bytes())
+        .await
+        .map_err(|err| cannot_connect(err.to_string()))?;
+
+    let response = tokio::time::timeout(AUTH_TIMEOUT, connection.next())
+        .await
+        .ok()
+        .flatten()
+        .and_then(|frame| frame.ok())
+        .and_then(|frame| Frame::from_bytes(frame).ok());
+    match response {
+        Some(Frame::AuthResponse {
+            request_id: response_id,
+        }) if response_id == request_id => {
+            debug!(
+                "Connected to account {}'s ILP-over-TCP server",
+                account.id()
+            );
+            service.add_connection(account, connection);
+            Ok(())
+        }
+        _ => Err(TcpClientError::Unauthorized(account.username().to_string())),
+    }
+}
@@ -0,0 +1,218 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use interledger_service::Username;
+use secrecy::{ExposeSecret, SecretString};
+use std::str::{self, FromStr};
+
+/// Maximum length of a single frame (not including the length prefix).
+/// ILP packets are at most 32767 bytes of data plus their envelope.
+pub(crate) const MAX_FRAME_LENGTH: usize = 40000;
+
+const FRAME_HEADER_LENGTH: usize = 5;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+enum FrameType {
+    Auth = 1,
+    AuthResponse = 2,
+    Ilp = 3,
+    Ping = 4,
+    Pong = 5,
+}
+
+impl FrameType {
+    fn from_u8(type_int: u8) -> Option<Self> {
+        match type_int {
+            1 => Some(FrameType::Auth),
+            2 => Some(FrameType::AuthResponse),
+            3 => Some(FrameType::Ilp),
+            4 => Some(FrameType::Ping),
+            5 => Some(FrameType::Pong),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum FrameError {
+    #[error("frame is too short")]
+    TooShort,
+    #[error("frame type {0} is not supported")]
+    UnknownType(u8),
+    #[error("invalid username in auth frame")]
+    InvalidUsername,
+    #[error("auth token is not valid UTF-8")]
+    InvalidToken,
+}
+
+/// A single message exchanged over an ILP-over-TCP connection.
+///
+/// Every frame is sent with a 4-byte big-endian length prefix, followed by
+/// the 1-byte frame type and the 4-byte big-endian request ID:
+///
+/// ```text
+/// | length (u32) | type (u8) | request_id (u32) | data ... |
+/// ```
+///
+/// The data of an `Ilp` frame is the raw ILP packet. Whether a frame is a
+/// request or a response is determined by the ILP packet type it carries,
+/// so responses reuse the request ID of the Prepare they answer.
+#[derive(Debug, Clone)]
+pub(crate) enum Frame {
+    /// First frame sent by the connecting side. The data is the length-prefixed
+    /// username followed by the auth token
+    Auth {
+        request_id: u32,
+        username: Username,
+        token: SecretString,
+    },
+    /// Sent by the listening side once the auth details were accepted
+    AuthResponse {
+        request_id: u32,
+    },
+    /// A Prepare, Fulfill or Reject packet
+    Ilp {
+        request_id: u32,
+        packet: BytesMut,
+    },
+    Ping,
+    Pong,
+}
+
+impl Frame {
+    pub(crate) fn to_bytes(&self) -> Bytes {
+        let (frame_type, request_id) = match self {
+            Frame::Auth { request_id, .. } => (FrameType::Auth, *request_id),
+            Frame::AuthResponse { request_id } => (FrameType::AuthResponse, *request_id),
+            Frame::Ilp { request_id, .. } => (FrameType::Ilp, *request_id),
+            Frame::Ping => (FrameType::Ping, 0),
+            Frame::Pong => (FrameType::Pong, 0),
+        };
+        let mut buf = BytesMut::with_capacity(FRAME_HEADER_LENGTH + self.data_len());
+        buf.put_u8(frame_type as u8);
+        buf.put_u32(request_id);
+        match self {
+            Frame::Auth {
+                username, token, ..
+            } => {
+                let username = username.as_bytes();
+                buf.put_u8(username.len() as u8);
+                buf.put_slice(username);
+                buf.put_slice(token.expose_secret().as_bytes());
+            }
+            Frame::Ilp { packet, .. } => buf.put_slice(packet.as_ref()),
+            _ => {}
+        }
+        buf.freeze()
+    }
+
+    pub(crate) fn from_bytes(mut bytes: BytesMut) -> Result<Self, FrameError> {
+        if bytes.len() < FRAME_HEADER_LENGTH {
+            return Err(FrameError::TooShort);
+        }
+        let type_int = bytes.get_u8();
+        let request_id = bytes.get_u32();
+        match FrameType::from_u8(type_int) {
+            Some(FrameType::Auth) => {
+                if bytes.is_empty() {
+                    return Err(FrameError::TooShort);
+                }
+                let username_len = bytes.get_u8() as usize;
+                if bytes.len() < username_len {
+                    return Err(FrameError::TooShort);
+                }
+                let username = bytes.split_to(username_len);
+                let username = str::from_utf8(&username)
+                    .ok()
+                    .and_then(|username| Username::from_str(username).ok())
+                    .ok_or(FrameError::InvalidUsername)?;
+                let token = str::from_utf8(&bytes).map_err(|_| FrameError::InvalidToken)?;
+                Ok(Frame::Auth {
+                    request_id,
+                    username,
+                    token: SecretString::new(token.to_string()),
+                })
+            }
+            Some(FrameType::AuthResponse) => Ok(Frame::AuthResponse { request_id }),
+            Some(FrameType::Ilp) => Ok(Frame::Ilp {
+                request_id,
+                packet: bytes,
+            }),
+            Some(FrameType::Ping) => Ok(Frame::Ping),
+            Some(FrameType::Pong) => Ok(Frame::Pong),
+            None => Err(FrameError::UnknownType(type_int)),
+        }
+    }
+
+    fn data_len(&self) -> usize {
+        match self {
+            Frame::Auth {
+                username, token, ..
+            } => 1 + username.len() + token.expose_secret().len(),
+            Frame::Ilp { packet, .. } => packet.len(),
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_roundtrip() {
+        let frame = Frame::Auth {
+            request_id: 7,
+            username: Username::from_str("alice").unwrap(),
+            token: SecretString::new("secret_token".to_string()),
+        };
+        let bytes = frame.to_bytes();
+        assert_eq!(&bytes[..11], b"\x01\x00\x00\x00\x07\x05alice");
+
+        match Frame::from_bytes(BytesMut::from(bytes.as_ref())).unwrap() {
+            Frame::Auth {
+                request_id,
+                username,
+                token,
+            } => {
+                assert_eq!(request_id, 7);
+                assert_eq!(username.as_ref(), "alice");
+                assert_eq!(token.expose_secret(), "secret_token");
+            }
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ilp_roundtrip() {
+        let frame = Frame::Ilp {
+            request_id: 0xdead_beef,
+            packet: BytesMut::from(&b"\x0c\x01\x02"[..]),
+        };
+        let bytes = frame.to_bytes();
+        assert_eq!(bytes.as_ref(), b"\x03\xde\xad\xbe\xef\x0c\x01\x02");
+
+        match Frame::from_bytes(BytesMut::from(bytes.as_ref())).unwrap() {
+            Frame::Ilp { request_id, packet } => {
+                assert_eq!(request_id, 0xdead_beef);
+                assert_eq!(packet.as_ref(), b"\x0c\x01\x02");
+            }
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        assert_eq!(
+            Frame::from_bytes(BytesMut::from(&b"\x03\x00"[..])).unwrap_err(),
+            FrameError::TooShort
+        );
+        assert_eq!(
+            Frame::from_bytes(BytesMut::from(&b"\x09\x00\x00\x00\x00"[..])).unwrap_err(),
+            FrameError::UnknownType(9)
+        );
+        assert_eq!(
+            Frame::from_bytes(BytesMut::from(&b"\x01\x00\x00\x00\x00\x09alice"[..])).unwrap_err(),
+            FrameError::TooShort
+        );
+    }
+}
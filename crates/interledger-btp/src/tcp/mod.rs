@@ -0,0 +1,51 @@
+//! A lightweight transport for exchanging ILP packets with a directly connected peer
+//! over a plain TCP or TLS connection, without the WebSocket and BTP framing overhead.
+//!
+//! Accounts use it when their `ilp_over_btp_url` has the `btp+tcp` or `btp+tls` scheme,
+//! for example `btp+tcp://alice@peer.example:7772`, where `alice` is our username on
+//! the peer's node. The connection is authenticated with the same tokens as BTP.
+//!
+//! Every frame is prefixed with its length; see `Frame` for the format. Like BTP, each
+//! Prepare gets a request ID that the peer echoes on the Fulfill or Reject, so many
+//! requests can be in flight on one connection.
+
+use crate::BtpAccount;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use url::Url;
+
+mod client;
+mod frame;
+mod server;
+mod service;
+
+pub use client::{connect_to_tcp_account, TcpClientError};
+pub use server::{TcpServer, TcpServerError};
+pub use service::{TcpOutgoingService, TcpService};
+
+pub(crate) const TCP_SCHEME: &str = "btp+tcp";
+pub(crate) const TLS_SCHEME: &str = "btp+tls";
+
+/// Returns true if the URL selects the ILP-over-TCP transport instead of BTP over WebSockets
+pub fn is_tcp_url(url: &Url) -> bool {
+    url.scheme() == TCP_SCHEME || url.scheme() == TLS_SCHEME
+}
+
+fn has_tcp_url<A: BtpAccount>(account: &A) -> bool {
+    match account.get_ilp_over_btp_url() {
+        Some(url) => is_tcp_url(url),
+        None => false,
+    }
+}
+
+pub(crate) trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+pub(crate) type Connection = Framed<Box<dyn Io>, LengthDelimitedCodec>;
+
+fn framed<T: AsyncRead + AsyncWrite + Send + Unpin + 'static>(io: T) -> Connection {
+    let codec = LengthDelimitedCodec::builder()
+        .max_frame_length(frame::MAX_FRAME_LENGTH)
+        .new_codec();
+    Framed::new(Box::new(io) as Box<dyn Io>, codec)
+}
@@ -0,0 +1,147 @@
+use super::frame::Frame;
+use super::service::TcpOutgoingService;
+use super::{framed, Connection};
+use crate::{BtpAccount, BtpStore};
+use futures::{SinkExt, StreamExt};
+use interledger_service::OutgoingService;
+use secrecy::ExposeSecret;
+use std::{net::SocketAddr, time::Duration};
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, warn};
+
+// Close the incoming connection if the auth details
+// have not been received within this timeout
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum TcpServerError {
+    #[error("Invalid TLS identity: {0}")]
+    InvalidIdentity(#[from] native_tls::Error),
+    #[error("Unable to listen for ILP-over-TCP connections: {0}")]
+    Bind(#[from] std::io::Error),
+}
+
+/// Accepts ILP-over-TCP connections and adds them to the TcpOutgoingService.
+///
+/// The first frame on every connection must be an auth frame with the username
+/// and incoming BTP token of one of the accounts in the store.
+#[derive(Clone)]
+pub struct TcpServer<O, S, A: BtpAccount> {
+    service: TcpOutgoingService<O, A>,
+    store: S,
+    tls_acceptor: Option<tokio_tls::TlsAcceptor>,
+}
+
+impl<O, S, A> TcpServer<O, S, A>
+where
+    O: OutgoingService<A> + Clone + Send + Sync + 'static,
+    S: BtpStore<Account = A> + Clone + Send + Sync + 'static,
+    A: BtpAccount + Send + Sync + 'static,
+{
+    pub fn new(service: TcpOutgoingService<O, A>, store: S) -> Self {
+        TcpServer {
+            service,
+            store,
+            tls_acceptor: None,
+        }
+    }
+
+    /// Require TLS on incoming connections, using the certificate and private key
+    /// from the given PKCS #12 archive
+    pub fn tls_identity(mut self, pkcs12: &[u8], password: &str) -> Result<Self, TcpServerError> {
+        let identity = native_tls::Identity::from_pkcs12(pkcs12, password)?;
+        let acceptor = native_tls::TlsAcceptor::new(identity)?;
+        self.tls_acceptor = Some(tokio_tls::TlsAcceptor::from(acceptor));
+        Ok(self)
+    }
+
+    /// Binds to the given address and spawns a task accepting connections on it
+    pub async fn bind(self, address: SocketAddr) -> Result<SocketAddr, TcpServerError> {
+        let mut listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().await {
+                match stream {
+                    Ok(stream) => {
+                        let server = self.clone();
+                        tokio::spawn(async move { server.accept(stream).await });
+                    }
+                    Err(err) => warn!("Error accepting ILP-over-TCP connection: {}", err),
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    async fn accept(self, stream: TcpStream) {
+        let peer = stream.peer_addr().ok();
+        let _ = stream.set_nodelay(true);
+        let connection = match self.tls_acceptor {
+            Some(ref acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => framed(stream),
+                Err(err) => {
+                    debug!("TLS handshake with {:?} failed: {}", peer, err);
+                    return;
+                }
+            },
+            None => framed(stream),
+        };
+
+        match tokio::time::timeout(AUTH_TIMEOUT, self.validate_auth(connection)).await {
+            Ok(Ok((account, connection))) => {
+                debug!(
+                    "Added ILP-over-TCP connection for account {}: (id: {})",
+                    account.username(),
+                    account.id()
+                );
+                self.service.add_connection(account, connection);
+            }
+            Ok(Err(_)) => {
+                warn!(
+                    "Closing ILP-over-TCP connection from {:?} because of invalid credentials",
+                    peer
+                );
+            }
+            Err(_) => {
+                warn!(
+                    "Closing ILP-over-TCP connection from {:?} because it did not authenticate in time",
+                    peer
+                );
+            }
+        }
+    }
+
+    async fn validate_auth(&self, mut connection: Connection) -> Result<(A, Connection), ()> {
+        let frame = connection
+            .next()
+            .await
+            .and_then(|frame| frame.ok())
+            .and_then(|frame| Frame::from_bytes(frame).ok());
+        let (request_id, username, token) = match frame {
+            Some(Frame::Auth {
+                request_id,
+                username,
+                token,
+            }) => (request_id, username, token),
+            _ => {
+                warn!("Got an ILP-over-TCP connection where the first frame was not a valid auth frame");
+                return Err(());
+            }
+        };
+
+        let account = self
+            .store
+            .get_account_from_btp_auth(&username, token.expose_secret())
+            .await
+            .map_err(|_| warn!("ILP-over-TCP connection does not correspond to an account"))?;
+
+        connection
+            .send(Frame::AuthResponse { request_id }.to_bytes())
+            .await
+            .map_err(|err| error!("Error sending ILP-over-TCP auth response: {}", err))?;
+
+        Ok((account, connection))
+    }
+}
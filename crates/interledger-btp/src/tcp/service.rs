@@ -0,0 +1,575 @@
+use super::client::connect_to_tcp_account;
+use super::frame::Frame;
+use super::{has_tcp_url, Connection};
+use crate::BtpAccount;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use futures::{
+    channel::{
+        mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    future, SinkExt, StreamExt,
+};
+use interledger_packet::{Address, ErrorCode, Fulfill, Packet, Prepare, Reject, RejectBuilder};
+use interledger_service::*;
+use parking_lot::{Mutex, RwLock};
+use rand::random;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use stream_cancel::{Trigger, Valve};
+use tokio::time;
+use tracing::{debug, error, trace, warn};
+use uuid::Uuid;
+
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// After a failed dial, the account is not dialed again until the backoff has passed.
+// The backoff doubles after each consecutive failure.
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+type IlpResultChannel = oneshot::Sender<Result<Fulfill, Reject>>;
+type IncomingRequestBuffer<A> = UnboundedReceiver<(A, u32, Prepare)>;
+/// Outgoing requests waiting for a response, indexed by account id and request id,
+/// since request ids are only unique per connection
+type PendingRequests = HashMap<(Uuid, u32), IlpResultChannel>;
+
+/// Dialing state of the accounts with an ILP-over-TCP URL
+#[derive(Default)]
+struct Dials {
+    /// Held while dialing the account so that concurrent requests do not open duplicate connections
+    locks: HashMap<Uuid, Arc<tokio::sync::Mutex<()>>>,
+    /// When the account may be dialed again after a failed dial, and the backoff which applied
+    backoff: HashMap<Uuid, (Instant, Duration)>,
+}
+
+impl Dials {
+    fn backing_off(&self, account_id: &Uuid) -> bool {
+        match self.backoff.get(account_id) {
+            Some((retry_at, _)) => Instant::now() < *retry_at,
+            None => false,
+        }
+    }
+}
+
+struct ConnectionHandle {
+    /// Frames sent here are written to the connection
+    sender: UnboundedSender<Frame>,
+    _close: Trigger,
+}
+
+/// The TcpOutgoingService wraps all ILP-over-TCP connections, both the ones accepted
+/// by the `TcpServer` and the ones it dials itself. It implements OutgoingService for
+/// sending outgoing ILP Prepare packets over the connection to the account in `request.to`.
+///
+/// If there is no open connection for an account with a `btp+tcp` or `btp+tls` URL,
+/// the service connects to it before sending the request. Requests for any other
+/// account are passed through to the `next` service.
+///
+/// Like the `BtpOutgoingService`, calling `handle_incoming` with an `IncomingService`
+/// turns it into a bidirectional handler.
+#[derive(Clone)]
+pub struct TcpOutgoingService<O, A: Account> {
+    ilp_address: Address,
+    /// Open connections indexed by account id
+    connections: Arc<RwLock<HashMap<Uuid, ConnectionHandle>>>,
+    pending_outgoing: Arc<Mutex<PendingRequests>>,
+    pending_incoming: Arc<Mutex<Option<IncomingRequestBuffer<A>>>>,
+    incoming_sender: UnboundedSender<(A, u32, Prepare)>,
+    dials: Arc<Mutex<Dials>>,
+    next: O,
+    close_all_connections: Arc<Mutex<Option<Trigger>>>,
+    stream_valve: Arc<Valve>,
+}
+
+impl<O, A> TcpOutgoingService<O, A>
+where
+    O: OutgoingService<A> + Clone,
+    A: BtpAccount + Send + Sync + 'static,
+{
+    pub fn new(ilp_address: Address, next: O) -> Self {
+        let (incoming_sender, incoming_receiver) = unbounded();
+        let (close_all_connections, stream_valve) = Valve::new();
+        TcpOutgoingService {
+            ilp_address,
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            pending_outgoing: Arc::new(Mutex::new(HashMap::new())),
+            pending_incoming: Arc::new(Mutex::new(Some(incoming_receiver))),
+            incoming_sender,
+            dials: Arc::new(Mutex::new(Dials::default())),
+            next,
+            close_all_connections: Arc::new(Mutex::new(Some(close_all_connections))),
+            stream_valve: Arc::new(stream_valve),
+        }
+    }
+
+    /// Closes the connection associated with the provided `account_id`
+    pub fn close_connection(&self, account_id: &Uuid) {
+        self.connections.write().remove(account_id);
+    }
+
+    /// Close all of the open connections
+    pub fn close(&self) {
+        debug!("Closing all ILP-over-TCP connections");
+        self.close_all_connections.lock().take();
+    }
+
+    /// Returns true if there is an open connection to the account
+    pub fn is_connected(&self, account_id: &Uuid) -> bool {
+        self.connections.read().contains_key(account_id)
+    }
+
+    /// Connects to the provided accounts which have a `btp+tcp` or `btp+tls` URL.
+    /// Accounts with any other URL are skipped.
+    pub async fn connect_accounts(&self, accounts: impl IntoIterator<Item = A>) {
+        let connect = accounts
+            .into_iter()
+            .filter(has_tcp_url)
+            .map(|account| async move {
+                if let Err(err) = connect_to_tcp_account(account, self.clone()).await {
+                    warn!("{}", err);
+                }
+            });
+        future::join_all(connect).await;
+    }
+
+    // Set up a connection so that outgoing Prepare packets can be sent to it,
+    // incoming Prepare packets are buffered in a channel (until an IncomingService is added
+    // via the handle_incoming method), and ILP Fulfill and Reject packets will be
+    // sent back to the Future that sent the outgoing request originally.
+    pub(crate) fn add_connection(&self, account: A, connection: Connection) {
+        let account_id = account.id();
+        let (tx, rx) = unbounded::<Frame>();
+        let (mut write, read) = connection.split();
+        // Dropping the trigger (by removing the connection from the map) closes the connection
+        let (close_connection, valve) = Valve::new();
+        let valve = Arc::new(valve);
+
+        // tx -> rx -> write -> our peer
+        let mut rx = self.stream_valve.wrap(valve.wrap(rx));
+        let remove_connection = self.connection_remover(account_id, tx.clone());
+        tokio::spawn(async move {
+            while let Some(frame) = rx.next().await {
+                if let Err(err) = write.send(frame.to_bytes()).await {
+                    debug!(
+                        "Error writing to ILP-over-TCP connection for account {}: {}",
+                        account_id, err
+                    );
+                    break;
+                }
+            }
+            debug!(
+                "Finished writing to ILP-over-TCP connection for account: {}",
+                account_id
+            );
+            remove_connection();
+        });
+
+        let pending_outgoing = self.pending_outgoing.clone();
+        let incoming_sender = self.incoming_sender.clone();
+        let pong_tx = tx.clone();
+        let mut read = self.stream_valve.wrap(valve.wrap(read));
+        let remove_connection = self.connection_remover(account_id, tx.clone());
+        tokio::spawn(async move {
+            while let Some(frame) = read.next().await {
+                let frame = match frame {
+                    Ok(bytes) => Frame::from_bytes(bytes),
+                    Err(err) => {
+                        debug!(
+                            "Error reading from ILP-over-TCP connection for account {}: {}",
+                            account_id, err
+                        );
+                        break;
+                    }
+                };
+                match frame {
+                    Ok(Frame::Ilp { request_id, packet }) => handle_packet(
+                        &account,
+                        request_id,
+                        packet,
+                        &pending_outgoing,
+                        &incoming_sender,
+                    ),
+                    Ok(Frame::Ping) => {
+                        trace!("Responding to Ping from account {}", account_id);
+                        let _ = pong_tx.unbounded_send(Frame::Pong);
+                    }
+                    Ok(Frame::Pong) => {}
+                    Ok(frame) => warn!(
+                        "Got unexpected frame on ILP-over-TCP connection for account {}: {:?}",
+                        account_id, frame
+                    ),
+                    Err(err) => warn!("Unable to parse frame from account {}: {}", account_id, err),
+                }
+            }
+            debug!(
+                "Finished reading from ILP-over-TCP connection for account: {}",
+                account_id
+            );
+            remove_connection();
+        });
+
+        // Send pings until the connection or the service closes
+        let ping_tx = tx.clone();
+        let pings = time::interval_at(time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        let pings = self.stream_valve.wrap(valve.wrap(pings));
+        tokio::spawn(pings.for_each(move |_| {
+            let _ = ping_tx.unbounded_send(Frame::Ping);
+            future::ready(())
+        }));
+
+        // Replacing a previous connection drops its trigger, which closes it
+        self.connections.write().insert(
+            account_id,
+            ConnectionHandle {
+                sender: tx,
+                _close: close_connection,
+            },
+        );
+    }
+
+    /// Returns a function which forgets the connection, unless it was already
+    /// replaced by a newer one for the same account
+    fn connection_remover(
+        &self,
+        account_id: Uuid,
+        sender: UnboundedSender<Frame>,
+    ) -> impl FnOnce() + Send + 'static {
+        let connections = self.connections.clone();
+        move || {
+            let mut connections = connections.write();
+            if let Some(current) = connections.get(&account_id) {
+                if current.sender.same_receiver(&sender) {
+                    connections.remove(&account_id);
+                }
+            }
+        }
+    }
+
+    /// Convert this TcpOutgoingService into a bidirectional TcpService by adding a handler for incoming requests.
+    /// Incoming Prepare packets are each handled on their own task so that a slow request does not
+    /// hold up the others on the same connection.
+    pub async fn handle_incoming<I>(self, incoming_handler: I) -> TcpService<I, O, A>
+    where
+        I: IncomingService<A> + Clone + Send + 'static,
+    {
+        let connections = self.connections.clone();
+        let mut pending_incoming = self
+            .pending_incoming
+            .lock()
+            .take()
+            .expect("handle_incoming can only be called once");
+        tokio::spawn(async move {
+            while let Some((account, request_id, prepare)) = pending_incoming.next().await {
+                let account_id = account.id();
+                let connections = connections.clone();
+                let mut handler = incoming_handler.clone();
+                tokio::spawn(async move {
+                    trace!(
+                        "Handling incoming request {} from account: {} (id: {})",
+                        request_id,
+                        account.username(),
+                        account_id
+                    );
+                    let packet = match handler
+                        .handle_request(IncomingRequest {
+                            from: account,
+                            prepare,
+                        })
+                        .await
+                    {
+                        Ok(fulfill) => BytesMut::from(fulfill),
+                        Err(reject) => BytesMut::from(reject),
+                    };
+                    if let Some(connection) = connections.read().get(&account_id) {
+                        let _ = connection
+                            .sender
+                            .unbounded_send(Frame::Ilp { request_id, packet })
+                            .map_err(|err| {
+                                error!(
+                                    "Error sending response to account: {} {:?}",
+                                    account_id, err
+                                )
+                            });
+                    } else {
+                        error!(
+                            "Error sending response to account: {}, connection was closed",
+                            account_id
+                        );
+                    }
+                });
+            }
+            trace!("Finished reading from pending_incoming buffer");
+        });
+
+        TcpService {
+            outgoing: self,
+            incoming_handler_type: PhantomData,
+        }
+    }
+
+    fn reject(&self, code: ErrorCode, message: &[u8]) -> Reject {
+        RejectBuilder {
+            code,
+            message,
+            triggered_by: Some(&self.ilp_address),
+            data: &[],
+        }
+        .build()
+    }
+
+    /// Returns the sender for the account's connection, dialing it first if the account has
+    /// an ILP-over-TCP URL but no open connection. Accounts are dialed independently of each
+    /// other, and an account which failed to connect is not dialed again until its backoff has passed.
+    async fn get_or_connect(&self, account: &A) -> Option<UnboundedSender<Frame>> {
+        let account_id = account.id();
+        if let Some(connection) = self.connections.read().get(&account_id) {
+            return Some(connection.sender.clone());
+        }
+        if !has_tcp_url(account) {
+            return None;
+        }
+
+        let lock = {
+            let mut dials = self.dials.lock();
+            if dials.backing_off(&account_id) {
+                debug!("Not dialing account {} again yet", account_id);
+                return None;
+            }
+            dials.locks.entry(account_id).or_default().clone()
+        };
+        let _dialing = lock.lock().await;
+        // Another request may have connected, or failed to, while we were waiting
+        if let Some(connection) = self.connections.read().get(&account_id) {
+            return Some(connection.sender.clone());
+        }
+        if self.dials.lock().backing_off(&account_id) {
+            return None;
+        }
+        let result = connect_to_tcp_account(account.clone(), self.clone()).await;
+        {
+            let mut dials = self.dials.lock();
+            match result {
+                Ok(()) => {
+                    dials.backoff.remove(&account_id);
+                }
+                Err(err) => {
+                    let backoff = match dials.backoff.get(&account_id) {
+                        Some((_, backoff)) => (*backoff * 2).min(MAX_RECONNECT_BACKOFF),
+                        None => MIN_RECONNECT_BACKOFF,
+                    };
+                    warn!("{}, retrying in {:?}", err, backoff);
+                    dials
+                        .backoff
+                        .insert(account_id, (Instant::now() + backoff, backoff));
+                }
+            }
+        }
+        self.connections
+            .read()
+            .get(&account_id)
+            .map(|connection| connection.sender.clone())
+    }
+}
+
+fn handle_packet<A: Account>(
+    account: &A,
+    request_id: u32,
+    packet: BytesMut,
+    pending_outgoing: &Mutex<PendingRequests>,
+    incoming_sender: &UnboundedSender<(A, u32, Prepare)>,
+) {
+    let result = match Packet::try_from(packet) {
+        Ok(Packet::Prepare(prepare)) => {
+            trace!("Got incoming Prepare packet on request ID: {}", request_id);
+            let _ = incoming_sender
+                .unbounded_send((account.clone(), request_id, prepare))
+                .map_err(|err| error!("Unable to buffer incoming request: {:?}", err));
+            return;
+        }
+        Ok(Packet::Fulfill(fulfill)) => Ok(fulfill),
+        Ok(Packet::Reject(reject)) => Err(reject),
+        Err(err) => {
+            warn!(
+                "Unable to parse ILP packet from account {}: {:?}",
+                account.id(),
+                err
+            );
+            return;
+        }
+    };
+    if let Some(channel) = pending_outgoing.lock().remove(&(account.id(), request_id)) {
+        let _ = channel.send(result);
+    } else {
+        warn!(
+            "Got response to request {} from account {} that does not match an outgoing Prepare we sent it",
+            request_id,
+            account.id()
+        );
+    }
+}
+
+#[async_trait]
+impl<O, A> OutgoingService<A> for TcpOutgoingService<O, A>
+where
+    O: OutgoingService<A> + Send + Sync + Clone + 'static,
+    A: BtpAccount + Send + Sync + Clone + 'static,
+{
+    /// Send an outgoing request over the connection to `request.to`.
+    ///
+    /// Requests to accounts without an ILP-over-TCP connection are passed
+    /// through to the `next` handler.
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        let account_id = request.to.id();
+        let connection = match self.get_or_connect(&request.to).await {
+            Some(connection) => connection,
+            None => {
+                if has_tcp_url(&request.to) {
+                    return Err(self.reject(
+                        ErrorCode::T01_PEER_UNREACHABLE,
+                        b"Unable to connect to peer over ILP-over-TCP",
+                    ));
+                }
+                return self.next.send_request(request).await;
+            }
+        };
+
+        let request_id = random::<u32>();
+        let expires_at = request.prepare.expires_at();
+        trace!(
+            "Sending outgoing request {} to {} ({})",
+            request_id,
+            request.to.username(),
+            account_id
+        );
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending_outgoing
+            .lock()
+            .insert((account_id, request_id), sender);
+        let frame = Frame::Ilp {
+            request_id,
+            packet: BytesMut::from(request.prepare),
+        };
+        if let Err(err) = connection.unbounded_send(frame) {
+            self.pending_outgoing
+                .lock()
+                .remove(&(account_id, request_id));
+            error!(
+                "Error sending request {} to account {}: {:?}",
+                request_id, account_id, err
+            );
+            return Err(self.reject(ErrorCode::T01_PEER_UNREACHABLE, &[]));
+        }
+
+        // Wait until the Prepare expires at the latest
+        let timeout = expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        match time::timeout(timeout, receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => {
+                error!(
+                    "Connection closed before a response to request {} was received",
+                    request_id
+                );
+                Err(self.reject(ErrorCode::T01_PEER_UNREACHABLE, &[]))
+            }
+            Err(_) => {
+                debug!(
+                    "Request {} to account {} expired without a response",
+                    request_id, account_id
+                );
+                self.pending_outgoing
+                    .lock()
+                    .remove(&(account_id, request_id));
+                Err(self.reject(ErrorCode::R00_TRANSFER_TIMED_OUT, &[]))
+            }
+        }
+    }
+}
+
+/// A TcpOutgoingService which also handles incoming requests (see `TcpOutgoingService::handle_incoming`)
+#[derive(Clone)]
+pub struct TcpService<I, O, A: Account> {
+    outgoing: TcpOutgoingService<O, A>,
+    incoming_handler_type: PhantomData<I>,
+}
+
+impl<I, O, A> TcpService<I, O, A>
+where
+    I: IncomingService<A> + Clone + Send + 'static,
+    O: OutgoingService<A> + Clone,
+    A: BtpAccount + Send + Sync + 'static,
+{
+    /// Close all of the open connections
+    pub fn close(&self) {
+        self.outgoing.close();
+    }
+
+    pub fn close_connection(&self, account_id: &Uuid) {
+        self.outgoing.close_connection(account_id);
+    }
+}
+
+#[async_trait]
+impl<I, O, A> OutgoingService<A> for TcpService<I, O, A>
+where
+    I: Send, // This is a async/await requirement
+    O: OutgoingService<A> + Send + Sync + Clone + 'static,
+    A: BtpAccount + Send + Sync + Clone + 'static,
+{
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        self.outgoing.send_request(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_server::TestAccount;
+    use interledger_packet::FulfillBuilder;
+
+    fn account() -> TestAccount {
+        TestAccount {
+            id: Uuid::new_v4(),
+            ilp_over_btp_incoming_token: None,
+            ilp_over_btp_outgoing_token: None,
+            ilp_over_btp_url: None,
+        }
+    }
+
+    fn fulfill() -> BytesMut {
+        BytesMut::from(
+            FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        )
+    }
+
+    #[test]
+    fn ignores_responses_from_other_accounts() {
+        let alice = account();
+        let bob = account();
+        let (incoming_sender, _incoming_receiver) = unbounded();
+        let (sender, mut receiver) = oneshot::channel();
+        let pending_outgoing = Mutex::new(HashMap::new());
+        pending_outgoing.lock().insert((alice.id, 1), sender);
+
+        // Bob cannot resolve the request we sent to Alice with the same request id
+        handle_packet(&bob, 1, fulfill(), &pending_outgoing, &incoming_sender);
+        assert!(pending_outgoing.lock().contains_key(&(alice.id, 1)));
+        assert_eq!(receiver.try_recv().unwrap(), None);
+
+        handle_packet(&alice, 1, fulfill(), &pending_outgoing, &incoming_sender);
+        assert!(pending_outgoing.lock().is_empty());
+        assert!(receiver.try_recv().unwrap().unwrap().is_ok());
+    }
+}
@@ -40,6 +40,13 @@ pub const DEFAULT_METHOD_NOT_ALLOWED_TYPE: ApiErrorType = ApiErrorType {
     status: StatusCode::METHOD_NOT_ALLOWED,
 };
 
+/// 406 Not Acceptable HTTP Status Code
+pub const DEFAULT_NOT_ACCEPTABLE_TYPE: ApiErrorType = ApiErrorType {
+    r#type: &ProblemType::Default,
+    title: "Not Acceptable",
+    status: StatusCode::NOT_ACCEPTABLE,
+};
+
 /// 409 Conflict HTTP Status Code (used for conflicts)
 pub const DEFAULT_CONFLICT_TYPE: ApiErrorType = ApiErrorType {
     r#type: &ProblemType::Default,
@@ -160,6 +160,11 @@ impl ApiError {
         ApiError::from_api_error_type(&DEFAULT_METHOD_NOT_ALLOWED_TYPE)
     }
 
+    /// Returns a Not Acceptable [ApiError](./struct.ApiError.html)
+    pub fn not_acceptable() -> Self {
+        ApiError::from_api_error_type(&DEFAULT_NOT_ACCEPTABLE_TYPE)
+    }
+
     /// Returns an Account not Found [ApiError](./struct.ApiError.html)
     pub fn account_not_found() -> Self {
         ApiError::from_api_error_type(&ACCOUNT_NOT_FOUND_TYPE)
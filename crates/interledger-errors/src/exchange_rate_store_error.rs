@@ -12,6 +12,15 @@ pub enum ExchangeRateStoreError {
     PairNotFound { from: String, to: String },
 }
 
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for ExchangeRateStoreError {
+    fn from(src: RedisError) -> Self {
+        ExchangeRateStoreError::Other(Box::new(src))
+    }
+}
+
 impl From<ExchangeRateStoreError> for ApiError {
     fn from(src: ExchangeRateStoreError) -> Self {
         ApiError::internal_server_error().detail(src.to_string())
@@ -0,0 +1,38 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the InvoiceStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum InvoiceStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+    #[error("invoice `{0}` was not found")]
+    InvoiceNotFound(String),
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for InvoiceStoreError {
+    fn from(src: RedisError) -> Self {
+        InvoiceStoreError::Other(Box::new(src))
+    }
+}
+
+impl From<InvoiceStoreError> for ApiError {
+    fn from(src: InvoiceStoreError) -> Self {
+        match src {
+            InvoiceStoreError::InvoiceNotFound(_) => ApiError::not_found().detail(src.to_string()),
+            _ => ApiError::internal_server_error().detail(src.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<InvoiceStoreError> for warp::Rejection {
+    fn from(src: InvoiceStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
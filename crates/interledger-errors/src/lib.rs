@@ -26,6 +26,15 @@ pub use node_store_error::NodeStoreError;
 mod exchange_rate_store_error;
 pub use exchange_rate_store_error::ExchangeRateStoreError;
 
+mod invoice_store_error;
+pub use invoice_store_error::InvoiceStoreError;
+
+mod outgoing_payment_store_error;
+pub use outgoing_payment_store_error::OutgoingPaymentStoreError;
+
+mod receipt_store_error;
+pub use receipt_store_error::ReceiptStoreError;
+
 mod settlement_errors;
 pub use settlement_errors::{IdempotentStoreError, LeftoversStoreError, SettlementStoreError};
 
@@ -0,0 +1,40 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the OutgoingPaymentStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum OutgoingPaymentStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+    #[error("payment `{0}` was not found")]
+    PaymentNotFound(String),
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for OutgoingPaymentStoreError {
+    fn from(src: RedisError) -> Self {
+        OutgoingPaymentStoreError::Other(Box::new(src))
+    }
+}
+
+impl From<OutgoingPaymentStoreError> for ApiError {
+    fn from(src: OutgoingPaymentStoreError) -> Self {
+        match src {
+            OutgoingPaymentStoreError::PaymentNotFound(_) => {
+                ApiError::not_found().detail(src.to_string())
+            }
+            _ => ApiError::internal_server_error().detail(src.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<OutgoingPaymentStoreError> for warp::Rejection {
+    fn from(src: OutgoingPaymentStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
@@ -16,9 +16,9 @@ pub use self::address::{Address, AddressError};
 pub use self::error::{ErrorClass, ErrorCode};
 pub use self::errors::{PacketTypeError, ParseError, TrailingBytesError};
 
-pub use self::packet::MaxPacketAmountDetails;
 pub use self::packet::{Fulfill, Packet, PacketType, Prepare, Reject};
 pub use self::packet::{FulfillBuilder, PrepareBuilder, RejectBuilder};
+pub use self::packet::{MaxPacketAmountDetails, RateLimitedDetails};
 
 #[cfg(any(fuzzing, test))]
 pub fn lenient_packet_roundtrips(data: &[u8]) -> Result<(), ParseError> {
@@ -1,7 +1,7 @@
 use std::fmt;
 use std::io::prelude::*;
 use std::str;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::{Buf, BufMut, BytesMut};
@@ -639,6 +639,43 @@ impl MaxPacketAmountDetails {
     }
 }
 
+/// The data of a `T05 Rate Limited` reject: how long the sender should wait before
+/// sending the packet again
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitedDetails {
+    retry_after_ms: u64,
+}
+
+impl RateLimitedDetails {
+    #[inline]
+    pub fn new(retry_after: Duration) -> Self {
+        RateLimitedDetails {
+            retry_after_ms: u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX),
+        }
+    }
+
+    pub fn from_bytes<B: Buf>(mut bytes: B) -> Result<Self, std::io::Error> {
+        if bytes.remaining() < 8 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        Ok(RateLimitedDetails {
+            retry_after_ms: bytes.get_u64(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; 8] {
+        self.retry_after_ms.to_be_bytes()
+    }
+
+    #[inline]
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_millis(self.retry_after_ms)
+    }
+}
+
 impl From<Prepare> for BytesMut {
     fn from(prepare: Prepare) -> Self {
         prepare.buffer
@@ -1157,3 +1194,28 @@ mod test_max_packet_amount_details {
         assert_eq!(DETAILS.max_amount(), 0x0006_0504);
     }
 }
+
+#[cfg(test)]
+mod test_rate_limited_details {
+    use super::*;
+
+    static BYTES: &[u8] = b"\x00\x00\x00\x00\x00\x00\x05\xdc";
+
+    #[test]
+    fn test_from_bytes() {
+        let details = RateLimitedDetails::from_bytes(BYTES).unwrap();
+        assert_eq!(details.retry_after(), Duration::from_millis(1500));
+        assert_eq!(
+            RateLimitedDetails::from_bytes(&[0u8; 7][..])
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::UnexpectedEof,
+        );
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        let details = RateLimitedDetails::new(Duration::from_micros(1_500_999));
+        assert_eq!(&details.to_bytes()[..], BYTES);
+    }
+}
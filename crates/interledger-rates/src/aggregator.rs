@@ -0,0 +1,467 @@
+use crate::{ExchangeRate, PairRate};
+use num::rational::BigRational;
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// A rate returned by one of the polled providers
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RateSource {
+    /// Name of the provider, e.g. "CoinCap"
+    pub provider: String,
+    pub rate: ExchangeRate,
+}
+
+/// Where an aggregated rate came from
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RateProvenance {
+    /// The median of the rates of the sources
+    pub rate: ExchangeRate,
+    /// The providers which returned a rate for the asset in the poll the rate was taken from
+    pub sources: Vec<RateSource>,
+    /// When the rate was fetched, in seconds since the UNIX epoch
+    pub fetched_at: u64,
+}
+
+/// Combines the rates polled from several providers into one rate per asset.
+///
+/// For each asset, the median of the rates returned by the providers which responded
+/// is taken, if at least `quorum` of them returned a rate for it. Assets without a
+/// quorum keep their previous rate until it is older than `max_age`, after which they
+/// are dropped so that packets are not converted with outdated rates. Without a
+/// `max_age`, assets without a quorum are dropped right away. The bids and asks of
+/// pair rates are aggregated the same way, per pair.
+#[derive(Clone, Debug)]
+pub struct RateAggregator {
+    quorum: usize,
+    max_age: Option<Duration>,
+    latest: Arc<RwLock<HashMap<String, RateProvenance>>>,
+    latest_pairs: Arc<RwLock<HashMap<String, PolledPairRate>>>,
+}
+
+#[derive(Clone, Debug)]
+struct PolledPairRate {
+    rate: PairRate,
+    fetched_at: u64,
+}
+
+impl Default for RateAggregator {
+    /// Takes any rate returned by at least one provider, dropping the others
+    fn default() -> Self {
+        RateAggregator::new(1, None)
+    }
+}
+
+impl RateAggregator {
+    /// A `quorum` of 0 is treated as 1
+    pub fn new(quorum: usize, max_age: Option<Duration>) -> Self {
+        RateAggregator {
+            quorum: quorum.max(1),
+            max_age,
+            latest: Arc::new(RwLock::new(HashMap::new())),
+            latest_pairs: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// The number of providers which need to return a rate for an asset for it to be updated
+    pub fn quorum(&self) -> usize {
+        self.quorum
+    }
+
+    /// Takes the rates returned by each provider which responded, by provider name, and
+    /// returns the rates to apply: the aggregated rates of the assets which reached a
+    /// quorum, plus the previous rates of the other assets which are not too old yet
+    /// if there is a maximum age.
+    pub fn aggregate(
+        &self,
+        responses: Vec<(String, HashMap<String, ExchangeRate>)>,
+    ) -> HashMap<String, ExchangeRate> {
+        self.aggregate_at(responses, now())
+    }
+
+    fn aggregate_at(
+        &self,
+        responses: Vec<(String, HashMap<String, ExchangeRate>)>,
+        now: u64,
+    ) -> HashMap<String, ExchangeRate> {
+        let mut sources: HashMap<String, Vec<RateSource>> = HashMap::new();
+        for (provider, rates) in responses {
+            for (asset_code, rate) in rates {
+                sources.entry(asset_code).or_default().push(RateSource {
+                    provider: provider.clone(),
+                    rate,
+                });
+            }
+        }
+
+        let mut latest = self.latest.write();
+        let mut updated = HashSet::new();
+        for (asset_code, sources) in sources {
+            if sources.len() < self.quorum {
+                warn!(
+                    "Only {} of the required {} exchange rate providers returned a rate for {}, not updating it",
+                    sources.len(),
+                    self.quorum,
+                    asset_code
+                );
+                continue;
+            }
+            let rate = median(sources.iter().map(|source| &source.rate));
+            updated.insert(asset_code.clone());
+            latest.insert(
+                asset_code,
+                RateProvenance {
+                    rate,
+                    sources,
+                    fetched_at: now,
+                },
+            );
+        }
+        self.remove_outdated(&mut latest, &updated, now, |provenance| {
+            provenance.fetched_at
+        });
+
+        latest
+            .iter()
+            .map(|(asset_code, provenance)| (asset_code.clone(), provenance.rate.clone()))
+            .collect()
+    }
+
+    /// Takes the pair rates returned by each provider which responded and returns the pair
+    /// rates to apply: the median of the bids and of the asks of the pairs which reached a
+    /// quorum, plus the previous pair rates which are not too old yet if there is a maximum age.
+    pub fn aggregate_pairs(
+        &self,
+        responses: Vec<HashMap<String, PairRate>>,
+    ) -> HashMap<String, PairRate> {
+        self.aggregate_pairs_at(responses, now())
+    }
+
+    fn aggregate_pairs_at(
+        &self,
+        responses: Vec<HashMap<String, PairRate>>,
+        now: u64,
+    ) -> HashMap<String, PairRate> {
+        let mut pair_rates: HashMap<String, Vec<PairRate>> = HashMap::new();
+        for (pair, pair_rate) in responses.into_iter().flatten() {
+            pair_rates.entry(pair).or_default().push(pair_rate);
+        }
+
+        let mut latest = self.latest_pairs.write();
+        let mut updated = HashSet::new();
+        for (pair, pair_rates) in pair_rates {
+            if pair_rates.len() < self.quorum {
+                warn!(
+                    "Only {} of the required {} exchange rate providers returned a rate for {}, not updating it",
+                    pair_rates.len(),
+                    self.quorum,
+                    pair
+                );
+                continue;
+            }
+            let bid = median(pair_rates.iter().map(PairRate::bid));
+            let ask = median(pair_rates.iter().map(PairRate::ask));
+            // Each bid is at most its ask, so the median bid is at most the median ask
+            if let Some(rate) = PairRate::new(bid, ask) {
+                updated.insert(pair.clone());
+                latest.insert(
+                    pair,
+                    PolledPairRate {
+                        rate,
+                        fetched_at: now,
+                    },
+                );
+            }
+        }
+        self.remove_outdated(&mut latest, &updated, now, |polled| polled.fetched_at);
+
+        latest
+            .iter()
+            .map(|(pair, polled)| (pair.clone(), polled.rate.clone()))
+            .collect()
+    }
+
+    /// Drops the rates which are older than the maximum age, returning the remaining
+    /// ones if any were dropped. Used when no provider could be polled.
+    pub fn expire(&self) -> Option<HashMap<String, ExchangeRate>> {
+        let mut latest = self.latest.write();
+        if !self.remove_stale(&mut latest, now(), |provenance| provenance.fetched_at) {
+            return None;
+        }
+        Some(
+            latest
+                .iter()
+                .map(|(asset_code, provenance)| (asset_code.clone(), provenance.rate.clone()))
+                .collect(),
+        )
+    }
+
+    /// Drops the pair rates which are older than the maximum age, returning the
+    /// remaining ones if any were dropped. Used when no provider could be polled.
+    pub fn expire_pairs(&self) -> Option<HashMap<String, PairRate>> {
+        let mut latest = self.latest_pairs.write();
+        if !self.remove_stale(&mut latest, now(), |polled| polled.fetched_at) {
+            return None;
+        }
+        Some(
+            latest
+                .iter()
+                .map(|(pair, polled)| (pair.clone(), polled.rate.clone()))
+                .collect(),
+        )
+    }
+
+    /// Forgets all of the previous rates and pair rates
+    pub fn clear(&self) {
+        self.latest.write().clear();
+        self.latest_pairs.write().clear();
+    }
+
+    /// The provenance of the latest aggregated rate of each asset
+    pub fn provenance(&self) -> HashMap<String, RateProvenance> {
+        self.latest.read().clone()
+    }
+
+    /// Drops the stale rates if there is a maximum age, or else the rates which
+    /// were not `updated` by this poll
+    fn remove_outdated<T>(
+        &self,
+        latest: &mut HashMap<String, T>,
+        updated: &HashSet<String>,
+        now: u64,
+        fetched_at: impl Fn(&T) -> u64,
+    ) {
+        if self.max_age.is_some() {
+            self.remove_stale(latest, now, fetched_at);
+        } else {
+            // Without a maximum age, only the rates of this poll are applied
+            latest.retain(|asset_code, _| {
+                if updated.contains(asset_code) {
+                    return true;
+                }
+                warn!(
+                    "Exchange rate for {} was not updated by the latest poll, dropping it",
+                    asset_code
+                );
+                false
+            });
+        }
+    }
+
+    /// Returns whether any rate was removed
+    fn remove_stale<T>(
+        &self,
+        latest: &mut HashMap<String, T>,
+        now: u64,
+        fetched_at: impl Fn(&T) -> u64,
+    ) -> bool {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age.as_secs(),
+            None => return false,
+        };
+        let count = latest.len();
+        latest.retain(|asset_code, rate| {
+            let age = now.saturating_sub(fetched_at(rate));
+            let fresh = age <= max_age;
+            if !fresh {
+                warn!(
+                    "Exchange rate for {} was last updated {} seconds ago, dropping it",
+                    asset_code, age
+                );
+            }
+            fresh
+        });
+        latest.len() != count
+    }
+}
+
+/// The middle rate, or the mean of the two middle rates for an even number of rates
+fn median<'a>(rates: impl Iterator<Item = &'a ExchangeRate>) -> ExchangeRate {
+    let mut rates: Vec<&ExchangeRate> = rates.collect();
+    rates.sort();
+    let middle = rates.len() / 2;
+    if rates.len() % 2 == 1 {
+        rates[middle].clone()
+    } else {
+        let sum = rates[middle - 1].as_rational() + rates[middle].as_rational();
+        ExchangeRate::new(sum / BigRational::from_integer(2.into()))
+            .expect("the mean of non-negative rates is non-negative")
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn rates(rates: &[(&str, &str)]) -> HashMap<String, ExchangeRate> {
+        rates
+            .iter()
+            .map(|(code, rate)| (code.to_string(), ExchangeRate::from_str(rate).unwrap()))
+            .collect()
+    }
+
+    fn response(
+        provider: &str,
+        rates_: &[(&str, &str)],
+    ) -> (String, HashMap<String, ExchangeRate>) {
+        (provider.to_string(), rates(rates_))
+    }
+
+    #[test]
+    fn takes_median_of_providers() {
+        let aggregator = RateAggregator::default();
+        let aggregated = aggregator.aggregate_at(
+            vec![
+                response("A", &[("BTC", "10000"), ("ETH", "200")]),
+                response("B", &[("BTC", "10100"), ("ETH", "210")]),
+                response("C", &[("BTC", "50000")]),
+            ],
+            100,
+        );
+        assert_eq!(aggregated, rates(&[("BTC", "10100"), ("ETH", "205")]));
+
+        let provenance = aggregator.provenance();
+        assert_eq!(provenance["ETH"].fetched_at, 100);
+        assert_eq!(
+            provenance["ETH"]
+                .sources
+                .iter()
+                .map(|source| source.provider.as_str())
+                .collect::<Vec<_>>(),
+            vec!["A", "B"]
+        );
+    }
+
+    fn pair(bid: &str, ask: &str) -> PairRate {
+        PairRate::new(
+            ExchangeRate::from_str(bid).unwrap(),
+            ExchangeRate::from_str(ask).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn pairs(pairs: &[(&str, PairRate)]) -> HashMap<String, PairRate> {
+        pairs
+            .iter()
+            .map(|(key, pair)| (key.to_string(), pair.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn takes_median_of_pair_sides() {
+        let response = pairs;
+        let aggregated = RateAggregator::default().aggregate_pairs(vec![
+            response(&[("XRP/EUR", pair("0.2", "0.3")), ("ABC/DEF", pair("2", "2"))]),
+            response(&[("XRP/EUR", pair("0.28", "0.29"))]),
+            response(&[("XRP/EUR", pair("0.22", "0.25"))]),
+        ]);
+        assert_eq!(
+            aggregated,
+            response(&[
+                ("XRP/EUR", pair("0.22", "0.29")),
+                ("ABC/DEF", pair("2", "2"))
+            ])
+        );
+    }
+
+    #[test]
+    fn requires_quorum_and_drops_stale_rates() {
+        let aggregator = RateAggregator::new(2, Some(Duration::from_secs(60)));
+        let aggregated = aggregator.aggregate_at(
+            vec![
+                response("A", &[("BTC", "10000"), ("ETH", "200")]),
+                response("B", &[("BTC", "10200")]),
+            ],
+            100,
+        );
+        assert_eq!(aggregated, rates(&[("BTC", "10100")]));
+
+        // BTC misses the quorum but is recent enough to keep its previous rate
+        let aggregated = aggregator.aggregate_at(
+            vec![
+                response("A", &[("BTC", "11000"), ("ETH", "200")]),
+                response("B", &[("ETH", "202")]),
+            ],
+            160,
+        );
+        assert_eq!(aggregated, rates(&[("BTC", "10100"), ("ETH", "201")]));
+        assert_eq!(aggregator.provenance()["BTC"].fetched_at, 100);
+
+        let aggregated = aggregator.aggregate_at(
+            vec![
+                response("A", &[("ETH", "200")]),
+                response("B", &[("ETH", "200")]),
+            ],
+            161,
+        );
+        assert_eq!(aggregated, rates(&[("ETH", "200")]));
+        assert!(!aggregator.provenance().contains_key("BTC"));
+    }
+
+    #[test]
+    fn requires_quorum_and_drops_stale_pair_rates() {
+        let aggregator = RateAggregator::new(2, Some(Duration::from_secs(60)));
+        let aggregated = aggregator.aggregate_pairs_at(
+            vec![
+                pairs(&[
+                    ("XRP/EUR", pair("0.2", "0.22")),
+                    ("ABC/DEF", pair("2", "2")),
+                ]),
+                pairs(&[("XRP/EUR", pair("0.22", "0.24"))]),
+            ],
+            100,
+        );
+        assert_eq!(aggregated, pairs(&[("XRP/EUR", pair("0.21", "0.23"))]));
+
+        // XRP/EUR misses the quorum but is recent enough to keep its previous rate
+        let aggregated = aggregator.aggregate_pairs_at(
+            vec![pairs(&[("XRP/EUR", pair("1", "1"))]), HashMap::new()],
+            160,
+        );
+        assert_eq!(aggregated, pairs(&[("XRP/EUR", pair("0.21", "0.23"))]));
+
+        // Dropped once it is older than the maximum age
+        assert!(aggregator
+            .aggregate_pairs_at(vec![HashMap::new(), HashMap::new()], 161)
+            .is_empty());
+        assert_eq!(aggregator.expire_pairs(), None);
+    }
+
+    #[test]
+    fn drops_rates_missing_from_the_latest_poll_without_max_age() {
+        let aggregator = RateAggregator::new(2, None);
+        let aggregated = aggregator.aggregate_at(
+            vec![
+                response("A", &[("BTC", "10000"), ("ETH", "200")]),
+                response("B", &[("BTC", "10200"), ("ETH", "200")]),
+            ],
+            100,
+        );
+        assert_eq!(aggregated, rates(&[("BTC", "10100"), ("ETH", "200")]));
+
+        // BTC misses the quorum and ETH is not returned at all, within the same second
+        let aggregated = aggregator.aggregate_at(
+            vec![
+                response("A", &[("BTC", "11000"), ("XRP", "0.2")]),
+                response("B", &[("XRP", "0.2")]),
+            ],
+            100,
+        );
+        assert_eq!(aggregated, rates(&[("XRP", "0.2")]));
+        assert_eq!(
+            aggregator.provenance().keys().collect::<Vec<_>>(),
+            vec!["XRP"]
+        );
+    }
+}
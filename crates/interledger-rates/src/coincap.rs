@@ -1,3 +1,5 @@
+use crate::{ExchangeRate, RateProvider};
+use async_trait::async_trait;
 use futures::TryFutureExt;
 use once_cell::sync::Lazy;
 use reqwest::{Client, Url};
@@ -14,7 +16,7 @@ static COINCAP_RATES_URL: Lazy<Url> =
     Lazy::new(|| Url::parse("https://api.coincap.io/v2/rates").unwrap());
 
 #[derive(Deserialize, Debug)]
-struct Rate {
+struct CoinCapRate {
     symbol: String,
     #[serde(alias = "rateUsd", alias = "priceUsd")]
     rate_usd: String,
@@ -22,30 +24,55 @@ struct Rate {
 
 #[derive(Deserialize, Debug)]
 struct RateResponse {
-    data: Vec<Rate>,
+    data: Vec<CoinCapRate>,
 }
 
-pub async fn query_coincap(client: &Client) -> Result<HashMap<String, f64>, ()> {
+/// Polls the [CoinCap](https://coincap.io/) API
+#[derive(Clone, Debug, Default)]
+pub struct CoinCap {
+    client: Client,
+}
+
+impl CoinCap {
+    pub fn new() -> Self {
+        CoinCap::default()
+    }
+}
+
+#[async_trait]
+impl RateProvider for CoinCap {
+    fn name(&self) -> &str {
+        "CoinCap"
+    }
+
+    async fn fetch_rates(&self) -> Result<HashMap<String, ExchangeRate>, ()> {
+        query_coincap(&self.client).await
+    }
+}
+
+async fn query_coincap(client: &Client) -> Result<HashMap<String, ExchangeRate>, ()> {
     let (assets, rates) = futures::future::join(
         query_coincap_endpoint(client, COINCAP_ASSETS_URL.clone()),
         query_coincap_endpoint(client, COINCAP_RATES_URL.clone()),
     )
     .await;
 
-    let all_rates: HashMap<String, f64> = assets?
+    let all_rates: HashMap<String, ExchangeRate> = assets?
         .data
         .into_iter()
         .chain(rates?.data.into_iter())
-        .filter_map(|record| match f64::from_str(record.rate_usd.as_str()) {
-            Ok(rate) => Some((record.symbol.to_uppercase(), rate)),
-            Err(err) => {
-                warn!(
-                    "Unable to parse {} rate as an f64: {} {:?}",
-                    record.symbol, record.rate_usd, err
-                );
-                None
-            }
-        })
+        .filter_map(
+            |record| match ExchangeRate::from_str(record.rate_usd.as_str()) {
+                Ok(rate) => Some((record.symbol.to_uppercase(), rate)),
+                Err(err) => {
+                    warn!(
+                        "Unable to parse {} rate: {} {:?}",
+                        record.symbol, record.rate_usd, err
+                    );
+                    None
+                }
+            },
+        )
         .collect();
     Ok(all_rates)
 }
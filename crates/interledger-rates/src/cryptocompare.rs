@@ -1,10 +1,12 @@
+use crate::{ExchangeRate, RateProvider};
+use async_trait::async_trait;
 use futures::TryFutureExt;
 use once_cell::sync::Lazy;
 use reqwest::{Client, Url};
 use secrecy::{ExposeSecret, SecretString};
 use serde::Deserialize;
 use std::{collections::HashMap, iter::once};
-use tracing::error;
+use tracing::{error, warn};
 
 static CRYPTOCOMPARE_URL: Lazy<Url> = Lazy::new(|| {
     Url::parse("https://min-api.cryptocompare.com/data/top/mktcapfull?limit=100&tsym=USD").unwrap()
@@ -42,10 +44,37 @@ struct Response {
     data: Vec<Record>,
 }
 
-pub async fn query_cryptocompare(
+/// Polls the [CryptoCompare](https://cryptocompare.com) API, which requires an API key
+#[derive(Clone, Debug)]
+pub struct CryptoCompare {
+    client: Client,
+    api_key: SecretString,
+}
+
+impl CryptoCompare {
+    pub fn new(api_key: SecretString) -> Self {
+        CryptoCompare {
+            client: Client::new(),
+            api_key,
+        }
+    }
+}
+
+#[async_trait]
+impl RateProvider for CryptoCompare {
+    fn name(&self) -> &str {
+        "CryptoCompare"
+    }
+
+    async fn fetch_rates(&self) -> Result<HashMap<String, ExchangeRate>, ()> {
+        query_cryptocompare(&self.client, &self.api_key).await
+    }
+}
+
+async fn query_cryptocompare(
     client: &Client,
     api_key: &SecretString,
-) -> Result<HashMap<String, f64>, ()> {
+) -> Result<HashMap<String, ExchangeRate>, ()> {
     // ref: https://github.com/rust-lang/rust/pull/64856
     let header = format!("Apikey {}", api_key.expose_secret());
     let res = client
@@ -82,12 +111,13 @@ pub async fn query_cryptocompare(
         .data
         .into_iter()
         .filter_map(|asset| {
-            if let Some(raw) = asset.raw {
-                Some((asset.coin_info.name.to_uppercase(), raw.usd.price))
-            } else {
-                None
+            // The prices are JSON numbers, so they are taken as their shortest decimal representation
+            let price = ExchangeRate::from_f64(asset.raw?.usd.price);
+            if price.is_none() {
+                warn!("Ignoring invalid {} price", asset.coin_info.name);
             }
+            Some((asset.coin_info.name.to_uppercase(), price?))
         })
-        .chain(once(("USD".to_string(), 1.0)));
+        .chain(once(("USD".to_string(), ExchangeRate::one())));
     Ok(rates.collect())
 }
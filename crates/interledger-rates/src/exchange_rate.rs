@@ -0,0 +1,308 @@
+use num::{
+    bigint::BigInt,
+    integer::Integer,
+    rational::BigRational,
+    traits::{One, Signed, ToPrimitive, Zero},
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    error::Error as StdError,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// Exponents further from zero than this are rejected when parsing, so that
+/// untrusted input cannot make the node allocate arbitrarily large numbers
+const MAX_EXPONENT: i64 = 1000;
+
+/// Error returned when a string is not a valid exchange rate
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseExchangeRateError(String);
+
+impl Display for ParseExchangeRateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid exchange rate: {}", self.0)
+    }
+}
+
+impl StdError for ParseExchangeRateError {}
+
+/// The price of an asset, kept as an exact, non-negative rational number so that
+/// amounts are converted without the precision loss of floating point numbers.
+///
+/// Rates are parsed from decimal strings such as `"0.000123"` or `"1.5e-7"`, or
+/// from fractions such as `"1/3"`, and are displayed as decimals whenever they
+/// have a finite decimal expansion.
+///
+/// When serialized, rates which a JSON number represents exactly are written as
+/// numbers, and all other rates as strings. Both are accepted when deserializing,
+/// but numbers are limited to the precision of an `f64`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExchangeRate(BigRational);
+
+impl ExchangeRate {
+    /// Returns `None` if the rate is negative
+    pub fn new(rate: BigRational) -> Option<Self> {
+        if rate.is_negative() {
+            None
+        } else {
+            Some(ExchangeRate(rate))
+        }
+    }
+
+    pub fn one() -> Self {
+        ExchangeRate(BigRational::one())
+    }
+
+    /// Takes the exact value of the shortest decimal representation of the float, so that
+    /// for example `0.1` becomes exactly one tenth. Returns `None` for negative and
+    /// non-finite floats.
+    pub fn from_f64(rate: f64) -> Option<Self> {
+        rational_from_f64(rate).and_then(ExchangeRate::new)
+    }
+
+    /// The closest `f64` to the rate, for estimates which do not need to be exact
+    pub fn to_f64(&self) -> f64 {
+        // Parsing the decimal representation rounds correctly, unlike dividing
+        // the (possibly rounded) numerator by the denominator
+        match self.to_string().parse() {
+            Ok(rate) => rate,
+            Err(_) => {
+                let numer = self.0.numer().to_f64().unwrap_or(f64::INFINITY);
+                let denom = self.0.denom().to_f64().unwrap_or(f64::INFINITY);
+                numer / denom
+            }
+        }
+    }
+
+    pub fn as_rational(&self) -> &BigRational {
+        &self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+/// Converts the float to the exact value of its shortest decimal representation,
+/// which is what a human would write for it. Returns `None` for non-finite floats.
+pub fn rational_from_f64(value: f64) -> Option<BigRational> {
+    if !value.is_finite() {
+        return None;
+    }
+    // The `Display` implementation of floats prints the shortest decimal which
+    // parses back to the same float, without using exponents
+    parse_decimal(&value.to_string()).ok()
+}
+
+/// Parses a decimal with an optional exponent, such as `-12.5e-3`
+fn parse_decimal(s: &str) -> Result<BigRational, ParseExchangeRateError> {
+    let invalid = || ParseExchangeRateError(s.to_string());
+    let (negative, unsigned) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let (mantissa, exponent) = match unsigned.find(&['e', 'E'][..]) {
+        Some(index) => {
+            let exponent = i64::from_str(&unsigned[index + 1..]).map_err(|_| invalid())?;
+            (&unsigned[..index], exponent)
+        }
+        None => (unsigned, 0),
+    };
+    if exponent.abs() > MAX_EXPONENT {
+        return Err(invalid());
+    }
+    let (integer, fraction) = match mantissa.find('.') {
+        Some(index) => (&mantissa[..index], &mantissa[index + 1..]),
+        None => (mantissa, ""),
+    };
+    if (integer.is_empty() && fraction.is_empty())
+        || !integer
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+
+    let digits = BigInt::from_str(&format!("{}{}", integer, fraction)).map_err(|_| invalid())?;
+    let scale = fraction.len() as i64 - exponent;
+    let ten = BigInt::from(10u8);
+    let value = if scale >= 0 {
+        BigRational::new(digits, num::pow(ten, scale as usize))
+    } else {
+        BigRational::from_integer(digits * num::pow(ten, (-scale) as usize))
+    };
+    Ok(if negative { -value } else { value })
+}
+
+impl FromStr for ExchangeRate {
+    type Err = ParseExchangeRateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let rate = match s.find('/') {
+            Some(index) => {
+                let invalid = || ParseExchangeRateError(s.to_string());
+                let numer = BigInt::from_str(s[..index].trim()).map_err(|_| invalid())?;
+                let denom = BigInt::from_str(s[index + 1..].trim()).map_err(|_| invalid())?;
+                if denom.is_zero() {
+                    return Err(invalid());
+                }
+                BigRational::new(numer, denom)
+            }
+            None => parse_decimal(s)?,
+        };
+        ExchangeRate::new(rate).ok_or_else(|| ParseExchangeRateError(s.to_string()))
+    }
+}
+
+impl Display for ExchangeRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A reduced fraction has a finite decimal expansion if its denominator has
+        // no prime factors other than 2 and 5. The number of decimal places is then
+        // the larger of the two multiplicities.
+        let mut denom = self.0.denom().clone();
+        let (two, five) = (BigInt::from(2u8), BigInt::from(5u8));
+        let (mut twos, mut fives) = (0usize, 0usize);
+        while denom.is_even() {
+            denom /= &two;
+            twos += 1;
+        }
+        while (&denom % &five).is_zero() {
+            denom /= &five;
+            fives += 1;
+        }
+        if !denom.is_one() {
+            return write!(f, "{}/{}", self.0.numer(), self.0.denom());
+        }
+
+        let places = twos.max(fives);
+        let scaled = (&self.0 * BigRational::from_integer(num::pow(BigInt::from(10u8), places)))
+            .to_integer()
+            .to_string();
+        if places == 0 {
+            return f.write_str(&scaled);
+        }
+        let padded = format!("{:0>width$}", scaled, width = places + 1);
+        let (integer, fraction) = padded.split_at(padded.len() - places);
+        write!(f, "{}.{}", integer, fraction)
+    }
+}
+
+impl Serialize for ExchangeRate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let float = self.to_f64();
+        if ExchangeRate::from_f64(float).as_ref() == Some(self) {
+            serializer.serialize_f64(float)
+        } else {
+            serializer.serialize_str(&self.to_string())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ExchangeRate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ExchangeRateVisitor;
+
+        impl<'de> de::Visitor<'de> for ExchangeRateVisitor {
+            type Value = ExchangeRate;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a non-negative number, or a string containing one")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                ExchangeRate::from_str(value).map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+                Ok(ExchangeRate(BigRational::from_integer(BigInt::from(value))))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+                ExchangeRate::new(BigRational::from_integer(BigInt::from(value)))
+                    .ok_or_else(|| E::custom(ParseExchangeRateError(value.to_string())))
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+                ExchangeRate::from_f64(value)
+                    .ok_or_else(|| E::custom(ParseExchangeRateError(value.to_string())))
+            }
+        }
+
+        deserializer.deserialize_any(ExchangeRateVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(s: &str) -> ExchangeRate {
+        ExchangeRate::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn parses_decimals_and_fractions() {
+        let ratio = |numer: i64, denom: i64| {
+            ExchangeRate::new(BigRational::new(numer.into(), denom.into())).unwrap()
+        };
+        assert_eq!(rate("0.25"), ratio(1, 4));
+        assert_eq!(rate("1.5e-3"), ratio(3, 2000));
+        assert_eq!(rate("2E2"), ratio(200, 1));
+        assert_eq!(rate(".5"), ratio(1, 2));
+        assert_eq!(rate("7."), ratio(7, 1));
+        assert_eq!(rate(" 2/6 "), ratio(1, 3));
+        for invalid in &[
+            "", ".", "-1", "1/0", "abc", "1.2.3", "1e", "1e100000", "0x10",
+        ] {
+            assert!(ExchangeRate::from_str(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn displays_exact_decimals() {
+        assert_eq!(
+            rate("0.000000000000000000123").to_string(),
+            "0.000000000000000000123"
+        );
+        assert_eq!(
+            rate("12345678901234567890.5").to_string(),
+            "12345678901234567890.5"
+        );
+        assert_eq!(rate("2.50").to_string(), "2.5");
+        assert_eq!(rate("3e2").to_string(), "300");
+        assert_eq!(rate("1/3").to_string(), "1/3");
+        assert_eq!(rate("0").to_string(), "0");
+    }
+
+    #[test]
+    fn converts_floats_by_shortest_representation() {
+        assert_eq!(ExchangeRate::from_f64(0.1).unwrap(), rate("0.1"));
+        assert_eq!(ExchangeRate::from_f64(1e-7).unwrap(), rate("0.0000001"));
+        assert_eq!(ExchangeRate::from_f64(-1.0), None);
+        assert_eq!(ExchangeRate::from_f64(f64::NAN), None);
+        assert_eq!(rational_from_f64(-0.01).unwrap(), -rate("0.01").0);
+        assert_eq!(rate("0.1").to_f64(), 0.1);
+    }
+
+    #[test]
+    fn serializes_inexact_rates_as_strings() {
+        let rates: Vec<ExchangeRate> =
+            serde_json::from_str(r#"[2, 0.5, "0.1", "1.00000000000000000001", "1/3"]"#).unwrap();
+        assert_eq!(
+            serde_json::to_string(&rates).unwrap(),
+            r#"[2.0,0.5,0.1,"1.00000000000000000001","1/3"]"#
+        );
+        assert!(serde_json::from_str::<ExchangeRate>("-1").is_err());
+        assert!(serde_json::from_str::<ExchangeRate>("\"-1\"").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{parse_pair, ExchangeRate, PairRate, RateProvider};
+use async_trait::async_trait;
+use futures::TryFutureExt;
+use parking_lot::Mutex;
+use std::{collections::HashMap, path::PathBuf, str::FromStr, time::SystemTime};
+use tracing::{debug, error, warn};
+use yaml_rust::{Yaml, YamlLoader};
+
+/// Loads rates from a local JSON or YAML file which maps asset codes to their
+/// price in USD, such as `{"EUR": 1.1, "XRP": "0.25"}`. Keys of the form `BASE/QUOTE`
+/// are [pair rates](./struct.PairRate.html), with a single rate or a `bid` and an `ask`.
+///
+/// The file is read again whenever it changed since the previous poll, so rates can
+/// be pinned or updated without restarting the node. Numbers are taken exactly as
+/// written in the file.
+#[derive(Debug)]
+pub struct FileProvider {
+    path: PathBuf,
+    name: String,
+    loaded: Mutex<Option<LoadedFile>>,
+}
+
+#[derive(Debug)]
+struct LoadedFile {
+    modified: SystemTime,
+    len: u64,
+    rates: HashMap<String, ExchangeRate>,
+    pair_rates: HashMap<String, PairRate>,
+}
+
+type FileRates = (HashMap<String, ExchangeRate>, HashMap<String, PairRate>);
+
+impl FileProvider {
+    pub fn new(path: PathBuf) -> Self {
+        FileProvider {
+            name: path.display().to_string(),
+            path,
+            loaded: Mutex::new(None),
+        }
+    }
+
+    /// Returns the rates of the file, reading it again only if it changed
+    async fn load(&self) -> Result<FileRates, ()> {
+        let metadata = tokio::fs::metadata(&self.path)
+            .map_err(|err| error!("Error reading exchange rates file {}: {}", self.name, err))
+            .await?;
+        let modified = metadata
+            .modified()
+            .map_err(|err| error!("Error reading exchange rates file {}: {}", self.name, err))?;
+        if let Some(ref loaded) = *self.loaded.lock() {
+            if loaded.modified == modified && loaded.len == metadata.len() {
+                return Ok((loaded.rates.clone(), loaded.pair_rates.clone()));
+            }
+        }
+
+        let contents = tokio::fs::read_to_string(&self.path)
+            .map_err(|err| error!("Error reading exchange rates file {}: {}", self.name, err))
+            .await?;
+        let (rates, pair_rates) = parse_rates(&contents).map_err(|err| {
+            error!("Invalid exchange rates file {}: {}", self.name, err);
+        })?;
+        debug!(
+            "Loaded {} exchange rates and {} pair rates from {}",
+            rates.len(),
+            pair_rates.len(),
+            self.name
+        );
+        *self.loaded.lock() = Some(LoadedFile {
+            modified,
+            len: metadata.len(),
+            rates: rates.clone(),
+            pair_rates: pair_rates.clone(),
+        });
+        Ok((rates, pair_rates))
+    }
+}
+
+#[async_trait]
+impl RateProvider for FileProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn fetch_rates(&self) -> Result<HashMap<String, ExchangeRate>, ()> {
+        Ok(self.load().await?.0)
+    }
+
+    async fn fetch_pair_rates(&self) -> Result<HashMap<String, PairRate>, ()> {
+        Ok(self.load().await?.1)
+    }
+}
+
+/// Parses a YAML mapping of asset codes and pairs to rates. JSON objects are valid YAML mappings.
+fn parse_rates(contents: &str) -> Result<FileRates, String> {
+    let documents = YamlLoader::load_from_str(contents).map_err(|err| err.to_string())?;
+    let entries = match documents.first() {
+        Some(Yaml::Hash(entries)) => entries,
+        _ => return Err("expected a mapping of asset codes to rates".to_string()),
+    };
+    let mut rates = HashMap::with_capacity(entries.len());
+    let mut pair_rates = HashMap::new();
+    for (symbol, rate) in entries {
+        let symbol = match symbol {
+            Yaml::String(symbol) => symbol.to_uppercase(),
+            _ => return Err(format!("invalid asset code: {:?}", symbol)),
+        };
+        if symbol.contains('/') {
+            let pair_rate = match rate {
+                Yaml::Hash(sides) => {
+                    let side = |name: &str| {
+                        sides
+                            .get(&Yaml::String(name.to_string()))
+                            .and_then(rate_from_yaml)
+                    };
+                    match (side("bid"), side("ask")) {
+                        (Some(bid), Some(ask)) => PairRate::new(bid, ask),
+                        _ => None,
+                    }
+                }
+                rate => rate_from_yaml(rate).and_then(PairRate::mid),
+            };
+            match pair_rate {
+                Some(pair_rate) if parse_pair(&symbol).is_some() => {
+                    pair_rates.insert(symbol, pair_rate);
+                }
+                _ => warn!("Unable to parse {} pair rate: {:?}", symbol, rate),
+            }
+            continue;
+        }
+        match rate_from_yaml(rate) {
+            Some(rate) => {
+                rates.insert(symbol, rate);
+            }
+            None => warn!("Unable to parse {} rate: {:?}", symbol, rate),
+        }
+    }
+    Ok((rates, pair_rates))
+}
+
+fn rate_from_yaml(rate: &Yaml) -> Option<ExchangeRate> {
+    match rate {
+        Yaml::Real(rate) | Yaml::String(rate) => ExchangeRate::from_str(rate).ok(),
+        Yaml::Integer(rate) => ExchangeRate::from_str(&rate.to_string()).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn rates(rates: &[(&str, &str)]) -> HashMap<String, ExchangeRate> {
+        rates
+            .iter()
+            .map(|(code, rate)| (code.to_string(), ExchangeRate::from_str(rate).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_json_and_yaml() {
+        let expected = rates(&[
+            ("EUR", "1.1"),
+            ("XRP", "0.000000000000000000123"),
+            ("BTC", "10000"),
+            ("ABC", "1/3"),
+        ]);
+        assert_eq!(
+            parse_rates(
+                r#"{"EUR": 1.1, "xrp": 0.000000000000000000123, "BTC": 10000, "ABC": "1/3"}"#
+            )
+            .unwrap()
+            .0,
+            expected
+        );
+        assert_eq!(
+            parse_rates("EUR: 1.1\nxrp: 1.23e-19\nBTC: 10000\nABC: '1/3'\nDEF: -1\n")
+                .unwrap()
+                .0,
+            expected
+        );
+        assert!(parse_rates("- EUR\n- XRP").is_err());
+    }
+
+    #[test]
+    fn parses_pair_rates() {
+        let (prices, pair_rates) = parse_rates(
+            "EUR: 1.1\nxrp/eur: {bid: 0.2, ask: 0.21}\nABC/DEF: 3\nBAD/PAIR: {bid: 2, ask: 1}\nA/B/C: 1\n",
+        )
+        .unwrap();
+        assert_eq!(prices, rates(&[("EUR", "1.1")]));
+        let rate = |rate: &str| ExchangeRate::from_str(rate).unwrap();
+        let expected: HashMap<String, PairRate> = vec![
+            (
+                "XRP/EUR".to_string(),
+                PairRate::new(rate("0.2"), rate("0.21")).unwrap(),
+            ),
+            ("ABC/DEF".to_string(), PairRate::mid(rate("3")).unwrap()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(pair_rates, expected);
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "{{\"EUR\": 1.1}}").unwrap();
+        let provider = FileProvider::new(file.path().to_path_buf());
+        assert_eq!(
+            provider.fetch_rates().await.unwrap(),
+            rates(&[("EUR", "1.1")])
+        );
+
+        std::fs::write(file.path(), "EUR: 1.2\nGBP: 1.3\n").unwrap();
+        assert_eq!(
+            provider.fetch_rates().await.unwrap(),
+            rates(&[("EUR", "1.2"), ("GBP", "1.3")])
+        );
+
+        std::fs::remove_file(file.path()).unwrap();
+        assert!(provider.fetch_rates().await.is_err());
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{ExchangeRate, RateProvider};
+use async_trait::async_trait;
+use futures::TryFutureExt;
+use reqwest::{Client, Url};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, str::FromStr};
+use tracing::{error, warn};
+
+/// Configuration of an [`HttpJsonProvider`](./struct.HttpJsonProvider.html).
+///
+/// Paths are dot-separated object keys and array indices, such as `data.rates` or `0.price`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct HttpJsonConfig {
+    /// Identifies the provider in logs and in the provenance of the rates.
+    /// Defaults to the host of the URL.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The endpoint to poll with GET requests
+    pub url: Url,
+    /// Headers to send with each request, e.g. for an API key
+    #[serde(default)]
+    pub headers: HashMap<String, SecretString>,
+    /// Path to the rates within the response. Empty if the response is the rates.
+    #[serde(default)]
+    pub rates_path: String,
+    /// Path to the asset code within each entry, if the rates are an array of entries.
+    /// If not set, the rates must be an object keyed by asset code.
+    #[serde(default)]
+    pub symbol_path: Option<String>,
+    /// Path to the rate within each entry. Empty if the entries are the rates.
+    #[serde(default)]
+    pub rate_path: String,
+    /// The asset the rates are quoted in. If it is not USD, the response must
+    /// also contain the rate of USD so the rates can be converted.
+    #[serde(default = "HttpJsonConfig::default_base")]
+    pub base: String,
+    /// Whether the rates are the amount of each asset which one unit of the base
+    /// asset buys, rather than the price of each asset in the base asset
+    #[serde(default)]
+    pub inverse: bool,
+}
+
+impl HttpJsonConfig {
+    fn default_base() -> String {
+        "USD".to_string()
+    }
+}
+
+impl PartialEq for HttpJsonConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.url == other.url
+            && self.headers.len() == other.headers.len()
+            && self.headers.iter().all(|(name, value)| {
+                other
+                    .headers
+                    .get(name)
+                    .map(|other| other.expose_secret() == value.expose_secret())
+                    .unwrap_or(false)
+            })
+            && self.rates_path == other.rates_path
+            && self.symbol_path == other.symbol_path
+            && self.rate_path == other.rate_path
+            && self.base == other.base
+            && self.inverse == other.inverse
+    }
+}
+
+/// Polls any HTTP endpoint which returns rates as JSON, such as an in-house rates service.
+///
+/// Rates may be JSON numbers or strings with exact decimals, and are converted to
+/// prices in USD using the configured base asset.
+#[derive(Clone, Debug)]
+pub struct HttpJsonProvider {
+    name: String,
+    config: HttpJsonConfig,
+    client: Client,
+}
+
+impl HttpJsonProvider {
+    pub fn new(config: HttpJsonConfig) -> Self {
+        let name = config
+            .name
+            .clone()
+            .or_else(|| config.url.host_str().map(str::to_string))
+            .unwrap_or_else(|| config.url.to_string());
+        HttpJsonProvider {
+            name,
+            config,
+            client: Client::new(),
+        }
+    }
+
+    /// Extracts the rates from the response and converts them to prices in USD
+    fn parse_rates(&self, response: &Value) -> Result<HashMap<String, ExchangeRate>, ()> {
+        let rates = select(response, &self.config.rates_path).ok_or_else(|| {
+            error!(
+                "Response from {} has no rates at {:?}",
+                self.name, self.config.rates_path
+            )
+        })?;
+        let entries: Vec<(String, &Value)> = match (&self.config.symbol_path, rates) {
+            (Some(symbol_path), Value::Array(entries)) => entries
+                .iter()
+                .filter_map(|entry| {
+                    let symbol = select(entry, symbol_path).and_then(Value::as_str);
+                    if symbol.is_none() {
+                        warn!("Ignoring rate without asset code from {}", self.name);
+                    }
+                    Some((symbol?.to_uppercase(), entry))
+                })
+                .collect(),
+            (None, Value::Object(entries)) => entries
+                .iter()
+                .map(|(symbol, entry)| (symbol.to_uppercase(), entry))
+                .collect(),
+            _ => {
+                error!(
+                    "Rates from {} are not an {}",
+                    self.name,
+                    if self.config.symbol_path.is_some() {
+                        "array"
+                    } else {
+                        "object"
+                    }
+                );
+                return Err(());
+            }
+        };
+
+        let mut prices = HashMap::with_capacity(entries.len() + 1);
+        for (symbol, entry) in entries {
+            let rate = select(entry, &self.config.rate_path).and_then(rate_from_value);
+            let price = match rate {
+                Some(ref rate) if self.config.inverse && rate.is_zero() => None,
+                Some(rate) if self.config.inverse => ExchangeRate::new(rate.as_rational().recip()),
+                rate => rate,
+            };
+            match price {
+                Some(price) => {
+                    prices.insert(symbol, price);
+                }
+                None => warn!("Unable to parse {} rate from {}", symbol, self.name),
+            }
+        }
+
+        let base = self.config.base.to_uppercase();
+        prices.insert(base.clone(), ExchangeRate::one());
+        if base == "USD" {
+            return Ok(prices);
+        }
+        let usd = match prices.get("USD") {
+            Some(usd) if !usd.is_zero() => usd.as_rational().clone(),
+            _ => {
+                error!(
+                    "Response from {} has no rate for USD, which is needed to convert its rates from {}",
+                    self.name, base
+                );
+                return Err(());
+            }
+        };
+        Ok(prices
+            .into_iter()
+            .filter_map(|(symbol, price)| {
+                Some((symbol, ExchangeRate::new(price.as_rational() / &usd)?))
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl RateProvider for HttpJsonProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn fetch_rates(&self) -> Result<HashMap<String, ExchangeRate>, ()> {
+        let mut request = self.client.get(self.config.url.clone());
+        for (name, value) in &self.config.headers {
+            request = request.header(name.as_str(), value.expose_secret().as_str());
+        }
+        let res = request
+            .send()
+            .map_err(|err| {
+                error!(
+                    "Error fetching exchange rates from {}: {:?}",
+                    self.name, err
+                );
+            })
+            .await?;
+
+        let res = res.error_for_status().map_err(|err| {
+            error!(
+                "HTTP error getting exchange rates from {}: {:?}",
+                self.name, err
+            );
+        })?;
+
+        let response: Value = res
+            .json()
+            .map_err(|err| {
+                error!(
+                    "Error getting exchange rate response body from {}, invalid JSON: {:?}",
+                    self.name, err
+                );
+            })
+            .await?;
+        self.parse_rates(&response)
+    }
+}
+
+/// Follows a dot-separated path of object keys and array indices
+fn select<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .filter(|key| !key.is_empty())
+        .try_fold(value, |value, key| match value {
+            Value::Object(map) => map.get(key),
+            Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+fn rate_from_value(value: &Value) -> Option<ExchangeRate> {
+    match value {
+        Value::String(rate) => ExchangeRate::from_str(rate).ok(),
+        // Numbers are taken as their shortest decimal representation
+        Value::Number(rate) => ExchangeRate::from_str(&rate.to_string()).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn provider(config: Value) -> HttpJsonProvider {
+        HttpJsonProvider::new(serde_json::from_value(config).unwrap())
+    }
+
+    fn rate(rate: &str) -> ExchangeRate {
+        ExchangeRate::from_str(rate).unwrap()
+    }
+
+    #[test]
+    fn extracts_rates_from_arrays() {
+        let provider = provider(json!({
+            "url": "https://rates.example/v1/prices",
+            "rates_path": "data.prices",
+            "symbol_path": "asset.code",
+            "rate_path": "usd",
+        }));
+        assert_eq!(provider.name(), "rates.example");
+        let rates = provider
+            .parse_rates(&json!({"data": {"prices": [
+                {"asset": {"code": "btc"}, "usd": "10000.123456789012345"},
+                {"asset": {"code": "XRP"}, "usd": 0.25},
+                {"asset": {"code": "ABC"}, "usd": "not a rate"},
+                {"usd": 1},
+            ]}}))
+            .unwrap();
+        let expected: HashMap<String, ExchangeRate> = vec![
+            ("BTC".to_string(), rate("10000.123456789012345")),
+            ("XRP".to_string(), rate("0.25")),
+            ("USD".to_string(), rate("1")),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(rates, expected);
+        assert!(provider.parse_rates(&json!({"data": {}})).is_err());
+    }
+
+    #[test]
+    fn converts_rates_from_base_asset() {
+        let provider = provider(json!({
+            "name": "In-house",
+            "url": "https://rates.example/latest",
+            "rates_path": "rates",
+            "base": "EUR",
+            "inverse": true,
+        }));
+        assert_eq!(provider.name(), "In-house");
+        // 1 EUR buys 1.25 USD and 0.8 GBP
+        let rates = provider
+            .parse_rates(&json!({"base": "EUR", "rates": {"USD": 1.25, "GBP": "0.8"}}))
+            .unwrap();
+        assert_eq!(rates["EUR"], rate("1.25"));
+        assert_eq!(rates["GBP"], rate("1.5625"));
+        assert_eq!(rates["USD"], rate("1"));
+        assert!(provider
+            .parse_rates(&json!({"rates": {"GBP": 0.8}}))
+            .is_err());
+    }
+}
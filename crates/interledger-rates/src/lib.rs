@@ -1,25 +1,54 @@
-use futures::TryFutureExt;
+use async_trait::async_trait;
+use futures::future::{join, join_all};
 use interledger_errors::ExchangeRateStoreError;
-use reqwest::Client;
+use parking_lot::Mutex;
 use secrecy::SecretString;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tracing::{debug, error, trace, warn};
 
+mod aggregator;
+pub use aggregator::{RateAggregator, RateProvenance, RateSource};
+
 mod cryptocompare;
+pub use cryptocompare::CryptoCompare;
 
 mod coincap;
+pub use coincap::CoinCap;
+
+mod file;
+pub use file::FileProvider;
+
+mod http_json;
+pub use http_json::{HttpJsonConfig, HttpJsonProvider};
+
+mod exchange_rate;
+pub use exchange_rate::{rational_from_f64, ExchangeRate, ParseExchangeRateError};
+
+mod pair_rate;
+pub use pair_rate::{parse_pair, PairRate};
+
+mod rate_change_guard;
+pub use rate_change_guard::{
+    AcceptedRates, AcceptedRatesStore, HeldPairRate, HeldRate, RateChangeGuard,
+};
 
 pub trait ExchangeRateStore: Clone {
     // TODO we may want to make this async if/when we use pubsub to broadcast
     // rate changes to different instances of a horizontally-scalable node
-    fn set_exchange_rates(&self, rates: HashMap<String, f64>)
-        -> Result<(), ExchangeRateStoreError>;
+    fn set_exchange_rates(
+        &self,
+        rates: HashMap<String, ExchangeRate>,
+    ) -> Result<(), ExchangeRateStoreError>;
 
-    fn get_exchange_rates(&self, asset_codes: &[&str]) -> Result<Vec<f64>, ExchangeRateStoreError>;
+    fn get_exchange_rates(
+        &self,
+        asset_codes: &[&str],
+    ) -> Result<Vec<ExchangeRate>, ExchangeRateStoreError>;
 
     // TODO should this be on the API instead? That's where it's actually used
     // TODO should we combine this method with get_exchange_rates?
@@ -27,10 +56,64 @@ pub trait ExchangeRateStore: Clone {
     // (so that we don't accidentally lock up the RwLock on the store's exchange_rates)
     // but in the normal case of getting the rate between two assets, we don't want to
     // copy all the rate data
-    fn get_all_exchange_rates(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError>;
+    fn get_all_exchange_rates(
+        &self,
+    ) -> Result<HashMap<String, ExchangeRate>, ExchangeRateStoreError>;
+
+    /// Sets the direct rates between pairs of assets, keyed by `BASE/QUOTE`,
+    /// replacing all of the previous ones
+    fn set_pair_rates(
+        &self,
+        rates: HashMap<String, PairRate>,
+    ) -> Result<(), ExchangeRateStoreError>;
+
+    fn get_all_pair_rates(&self) -> Result<HashMap<String, PairRate>, ExchangeRateStoreError>;
+
+    /// The direct rate of the `base/quote` pair, if one is set
+    fn get_pair_rate(
+        &self,
+        base: &str,
+        quote: &str,
+    ) -> Result<Option<PairRate>, ExchangeRateStoreError> {
+        Ok(self
+            .get_all_pair_rates()?
+            .remove(&format!("{}/{}", base, quote)))
+    }
+}
+
+/// A source of exchange rates polled by the [`ExchangeRateFetcher`](./struct.ExchangeRateFetcher.html).
+/// Providers other than the built-in ones can be added by implementing this trait.
+#[async_trait]
+pub trait RateProvider: Send + Sync {
+    /// Identifies the provider in logs and in the provenance of the rates
+    fn name(&self) -> &str;
+
+    /// Returns the price in USD of each asset, by asset code
+    async fn fetch_rates(&self) -> Result<HashMap<String, ExchangeRate>, ()>;
+
+    /// Returns direct rates between pairs of assets, keyed by `BASE/QUOTE`.
+    /// Most providers only have prices in USD, so there are none by default.
+    async fn fetch_pair_rates(&self) -> Result<HashMap<String, PairRate>, ()> {
+        Ok(HashMap::new())
+    }
+}
+
+#[async_trait]
+impl RateProvider for Box<dyn RateProvider> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    async fn fetch_rates(&self) -> Result<HashMap<String, ExchangeRate>, ()> {
+        (**self).fetch_rates().await
+    }
+
+    async fn fetch_pair_rates(&self) -> Result<HashMap<String, PairRate>, ()> {
+        (**self).fetch_pair_rates().await
+    }
 }
 
-/// This determines which external API service to poll for exchange rates.
+/// This determines which of the built-in providers to poll for exchange rates.
 #[derive(Debug, Clone, Deserialize)]
 pub enum ExchangeRateProvider {
     /// Use the [CoinCap] API.
@@ -51,6 +134,26 @@ pub enum ExchangeRateProvider {
     /// [CryptoCompare]: https://cryptocompare.com
     #[serde(alias = "cryptocompare")]
     CryptoCompare(SecretString),
+    /// Poll any HTTP endpoint which returns rates as JSON, see
+    /// [`HttpJsonConfig`](./struct.HttpJsonConfig.html)
+    #[serde(alias = "http")]
+    Http(Box<HttpJsonConfig>),
+    /// Load the rates from a local JSON or YAML file, see
+    /// [`FileProvider`](./struct.FileProvider.html)
+    #[serde(alias = "file")]
+    File(PathBuf),
+}
+
+impl ExchangeRateProvider {
+    /// Creates the configured provider
+    pub fn into_provider(self) -> Box<dyn RateProvider> {
+        match self {
+            ExchangeRateProvider::CoinCap => Box::new(CoinCap::new()),
+            ExchangeRateProvider::CryptoCompare(api_key) => Box::new(CryptoCompare::new(api_key)),
+            ExchangeRateProvider::Http(config) => Box::new(HttpJsonProvider::new(*config)),
+            ExchangeRateProvider::File(path) => Box::new(FileProvider::new(path)),
+        }
+    }
 }
 
 impl PartialEq<ExchangeRateProvider> for ExchangeRateProvider {
@@ -65,6 +168,8 @@ impl PartialEq<ExchangeRateProvider> for ExchangeRateProvider {
             {
                 true
             }
+            (ExchangeRateProvider::Http(l), ExchangeRateProvider::Http(r)) => l == r,
+            (ExchangeRateProvider::File(l), ExchangeRateProvider::File(r)) => l == r,
             _ => false,
         }
     }
@@ -73,11 +178,14 @@ impl PartialEq<ExchangeRateProvider> for ExchangeRateProvider {
 /// Poll exchange rate providers for the current exchange rates
 #[derive(Clone)]
 pub struct ExchangeRateFetcher<S> {
-    provider: ExchangeRateProvider,
+    providers: Vec<Arc<dyn RateProvider>>,
+    aggregator: RateAggregator,
     consecutive_failed_polls: Arc<AtomicU32>,
     failed_polls_before_invalidation: u32,
     store: S,
-    client: Client,
+    rate_change_guard: Option<RateChangeGuard>,
+    /// The pair rates which were set by the previous poll, as opposed to those set via the API
+    polled_pairs: Arc<Mutex<HashSet<String>>>,
 }
 
 impl<S> ExchangeRateFetcher<S>
@@ -85,25 +193,48 @@ where
     S: ExchangeRateStore + Send + Sync + 'static,
 {
     /// Simple constructor
-    pub fn new(
-        provider: ExchangeRateProvider,
+    pub fn new<P: RateProvider + 'static>(
+        provider: P,
         failed_polls_before_invalidation: u32,
         store: S,
     ) -> Self {
         ExchangeRateFetcher {
-            provider,
+            providers: vec![Arc::new(provider)],
+            aggregator: RateAggregator::default(),
             consecutive_failed_polls: Arc::new(AtomicU32::new(0)),
             failed_polls_before_invalidation,
             store,
-            client: Client::new(),
+            rate_change_guard: None,
+            polled_pairs: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
+    /// Also polls the given provider. The rates of all providers are combined by the
+    /// [`RateAggregator`](./struct.RateAggregator.html)
+    pub fn with_provider<P: RateProvider + 'static>(mut self, provider: P) -> Self {
+        self.providers.push(Arc::new(provider));
+        self
+    }
+
+    /// Combines the rates of the providers with the given aggregator, which
+    /// by default takes the median of the rates of any provider which responded
+    pub fn with_aggregator(mut self, aggregator: RateAggregator) -> Self {
+        self.aggregator = aggregator;
+        self
+    }
+
+    /// Holds back polled rates which moved too much since the previous poll,
+    /// see [`RateChangeGuard`](./struct.RateChangeGuard.html)
+    pub fn with_rate_change_guard(mut self, rate_change_guard: RateChangeGuard) -> Self {
+        self.rate_change_guard = Some(rate_change_guard);
+        self
+    }
+
     /// Spawns a future which calls [`self.update_rates()`](./struct.ExchangeRateFetcher.html#method.update_rates) every `interval`
     pub fn spawn_interval(self, interval: Duration) {
         debug!(
-            "Starting interval to poll exchange rate provider: {:?} for rates",
-            self.provider
+            "Starting interval to poll exchange rate providers: {:?} for rates",
+            self.provider_names()
         );
         let interval = async move {
             let mut interval = tokio::time::interval(interval);
@@ -116,13 +247,73 @@ where
         tokio::spawn(interval);
     }
 
-    /// Calls the proper exchange rate provider
-    async fn fetch_rates(&self) -> Result<HashMap<String, f64>, ()> {
-        match self.provider {
-            ExchangeRateProvider::CryptoCompare(ref api_key) => {
-                cryptocompare::query_cryptocompare(&self.client, api_key).await
-            }
-            ExchangeRateProvider::CoinCap => coincap::query_coincap(&self.client).await,
+    fn provider_names(&self) -> Vec<&str> {
+        self.providers
+            .iter()
+            .map(|provider| provider.name())
+            .collect()
+    }
+
+    /// Polls all of the providers and aggregates their prices and pair rates.
+    /// Fails if fewer providers than the quorum responded.
+    async fn fetch_rates(
+        &self,
+    ) -> Result<(HashMap<String, ExchangeRate>, HashMap<String, PairRate>), ()> {
+        let responses = join_all(self.providers.iter().map(|provider| async move {
+            let (rates, pair_rates) =
+                join(provider.fetch_rates(), provider.fetch_pair_rates()).await;
+            (provider.name(), rates, pair_rates)
+        }))
+        .await;
+        let mut pair_responses = Vec::new();
+        let responses: Vec<_> = responses
+            .into_iter()
+            .filter_map(|(name, rates, pair_rates)| match rates {
+                Ok(rates) => {
+                    match pair_rates {
+                        Ok(pair_rates) => pair_responses.push(pair_rates),
+                        Err(_) => warn!("Failed to fetch pair rates from {}", name),
+                    }
+                    Some((name.to_string(), rates))
+                }
+                Err(_) => {
+                    warn!("Failed to fetch exchange rates from {}", name);
+                    None
+                }
+            })
+            .collect();
+        if responses.len() < self.aggregator.quorum() {
+            return Err(());
+        }
+        Ok((
+            self.aggregator.aggregate(responses),
+            self.aggregator.aggregate_pairs(pair_responses),
+        ))
+    }
+
+    /// Replaces the pair rates set by the previous poll with the polled ones,
+    /// keeping the other pair rates, such as those set via the API
+    fn apply_pair_rates(&self, pair_rates: HashMap<String, PairRate>) {
+        let mut polled_pairs = self.polled_pairs.lock();
+        if polled_pairs.is_empty() && pair_rates.is_empty() {
+            return;
+        }
+        // Held pairs are replaced by the next poll too, also once they are confirmed
+        let polled: HashSet<String> = pair_rates.keys().cloned().collect();
+        let result = match self.rate_change_guard {
+            Some(ref guard) => guard.apply_pairs(&self.store, &polled_pairs, pair_rates),
+            None => self
+                .store
+                .get_all_pair_rates()
+                .and_then(|mut all_pair_rates| {
+                    all_pair_rates.retain(|pair, _| !polled_pairs.contains(pair));
+                    all_pair_rates.extend(pair_rates);
+                    self.store.set_pair_rates(all_pair_rates)
+                }),
+        };
+        match result {
+            Ok(()) => *polled_pairs = polled,
+            Err(err) => error!("Error setting pair rates in store: {}", err),
         }
     }
 
@@ -133,32 +324,58 @@ where
         let failed_polls_before_invalidation = self.failed_polls_before_invalidation;
         let store = self.store.clone();
         let store_clone = self.store.clone();
-        let provider = self.provider.clone();
+        let aggregator = self.aggregator.clone();
         #[allow(clippy::cognitive_complexity)]
-        let mut rates = self.fetch_rates()
-            .map_err(move |_| {
+        let (mut rates, pair_rates) = self.fetch_rates()
+            .await
+            .map_err(|_| {
                 // Note that a race between the read on this line and the check on the line after
                 // is quite unlikely as long as the interval between polls is reasonable.
                 let failed_polls = consecutive_failed_polls.fetch_add(1, Ordering::Relaxed);
                 if failed_polls < failed_polls_before_invalidation {
                     warn!("Failed to update exchange rates (previous consecutive failed attempts: {})", failed_polls);
+                    // Rates which were not updated for too long are dropped either way
+                    if let Some(mut rates) = aggregator.expire() {
+                        rates.insert("USD".to_string(), ExchangeRate::one());
+                        if store.set_exchange_rates(rates).is_err() {
+                            error!("Failed to remove outdated exchange rates from the store");
+                        }
+                    }
+                    if let Some(pair_rates) = aggregator.expire_pairs() {
+                        self.apply_pair_rates(pair_rates);
+                    }
                 } else {
                     error!("Failed to update exchange rates (previous consecutive failed attempts: {}), removing old rates for safety", failed_polls);
                     // Clear out all of the old rates
+                    aggregator.clear();
+                    self.apply_pair_rates(HashMap::new());
                     if store.set_exchange_rates(HashMap::new()).is_err() {
                         error!("Failed to clear exchange rates cache after exchange rates server became unresponsive; panicking");
                         panic!("Failed to clear exchange rates cache after exchange rates server became unresponsive");
                     }
                 }
-            }).await?;
+            })?;
 
-        trace!("Fetched exchange rates: {:?}", rates);
+        trace!(
+            "Fetched exchange rates: {:?}, pair rates: {:?}",
+            rates,
+            pair_rates
+        );
+        self.apply_pair_rates(pair_rates);
         let num_rates = rates.len();
-        rates.insert("USD".to_string(), 1.0);
-        if store_clone.set_exchange_rates(rates).is_ok() {
+        rates.insert("USD".to_string(), ExchangeRate::one());
+        let result = match self.rate_change_guard {
+            Some(ref guard) => guard.apply(&store_clone, rates),
+            None => store_clone.set_exchange_rates(rates),
+        };
+        if result.is_ok() {
             // Reset our invalidation counter
             consecutive_failed_polls_zeroer.store(0, Ordering::Relaxed);
-            debug!("Updated {} exchange rates from {:?}", num_rates, provider);
+            debug!(
+                "Updated {} exchange rates from {:?}",
+                num_rates,
+                self.provider_names()
+            );
             Ok(())
         } else {
             error!("Error setting exchange rates in store");
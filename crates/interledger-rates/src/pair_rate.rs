@@ -0,0 +1,125 @@
+use crate::ExchangeRate;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::fmt;
+
+/// A direct exchange rate between two assets, configured for a `BASE/QUOTE` pair
+/// such as `XRP/EUR` and expressed in units of the quote asset per unit of the base asset.
+///
+/// Pair rates take precedence over the prices in USD, for assets which have no price
+/// in USD or for which the node has contracted cross rates. The bid is used to convert
+/// from the base to the quote asset, and the ask to convert from the quote to the base
+/// asset, so a bid below the ask leaves the difference to the node.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PairRate {
+    /// The rate at which the node buys the base asset
+    bid: ExchangeRate,
+    /// The rate at which the node sells the base asset
+    ask: ExchangeRate,
+}
+
+impl PairRate {
+    /// Returns `None` if the bid is higher than the ask or the ask is zero
+    pub fn new(bid: ExchangeRate, ask: ExchangeRate) -> Option<Self> {
+        if bid > ask || ask.is_zero() {
+            None
+        } else {
+            Some(PairRate { bid, ask })
+        }
+    }
+
+    /// A pair rate with the same bid and ask
+    pub fn mid(rate: ExchangeRate) -> Option<Self> {
+        PairRate::new(rate.clone(), rate)
+    }
+
+    pub fn bid(&self) -> &ExchangeRate {
+        &self.bid
+    }
+
+    pub fn ask(&self) -> &ExchangeRate {
+        &self.ask
+    }
+}
+
+impl fmt::Display for PairRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bid {} ask {}", self.bid, self.ask)
+    }
+}
+
+/// Splits a `BASE/QUOTE` pair into its asset codes
+pub fn parse_pair(pair: &str) -> Option<(&str, &str)> {
+    let mut assets = pair.split('/');
+    match (assets.next(), assets.next(), assets.next()) {
+        (Some(base), Some(quote), None) if !base.is_empty() && !quote.is_empty() => {
+            Some((base, quote))
+        }
+        _ => None,
+    }
+}
+
+impl<'de> Deserialize<'de> for PairRate {
+    /// Accepts `{"bid": .., "ask": ..}`, or a single rate for both sides
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum PairRateRepr {
+            Sides {
+                bid: ExchangeRate,
+                ask: ExchangeRate,
+            },
+            Mid(ExchangeRate),
+        }
+
+        struct InvalidPairRate<'a>(&'a ExchangeRate, &'a ExchangeRate);
+
+        impl fmt::Display for InvalidPairRate<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(
+                    f,
+                    "invalid pair rate with bid {} and ask {}, the ask must be positive and at least the bid",
+                    self.0, self.1
+                )
+            }
+        }
+
+        let (bid, ask) = match PairRateRepr::deserialize(deserializer)? {
+            PairRateRepr::Sides { bid, ask } => (bid, ask),
+            PairRateRepr::Mid(rate) => (rate.clone(), rate),
+        };
+        PairRate::new(bid.clone(), ask.clone())
+            .ok_or_else(|| de::Error::custom(InvalidPairRate(&bid, &ask)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn rate(s: &str) -> ExchangeRate {
+        ExchangeRate::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn deserializes_sides_or_single_rate() {
+        let pair: PairRate = serde_json::from_str(r#"{"bid": 0.2, "ask": "0.21"}"#).unwrap();
+        assert_eq!(pair, PairRate::new(rate("0.2"), rate("0.21")).unwrap());
+        let pair: PairRate = serde_json::from_str(r#""1/3""#).unwrap();
+        assert_eq!(pair, PairRate::mid(rate("1/3")).unwrap());
+
+        assert!(serde_json::from_str::<PairRate>(r#"{"bid": 0.3, "ask": 0.2}"#).is_err());
+        assert!(serde_json::from_str::<PairRate>("0").is_err());
+    }
+
+    #[test]
+    fn parses_pairs() {
+        assert_eq!(parse_pair("XRP/EUR"), Some(("XRP", "EUR")));
+        assert_eq!(parse_pair("XRP"), None);
+        assert_eq!(parse_pair("XRP/"), None);
+        assert_eq!(parse_pair("A/B/C"), None);
+    }
+}
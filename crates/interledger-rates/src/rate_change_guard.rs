@@ -0,0 +1,691 @@
+use crate::{ExchangeRate, ExchangeRateStore, PairRate};
+use async_trait::async_trait;
+use interledger_errors::ExchangeRateStoreError;
+use num::{rational::BigRational, traits::Signed};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info};
+
+/// A polled rate which moved more than allowed since the previous poll and
+/// is held back, keeping the previous rate, until it is confirmed
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HeldRate<R = ExchangeRate> {
+    /// The rate which is still applied
+    pub previous: R,
+    /// The latest rate polled from the provider
+    pub proposed: R,
+    /// The relative change from the previous to the proposed rate, e.g. 0.5 for 50%.
+    /// For pair rates, the larger change of the bid and of the ask.
+    pub change: f64,
+    /// When the rate was first held, in seconds since the UNIX epoch
+    pub held_since: u64,
+}
+
+/// A polled pair rate whose bid or ask moved more than allowed since the previous poll
+pub type HeldPairRate = HeldRate<PairRate>;
+
+/// The rates the guard last applied or confirmed, which polled rates are compared with
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AcceptedRates {
+    /// Incremented whenever the rates change, so that an older copy of the
+    /// rates never replaces a newer one
+    pub version: u64,
+    pub rates: HashMap<String, ExchangeRate>,
+    /// The polled pair rates, keyed by `BASE/QUOTE`
+    #[serde(default)]
+    pub pair_rates: HashMap<String, PairRate>,
+}
+
+/// Persists the rates accepted by the [`RateChangeGuard`](./struct.RateChangeGuard.html),
+/// so that the guard still has rates to compare with after a restart
+#[async_trait]
+pub trait AcceptedRatesStore {
+    /// The saved rates, if any
+    async fn load_accepted_rates(&self) -> Result<Option<AcceptedRates>, ExchangeRateStoreError>;
+
+    /// Saves the rates, unless the saved rates have the same or a higher version
+    async fn save_accepted_rates(&self, rates: AcceptedRates)
+        -> Result<(), ExchangeRateStoreError>;
+}
+
+type OnHold<R> = Arc<dyn Fn(&str, &HeldRate<R>) + Send + Sync>;
+type OnAccept = Arc<dyn Fn(&AcceptedRates) + Send + Sync>;
+
+#[derive(Debug, Default)]
+struct GuardState {
+    accepted: AcceptedRates,
+    held: HashMap<String, HeldRate>,
+    held_pairs: HashMap<String, HeldPairRate>,
+}
+
+/// A rate which the guard compares with its previous value
+trait GuardedRate: Clone + PartialEq + fmt::Display {
+    /// Returns `None` if the change cannot be expressed relative to the previous rate
+    fn change_from(&self, previous: &Self) -> Option<BigRational>;
+}
+
+impl GuardedRate for ExchangeRate {
+    fn change_from(&self, previous: &Self) -> Option<BigRational> {
+        relative_change(previous, self)
+    }
+}
+
+impl GuardedRate for PairRate {
+    fn change_from(&self, previous: &Self) -> Option<BigRational> {
+        let bid = relative_change(previous.bid(), self.bid())?;
+        let ask = relative_change(previous.ask(), self.ask())?;
+        Some(bid.max(ask))
+    }
+}
+
+/// Compares each set of polled rates with the last accepted rates and holds back
+/// the rates which moved more than `max_change` within one poll, so that a
+/// provider briefly returning a bad price does not affect the packets being converted.
+///
+/// Held rates are released when the provider returns a rate close to the previous one
+/// again, or when they are confirmed (for example through the API). Polled pair rates
+/// are held the same way if their bid or their ask moved too much. The accepted rates
+/// are kept when the rates in the store are cleared, and can be persisted with
+/// [`on_accept`](#method.on_accept) and restored with [`with_accepted_rates`](#method.with_accepted_rates).
+#[derive(Clone)]
+pub struct RateChangeGuard {
+    max_change: BigRational,
+    state: Arc<RwLock<GuardState>>,
+    on_hold: Option<OnHold<ExchangeRate>>,
+    on_hold_pair: Option<OnHold<PairRate>>,
+    on_accept: Option<OnAccept>,
+}
+
+impl fmt::Debug for RateChangeGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RateChangeGuard")
+            .field("max_change", &self.max_change)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl RateChangeGuard {
+    /// `max_change` is the largest relative change allowed within one poll, e.g. 0.1 for 10%.
+    /// Returns `None` if it is negative or not finite.
+    pub fn new(max_change: f64) -> Option<Self> {
+        let max_change = ExchangeRate::from_f64(max_change)?;
+        Some(RateChangeGuard {
+            max_change: max_change.as_rational().clone(),
+            state: Arc::new(RwLock::new(GuardState::default())),
+            on_hold: None,
+            on_hold_pair: None,
+            on_accept: None,
+        })
+    }
+
+    /// Calls the given function whenever a rate starts being held, e.g. to record a metric
+    pub fn on_hold<F>(mut self, on_hold: F) -> Self
+    where
+        F: Fn(&str, &HeldRate) + Send + Sync + 'static,
+    {
+        self.on_hold = Some(Arc::new(on_hold));
+        self
+    }
+
+    /// Calls the given function whenever a pair rate starts being held
+    pub fn on_hold_pair<F>(mut self, on_hold: F) -> Self
+    where
+        F: Fn(&str, &HeldPairRate) + Send + Sync + 'static,
+    {
+        self.on_hold_pair = Some(Arc::new(on_hold));
+        self
+    }
+
+    /// Calls the given function whenever the accepted rates change, e.g. to persist them
+    /// with an [`AcceptedRatesStore`](./trait.AcceptedRatesStore.html)
+    pub fn on_accept<F>(mut self, on_accept: F) -> Self
+    where
+        F: Fn(&AcceptedRates) + Send + Sync + 'static,
+    {
+        self.on_accept = Some(Arc::new(on_accept));
+        self
+    }
+
+    /// Starts from previously accepted rates, e.g. those persisted before a restart
+    pub fn with_accepted_rates(self, accepted: AcceptedRates) -> Self {
+        self.state.write().accepted = accepted;
+        self
+    }
+
+    /// The rates polled rates are currently compared with
+    pub fn accepted_rates(&self) -> AcceptedRates {
+        self.state.read().accepted.clone()
+    }
+
+    /// Returns the rates to apply instead of the `polled` ones: rates which moved more than
+    /// allowed since they were last accepted keep their `current` value and are held. Assets
+    /// without an accepted rate are compared with their `current` rate instead.
+    pub fn check(
+        &self,
+        current: &HashMap<String, ExchangeRate>,
+        polled: HashMap<String, ExchangeRate>,
+    ) -> HashMap<String, ExchangeRate> {
+        let mut state = self.state.write();
+        self.check_locked(&mut state, current, polled)
+    }
+
+    /// Checks the `polled` rates and sets the result in the store, without
+    /// letting a confirmation happen in between
+    pub fn apply<S: ExchangeRateStore>(
+        &self,
+        store: &S,
+        polled: HashMap<String, ExchangeRate>,
+    ) -> Result<(), ExchangeRateStoreError> {
+        let mut state = self.state.write();
+        let current = store.get_all_exchange_rates()?;
+        let rates = self.check_locked(&mut state, &current, polled);
+        store.set_exchange_rates(rates)
+    }
+
+    fn check_locked(
+        &self,
+        state: &mut GuardState,
+        current: &HashMap<String, ExchangeRate>,
+        polled: HashMap<String, ExchangeRate>,
+    ) -> HashMap<String, ExchangeRate> {
+        let GuardState { accepted, held, .. } = state;
+        let (applied, rates) = check_rates(
+            &self.max_change,
+            &accepted.rates,
+            held,
+            current,
+            polled,
+            self.on_hold.as_ref(),
+        );
+        let pair_rates = accepted.pair_rates.clone();
+        self.accept(accepted, rates, pair_rates);
+        applied
+    }
+
+    /// Checks the `polled` pair rates like [`apply`](#method.apply) and sets the result in
+    /// the store in place of the `replaced` pairs, keeping the other pair rates, such as
+    /// those set via the API
+    pub fn apply_pairs<S: ExchangeRateStore>(
+        &self,
+        store: &S,
+        replaced: &HashSet<String>,
+        polled: HashMap<String, PairRate>,
+    ) -> Result<(), ExchangeRateStoreError> {
+        let mut state = self.state.write();
+        let mut pair_rates = store.get_all_pair_rates()?;
+        let GuardState {
+            accepted,
+            held_pairs,
+            ..
+        } = &mut *state;
+        let (applied, accepted_pair_rates) = check_rates(
+            &self.max_change,
+            &accepted.pair_rates,
+            held_pairs,
+            &pair_rates,
+            polled,
+            self.on_hold_pair.as_ref(),
+        );
+        let rates = accepted.rates.clone();
+        self.accept(accepted, rates, accepted_pair_rates);
+        pair_rates.retain(|pair, _| !replaced.contains(pair));
+        pair_rates.extend(applied);
+        store.set_pair_rates(pair_rates)
+    }
+
+    /// Replaces the accepted rates, bumping their version if they changed
+    fn accept(
+        &self,
+        accepted: &mut AcceptedRates,
+        rates: HashMap<String, ExchangeRate>,
+        pair_rates: HashMap<String, PairRate>,
+    ) {
+        if accepted.rates == rates && accepted.pair_rates == pair_rates {
+            return;
+        }
+        accepted.rates = rates;
+        accepted.pair_rates = pair_rates;
+        accepted.version += 1;
+        if let Some(ref on_accept) = self.on_accept {
+            on_accept(accepted);
+        }
+    }
+
+    /// The rates which are currently held back
+    pub fn held_rates(&self) -> HashMap<String, HeldRate> {
+        self.state.read().held.clone()
+    }
+
+    /// Applies the proposed rate of the held asset to the store. Returns the applied
+    /// rate, or `None` if the rate of the asset was not held.
+    pub fn confirm<S: ExchangeRateStore>(
+        &self,
+        asset_code: &str,
+        store: &S,
+    ) -> Result<Option<ExchangeRate>, ExchangeRateStoreError> {
+        let mut state = self.state.write();
+        let proposed = match state.held.get(asset_code) {
+            Some(held_rate) => held_rate.proposed.clone(),
+            None => return Ok(None),
+        };
+        let mut rates = store.get_all_exchange_rates()?;
+        rates.insert(asset_code.to_string(), proposed.clone());
+        store.set_exchange_rates(rates)?;
+        state.held.remove(asset_code);
+        let mut accepted = state.accepted.rates.clone();
+        accepted.insert(asset_code.to_string(), proposed.clone());
+        let pair_rates = state.accepted.pair_rates.clone();
+        self.accept(&mut state.accepted, accepted, pair_rates);
+        info!("Confirmed exchange rate for {}: {}", asset_code, proposed);
+        Ok(Some(proposed))
+    }
+
+    /// Stops holding the rate of the asset, keeping the previous rate until the
+    /// next poll. Returns the rate which was held, if any.
+    pub fn dismiss(&self, asset_code: &str) -> Option<HeldRate> {
+        self.state.write().held.remove(asset_code)
+    }
+
+    /// The pair rates which are currently held back, keyed by `BASE/QUOTE`
+    pub fn held_pair_rates(&self) -> HashMap<String, HeldPairRate> {
+        self.state.read().held_pairs.clone()
+    }
+
+    /// Applies the proposed rate of the held pair to the store. Returns the applied
+    /// rate, or `None` if the rate of the pair was not held.
+    pub fn confirm_pair<S: ExchangeRateStore>(
+        &self,
+        pair: &str,
+        store: &S,
+    ) -> Result<Option<PairRate>, ExchangeRateStoreError> {
+        let mut state = self.state.write();
+        let proposed = match state.held_pairs.get(pair) {
+            Some(held_rate) => held_rate.proposed.clone(),
+            None => return Ok(None),
+        };
+        let mut pair_rates = store.get_all_pair_rates()?;
+        pair_rates.insert(pair.to_string(), proposed.clone());
+        store.set_pair_rates(pair_rates)?;
+        state.held_pairs.remove(pair);
+        let mut accepted = state.accepted.pair_rates.clone();
+        accepted.insert(pair.to_string(), proposed.clone());
+        let rates = state.accepted.rates.clone();
+        self.accept(&mut state.accepted, rates, accepted);
+        info!("Confirmed exchange rate for {}: {}", pair, proposed);
+        Ok(Some(proposed))
+    }
+
+    /// Stops holding the rate of the pair, keeping the previous rate until the
+    /// next poll. Returns the rate which was held, if any.
+    pub fn dismiss_pair(&self, pair: &str) -> Option<HeldPairRate> {
+        self.state.write().held_pairs.remove(pair)
+    }
+}
+
+/// Holds back the `polled` rates which moved more than `max_change` since they were
+/// last `accepted`, keeping their `current` value. Returns the rates to apply and the
+/// rates to accept.
+fn check_rates<R: GuardedRate>(
+    max_change: &BigRational,
+    accepted: &HashMap<String, R>,
+    held: &mut HashMap<String, HeldRate<R>>,
+    current: &HashMap<String, R>,
+    mut polled: HashMap<String, R>,
+    on_hold: Option<&OnHold<R>>,
+) -> (HashMap<String, R>, HashMap<String, R>) {
+    let mut not_applied = Vec::new();
+    for (asset_code, rate) in polled.iter_mut() {
+        let previous = match accepted.get(asset_code).or_else(|| current.get(asset_code)) {
+            Some(previous) => previous.clone(),
+            None => {
+                held.remove(asset_code);
+                continue;
+            }
+        };
+        let change = rate.change_from(&previous);
+        if matches!(change, Some(ref change) if change <= max_change) {
+            if held.remove(asset_code).is_some() {
+                info!(
+                    "Exchange rate for {} is back within the allowed change, releasing it",
+                    asset_code
+                );
+            }
+            continue;
+        }
+
+        let change = change.map_or(f64::INFINITY, |change| {
+            ExchangeRate::new(change).map_or(f64::INFINITY, |change| change.to_f64())
+        });
+        let proposed = std::mem::replace(rate, previous.clone());
+        // The previous rate only stays in use if it still is, e.g. not after the
+        // rates were cleared because the providers stopped responding
+        if !current.contains_key(asset_code) {
+            not_applied.push(asset_code.clone());
+        }
+        match held.get_mut(asset_code) {
+            Some(held_rate) => {
+                debug!(
+                    "Still holding exchange rate for {}: {} (previous: {})",
+                    asset_code, proposed, previous
+                );
+                held_rate.proposed = proposed;
+                held_rate.change = change;
+            }
+            None => {
+                error!(
+                    "Exchange rate for {} moved by {:.2}% within one poll, from {} to {}. Keeping the previous rate until the new one is confirmed",
+                    asset_code,
+                    change * 100.0,
+                    previous,
+                    proposed
+                );
+                let held_rate = HeldRate {
+                    previous,
+                    proposed,
+                    change,
+                    held_since: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|since| since.as_secs())
+                        .unwrap_or_default(),
+                };
+                if let Some(on_hold) = on_hold {
+                    on_hold(asset_code, &held_rate);
+                }
+                held.insert(asset_code.clone(), held_rate);
+            }
+        }
+    }
+    // Rates which are no longer polled are not held either
+    held.retain(|asset_code, _| polled.contains_key(asset_code));
+    let mut rates = accepted.clone();
+    rates.extend(
+        polled
+            .iter()
+            .map(|(code, rate)| (code.clone(), rate.clone())),
+    );
+    for asset_code in not_applied {
+        polled.remove(&asset_code);
+    }
+    (polled, rates)
+}
+
+/// Returns `None` if the previous rate was zero and the new one is not
+fn relative_change(previous: &ExchangeRate, rate: &ExchangeRate) -> Option<BigRational> {
+    if previous == rate {
+        return Some(BigRational::from_integer(0.into()));
+    }
+    if previous.is_zero() {
+        return None;
+    }
+    Some(((rate.as_rational() - previous.as_rational()) / previous.as_rational()).abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn rates(rates: &[(&str, &str)]) -> HashMap<String, ExchangeRate> {
+        rates
+            .iter()
+            .map(|(code, rate)| (code.to_string(), ExchangeRate::from_str(rate).unwrap()))
+            .collect()
+    }
+
+    fn pair(bid: &str, ask: &str) -> PairRate {
+        PairRate::new(
+            ExchangeRate::from_str(bid).unwrap(),
+            ExchangeRate::from_str(ask).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn pairs(pairs: &[(&str, PairRate)]) -> HashMap<String, PairRate> {
+        pairs
+            .iter()
+            .map(|(key, pair)| (key.to_string(), pair.clone()))
+            .collect()
+    }
+
+    #[derive(Clone, Default)]
+    struct TestStore(
+        Arc<RwLock<HashMap<String, ExchangeRate>>>,
+        Arc<RwLock<HashMap<String, PairRate>>>,
+    );
+
+    impl ExchangeRateStore for TestStore {
+        fn set_exchange_rates(
+            &self,
+            rates: HashMap<String, ExchangeRate>,
+        ) -> Result<(), ExchangeRateStoreError> {
+            *self.0.write() = rates;
+            Ok(())
+        }
+
+        fn get_exchange_rates(
+            &self,
+            _: &[&str],
+        ) -> Result<Vec<ExchangeRate>, ExchangeRateStoreError> {
+            unreachable!()
+        }
+
+        fn get_all_exchange_rates(
+            &self,
+        ) -> Result<HashMap<String, ExchangeRate>, ExchangeRateStoreError> {
+            Ok(self.0.read().clone())
+        }
+
+        fn set_pair_rates(
+            &self,
+            pair_rates: HashMap<String, PairRate>,
+        ) -> Result<(), ExchangeRateStoreError> {
+            *self.1.write() = pair_rates;
+            Ok(())
+        }
+
+        fn get_all_pair_rates(&self) -> Result<HashMap<String, PairRate>, ExchangeRateStoreError> {
+            Ok(self.1.read().clone())
+        }
+    }
+
+    #[test]
+    fn holds_rates_which_move_too_much() {
+        let alerts = Arc::new(AtomicUsize::new(0));
+        let alerts_clone = alerts.clone();
+        let guard = RateChangeGuard::new(0.1).unwrap().on_hold(move |_, _| {
+            alerts_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        let current = rates(&[("BTC", "10000"), ("ETH", "200"), ("XRP", "0.2")]);
+
+        let applied = guard.check(
+            &current,
+            rates(&[
+                ("BTC", "11000"),
+                ("ETH", "100"),
+                ("XRP", "0"),
+                ("EUR", "1.1"),
+            ]),
+        );
+        assert_eq!(
+            applied,
+            rates(&[
+                ("BTC", "11000"),
+                ("ETH", "200"),
+                ("XRP", "0.2"),
+                ("EUR", "1.1")
+            ])
+        );
+        let held = guard.held_rates();
+        assert_eq!(held.len(), 2);
+        assert_eq!(held["ETH"].proposed, ExchangeRate::from_str("100").unwrap());
+        assert_eq!(held["ETH"].change, 0.5);
+        assert_eq!(held["XRP"].change, 1.0);
+        assert_eq!(alerts.load(Ordering::SeqCst), 2);
+
+        // Still off: stays held, without alerting again
+        let applied = guard.check(&applied, rates(&[("ETH", "99"), ("XRP", "0")]));
+        assert_eq!(applied, rates(&[("ETH", "200"), ("XRP", "0.2")]));
+        assert_eq!(
+            guard.held_rates()["ETH"].proposed,
+            ExchangeRate::from_str("99").unwrap()
+        );
+        assert_eq!(alerts.load(Ordering::SeqCst), 2);
+
+        // Back to normal: released
+        let applied = guard.check(&applied, rates(&[("ETH", "205"), ("XRP", "0")]));
+        assert_eq!(applied, rates(&[("ETH", "205"), ("XRP", "0.2")]));
+        assert_eq!(guard.held_rates().keys().collect::<Vec<_>>(), vec!["XRP"]);
+
+        // No longer polled: dropped
+        guard.check(&applied, rates(&[("ETH", "205")]));
+        assert!(guard.held_rates().is_empty());
+    }
+
+    #[test]
+    fn holds_rates_moving_from_zero() {
+        let guard = RateChangeGuard::new(0.1).unwrap();
+        let applied = guard.check(&rates(&[("ABC", "0")]), rates(&[("ABC", "1")]));
+        assert_eq!(applied, rates(&[("ABC", "0")]));
+        assert!(guard.held_rates()["ABC"].change.is_infinite());
+        assert!(RateChangeGuard::new(-0.1).is_none());
+    }
+
+    #[test]
+    fn confirms_and_dismisses_held_rates() {
+        let store = TestStore::default();
+        let guard = RateChangeGuard::new(0.1).unwrap();
+        let current = rates(&[("BTC", "10000"), ("ETH", "200")]);
+        let applied = guard.check(&current, rates(&[("BTC", "5000"), ("ETH", "100")]));
+        store.set_exchange_rates(applied).unwrap();
+
+        assert_eq!(guard.confirm("XRP", &store).unwrap(), None);
+        assert_eq!(
+            guard.confirm("BTC", &store).unwrap(),
+            Some(ExchangeRate::from_str("5000").unwrap())
+        );
+        assert_eq!(
+            store.get_all_exchange_rates().unwrap(),
+            rates(&[("BTC", "5000"), ("ETH", "200")])
+        );
+        assert!(guard.dismiss("ETH").is_some());
+        assert!(guard.held_rates().is_empty());
+    }
+
+    #[test]
+    fn compares_with_accepted_rates_after_clear_and_restart() {
+        let store = TestStore::default();
+        let saved = Arc::new(RwLock::new(AcceptedRates::default()));
+        let saved_clone = saved.clone();
+        let guard = RateChangeGuard::new(0.1)
+            .unwrap()
+            .on_accept(move |accepted| *saved_clone.write() = accepted.clone());
+        guard.apply(&store, rates(&[("ETH", "200")])).unwrap();
+        assert_eq!(saved.read().version, 1);
+        assert_eq!(saved.read().rates, rates(&[("ETH", "200")]));
+
+        // The rates are cleared when the providers stop responding, but the
+        // guard still compares with the rate it accepted last
+        store.set_exchange_rates(HashMap::new()).unwrap();
+        guard.apply(&store, rates(&[("ETH", "100")])).unwrap();
+        assert!(store.get_all_exchange_rates().unwrap().is_empty());
+        assert_eq!(
+            guard.held_rates()["ETH"].previous,
+            ExchangeRate::from_str("200").unwrap()
+        );
+        assert_eq!(saved.read().version, 1);
+
+        // After a restart, with the saved rates
+        let guard = RateChangeGuard::new(0.1)
+            .unwrap()
+            .with_accepted_rates(saved.read().clone());
+        guard.apply(&store, rates(&[("ETH", "100")])).unwrap();
+        assert!(store.get_all_exchange_rates().unwrap().is_empty());
+        assert_eq!(
+            guard.confirm("ETH", &store).unwrap(),
+            Some(ExchangeRate::from_str("100").unwrap())
+        );
+        assert_eq!(
+            guard.accepted_rates(),
+            AcceptedRates {
+                version: 2,
+                rates: rates(&[("ETH", "100")]),
+                pair_rates: HashMap::new(),
+            }
+        );
+
+        // A poll which fetched the old rate before the confirmation is held
+        // against the confirmed rate instead of replacing it
+        guard.apply(&store, rates(&[("ETH", "200")])).unwrap();
+        assert_eq!(
+            store.get_all_exchange_rates().unwrap(),
+            rates(&[("ETH", "100")])
+        );
+        assert_eq!(guard.accepted_rates().version, 2);
+    }
+
+    #[test]
+    fn holds_pair_rates_which_move_too_much() {
+        let store = TestStore::default();
+        let held = Arc::new(AtomicUsize::new(0));
+        let held_clone = held.clone();
+        let guard = RateChangeGuard::new(0.1)
+            .unwrap()
+            .on_hold_pair(move |_, _| {
+                held_clone.fetch_add(1, Ordering::SeqCst);
+            });
+        store
+            .set_pair_rates(pairs(&[("ABC/DEF", pair("2", "2"))]))
+            .unwrap();
+        let polled = pairs(&[("XRP/EUR", pair("0.2", "0.22"))]);
+        let replaced = HashSet::new();
+        guard.apply_pairs(&store, &replaced, polled).unwrap();
+        assert_eq!(
+            store.get_all_pair_rates().unwrap(),
+            pairs(&[
+                ("ABC/DEF", pair("2", "2")),
+                ("XRP/EUR", pair("0.2", "0.22"))
+            ])
+        );
+        assert_eq!(guard.accepted_rates().version, 1);
+
+        // Only the ask moved too much, which holds the whole pair
+        let replaced = vec!["XRP/EUR".to_string()].into_iter().collect();
+        let polled = pairs(&[("XRP/EUR", pair("0.21", "0.4"))]);
+        guard.apply_pairs(&store, &replaced, polled).unwrap();
+        assert_eq!(
+            store.get_all_pair_rates().unwrap()["XRP/EUR"],
+            pair("0.2", "0.22")
+        );
+        let held_pair_rates = guard.held_pair_rates();
+        assert_eq!(held_pair_rates["XRP/EUR"].proposed, pair("0.21", "0.4"));
+        assert!(held_pair_rates["XRP/EUR"].change > 0.8);
+        assert_eq!(held.load(Ordering::SeqCst), 1);
+
+        assert_eq!(guard.confirm_pair("ABC/DEF", &store).unwrap(), None);
+        assert_eq!(
+            guard.confirm_pair("XRP/EUR", &store).unwrap(),
+            Some(pair("0.21", "0.4"))
+        );
+        assert_eq!(
+            store.get_all_pair_rates().unwrap()["XRP/EUR"],
+            pair("0.21", "0.4")
+        );
+        assert_eq!(
+            guard.accepted_rates().pair_rates,
+            pairs(&[("XRP/EUR", pair("0.21", "0.4"))])
+        );
+        assert!(guard.held_pair_rates().is_empty());
+
+        // Held again, then dismissed
+        let polled = pairs(&[("XRP/EUR", pair("0.1", "0.4"))]);
+        guard.apply_pairs(&store, &replaced, polled).unwrap();
+        assert!(guard.dismiss_pair("XRP/EUR").is_some());
+        assert!(guard.held_pair_rates().is_empty());
+    }
+}
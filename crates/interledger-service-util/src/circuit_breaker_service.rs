@@ -0,0 +1,661 @@
+use async_trait::async_trait;
+use interledger_packet::{Address, ErrorClass, ErrorCode, Reject, RejectBuilder};
+use interledger_service::{
+    Account, AddressStore, IlpResult, OutgoingRequest, OutgoingService, Username,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Configuration of the [`CircuitBreaker`](./struct.CircuitBreaker.html)
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive temporary rejects and transport errors after which the circuit
+    /// to an outgoing account opens
+    #[serde(default = "CircuitBreakerConfig::default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How long, in milliseconds, an open circuit rejects packets before letting probes through
+    #[serde(default = "CircuitBreakerConfig::default_cooldown")]
+    pub cooldown: u64,
+    /// How many probe packets may be in flight while the circuit is half-open
+    #[serde(default = "CircuitBreakerConfig::default_probes")]
+    pub probes: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: Self::default_failure_threshold(),
+            cooldown: Self::default_cooldown(),
+            probes: Self::default_probes(),
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    fn default_failure_threshold() -> u32 {
+        5
+    }
+
+    fn default_cooldown() -> u64 {
+        30_000
+    }
+
+    fn default_probes() -> u32 {
+        1
+    }
+}
+
+/// The state of the circuit to an outgoing account
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Packets are forwarded to the account
+    Closed,
+    /// Packets are rejected right away, without being forwarded
+    Open,
+    /// Only probe packets are forwarded, to find out whether the account is reachable again
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// The state of the circuit to an outgoing account, as exposed through the API
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CircuitStatus {
+    pub username: Username,
+    pub state: CircuitState,
+    /// Temporary rejects and transport errors since the last packet which went through
+    pub consecutive_failures: u32,
+    /// How long, in milliseconds, until an open circuit lets probes through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    username: Username,
+    state: CircuitState,
+    /// When the circuit entered its current state
+    since: Instant,
+    failures: u32,
+    probes_in_flight: u32,
+}
+
+/// Whether a packet may be forwarded to the account
+#[derive(Debug, PartialEq)]
+enum Admission {
+    Forward,
+    Probe,
+    Reject(Duration),
+}
+
+type OnChange = Arc<dyn Fn(&Username, CircuitState) + Send + Sync>;
+
+/// Tracks the consecutive temporary rejects and transport errors of each outgoing
+/// account. Once they reach the threshold, the circuit to the account opens and
+/// packets are rejected right away instead of waiting for the transport to time out.
+/// After the cooldown, the circuit half-opens and lets probe packets through: the
+/// circuit closes again when a probe goes through, and reopens when it fails.
+///
+/// Clones share the same circuits, so that the state can be exposed (for example through the API).
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    probes: u32,
+    circuits: Arc<Mutex<HashMap<Uuid, Circuit>>>,
+    on_change: Option<OnChange>,
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("failure_threshold", &self.failure_threshold)
+            .field("cooldown", &self.cooldown)
+            .field("probes", &self.probes)
+            .field("circuits", &self.circuits)
+            .finish()
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            failure_threshold: config.failure_threshold.max(1),
+            cooldown: Duration::from_millis(config.cooldown),
+            probes: config.probes.max(1),
+            circuits: Arc::new(Mutex::new(HashMap::new())),
+            on_change: None,
+        }
+    }
+
+    /// Calls the given function whenever a circuit changes state, e.g. to record a metric
+    pub fn on_change<F>(mut self, on_change: F) -> Self
+    where
+        F: Fn(&Username, CircuitState) + Send + Sync + 'static,
+    {
+        self.on_change = Some(Arc::new(on_change));
+        self
+    }
+
+    /// The circuits of the accounts which packets were sent to, by account id
+    pub fn circuits(&self) -> HashMap<Uuid, CircuitStatus> {
+        let now = Instant::now();
+        self.circuits
+            .lock()
+            .iter()
+            .map(|(id, circuit)| {
+                let retry_after_ms = if circuit.state == CircuitState::Open {
+                    let open_until = circuit.since + self.cooldown;
+                    Some(open_until.saturating_duration_since(now).as_millis() as u64)
+                } else {
+                    None
+                };
+                let status = CircuitStatus {
+                    username: circuit.username.clone(),
+                    state: circuit.state,
+                    consecutive_failures: circuit.failures,
+                    retry_after_ms,
+                };
+                (*id, status)
+            })
+            .collect()
+    }
+
+    fn admit(&self, id: Uuid, username: &Username, now: Instant) -> Admission {
+        let mut changed = None;
+        let admission = {
+            let mut circuits = self.circuits.lock();
+            let circuit = circuits.entry(id).or_insert_with(|| Circuit {
+                username: username.clone(),
+                state: CircuitState::Closed,
+                since: now,
+                failures: 0,
+                probes_in_flight: 0,
+            });
+            if circuit.state == CircuitState::Open && now >= circuit.since + self.cooldown {
+                circuit.state = CircuitState::HalfOpen;
+                circuit.since = now;
+                circuit.probes_in_flight = 0;
+                changed = Some(CircuitState::HalfOpen);
+            }
+            // Probes which have not completed within another cooldown are assumed lost
+            if circuit.state == CircuitState::HalfOpen && now >= circuit.since + self.cooldown {
+                circuit.since = now;
+                circuit.probes_in_flight = 0;
+            }
+            match circuit.state {
+                CircuitState::Closed => Admission::Forward,
+                CircuitState::Open => Admission::Reject(
+                    (circuit.since + self.cooldown).saturating_duration_since(now),
+                ),
+                CircuitState::HalfOpen if circuit.probes_in_flight < self.probes => {
+                    circuit.probes_in_flight += 1;
+                    Admission::Probe
+                }
+                CircuitState::HalfOpen => Admission::Reject(Duration::from_secs(0)),
+            }
+        };
+        if let Some(state) = changed {
+            self.changed(username, state);
+        }
+        admission
+    }
+
+    fn record(&self, id: Uuid, probe: bool, failed: bool, now: Instant) {
+        let mut changed = None;
+        {
+            let mut circuits = self.circuits.lock();
+            let circuit = match circuits.get_mut(&id) {
+                Some(circuit) => circuit,
+                None => return,
+            };
+            if failed {
+                circuit.failures = circuit.failures.saturating_add(1);
+            }
+            if probe {
+                circuit.probes_in_flight = circuit.probes_in_flight.saturating_sub(1);
+            }
+            let state = match circuit.state {
+                CircuitState::Closed if failed && circuit.failures >= self.failure_threshold => {
+                    CircuitState::Open
+                }
+                CircuitState::Closed if !failed => {
+                    circuit.failures = 0;
+                    CircuitState::Closed
+                }
+                CircuitState::HalfOpen if probe && failed => CircuitState::Open,
+                CircuitState::HalfOpen if probe => {
+                    circuit.failures = 0;
+                    CircuitState::Closed
+                }
+                state => state,
+            };
+            if state != circuit.state {
+                circuit.state = state;
+                circuit.since = now;
+                circuit.probes_in_flight = 0;
+                changed = Some((circuit.username.clone(), state));
+            }
+        }
+        if let Some((username, state)) = changed {
+            self.changed(&username, state);
+        }
+    }
+
+    fn changed(&self, username: &Username, state: CircuitState) {
+        match state {
+            CircuitState::Open => warn!(
+                "Opened the circuit to account {}, rejecting its packets for {:?}",
+                username, self.cooldown
+            ),
+            _ => debug!("Circuit to account {} is {}", username, state.as_str()),
+        }
+        if let Some(ref on_change) = self.on_change {
+            on_change(username, state);
+        }
+    }
+}
+
+/// Temporary rejects triggered by this node (e.g. the transports failing to reach the peer)
+/// or by the peer itself, and the timeouts which the transports reject with when the peer
+/// does not respond, count towards opening the circuit. Rejects triggered further along
+/// the path say nothing about the peer. Without the peer's own address, the rejects of the
+/// peer cannot be told apart from those further along, so all temporary rejects count.
+fn is_failure(reject: &Reject, ilp_address: &Address, peer_address: Option<&Address>) -> bool {
+    let triggered_by = reject.triggered_by();
+    match reject.code().class() {
+        ErrorClass::Temporary => match (triggered_by, peer_address) {
+            (Some(ref address), Some(peer_address)) => {
+                address == ilp_address || address == peer_address
+            }
+            (None, Some(_)) => false,
+            (_, None) => true,
+        },
+        _ => {
+            reject.code() == ErrorCode::R00_TRANSFER_TIMED_OUT
+                && triggered_by.as_ref() == Some(ilp_address)
+        }
+    }
+}
+
+/// # Circuit Breaker Service
+///
+/// Outgoing service which stops forwarding packets to accounts which keep failing,
+/// rejecting them with `T01: Peer Unreachable` while their circuit is open.
+/// See [`CircuitBreaker`](./struct.CircuitBreaker.html) for when circuits open and close.
+///
+/// This service should be placed right before the transports (HTTP and BTP), so that
+/// the rejects of the other services are not counted. Without a breaker, requests are
+/// forwarded as they are.
+#[derive(Clone)]
+pub struct CircuitBreakerService<O, S> {
+    next: O,
+    store: S,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl<O, S> CircuitBreakerService<O, S> {
+    pub fn new(store: S, breaker: Option<CircuitBreaker>, next: O) -> Self {
+        CircuitBreakerService {
+            next,
+            store,
+            breaker,
+        }
+    }
+}
+
+#[async_trait]
+impl<O, S, A> OutgoingService<A> for CircuitBreakerService<O, S>
+where
+    O: OutgoingService<A> + Send + Sync + Clone + 'static,
+    S: AddressStore + Send + Sync + Clone + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    /// On send request:
+    /// 1. If the circuit to the outgoing account is open, reject with T01 right away
+    /// 1. Otherwise forward the request (as a probe if the circuit is half-open)
+    /// 1. Record whether the request failed with a temporary reject of this node or the peer
+    ///    (any temporary reject if the account has the default address), or a transport error
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        let breaker = match self.breaker {
+            Some(ref breaker) => breaker.clone(),
+            None => return self.next.send_request(request).await,
+        };
+        let ilp_address = self.store.get_ilp_address();
+        // Accounts configured without an ILP address get `<our address>.<username>`,
+        // which a peer (unlike a child) does not trigger its rejects with
+        let default_address = ilp_address.with_suffix(request.to.username().as_bytes());
+        let peer_address = Some(request.to.ilp_address().clone())
+            .filter(|address| default_address.as_ref().ok() != Some(address));
+        let id = request.to.id();
+        let probe = match breaker.admit(id, request.to.username(), Instant::now()) {
+            Admission::Forward => false,
+            Admission::Probe => true,
+            Admission::Reject(retry_after) => {
+                debug!(
+                    "Circuit to account {} is open, rejecting packet (retry after {:?})",
+                    request.to.username(),
+                    retry_after
+                );
+                return Err(RejectBuilder {
+                    code: ErrorCode::T01_PEER_UNREACHABLE,
+                    message: b"Circuit to peer is open",
+                    triggered_by: Some(&ilp_address),
+                    data: &[],
+                }
+                .build());
+            }
+        };
+
+        let result = self.next.send_request(request).await;
+        let failed = match result {
+            Ok(_) => false,
+            Err(ref reject) => is_failure(reject, &ilp_address, peer_address.as_ref()),
+        };
+        breaker.record(id, probe, failed, Instant::now());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_errors::AddressStoreError;
+    use interledger_packet::{FulfillBuilder, PrepareBuilder};
+    use interledger_service::outgoing_service_fn;
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::SystemTime;
+
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static ID: Lazy<Uuid> = Lazy::new(Uuid::new_v4);
+    static EXAMPLE_ADDRESS: Lazy<Address> =
+        Lazy::new(|| Address::from_str("example.alice").unwrap());
+    static CONNECTOR_ADDRESS: Lazy<Address> =
+        Lazy::new(|| Address::from_str("example.connector").unwrap());
+
+    #[derive(Clone, Debug)]
+    struct TestAccount {
+        ilp_address: Address,
+    }
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            *ID
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &self.ilp_address
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestStore;
+
+    #[async_trait]
+    impl AddressStore for TestStore {
+        async fn set_ilp_address(&self, _: Address) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            CONNECTOR_ADDRESS.clone()
+        }
+    }
+
+    fn breaker(failure_threshold: u32) -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold,
+            cooldown: 1000,
+            probes: 1,
+        })
+    }
+
+    fn request() -> OutgoingRequest<TestAccount> {
+        request_to(EXAMPLE_ADDRESS.clone())
+    }
+
+    fn request_to(ilp_address: Address) -> OutgoingRequest<TestAccount> {
+        OutgoingRequest {
+            from: TestAccount {
+                ilp_address: EXAMPLE_ADDRESS.clone(),
+            },
+            to: TestAccount { ilp_address },
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: EXAMPLE_ADDRESS.clone(),
+                amount: 100,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(3);
+        let now = Instant::now();
+        for _ in 0..2 {
+            assert_eq!(breaker.admit(*ID, &ALICE, now), Admission::Forward);
+            breaker.record(*ID, false, true, now);
+        }
+        // A packet going through resets the count
+        breaker.record(*ID, false, false, now);
+        for _ in 0..3 {
+            assert_eq!(breaker.admit(*ID, &ALICE, now), Admission::Forward);
+            breaker.record(*ID, false, true, now);
+        }
+        assert_eq!(
+            breaker.admit(*ID, &ALICE, now + Duration::from_millis(400)),
+            Admission::Reject(Duration::from_millis(600))
+        );
+        let circuits = breaker.circuits();
+        assert_eq!(circuits[&*ID].state, CircuitState::Open);
+        assert_eq!(circuits[&*ID].consecutive_failures, 3);
+    }
+
+    #[test]
+    fn half_opens_after_cooldown() {
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let states_clone = states.clone();
+        let breaker = breaker(1).on_change(move |_, state| states_clone.lock().push(state));
+        let now = Instant::now();
+        breaker.admit(*ID, &ALICE, now);
+        breaker.record(*ID, false, true, now);
+
+        // Only one probe goes through at a time, and a failed one reopens the circuit
+        let later = now + Duration::from_millis(1000);
+        assert_eq!(breaker.admit(*ID, &ALICE, later), Admission::Probe);
+        assert_eq!(
+            breaker.admit(*ID, &ALICE, later),
+            Admission::Reject(Duration::from_secs(0))
+        );
+        breaker.record(*ID, true, true, later);
+        assert_eq!(
+            breaker.admit(*ID, &ALICE, later),
+            Admission::Reject(Duration::from_millis(1000))
+        );
+
+        // A probe going through closes it
+        let later = later + Duration::from_millis(1000);
+        assert_eq!(breaker.admit(*ID, &ALICE, later), Admission::Probe);
+        breaker.record(*ID, true, false, later);
+        assert_eq!(breaker.admit(*ID, &ALICE, later), Admission::Forward);
+        assert_eq!(breaker.circuits()[&*ID].consecutive_failures, 0);
+
+        assert_eq!(
+            *states.lock(),
+            vec![
+                CircuitState::Open,
+                CircuitState::HalfOpen,
+                CircuitState::Open,
+                CircuitState::HalfOpen,
+                CircuitState::Closed
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_without_forwarding_while_open() {
+        let forwarded = Arc::new(AtomicUsize::new(0));
+        let forwarded_clone = forwarded.clone();
+        let mut service = CircuitBreakerService::new(
+            TestStore,
+            Some(breaker(2)),
+            outgoing_service_fn(move |_| {
+                forwarded_clone.fetch_add(1, Ordering::SeqCst);
+                Err(RejectBuilder {
+                    code: ErrorCode::R00_TRANSFER_TIMED_OUT,
+                    message: &[],
+                    triggered_by: Some(&CONNECTOR_ADDRESS),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+
+        for _ in 0..2 {
+            let reject = service.send_request(request()).await.unwrap_err();
+            assert_eq!(reject.code(), ErrorCode::R00_TRANSFER_TIMED_OUT);
+        }
+        let reject = service.send_request(request()).await.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+        assert_eq!(reject.triggered_by(), Some(CONNECTOR_ADDRESS.clone()));
+        assert_eq!(forwarded.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn ignores_final_rejects() {
+        let fulfill = Arc::new(AtomicBool::new(false));
+        let fulfill_clone = fulfill.clone();
+        let mut service = CircuitBreakerService::new(
+            TestStore,
+            Some(breaker(1)),
+            outgoing_service_fn(move |_| {
+                if fulfill_clone.load(Ordering::SeqCst) {
+                    Ok(FulfillBuilder {
+                        fulfillment: &[0; 32],
+                        data: &[],
+                    }
+                    .build())
+                } else {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F99_APPLICATION_ERROR,
+                        message: &[],
+                        triggered_by: Some(&EXAMPLE_ADDRESS),
+                        data: &[],
+                    }
+                    .build())
+                }
+            }),
+        );
+
+        for _ in 0..3 {
+            let reject = service.send_request(request()).await.unwrap_err();
+            assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+        }
+        fulfill.store(true, Ordering::SeqCst);
+        assert!(service.send_request(request()).await.is_ok());
+        let circuits = service.breaker.unwrap().circuits();
+        assert_eq!(circuits[&*ID].state, CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn ignores_temporary_rejects_from_further_along() {
+        let triggered_by = Arc::new(Mutex::new(Address::from_str("example.remote").unwrap()));
+        let triggered_by_clone = triggered_by.clone();
+        let mut service = CircuitBreakerService::new(
+            TestStore,
+            Some(breaker(1)),
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+                    message: &[],
+                    triggered_by: Some(&*triggered_by_clone.lock()),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+
+        for _ in 0..3 {
+            let reject = service.send_request(request()).await.unwrap_err();
+            assert_eq!(reject.code(), ErrorCode::T04_INSUFFICIENT_LIQUIDITY);
+        }
+        let circuits = service.breaker.clone().unwrap().circuits();
+        assert_eq!(circuits[&*ID].state, CircuitState::Closed);
+
+        // The same reject from the peer itself opens it
+        *triggered_by.lock() = EXAMPLE_ADDRESS.clone();
+        service.send_request(request()).await.unwrap_err();
+        let reject = service.send_request(request()).await.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+    }
+
+    #[tokio::test]
+    async fn counts_all_temporary_rejects_of_peers_with_the_default_address() {
+        let mut service = CircuitBreakerService::new(
+            TestStore,
+            Some(breaker(2)),
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+                    message: &[],
+                    triggered_by: Some(&Address::from_str("example.peer").unwrap()),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+
+        // The peer rejects with its own address, which the account does not know
+        let default_address = CONNECTOR_ADDRESS.with_suffix(b"alice").unwrap();
+        service
+            .send_request(request_to(default_address.clone()))
+            .await
+            .unwrap_err();
+        service
+            .send_request(request_to(default_address.clone()))
+            .await
+            .unwrap_err();
+        let reject = service
+            .send_request(request_to(default_address))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+    }
+}
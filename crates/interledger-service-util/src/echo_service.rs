@@ -3,12 +3,19 @@ use byteorder::ReadBytesExt;
 use bytes::{BufMut, BytesMut};
 use core::borrow::Borrow;
 use interledger_packet::{
-    oer::BufOerExt, Address, ErrorCode, Prepare, PrepareBuilder, RejectBuilder,
+    oer::{self, BufOerExt, MutBufOerExt},
+    Address, ErrorCode, FulfillBuilder, PacketTypeError, ParseError, Prepare, PrepareBuilder,
+    RejectBuilder,
 };
 use interledger_service::*;
+use parking_lot::Mutex;
+use ring::digest::{digest, SHA256};
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::io::Read;
 use std::marker::PhantomData;
 use std::str;
+use std::sync::Arc;
 use std::time::SystemTime;
 use tracing::debug;
 
@@ -20,16 +27,130 @@ const ECHO_PREFIX_LEN: usize = 16;
 enum EchoPacketType {
     Request = 0,
     Response = 1,
+    /// Not part of the Echo protocol specification, which only defines the bidirectional
+    /// mode. Only nodes running this implementation understand it.
+    UnidirectionalRequest = 2,
+}
+
+/// Errors parsing the data section of an Echo protocol packet
+#[derive(Debug, thiserror::Error)]
+pub enum EchoPacketError {
+    #[error("Data does not start with the echo prefix")]
+    NotEcho,
+    #[error("Invalid echo packet: {0}")]
+    Invalid(#[from] ParseError),
+}
+
+/// The data section of an Echo protocol packet
+#[derive(Clone, Debug, PartialEq)]
+pub enum EchoPacket {
+    /// Asks the receiver to send a `Response` back to the `source_address` (bidirectional mode)
+    Request { source_address: Address },
+    /// The packet the receiver of a `Request` sends back to its initiator
+    Response,
+    /// Asks the receiver to fulfill the packet with the `fulfillment`, instead of
+    /// sending a packet back (unidirectional mode). This is a non-standard extension
+    /// (type 2) which other implementations reject, and its packets may not carry money.
+    UnidirectionalRequest { fulfillment: [u8; 32] },
+}
+
+impl EchoPacket {
+    /// Whether the data section is meant for the Echo protocol
+    pub fn has_echo_prefix(data: &[u8]) -> bool {
+        data.starts_with(ECHO_PREFIX.as_bytes())
+    }
+
+    /// Serializes the packet, including the echo prefix
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buffer = BytesMut::with_capacity(ECHO_PREFIX_LEN + 1 + 32);
+        buffer.put(ECHO_PREFIX.as_bytes());
+        match self {
+            EchoPacket::Request { source_address } => {
+                buffer.reserve(oer::predict_var_octet_string(source_address.len()));
+                buffer.put_u8(EchoPacketType::Request as u8);
+                buffer.put_var_octet_string(source_address.as_bytes());
+            }
+            EchoPacket::Response => buffer.put_u8(EchoPacketType::Response as u8),
+            EchoPacket::UnidirectionalRequest { fulfillment } => {
+                buffer.put_u8(EchoPacketType::UnidirectionalRequest as u8);
+                buffer.put_slice(&fulfillment[..]);
+            }
+        }
+        buffer
+    }
+
+    /// Parses the data following the echo prefix
+    fn parse(mut reader: &[u8]) -> Result<Self, ParseError> {
+        let packet_type = reader
+            .read_u8()
+            .map_err(|_| ParseError::PacketType(PacketTypeError::Eof))?;
+        match packet_type {
+            t if t == EchoPacketType::Request as u8 => {
+                let source_address = Address::try_from(reader.read_var_octet_string()?)?;
+                Ok(EchoPacket::Request { source_address })
+            }
+            t if t == EchoPacketType::Response as u8 => Ok(EchoPacket::Response),
+            t if t == EchoPacketType::UnidirectionalRequest as u8 => {
+                let mut fulfillment = [0; 32];
+                reader.read_exact(&mut fulfillment)?;
+                Ok(EchoPacket::UnidirectionalRequest { fulfillment })
+            }
+            t => Err(ParseError::PacketType(PacketTypeError::Unknown(t))),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for EchoPacket {
+    type Error = EchoPacketError;
+
+    fn try_from(reader: &[u8]) -> Result<Self, Self::Error> {
+        if !EchoPacket::has_echo_prefix(reader) {
+            return Err(EchoPacketError::NotEcho);
+        }
+        Ok(EchoPacket::parse(&reader[ECHO_PREFIX_LEN..])?)
+    }
+}
+
+/// The fulfillments of the bidirectional echo requests this node sent, indexed by their
+/// execution conditions. The `EchoService` fulfills the responses coming back with them.
+#[derive(Clone, Default)]
+pub struct PendingEchoes {
+    fulfillments: Arc<Mutex<HashMap<[u8; 32], [u8; 32]>>>,
+}
+
+impl PendingEchoes {
+    /// Expects a response to an echo request with the condition of the `fulfillment`,
+    /// which is returned
+    pub fn insert(&self, fulfillment: [u8; 32]) -> [u8; 32] {
+        let mut condition = [0; 32];
+        condition.copy_from_slice(digest(&SHA256, &fulfillment).as_ref());
+        self.fulfillments.lock().insert(condition, fulfillment);
+        condition
+    }
+
+    /// Stops expecting a response, once the echo request completed
+    pub fn remove(&self, condition: &[u8; 32]) {
+        self.fulfillments.lock().remove(condition);
+    }
+
+    fn fulfillment(&self, condition: &[u8]) -> Option<[u8; 32]> {
+        let condition = <[u8; 32]>::try_from(condition).ok()?;
+        self.fulfillments.lock().get(&condition).cloned()
+    }
 }
 
 /// A service that implements the Echo Protocol.
-/// Currently, this service only supports bidirectional mode (unidirectional mode is not supported yet).
+/// In bidirectional mode, the service sends the packet back to the source address of the
+/// request. It also supports a non-standard unidirectional mode, in which it fulfills
+/// packets carrying no money with the fulfillment from the request.
+/// Responses to the echo requests in its `PendingEchoes` are fulfilled, others are passed on.
 /// The service doesn't shorten expiry as it expects the expiry to be shortened by another service
 /// like `ExpiryShortenerService`.
 #[derive(Clone)]
 pub struct EchoService<I, S, A> {
     store: S,
     next: I,
+    pending: PendingEchoes,
     account_type: PhantomData<A>,
 }
 
@@ -44,9 +165,16 @@ where
         EchoService {
             store,
             next,
+            pending: PendingEchoes::default(),
             account_type: PhantomData,
         }
     }
+
+    /// Fulfills the responses to the echo requests this node sends with `pending`
+    pub fn with_pending_echoes(mut self, pending: PendingEchoes) -> Self {
+        self.pending = pending;
+        self
+    }
 }
 
 #[async_trait]
@@ -59,102 +187,87 @@ where
     async fn handle_request(&mut self, mut request: IncomingRequest<A>) -> IlpResult {
         let ilp_address = self.store.get_ilp_address();
         let should_echo = request.prepare.destination() == ilp_address
-            && request.prepare.data().starts_with(ECHO_PREFIX.as_bytes());
+            && EchoPacket::has_echo_prefix(request.prepare.data());
         if !should_echo {
             return self.next.handle_request(request).await;
         }
         debug!("Responding to Echo protocol request: {:?}", request);
 
-        // TODO Define EchoPacket struct and implement From<&p[u8]> for it
-
-        let mut reader = request.prepare.data();
-
-        // prefix is checked above, so skip prefix bytes.
-        reader.skip(ECHO_PREFIX_LEN).unwrap();
-
-        // check echo packet type
-        let echo_packet_type = match reader.read_u8() {
-            Ok(value) => value,
+        let echo_packet = match EchoPacket::try_from(request.prepare.data()) {
+            Ok(echo_packet) => echo_packet,
             Err(error) => {
-                eprintln!("Could not read packet type: {:?}", error);
+                debug!("Could not parse echo packet: {}", error);
                 return Err(RejectBuilder {
                     code: ErrorCode::F01_INVALID_PACKET,
-                    message: b"Could not read echo packet type.",
+                    message: format!("Could not parse echo packet: {}", error).as_bytes(),
                     triggered_by: Some(&ilp_address),
                     data: &[],
                 }
                 .build());
             }
         };
-        if echo_packet_type == EchoPacketType::Response as u8 {
-            // if the echo packet type is Response, just pass it to the next service
-            // so that the initiator could handle this packet
-            return self.next.handle_request(request).await;
-        }
-        if echo_packet_type != EchoPacketType::Request as u8 {
-            eprintln!("The packet type is not acceptable: {}", echo_packet_type);
-            return Err(RejectBuilder {
-                code: ErrorCode::F01_INVALID_PACKET,
-                message: format!(
-                    "The echo packet type: {} is not acceptable.",
-                    echo_packet_type
-                )
-                .as_bytes(),
-                triggered_by: Some(&ilp_address),
-                data: &[],
+
+        match echo_packet {
+            // if the echo packet type is Response, fulfill it if this node sent the request,
+            // otherwise pass it to the next service so that the initiator could handle it
+            EchoPacket::Response => {
+                match self
+                    .pending
+                    .fulfillment(request.prepare.execution_condition())
+                {
+                    Some(fulfillment) => Ok(FulfillBuilder {
+                        fulfillment: &fulfillment,
+                        data: &[],
+                    }
+                    .build()),
+                    None => self.next.handle_request(request).await,
+                }
             }
-            .build());
-        }
+            EchoPacket::Request { source_address } => {
+                // create a new prepare packet to echo the prepare
+                let execution_condition =
+                    <[u8; 32]>::try_from(request.prepare.execution_condition()).unwrap();
+                request.prepare = EchoResponseBuilder {
+                    amount: request.prepare.amount(),
+                    expires_at: request.prepare.expires_at(),
+                    execution_condition: &execution_condition,
+                    destination: &source_address,
+                }
+                .build();
 
-        // check source address
-        let source_address = match reader.read_var_octet_string() {
-            Ok(value) => match Address::try_from(value) {
-                Ok(value) => value,
-                Err(error) => {
-                    eprintln!(
-                        "Could not parse source address from echo packet: {:?}",
-                        error
-                    );
+                self.next.handle_request(request).await
+            }
+            EchoPacket::UnidirectionalRequest { fulfillment } => {
+                // Anyone can fulfill these, so money sent with them would not be safe
+                if request.prepare.amount() > 0 {
                     return Err(RejectBuilder {
-                        code: ErrorCode::F01_INVALID_PACKET,
-                        message: b"Could not parse source address from Echo packet",
+                        code: ErrorCode::F03_INVALID_AMOUNT,
+                        message: b"Unidirectional echo requests must not carry money",
                         triggered_by: Some(&ilp_address),
                         data: &[],
                     }
                     .build());
                 }
-            },
-            Err(error) => {
-                eprintln!("Could not read source address: {:?}", error);
-                return Err(RejectBuilder {
-                    code: ErrorCode::F01_INVALID_PACKET,
-                    message: b"Could not read source address.",
-                    triggered_by: Some(&ilp_address),
+                if digest(&SHA256, &fulfillment).as_ref() != request.prepare.execution_condition() {
+                    return Err(RejectBuilder {
+                        code: ErrorCode::F05_WRONG_CONDITION,
+                        message: b"Echo fulfillment does not match the execution condition",
+                        triggered_by: Some(&ilp_address),
+                        data: &[],
+                    }
+                    .build());
+                }
+                Ok(FulfillBuilder {
+                    fulfillment: &fulfillment,
                     data: &[],
                 }
-                .build());
+                .build())
             }
-        };
-
-        // create a new prepare packet to echo the prepare
-        let execution_condition =
-            <[u8; 32]>::try_from(request.prepare.execution_condition()).unwrap();
-        request.prepare = EchoResponseBuilder {
-            amount: request.prepare.amount(),
-            expires_at: request.prepare.expires_at(),
-            execution_condition: &execution_condition,
-            destination: &source_address,
         }
-        .build();
-
-        self.next.handle_request(request).await
     }
 }
 
-#[cfg(test)]
-use interledger_packet::{oer, oer::MutBufOerExt};
-// This should be exported when we have a use for it outside of the tests
-#[cfg(test)]
+/// Builds a Prepare which asks the `destination` to send it back (bidirectional mode)
 pub struct EchoRequestBuilder<'a> {
     pub amount: u64,
     pub expires_at: SystemTime,
@@ -165,20 +278,47 @@ pub struct EchoRequestBuilder<'a> {
     pub source_address: &'a Address,
 }
 
-#[cfg(test)]
 impl<'a> EchoRequestBuilder<'a> {
     pub fn build(&self) -> Prepare {
-        let source_address_len = oer::predict_var_octet_string(self.source_address.len());
-        let mut data_buffer = BytesMut::with_capacity(ECHO_PREFIX_LEN + 1 + source_address_len);
-        data_buffer.put(ECHO_PREFIX.as_bytes());
-        data_buffer.put_u8(EchoPacketType::Request as u8);
-        data_buffer.put_var_octet_string(self.source_address.as_bytes());
+        let data = EchoPacket::Request {
+            source_address: self.source_address.clone(),
+        }
+        .to_bytes();
         PrepareBuilder {
             amount: self.amount,
             expires_at: self.expires_at,
             execution_condition: self.execution_condition,
             destination: self.destination.clone(),
-            data: data_buffer.borrow(),
+            data: data.borrow(),
+        }
+        .build()
+    }
+}
+
+/// Builds a Prepare which the `destination` fulfills with the `fulfillment` (the non-standard
+/// unidirectional mode). The Prepare carries no money, since the receiver rejects any.
+pub struct UnidirectionalEchoRequestBuilder<'a> {
+    pub expires_at: SystemTime,
+    /// The fulfillment the receiver responds with, which the execution condition is derived from
+    pub fulfillment: &'a [u8; 32],
+    /// The ILP address that the initiator wants to Ping
+    pub destination: &'a Address,
+}
+
+impl<'a> UnidirectionalEchoRequestBuilder<'a> {
+    pub fn build(&self) -> Prepare {
+        let mut execution_condition = [0; 32];
+        execution_condition.copy_from_slice(digest(&SHA256, self.fulfillment).as_ref());
+        let data = EchoPacket::UnidirectionalRequest {
+            fulfillment: *self.fulfillment,
+        }
+        .to_bytes();
+        PrepareBuilder {
+            amount: 0,
+            expires_at: self.expires_at,
+            execution_condition: &execution_condition,
+            destination: self.destination.clone(),
+            data: data.borrow(),
         }
         .build()
     }
@@ -194,15 +334,13 @@ pub struct EchoResponseBuilder<'a> {
 
 impl<'a> EchoResponseBuilder<'a> {
     pub fn build(&self) -> Prepare {
-        let mut data_buffer = BytesMut::with_capacity(ECHO_PREFIX_LEN + 1);
-        data_buffer.put(ECHO_PREFIX.as_bytes());
-        data_buffer.put_u8(EchoPacketType::Response as u8);
+        let data = EchoPacket::Response.to_bytes();
         PrepareBuilder {
             amount: self.amount,
             expires_at: self.expires_at,
             execution_condition: self.execution_condition,
             destination: self.destination.clone(),
-            data: data_buffer.borrow(),
+            data: data.borrow(),
         }
         .build()
     }
@@ -409,6 +547,57 @@ mod echo_tests {
         assert!(result.is_ok());
     }
 
+    /// Responses to the echo requests the node sent are fulfilled, others are passed on.
+    #[tokio::test]
+    async fn test_fulfills_pending_echo_responses() {
+        let expires_at = SystemTime::now() + Duration::from_secs(30);
+        let fulfillment = get_random_fulfillment();
+        let node_address = Address::from_str("example.initiator").unwrap();
+        let pending = PendingEchoes::default();
+        let condition = pending.insert(fulfillment);
+        assert_eq!(condition, get_hash_of(&fulfillment));
+
+        let handler = incoming_service_fn(|_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                triggered_by: None,
+                data: &[],
+            }
+            .build())
+        });
+        let mut echo_service = EchoService::new(TestStore(node_address.clone()), handler)
+            .with_pending_echoes(pending.clone());
+        let response = |condition: &[u8; 32]| {
+            let prepare = EchoResponseBuilder {
+                amount: 0,
+                expires_at,
+                execution_condition: condition,
+                destination: &node_address,
+            }
+            .build();
+            IncomingRequest {
+                from: TestAccount(Uuid::new_v4()),
+                prepare,
+            }
+        };
+
+        let fulfill = echo_service
+            .handle_request(response(&condition))
+            .await
+            .unwrap();
+        assert_eq!(fulfill.fulfillment(), &fulfillment[..]);
+        assert!(echo_service
+            .handle_request(response(&[0; 32]))
+            .await
+            .is_err());
+        pending.remove(&condition);
+        assert!(echo_service
+            .handle_request(response(&condition))
+            .await
+            .is_err());
+    }
+
     /// If echo packet type is neither `1` nor `2`, the packet is considered to be malformed.
     #[tokio::test]
     async fn test_invalid_echo_packet_type() {
@@ -492,6 +681,96 @@ mod echo_tests {
         assert!(result.is_err());
     }
 
+    /// In unidirectional mode, the service fulfills the packet itself instead of sending it on.
+    #[tokio::test]
+    async fn test_unidirectional_echo_packet() {
+        let expires_at = SystemTime::now() + Duration::from_secs(30);
+        let fulfillment = get_random_fulfillment();
+        let node_address = Address::from_str("example.recipient").unwrap();
+
+        let handler = incoming_service_fn(|_| -> IlpResult {
+            panic!("unidirectional echo requests should not be passed on");
+        });
+        let mut echo_service = EchoService::new(TestStore(node_address.clone()), handler);
+
+        let prepare = UnidirectionalEchoRequestBuilder {
+            expires_at,
+            fulfillment: &fulfillment,
+            destination: &node_address,
+        }
+        .build();
+        assert_eq!(
+            prepare.execution_condition(),
+            &get_hash_of(&fulfillment)[..]
+        );
+        let from = TestAccount(Uuid::new_v4());
+        let fulfill = echo_service
+            .handle_request(IncomingRequest { from, prepare })
+            .await
+            .unwrap();
+        assert_eq!(fulfill.fulfillment(), &fulfillment[..]);
+
+        // the fulfillment in the data must match the condition
+        let prepare = PrepareBuilder {
+            amount: 0,
+            expires_at,
+            execution_condition: &[0; 32],
+            destination: node_address,
+            data: &EchoPacket::UnidirectionalRequest { fulfillment }.to_bytes(),
+        }
+        .build();
+        let from = TestAccount(Uuid::new_v4());
+        let reject = echo_service
+            .handle_request(IncomingRequest { from, prepare })
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F05_WRONG_CONDITION);
+
+        // anyone could fulfill them, so they must not carry money
+        let prepare = PrepareBuilder {
+            amount: 1,
+            expires_at,
+            execution_condition: &get_hash_of(&fulfillment),
+            destination: Address::from_str("example.recipient").unwrap(),
+            data: &EchoPacket::UnidirectionalRequest { fulfillment }.to_bytes(),
+        }
+        .build();
+        let from = TestAccount(Uuid::new_v4());
+        let reject = echo_service
+            .handle_request(IncomingRequest { from, prepare })
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F03_INVALID_AMOUNT);
+    }
+
+    #[test]
+    fn echo_packet_roundtrip() {
+        let packets = vec![
+            EchoPacket::Request {
+                source_address: Address::from_str("example.initiator").unwrap(),
+            },
+            EchoPacket::Response,
+            EchoPacket::UnidirectionalRequest {
+                fulfillment: [7; 32],
+            },
+        ];
+        for packet in packets {
+            let bytes = packet.to_bytes();
+            assert!(EchoPacket::has_echo_prefix(&bytes));
+            assert_eq!(EchoPacket::try_from(&bytes[..]).unwrap(), packet);
+        }
+        assert_eq!(
+            &EchoPacket::Response.to_bytes()[..],
+            &b"ECHOECHOECHOECHO\x01"[..]
+        );
+        assert!(EchoPacket::try_from(&b"ECHOECHOECHOECHO"[..]).is_err());
+        assert!(EchoPacket::try_from(&b"ECHOECHOECHOECHO\x02\x00"[..]).is_err());
+        assert!(matches!(
+            EchoPacket::try_from(&b"ECHOECHO"[..]),
+            Err(EchoPacketError::NotEcho)
+        ));
+    }
+
     fn get_random_fulfillment() -> [u8; 32] {
         let mut bytes: [u8; 32] = [0; 32];
         SystemRandom::new().fill(&mut bytes).unwrap();
@@ -1,8 +1,12 @@
 use async_trait::async_trait;
 use interledger_packet::{ErrorCode, RejectBuilder};
-use interledger_rates::ExchangeRateStore;
+use interledger_rates::{rational_from_f64, ExchangeRate, ExchangeRateStore};
 use interledger_service::*;
-use interledger_settlement::core::types::{ConversionError, Convert, ConvertDetails};
+use num::{
+    bigint::BigInt,
+    rational::BigRational,
+    traits::{One, Signed, ToPrimitive, Zero},
+};
 use std::marker::PhantomData;
 use tracing::{error, trace, warn};
 
@@ -12,7 +16,8 @@ use tracing::{error, trace, warn};
 /// Requires a `ExchangeRateStore`
 #[derive(Clone)]
 pub struct ExchangeRateService<S, O, A> {
-    spread: f64,
+    spread: BigRational,
+    same_currency_free: bool,
     store: S,
     next: O,
     account_type: PhantomData<A>,
@@ -25,13 +30,45 @@ where
     A: Account,
 {
     pub fn new(spread: f64, store: S, next: O) -> Self {
+        let spread = rational_from_f64(spread).unwrap_or_else(|| {
+            error!(
+                "Invalid spread: {}, the node will keep the whole amount of every packet",
+                spread
+            );
+            BigRational::one()
+        });
         ExchangeRateService {
             spread,
+            same_currency_free: false,
             store,
             next,
             account_type: PhantomData,
         }
     }
+
+    /// Forwards packets between accounts of the same asset without deducting the spread,
+    /// e.g. when the fees are configured to exempt them
+    pub fn with_same_currency_free(mut self, same_currency_free: bool) -> Self {
+        self.same_currency_free = same_currency_free;
+        self
+    }
+}
+
+/// The rates to convert with if a direct rate is set for the pair of assets.
+/// Converting from the base asset of the pair uses its bid, and converting
+/// to it uses its ask.
+pub(crate) fn pair_rates<S: ExchangeRateStore>(
+    store: &S,
+    from: &str,
+    to: &str,
+) -> Option<(ExchangeRate, ExchangeRate)> {
+    if let Ok(Some(pair_rate)) = store.get_pair_rate(from, to) {
+        return Some((pair_rate.bid().clone(), ExchangeRate::one()));
+    }
+    if let Ok(Some(pair_rate)) = store.get_pair_rate(to, from) {
+        return Some((ExchangeRate::one(), pair_rate.ask().clone()));
+    }
+    None
 }
 
 #[async_trait]
@@ -45,15 +82,24 @@ where
     /// On send request:
     /// 1. If the prepare packet's amount is 0, it just forwards
     /// 1. Retrieves the exchange rate from the store (the store independently is responsible for polling the rates)
+    ///     - uses the direct rate of the pair of assets if one is set, otherwise the rates of both assets in USD
     ///     - return reject if the call to the store fails
     /// 1. Calculates the exchange rate AND scales it up/down depending on how many decimals each asset requires
+    ///     - the spread is not deducted from same-currency packets if they are free
     /// 1. Updates the amount in the prepare packet and forwards it
     async fn send_request(&mut self, mut request: OutgoingRequest<A>) -> IlpResult {
         let ilp_address = self.store.get_ilp_address();
         if request.prepare.amount() > 0 {
-            let rates: (f64, f64) = if request.from.asset_code() == request.to.asset_code() {
-                (1f64, 1f64)
-            } else if let Ok(rates) = self
+            let same_currency = request.from.asset_code() == request.to.asset_code();
+            let rates = if same_currency {
+                (ExchangeRate::one(), ExchangeRate::one())
+            } else if let Some(rates) = pair_rates(
+                &self.store,
+                request.from.asset_code(),
+                request.to.asset_code(),
+            ) {
+                rates
+            } else if let Ok(mut rates) = self
                 .store
                 .get_exchange_rates(&[&request.from.asset_code(), &request.to.asset_code()])
             {
@@ -61,7 +107,9 @@ where
                 // we multiply by the incoming asset's rate and divide by the outgoing asset's rate. For example,
                 // if an incoming packet is denominated in an asset worth 1 USD and the outgoing asset is worth
                 // 10 USD, the outgoing amount will be 1/10th of the source amount.
-                (rates[0], rates[1])
+                let rate_dest = rates.pop().unwrap();
+                let rate_src = rates.pop().unwrap();
+                (rate_src, rate_dest)
             } else {
                 error!(
                     "No exchange rates available for assets: {}, {}",
@@ -82,51 +130,38 @@ where
                 .build());
             };
 
+            let no_spread = BigRational::zero();
+            let spread = if same_currency && self.same_currency_free {
+                &no_spread
+            } else {
+                &self.spread
+            };
             // Can we overflow here?
             let outgoing_amount = calculate_outgoing_amount(
                 request.prepare.amount(),
-                self.spread,
-                rates,
+                spread,
+                (&rates.0, &rates.1),
                 (request.from.asset_scale(), request.to.asset_scale()),
             );
 
             match outgoing_amount {
                 Ok(outgoing_amount) => {
-                    request.prepare.set_amount(outgoing_amount as u64);
+                    request.prepare.set_amount(outgoing_amount);
                     trace!("Converted incoming amount of: {} {} (scale {}) from account {} to outgoing amount of: {} {} (scale {}) for account {}",
                         request.original_amount, request.from.asset_code(), request.from.asset_scale(), request.from.id(),
                         outgoing_amount, request.to.asset_code(), request.to.asset_scale(), request.to.id());
                 }
                 Err(outgoing_amount_error) => {
                     let (code, message) = match outgoing_amount_error {
-                        // Amount was too small to be converted to a non-zero u64, i.e. smaller
-                        // than 1.0.
+                        // Amount was converted to less than one unit of the outgoing asset
                         OutgoingAmountError::LessThanOne(outgoing_amount) => (
                             ErrorCode::R01_INSUFFICIENT_SOURCE_AMOUNT,
-                            format!(
-                                "Could not cast to f64, amount too small: {}",
-                                outgoing_amount
-                            ),
+                            format!("Converted amount too small: {}", outgoing_amount),
                         ),
-                        // Amount was too large to be converted to u64 from f64, i.e. greater
-                        // than u64::MAX as f64.
-                        OutgoingAmountError::ToU64ConvertOverflow(outgoing_amount) => (
+                        // Amount was converted to more than fits in a packet
+                        OutgoingAmountError::TooLarge(outgoing_amount) => (
                             ErrorCode::F08_AMOUNT_TOO_LARGE,
-                            format!(
-                                "Could not cast to f64, amount too large: {}",
-                                outgoing_amount
-                            ),
-                        ),
-                        OutgoingAmountError::FloatOverflow => (
-                            ErrorCode::F08_AMOUNT_TOO_LARGE,
-                            format!(
-                                "Could not convert exchange rate from {}:{} to: {}:{}. Got incoming amount: {}",
-                                request.from.asset_code(),
-                                request.from.asset_scale(),
-                                request.to.asset_code(),
-                                request.to.asset_scale(),
-                                request.prepare.amount(),
-                            ),
+                            format!("Converted amount too large: {}", outgoing_amount),
                         ),
                     };
                     return Err(RejectBuilder {
@@ -145,51 +180,57 @@ where
 }
 
 #[derive(PartialEq, Debug)]
-enum OutgoingAmountError {
-    ToU64ConvertOverflow(f64),
-    FloatOverflow,
-    LessThanOne(f64),
+pub(crate) enum OutgoingAmountError {
+    TooLarge(BigRational),
+    LessThanOne(BigRational),
 }
 
-fn calculate_outgoing_amount(
+/// Converts the amount with the exchange rates, which are expressed as `base asset / asset`,
+/// after deducting the spread from the rate. The arithmetic is exact and the converted
+/// amount is rounded down, so the node never forwards more than the rates allow.
+pub(crate) fn calculate_outgoing_amount(
     input: u64,
-    spread: f64,
-    (rate_src, rate_dest): (f64, f64),
+    spread: &BigRational,
+    (rate_src, rate_dest): (&ExchangeRate, &ExchangeRate),
     (asset_scale_src, asset_scale_dest): (u8, u8),
 ) -> Result<u64, OutgoingAmountError> {
-    let rate = rate_src / rate_dest;
-    // Apply spread
-    // TODO should this be applied differently for "local" or same-currency packets?
-    let rate = rate * (1.0 - spread);
-    let rate = if rate.is_finite() && rate.is_sign_positive() {
-        rate
+    let rate = if rate_dest.is_zero() {
+        warn!("Exchange rate of the outgoing asset is 0, using a rate of 0 instead");
+        BigRational::zero()
     } else {
+        rate_src.as_rational() / rate_dest.as_rational()
+    };
+    // Apply spread
+    // Fees which depend on the accounts or route (and can exempt same-currency packets)
+    // are charged by the `FeeService` instead
+    let rate = rate * (BigRational::one() - spread);
+    let rate = if rate.is_negative() {
         warn!(
-            "Exchange rate would have been {} based on rate and spread, using 0.0 instead",
+            "Exchange rate would have been {} based on rate and spread, using 0 instead",
             rate
         );
-        0.0
+        BigRational::zero()
+    } else {
+        rate
     };
-    // Can we overflow here?
-    let outgoing_amount = 1.0f64
-        .normalize_scale(ConvertDetails {
-            from: asset_scale_src,
-            to: asset_scale_dest,
-        })
-        .map(|scale| rate * scale * (input as f64));
-
-    match outgoing_amount {
-        // Happens when rate == 0 or spread >= 1
-        // In latter case the node takes everything to itself
-        Ok(x) if x == 0.0f64 => Ok(0),
-        Ok(x) if x < 1.0f64 => Err(OutgoingAmountError::LessThanOne(x)),
-        Ok(x) if !x.is_finite() => Err(OutgoingAmountError::FloatOverflow),
-        // FIXME: u64::MAX is higher than 2^53 or whatever is the max integer precision in f64
-        Ok(x) if x > u64::MAX as f64 => Err(OutgoingAmountError::ToU64ConvertOverflow(x)),
-        Ok(x) => Ok(x as u64),
-        // Error happens if float happens to be std::f64::INFINITY after conversion
-        Err(ConversionError) => Err(OutgoingAmountError::FloatOverflow),
+
+    let ten = BigInt::from(10u8);
+    let scale_src = BigRational::from_integer(num::pow(ten.clone(), asset_scale_src as usize));
+    let scale_dest = BigRational::from_integer(num::pow(ten, asset_scale_dest as usize));
+    let outgoing_amount = rate * BigRational::from_integer(input.into()) * scale_dest / scale_src;
+
+    // Happens when rate == 0 or spread >= 1
+    // In latter case the node takes everything to itself
+    if outgoing_amount.is_zero() {
+        return Ok(0);
+    }
+    let rounded = outgoing_amount.floor().to_integer();
+    if rounded.is_zero() {
+        return Err(OutgoingAmountError::LessThanOne(outgoing_amount));
     }
+    rounded
+        .to_u64()
+        .ok_or(OutgoingAmountError::TooLarge(outgoing_amount))
 }
 
 #[cfg(test)]
@@ -197,6 +238,7 @@ mod tests {
     use super::*;
     use interledger_errors::{AddressStoreError, ExchangeRateStoreError};
     use interledger_packet::{Address, Fulfill, FulfillBuilder, PrepareBuilder, Reject};
+    use interledger_rates::PairRate;
     use interledger_service::{outgoing_service_fn, Account};
     use once_cell::sync::Lazy;
     use std::collections::HashMap;
@@ -222,27 +264,26 @@ mod tests {
 
     #[tokio::test]
     async fn exchange_conversion_error() {
-        // rejects f64 that does not fit in u64
+        // rejects amounts that do not fit in u64
         let ret = exchange_rate(std::u64::MAX, 1, 2.0, 1, 1.0, 0.0).await;
         let reject = ret.0.unwrap_err();
         assert_eq!(reject.code(), ErrorCode::F08_AMOUNT_TOO_LARGE);
-        assert!(reject
-            .message()
-            .starts_with(b"Could not cast to f64, amount too large"));
+        assert_eq!(
+            reject.message(),
+            b"Converted amount too large: 36893488147419103230"
+        );
 
-        // rejects f64 which gets rounded down to 0
+        // rejects amounts which get rounded down to 0
         let ret = exchange_rate(1, 2, 1.0, 1, 1.0, 0.0).await;
         let reject = ret.0.unwrap_err();
         assert_eq!(reject.code(), ErrorCode::R01_INSUFFICIENT_SOURCE_AMOUNT);
-        assert!(reject
-            .message()
-            .starts_with(b"Could not cast to f64, amount too small"));
+        assert_eq!(reject.message(), b"Converted amount too small: 1/10");
 
-        // `Convert` errored
+        // huge rates and scales do not overflow
         let ret = exchange_rate(std::u64::MAX, 1, std::f64::MAX, 255, 1.0, 0.0).await;
         let reject = ret.0.unwrap_err();
         assert_eq!(reject.code(), ErrorCode::F08_AMOUNT_TOO_LARGE);
-        assert!(reject.message().starts_with(b"Could not convert"));
+        assert!(reject.message().starts_with(b"Converted amount too large"));
     }
 
     #[tokio::test]
@@ -268,83 +309,206 @@ mod tests {
         assert_eq!(ret.1[0].prepare.amount(), 0);
     }
 
+    #[tokio::test]
+    async fn prefers_pair_rates() {
+        let rate = |s: &str| ExchangeRate::from_str(s).unwrap();
+        let mut store = test_store(1.0, 1.0);
+        store.pair_rates.insert(
+            "ABC/XYZ".to_string(),
+            PairRate::new(rate("2"), rate("4")).unwrap(),
+        );
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let outgoing = outgoing_service_fn(move |request| {
+            requests_clone.lock().unwrap().push(request);
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: &[],
+            }
+            .build())
+        });
+        let mut service = ExchangeRateService::new(0.0, store, outgoing);
+        let request = |from: &str, to: &str| OutgoingRequest {
+            from: TestAccount::new(from.to_owned(), 0),
+            to: TestAccount::new(to.to_owned(), 0),
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 100,
+                expires_at: SystemTime::now(),
+                execution_condition: &[1; 32],
+                data: &[],
+            }
+            .build(),
+        };
+
+        // The bid converts from the base asset, ignoring the rates in USD
+        service.send_request(request("ABC", "XYZ")).await.unwrap();
+        // The ask converts to the base asset
+        service.send_request(request("XYZ", "ABC")).await.unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].prepare.amount(), 200);
+        assert_eq!(requests[1].prepare.amount(), 25);
+    }
+
+    #[tokio::test]
+    async fn skips_spread_on_same_currency_packets_if_free() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let outgoing = outgoing_service_fn(move |request| {
+            requests_clone.lock().unwrap().push(request);
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: &[],
+            }
+            .build())
+        });
+        let mut service = test_service(1.0, 2.0, 0.01, outgoing);
+        let request = |asset_code: &str| OutgoingRequest {
+            from: TestAccount::new(asset_code.to_owned(), 2),
+            to: TestAccount::new(asset_code.to_owned(), 1),
+            original_amount: 1000,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 1000,
+                expires_at: SystemTime::now(),
+                execution_condition: &[1; 32],
+                data: &[],
+            }
+            .build(),
+        };
+
+        // The spread is charged by default
+        service.send_request(request("ABC")).await.unwrap();
+        // Only the scale changes
+        let mut service = service.with_same_currency_free(true);
+        service.send_request(request("ABC")).await.unwrap();
+        service.send_request(request("XYZ")).await.unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].prepare.amount(), 99);
+        assert_eq!(requests[1].prepare.amount(), 100);
+        assert_eq!(requests[2].prepare.amount(), 100);
+    }
+
     // Errors most likely are caused by floating point errors
     #[test]
     fn calculates_with_small_input() {
         for i in 1..100 {
-            assert_eq!(
-                calculate_outgoing_amount(i, 0.0, (0.00000025, 0.25), (0, 6)),
-                Ok(i)
-            );
+            assert_eq!(calculate(i, 0.0, (0.00000025, 0.25), (0, 6)), Ok(i));
         }
     }
     // Errors most likely are caused by floating point errors
     #[test]
     fn calculates_with_big_input() {
         assert_eq!(
-            calculate_outgoing_amount(159000000000, 0.0, (0.000009, 1.0), (3, 0)),
+            calculate(159000000000, 0.0, (0.000009, 1.0), (3, 0)),
             Ok(1431)
         );
     }
 
     #[test]
     fn calculates_with_positive_spread() {
-        assert_eq!(
-            calculate_outgoing_amount(50, 0.11, (1.0, 1.0), (0, 0)),
-            Ok(44)
-        );
+        assert_eq!(calculate(50, 0.11, (1.0, 1.0), (0, 0)), Ok(44));
     }
 
     #[test]
     fn calculates_with_maximum_spread() {
-        assert_eq!(
-            calculate_outgoing_amount(50, 1.0, (1.0, 1.0), (0, 0)),
-            Ok(0)
-        );
+        assert_eq!(calculate(50, 1.0, (1.0, 1.0), (0, 0)), Ok(0));
     }
 
     #[test]
     fn calculates_with_negative_spread() {
-        assert_eq!(
-            calculate_outgoing_amount(50, -0.11, (1.0, 1.0), (0, 0)),
-            Ok(55)
-        );
+        assert_eq!(calculate(50, -0.11, (1.0, 1.0), (0, 0)), Ok(55));
     }
 
     #[test]
     fn calculates_with_u64_convert_overflow() {
         assert_eq!(
-            calculate_outgoing_amount(u64::MAX, 0.0, (1.0, 1.0), (0, 1)),
-            Err(OutgoingAmountError::ToU64ConvertOverflow(
-                184467440737095500000.0
-            ))
+            calculate(u64::MAX, 0.0, (1.0, 1.0), (0, 1)),
+            Err(OutgoingAmountError::TooLarge(BigRational::from_integer(
+                BigInt::from(u64::MAX) * 10
+            )))
         );
     }
 
     #[test]
-    fn calculates_with_float_overflow() {
-        assert_eq!(
-            calculate_outgoing_amount(u64::MAX, 0.0, (f64::MAX, 1.0), (0, 255)),
-            Err(OutgoingAmountError::FloatOverflow)
-        );
+    fn calculates_with_huge_rate_and_scale() {
+        assert!(matches!(
+            calculate(u64::MAX, 0.0, (f64::MAX, 1.0), (0, 255)),
+            Err(OutgoingAmountError::TooLarge(_))
+        ));
     }
 
     #[test]
     fn calculates_with_less_than_one() {
         assert_eq!(
-            calculate_outgoing_amount(1, 0.0, (1.0, 2.0), (0, 0)),
-            Err(OutgoingAmountError::LessThanOne(0.5))
+            calculate(1, 0.0, (1.0, 2.0), (0, 0)),
+            Err(OutgoingAmountError::LessThanOne(BigRational::new(
+                1.into(),
+                2.into()
+            )))
         );
     }
 
     #[test]
     fn calculates_with_high_asset_scale() {
         assert_eq!(
-            calculate_outgoing_amount(10, 0.0, (1.0, 1.0), (i8::MAX as u8 + 1, i8::MAX as u8)),
+            calculate(10, 0.0, (1.0, 1.0), (i8::MAX as u8 + 1, i8::MAX as u8)),
             Ok(1)
         );
     }
 
+    #[test]
+    fn calculates_large_amounts_exactly() {
+        // Amounts above 2^53 lose precision as f64
+        assert_eq!(
+            calculate(9_007_199_254_740_993, 0.0, (1.0, 1.0), (9, 9)),
+            Ok(9_007_199_254_740_993)
+        );
+        assert_eq!(calculate(u64::MAX, 0.0, (1.0, 1.0), (18, 18)), Ok(u64::MAX));
+        let rate = |s: &str| ExchangeRate::from_str(s).unwrap();
+        assert_eq!(
+            calculate_outgoing_amount(
+                18_446_744_073_709_551_557,
+                &BigRational::zero(),
+                (&rate("0.000000000000000123"), &rate("0.000000000000000041")),
+                (18, 6)
+            ),
+            // 3 * 18446744073709551557 / 10^12, rounded down
+            Ok(55_340_232)
+        );
+    }
+
+    #[test]
+    fn rounds_down() {
+        assert_eq!(
+            calculate(2, 0.0, (1.0, 3.0), (0, 0)),
+            Err(OutgoingAmountError::LessThanOne(BigRational::new(
+                2.into(),
+                3.into()
+            )))
+        );
+        assert_eq!(calculate(5, 0.0, (1.0, 3.0), (0, 0)), Ok(1));
+        assert_eq!(calculate(299, 0.0, (1.0, 1.0), (2, 0)), Ok(2));
+    }
+
+    fn calculate(
+        input: u64,
+        spread: f64,
+        (rate_src, rate_dest): (f64, f64),
+        scales: (u8, u8),
+    ) -> Result<u64, OutgoingAmountError> {
+        calculate_outgoing_amount(
+            input,
+            &rational_from_f64(spread).unwrap(),
+            (
+                &ExchangeRate::from_f64(rate_src).unwrap(),
+                &ExchangeRate::from_f64(rate_dest).unwrap(),
+            ),
+            scales,
+        )
+    }
+
     // Instantiates an exchange rate service and returns the fulfill/reject
     // packet and the outgoing request after performing an asset conversion
     async fn exchange_rate(
@@ -444,19 +608,20 @@ mod tests {
     #[derive(Debug, Clone)]
     struct TestStore {
         rates: HashMap<Vec<String>, (f64, f64)>,
+        pair_rates: HashMap<String, PairRate>,
     }
 
     impl ExchangeRateStore for TestStore {
         fn get_exchange_rates(
             &self,
             asset_codes: &[&str],
-        ) -> Result<Vec<f64>, ExchangeRateStoreError> {
+        ) -> Result<Vec<ExchangeRate>, ExchangeRateStoreError> {
             let mut ret = Vec::new();
             let key = vec![asset_codes[0].to_owned(), asset_codes[1].to_owned()];
             let v = self.rates.get(&key);
             if let Some(v) = v {
-                ret.push(v.0);
-                ret.push(v.1);
+                ret.push(ExchangeRate::from_f64(v.0).unwrap());
+                ret.push(ExchangeRate::from_f64(v.1).unwrap());
             } else {
                 return Err(ExchangeRateStoreError::PairNotFound {
                     from: key[0].clone(),
@@ -468,20 +633,36 @@ mod tests {
 
         fn set_exchange_rates(
             &self,
-            _rates: HashMap<String, f64>,
+            _rates: HashMap<String, ExchangeRate>,
         ) -> Result<(), ExchangeRateStoreError> {
             unimplemented!()
         }
 
-        fn get_all_exchange_rates(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+        fn get_all_exchange_rates(
+            &self,
+        ) -> Result<HashMap<String, ExchangeRate>, ExchangeRateStoreError> {
+            unimplemented!()
+        }
+
+        fn set_pair_rates(
+            &self,
+            _rates: HashMap<String, PairRate>,
+        ) -> Result<(), ExchangeRateStoreError> {
             unimplemented!()
         }
+
+        fn get_all_pair_rates(&self) -> Result<HashMap<String, PairRate>, ExchangeRateStoreError> {
+            Ok(self.pair_rates.clone())
+        }
     }
 
     fn test_store(rate1: f64, rate2: f64) -> TestStore {
         let mut rates = HashMap::new();
         rates.insert(vec!["ABC".to_owned(), "XYZ".to_owned()], (rate1, rate2));
-        TestStore { rates }
+        TestStore {
+            rates,
+            pair_rates: HashMap::new(),
+        }
     }
 
     fn test_service(
@@ -0,0 +1,829 @@
+use super::exchange_rates_service::{calculate_outgoing_amount, pair_rates};
+use super::BalanceStore;
+use async_trait::async_trait;
+use interledger_packet::{ErrorCode, RejectBuilder};
+use interledger_rates::{ExchangeRate, ExchangeRateStore};
+use interledger_service::*;
+use interledger_settlement::core::types::SettlementStore;
+use num::{
+    bigint::BigInt,
+    rational::BigRational,
+    traits::{ToPrimitive, Zero},
+};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{error, trace, warn};
+
+/// A fee charged on the packets the node forwards, denominated in the asset
+/// (and scale) of the incoming account
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FeePolicy {
+    /// Amount charged on every packet
+    #[serde(default)]
+    pub fixed: u64,
+    /// Fraction of the packet amount charged on top of the fixed fee, e.g. 0.01 or
+    /// "1/100" for 1%. Kept exact so that large amounts are charged precisely.
+    #[serde(default = "no_percentage")]
+    pub percentage: ExchangeRate,
+    /// The least amount charged on a packet
+    #[serde(default)]
+    pub minimum: u64,
+}
+
+fn no_percentage() -> ExchangeRate {
+    ExchangeRate::new(BigRational::zero()).unwrap()
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        FeePolicy {
+            fixed: 0,
+            percentage: no_percentage(),
+            minimum: 0,
+        }
+    }
+}
+
+impl FeePolicy {
+    /// The fee charged on a packet with the given amount. Proportional fees are
+    /// rounded up, and the fee never exceeds the amount itself.
+    pub fn fee(&self, amount: u64) -> u64 {
+        let proportional =
+            BigRational::from_integer(BigInt::from(amount)) * self.percentage.as_rational();
+        // The percentage is never negative, so this only fails above u64::MAX
+        let proportional = proportional
+            .ceil()
+            .to_integer()
+            .to_u64()
+            .unwrap_or(u64::MAX);
+        self.fixed
+            .saturating_add(proportional)
+            .max(self.minimum)
+            .min(amount)
+    }
+}
+
+fn deserialize_optional_username<'de, D>(deserializer: D) -> Result<Option<Username>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(username) => Username::from_str(&username)
+            .map(Some)
+            .map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn deserialize_username_policies<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, FeePolicy>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let policies = HashMap::<String, FeePolicy>::deserialize(deserializer)?;
+    if let Some((username, err)) = policies.keys().find_map(|username| {
+        Username::from_str(username)
+            .err()
+            .map(|err| (username, err))
+    }) {
+        return Err(de::Error::custom(format!(
+            "invalid username {} in fee policies: {}",
+            username, err
+        )));
+    }
+    Ok(policies)
+}
+
+/// Which fees the node charges on the packets it forwards.
+///
+/// The policy of the incoming account applies first, then the policy of the outgoing
+/// account, then the policy of the longest prefix matching the destination and
+/// finally the default policy.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct FeeConfig {
+    /// The policy for packets no other policy applies to
+    #[serde(default)]
+    pub default: Option<FeePolicy>,
+    /// Policies for the packets coming from the accounts with the given usernames
+    #[serde(default, deserialize_with = "deserialize_username_policies")]
+    pub incoming: HashMap<String, FeePolicy>,
+    /// Policies for the packets forwarded to the accounts with the given usernames
+    #[serde(default, deserialize_with = "deserialize_username_policies")]
+    pub outgoing: HashMap<String, FeePolicy>,
+    /// Policies for the packets whose destination starts with the given ILP address prefixes
+    #[serde(default)]
+    pub routes: HashMap<String, FeePolicy>,
+    /// Whether packets between accounts of the same asset are forwarded without fees.
+    /// The `ExchangeRateService` also needs to be told to skip its spread for them.
+    #[serde(default)]
+    pub same_currency_free: bool,
+    /// Username of the account the collected fees are credited to
+    #[serde(default, deserialize_with = "deserialize_optional_username")]
+    pub fee_account: Option<Username>,
+}
+
+impl FeeConfig {
+    /// The usernames of the accounts with incoming or outgoing policies, which must
+    /// all exist for the config to be valid
+    pub fn usernames(&self) -> Result<Vec<Username>, String> {
+        self.incoming
+            .keys()
+            .chain(self.outgoing.keys())
+            .map(|username| {
+                Username::from_str(username)
+                    .map_err(|err| format!("invalid username {}: {}", username, err))
+            })
+            .collect()
+    }
+}
+
+/// The `FeeConfig` with the usernames parsed and the routes sorted from the longest prefix
+#[derive(Debug)]
+struct FeePolicies {
+    default: Option<FeePolicy>,
+    incoming: Vec<(Username, FeePolicy)>,
+    outgoing: Vec<(Username, FeePolicy)>,
+    routes: Vec<(String, FeePolicy)>,
+    same_currency_free: bool,
+}
+
+impl FeePolicies {
+    fn new(config: &FeeConfig) -> Self {
+        let usernames = |policies: &HashMap<String, FeePolicy>| {
+            policies
+                .iter()
+                .filter_map(|(username, policy)| match Username::from_str(username) {
+                    Ok(username) => Some((username, policy.clone())),
+                    Err(err) => {
+                        error!(
+                            "Ignoring fee policy for invalid username {}: {}",
+                            username, err
+                        );
+                        None
+                    }
+                })
+                .collect()
+        };
+        let mut routes: Vec<(String, FeePolicy)> = config
+            .routes
+            .iter()
+            .map(|(prefix, policy)| (prefix.clone(), policy.clone()))
+            .collect();
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        FeePolicies {
+            default: config.default.clone(),
+            incoming: usernames(&config.incoming),
+            outgoing: usernames(&config.outgoing),
+            routes,
+            same_currency_free: config.same_currency_free,
+        }
+    }
+
+    fn policy_for<A: Account>(&self, request: &OutgoingRequest<A>) -> Option<&FeePolicy> {
+        if self.same_currency_free && request.from.asset_code() == request.to.asset_code() {
+            return None;
+        }
+        let destination = request.prepare.destination();
+        find_by_username(&self.incoming, request.from.username())
+            .or_else(|| find_by_username(&self.outgoing, request.to.username()))
+            .or_else(|| {
+                self.routes
+                    .iter()
+                    .find(|(prefix, _)| destination.starts_with(prefix.as_str()))
+                    .map(|(_, policy)| policy)
+            })
+            .or(self.default.as_ref())
+    }
+}
+
+fn find_by_username<'a>(
+    policies: &'a [(Username, FeePolicy)],
+    username: &Username,
+) -> Option<&'a FeePolicy> {
+    policies
+        .iter()
+        .find(|(name, _)| name == username)
+        .map(|(_, policy)| policy)
+}
+
+/// # Fee Service
+///
+/// Deducts the fee of the applicable `FeePolicy` from the amount of the outgoing packets,
+/// before the amount is converted to the asset of the outgoing account. The fees of
+/// fulfilled packets are credited to the fee account, if one is configured, converted to its asset.
+/// The fee account is never settled: the node refuses to start if it has a settlement engine,
+/// and amounts its settle threshold would have settled are refunded to its balance.
+#[derive(Clone)]
+pub struct FeeService<S, O, A> {
+    policies: Arc<FeePolicies>,
+    fee_account: Option<A>,
+    store: S,
+    next: O,
+}
+
+impl<S, O, A> FeeService<S, O, A>
+where
+    S: AddressStore + BalanceStore + ExchangeRateStore + SettlementStore<Account = A>,
+    O: OutgoingService<A>,
+    A: Account,
+{
+    pub fn new(config: &FeeConfig, fee_account: Option<A>, store: S, next: O) -> Self {
+        FeeService {
+            policies: Arc::new(FeePolicies::new(config)),
+            fee_account,
+            store,
+            next,
+        }
+    }
+
+    /// Credits the fee, denominated in the asset of `from`, to the fee account. The fee is
+    /// converted with the same rates as the packets, preferring a direct rate of the pair.
+    async fn credit_fee(&self, from: &A, fee: u64) {
+        let fee_account = match self.fee_account {
+            Some(ref fee_account) => fee_account,
+            None => return,
+        };
+        let rates = if from.asset_code() == fee_account.asset_code() {
+            (ExchangeRate::one(), ExchangeRate::one())
+        } else if let Some(rates) =
+            pair_rates(&self.store, from.asset_code(), fee_account.asset_code())
+        {
+            rates
+        } else {
+            match self
+                .store
+                .get_exchange_rates(&[from.asset_code(), fee_account.asset_code()])
+            {
+                Ok(mut rates) => {
+                    let rate_fee_account = rates.pop().unwrap();
+                    (rates.pop().unwrap(), rate_fee_account)
+                }
+                Err(err) => {
+                    warn!(
+                        "Not crediting fee of {} {} to the fee account, no exchange rate: {}",
+                        fee,
+                        from.asset_code(),
+                        err
+                    );
+                    return;
+                }
+            }
+        };
+        let amount = match calculate_outgoing_amount(
+            fee,
+            &BigRational::zero(),
+            (&rates.0, &rates.1),
+            (from.asset_scale(), fee_account.asset_scale()),
+        ) {
+            Ok(amount) => amount,
+            Err(err) => {
+                warn!(
+                    "Not crediting fee of {} {} to the fee account, could not convert it: {:?}",
+                    fee,
+                    from.asset_code(),
+                    err
+                );
+                return;
+            }
+        };
+        if amount == 0 {
+            return;
+        }
+        let amount_to_settle = match self
+            .store
+            .update_balances_for_fulfill(fee_account.id(), amount)
+            .await
+        {
+            Ok((_, amount_to_settle)) => amount_to_settle,
+            Err(err) => {
+                error!(
+                    "Error crediting fee of {} to the fee account {}: {}",
+                    amount,
+                    fee_account.id(),
+                    err
+                );
+                return;
+            }
+        };
+        // The store deducts the amount to settle from the balance, which would lose
+        // the fees since the fee account is never settled
+        if amount_to_settle > 0 {
+            warn!(
+                "Fee account {} has a settle threshold, refunding the {} it would settle",
+                fee_account.id(),
+                amount_to_settle
+            );
+            if let Err(err) = self
+                .store
+                .refund_settlement(fee_account.id(), amount_to_settle)
+                .await
+            {
+                error!(
+                    "Error refunding {} to the fee account {}: {}",
+                    amount_to_settle,
+                    fee_account.id(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S, O, A> OutgoingService<A> for FeeService<S, O, A>
+where
+    S: AddressStore
+        + BalanceStore
+        + ExchangeRateStore
+        + SettlementStore<Account = A>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    O: OutgoingService<A> + Send + Sync + Clone + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    /// On send request:
+    /// 1. Finds the fee policy which applies to the packet, forwarding packets without one
+    /// 1. Rejects the packet if its amount does not cover more than the fee
+    /// 1. Deducts the fee from the packet amount and forwards it
+    /// 1. Credits the fee to the fee account if the packet is fulfilled
+    async fn send_request(&mut self, mut request: OutgoingRequest<A>) -> IlpResult {
+        let amount = request.prepare.amount();
+        let fee = match self.policies.policy_for(&request) {
+            Some(policy) if amount > 0 => policy.fee(amount),
+            _ => 0,
+        };
+        if fee == 0 {
+            return self.next.send_request(request).await;
+        }
+        if fee >= amount {
+            return Err(RejectBuilder {
+                code: ErrorCode::R01_INSUFFICIENT_SOURCE_AMOUNT,
+                message: format!(
+                    "Packet amount of {} does not cover the fee of {}",
+                    amount, fee
+                )
+                .as_bytes(),
+                triggered_by: Some(&self.store.get_ilp_address()),
+                data: &[],
+            }
+            .build());
+        }
+
+        trace!(
+            "Charging fee of {} {} on packet of {} from account {} to account {}",
+            fee,
+            request.from.asset_code(),
+            amount,
+            request.from.id(),
+            request.to.id()
+        );
+        request.prepare.set_amount(amount - fee);
+        let from = request.from.clone();
+        let fulfill = self.next.send_request(request).await?;
+        self.credit_fee(&from, fee).await;
+        Ok(fulfill)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_errors::{
+        AddressStoreError, BalanceStoreError, ExchangeRateStoreError, SettlementStoreError,
+    };
+    use interledger_packet::{Address, FulfillBuilder, PrepareBuilder};
+    use interledger_rates::PairRate;
+    use parking_lot::Mutex;
+    use std::time::{Duration, SystemTime};
+    use uuid::Uuid;
+
+    #[test]
+    fn calculates_fees() {
+        let policy = FeePolicy {
+            fixed: 1,
+            percentage: ExchangeRate::from_str("1/100").unwrap(),
+            minimum: 5,
+        };
+        assert_eq!(policy.fee(100), 5);
+        assert_eq!(policy.fee(1000), 11);
+        assert_eq!(policy.fee(1001), 12);
+        assert_eq!(policy.fee(3), 3);
+        assert_eq!(FeePolicy::default().fee(1000), 0);
+        let policy = FeePolicy {
+            fixed: u64::MAX,
+            percentage: ExchangeRate::from_f64(2.0).unwrap(),
+            minimum: 0,
+        };
+        assert_eq!(policy.fee(u64::MAX), u64::MAX);
+        // Amounts beyond the precision of an f64 are charged exactly
+        let policy = FeePolicy {
+            percentage: ExchangeRate::from_str("0.5").unwrap(),
+            ..FeePolicy::default()
+        };
+        assert_eq!(policy.fee(u64::MAX - 2), u64::MAX / 2);
+    }
+
+    #[test]
+    fn rejects_invalid_usernames_in_config() {
+        let config: FeeConfig = serde_json::from_str(
+            r#"{"incoming": {"alice": {"fixed": 1, "percentage": "1/3"}}, "outgoing": {"bob": {}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.incoming["alice"].percentage,
+            ExchangeRate::from_str("1/3").unwrap()
+        );
+        let mut usernames = config.usernames().unwrap();
+        usernames.sort();
+        assert_eq!(
+            usernames,
+            vec![
+                Username::from_str("alice").unwrap(),
+                Username::from_str("bob").unwrap()
+            ]
+        );
+        assert!(serde_json::from_str::<FeeConfig>(r#"{"outgoing": {"not valid!": {}}}"#).is_err());
+    }
+
+    #[test]
+    fn resolves_policies_in_order() {
+        let policies = FeePolicies::new(&test_config());
+        let fixed = |from: &TestAccount, to: &TestAccount, destination: &str| {
+            policies
+                .policy_for(&test_request(from, to, destination, 100))
+                .map(|policy| policy.fixed)
+        };
+        let (alice, bob, carol) = (
+            TestAccount::new("alice", "ABC", 0),
+            TestAccount::new("bob", "XYZ", 0),
+            TestAccount::new("carol", "XYZ", 0),
+        );
+        assert_eq!(fixed(&alice, &bob, "example.bob"), Some(1));
+        assert_eq!(fixed(&carol, &alice, "example.alice"), Some(2));
+        assert_eq!(fixed(&bob, &bob, "example.bob"), None);
+        let dave = TestAccount::new("dave", "ABC", 0);
+        assert_eq!(fixed(&dave, &bob, "example.route.deep.bob"), Some(4));
+        assert_eq!(fixed(&dave, &bob, "example.route.bob"), Some(3));
+        assert_eq!(fixed(&dave, &bob, "example.other"), Some(10));
+    }
+
+    #[tokio::test]
+    async fn deducts_fee_and_credits_fee_account() {
+        let store = TestStore::default();
+        let fee_account = TestAccount::new("fees", "XYZ", 2);
+        let (mut service, requests) = test_service(store.clone(), Some(fee_account.clone()), true);
+        let from = TestAccount::new("dave", "ABC", 0);
+        let to = TestAccount::new("bob", "XYZ", 0);
+        service
+            .send_request(test_request(&from, &to, "example.route.bob", 100))
+            .await
+            .unwrap();
+        assert_eq!(requests.lock()[0].prepare.amount(), 97);
+        // 3 ABC are worth 1.5 XYZ, or 150 in the fee account's scale
+        assert_eq!(*store.credited.lock(), vec![(fee_account.id, 150)]);
+    }
+
+    #[tokio::test]
+    async fn credits_fee_with_pair_rate() {
+        // DEF has no rate in USD, only a direct rate with ABC
+        let store = TestStore {
+            pair_rates: vec![(
+                "ABC/DEF".to_string(),
+                PairRate::new(
+                    ExchangeRate::from_f64(3.0).unwrap(),
+                    ExchangeRate::from_f64(4.0).unwrap(),
+                )
+                .unwrap(),
+            )]
+            .into_iter()
+            .collect(),
+            ..TestStore::default()
+        };
+        let fee_account = TestAccount::new("fees", "DEF", 2);
+        let (mut service, _) = test_service(store.clone(), Some(fee_account.clone()), true);
+        let from = TestAccount::new("dave", "ABC", 0);
+        let to = TestAccount::new("bob", "XYZ", 0);
+        service
+            .send_request(test_request(&from, &to, "example.route.bob", 100))
+            .await
+            .unwrap();
+        // 3 ABC are bought for 9 DEF, or 900 in the fee account's scale
+        assert_eq!(*store.credited.lock(), vec![(fee_account.id, 900)]);
+    }
+
+    #[tokio::test]
+    async fn refunds_what_the_fee_account_would_settle() {
+        let store = TestStore {
+            settle_over: Some(100),
+            ..TestStore::default()
+        };
+        let fee_account = TestAccount::new("fees", "XYZ", 2);
+        let (mut service, _) = test_service(store.clone(), Some(fee_account.clone()), true);
+        let from = TestAccount::new("dave", "ABC", 0);
+        let to = TestAccount::new("bob", "XYZ", 0);
+        service
+            .send_request(test_request(&from, &to, "example.route.bob", 100))
+            .await
+            .unwrap();
+        assert_eq!(*store.credited.lock(), vec![(fee_account.id, 150)]);
+        assert_eq!(*store.refunded.lock(), vec![(fee_account.id, 50)]);
+    }
+
+    #[tokio::test]
+    async fn does_not_credit_rejected_packets() {
+        let store = TestStore::default();
+        let fee_account = TestAccount::new("fees", "ABC", 0);
+        let (mut service, _) = test_service(store.clone(), Some(fee_account), false);
+        let from = TestAccount::new("dave", "ABC", 0);
+        let to = TestAccount::new("bob", "XYZ", 0);
+        let reject = service
+            .send_request(test_request(&from, &to, "example.route.bob", 100))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+        assert!(store.credited.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_packets_not_covering_fee() {
+        let store = TestStore::default();
+        let (mut service, requests) = test_service(store.clone(), None, true);
+        let from = TestAccount::new("dave", "ABC", 0);
+        let to = TestAccount::new("bob", "XYZ", 0);
+        let reject = service
+            .send_request(test_request(&from, &to, "example.other", 10))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::R01_INSUFFICIENT_SOURCE_AMOUNT);
+        assert_eq!(
+            reject.triggered_by().unwrap().to_string(),
+            "example.connector"
+        );
+        assert!(requests.lock().is_empty());
+
+        // Packets without amounts are forwarded as they are
+        service
+            .send_request(test_request(&from, &to, "example.other", 0))
+            .await
+            .unwrap();
+        assert_eq!(requests.lock()[0].prepare.amount(), 0);
+    }
+
+    fn test_config() -> FeeConfig {
+        let policy = |fixed| FeePolicy {
+            fixed,
+            ..FeePolicy::default()
+        };
+        FeeConfig {
+            default: Some(policy(10)),
+            incoming: vec![("alice".to_string(), policy(1))].into_iter().collect(),
+            outgoing: vec![("alice".to_string(), policy(2))].into_iter().collect(),
+            routes: vec![
+                ("example.route.".to_string(), policy(3)),
+                ("example.route.deep.".to_string(), policy(4)),
+            ]
+            .into_iter()
+            .collect(),
+            same_currency_free: true,
+            fee_account: None,
+        }
+    }
+
+    type ForwardedRequests = Arc<Mutex<Vec<OutgoingRequest<TestAccount>>>>;
+
+    fn test_service(
+        store: TestStore,
+        fee_account: Option<TestAccount>,
+        fulfill: bool,
+    ) -> (
+        FeeService<TestStore, impl OutgoingService<TestAccount> + Clone, TestAccount>,
+        ForwardedRequests,
+    ) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let next = outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+            requests_clone.lock().push(request);
+            if fulfill {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            } else {
+                Err(RejectBuilder {
+                    code: ErrorCode::F99_APPLICATION_ERROR,
+                    message: &[],
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build())
+            }
+        });
+        (
+            FeeService::new(&test_config(), fee_account, store, next),
+            requests,
+        )
+    }
+
+    fn test_request(
+        from: &TestAccount,
+        to: &TestAccount,
+        destination: &str,
+        amount: u64,
+    ) -> OutgoingRequest<TestAccount> {
+        OutgoingRequest {
+            from: from.clone(),
+            to: to.clone(),
+            original_amount: amount,
+            prepare: PrepareBuilder {
+                destination: Address::from_str(destination).unwrap(),
+                amount,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct TestAccount {
+        id: Uuid,
+        username: Username,
+        ilp_address: Address,
+        asset_code: String,
+        asset_scale: u8,
+    }
+
+    impl TestAccount {
+        fn new(username: &str, asset_code: &str, asset_scale: u8) -> Self {
+            TestAccount {
+                id: Uuid::new_v4(),
+                username: Username::from_str(username).unwrap(),
+                ilp_address: Address::from_str(&format!("example.{}", username)).unwrap(),
+                asset_code: asset_code.to_string(),
+                asset_scale,
+            }
+        }
+    }
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            self.id
+        }
+
+        fn username(&self) -> &Username {
+            &self.username
+        }
+
+        fn asset_code(&self) -> &str {
+            &self.asset_code
+        }
+
+        fn asset_scale(&self) -> u8 {
+            self.asset_scale
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &self.ilp_address
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct TestStore {
+        credited: Arc<Mutex<Vec<(Uuid, u64)>>>,
+        refunded: Arc<Mutex<Vec<(Uuid, u64)>>>,
+        /// Credits over this amount are settled, like with a settle threshold
+        settle_over: Option<u64>,
+        pair_rates: HashMap<String, PairRate>,
+    }
+
+    #[async_trait]
+    impl AddressStore for TestStore {
+        async fn set_ilp_address(&self, _: Address) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            Address::from_str("example.connector").unwrap()
+        }
+    }
+
+    #[async_trait]
+    impl BalanceStore for TestStore {
+        async fn get_balance(&self, _: Uuid) -> Result<i64, BalanceStoreError> {
+            Ok(0)
+        }
+
+        async fn update_balances_for_prepare(
+            &self,
+            _: Uuid,
+            _: u64,
+        ) -> Result<(), BalanceStoreError> {
+            Ok(())
+        }
+
+        async fn update_balances_for_fulfill(
+            &self,
+            account_id: Uuid,
+            amount: u64,
+        ) -> Result<(i64, u64), BalanceStoreError> {
+            self.credited.lock().push((account_id, amount));
+            let amount_to_settle = match self.settle_over {
+                Some(settle_over) => amount.saturating_sub(settle_over),
+                None => 0,
+            };
+            Ok(((amount - amount_to_settle) as i64, amount_to_settle))
+        }
+
+        async fn update_balances_for_reject(
+            &self,
+            _: Uuid,
+            _: u64,
+        ) -> Result<(), BalanceStoreError> {
+            Ok(())
+        }
+
+        async fn update_balances_for_delayed_settlement(
+            &self,
+            _: Uuid,
+        ) -> Result<(i64, u64), BalanceStoreError> {
+            Ok((0, 0))
+        }
+    }
+
+    #[async_trait]
+    impl SettlementStore for TestStore {
+        type Account = TestAccount;
+
+        async fn update_balance_for_incoming_settlement(
+            &self,
+            _: Uuid,
+            _: u64,
+            _: Option<String>,
+        ) -> Result<(), SettlementStoreError> {
+            Ok(())
+        }
+
+        async fn refund_settlement(
+            &self,
+            account_id: Uuid,
+            settle_amount: u64,
+        ) -> Result<(), SettlementStoreError> {
+            self.refunded.lock().push((account_id, settle_amount));
+            Ok(())
+        }
+    }
+
+    impl ExchangeRateStore for TestStore {
+        fn get_exchange_rates(
+            &self,
+            asset_codes: &[&str],
+        ) -> Result<Vec<ExchangeRate>, ExchangeRateStoreError> {
+            asset_codes
+                .iter()
+                .map(|code| match *code {
+                    "ABC" => Ok(ExchangeRate::from_f64(1.0).unwrap()),
+                    "XYZ" => Ok(ExchangeRate::from_f64(2.0).unwrap()),
+                    _ => Err(ExchangeRateStoreError::PairNotFound {
+                        from: asset_codes[0].to_string(),
+                        to: asset_codes[1].to_string(),
+                    }),
+                })
+                .collect()
+        }
+
+        fn set_exchange_rates(
+            &self,
+            _: HashMap<String, ExchangeRate>,
+        ) -> Result<(), ExchangeRateStoreError> {
+            Ok(())
+        }
+
+        fn get_all_exchange_rates(
+            &self,
+        ) -> Result<HashMap<String, ExchangeRate>, ExchangeRateStoreError> {
+            Ok(HashMap::new())
+        }
+
+        fn set_pair_rates(
+            &self,
+            _: HashMap<String, PairRate>,
+        ) -> Result<(), ExchangeRateStoreError> {
+            Ok(())
+        }
+
+        fn get_all_pair_rates(&self) -> Result<HashMap<String, PairRate>, ExchangeRateStoreError> {
+            Ok(self.pair_rates.clone())
+        }
+    }
+}
@@ -4,29 +4,43 @@
 
 /// Balance tracking service
 mod balance_service;
-/// Service which implements the echo protocol
+/// Service which stops forwarding packets to peers which keep failing, until they recover
+mod circuit_breaker_service;
+/// Service which implements the echo protocol, and the packets it handles
 mod echo_service;
 /// Service responsible for setting and fetching dollar denominated exchange rates
 mod exchange_rates_service;
 /// Service responsible for shortening the expiry time of packets,
 /// to take into account for network latency
 mod expiry_shortener_service;
+/// Service responsible for charging the configured fees on forwarded packets
+mod fee_service;
 /// Service responsible for capping the amount an account can send in a packet
 mod max_packet_amount_service;
 /// Service responsible for capping the amount of packets and amount in packets an account can send
 mod rate_limit_service;
+/// In-process token bucket limits, synced with the store periodically
+mod rate_limiter;
 /// Service responsible for checking that packets are not expired and that prepare packets' fulfillment conditions
 /// match the fulfillment inside the incoming fulfills
 mod validator_service;
 
 pub use self::balance_service::{start_delayed_settlement, BalanceService, BalanceStore};
-pub use self::echo_service::EchoService;
+pub use self::circuit_breaker_service::{
+    CircuitBreaker, CircuitBreakerConfig, CircuitBreakerService, CircuitState, CircuitStatus,
+};
+pub use self::echo_service::{
+    EchoPacket, EchoPacketError, EchoRequestBuilder, EchoResponseBuilder, EchoService,
+    PendingEchoes, UnidirectionalEchoRequestBuilder,
+};
 pub use self::exchange_rates_service::ExchangeRateService;
 pub use self::expiry_shortener_service::{
     ExpiryShortenerService, RoundTripTimeAccount, DEFAULT_ROUND_TRIP_TIME,
 };
+pub use self::fee_service::{FeeConfig, FeePolicy, FeeService};
 pub use self::max_packet_amount_service::{MaxPacketAmountAccount, MaxPacketAmountService};
 pub use self::rate_limit_service::{
-    RateLimitAccount, RateLimitError, RateLimitService, RateLimitStore,
+    RateLimitAccount, RateLimitError, RateLimitService, RateLimitStore, RemainingRateLimits,
 };
+pub use self::rate_limiter::{RateLimitConfig, RateLimiter, RateLimits};
 pub use self::validator_service::ValidatorService;
@@ -1,8 +1,10 @@
+use super::rate_limiter::RateLimiter;
 use async_trait::async_trait;
-use interledger_packet::{ErrorCode, RejectBuilder};
+use interledger_packet::{Address, ErrorCode, RateLimitedDetails, Reject, RejectBuilder};
 use interledger_service::{Account, AddressStore, IlpResult, IncomingRequest, IncomingService};
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::time::Duration;
 use tracing::{error, warn};
 
 /// Extension trait for [`Account`](../interledger_service/trait.Account.html) with rate limiting related information
@@ -12,10 +14,20 @@ pub trait RateLimitAccount: Account {
         None
     }
 
+    /// The maximum packets this account can send at once. Defaults to the packets per minute limit
+    fn packets_burst_limit(&self) -> Option<u32> {
+        None
+    }
+
     /// The maximum units per minute allowed for this account
     fn amount_per_minute_limit(&self) -> Option<u64> {
         None
     }
+
+    /// The maximum units this account can send at once. Defaults to the amount per minute limit
+    fn amount_burst_limit(&self) -> Option<u64> {
+        None
+    }
 }
 
 /// Rate limiting related errors
@@ -29,6 +41,15 @@ pub enum RateLimitError {
     StoreError,
 }
 
+/// What is left of the limits of an account in the store
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RemainingRateLimits {
+    /// Packets the account can still send, if it has a packet limit
+    pub packets: Option<u64>,
+    /// Amount the account can still send, if it has an amount limit
+    pub amount: Option<u64>,
+}
+
 /// Store trait which manages the rate limit related information of accounts
 #[async_trait]
 pub trait RateLimitStore {
@@ -52,6 +73,19 @@ pub trait RateLimitStore {
         account: Self::Account,
         prepare_amount: u64,
     ) -> Result<(), RateLimitError>;
+
+    /// Charges the packets and amount an account sent through a [`RateLimiter`](./struct.RateLimiter.html)
+    /// since the previous sync to its limits, and returns what is left of them.
+    /// The amount is negative if more was refunded than sent.
+    /// Stores which do not share the limits between nodes return `None`.
+    async fn sync_rate_limits(
+        &self,
+        _account: Self::Account,
+        _packets: u64,
+        _amount: i64,
+    ) -> Result<Option<RemainingRateLimits>, RateLimitError> {
+        Ok(None)
+    }
 }
 
 /// # Rate Limit Service
@@ -61,6 +95,10 @@ pub trait RateLimitStore {
 /// Talks with the associated Store in order to figure out
 /// and set the rate limits per account.
 /// This service does packet based limiting and amount based limiting.
+/// With a [`RateLimiter`](./struct.RateLimiter.html), the limits are applied in
+/// process and only synced with the store periodically.
+///
+/// Rejects tell the sender when to retry with [`RateLimitedDetails`](../interledger_packet/struct.RateLimitedDetails.html).
 ///
 /// Forwards everything else.
 /// Requires a `RateLimitAccount` and a `RateLimitStore`.
@@ -69,6 +107,7 @@ pub trait RateLimitStore {
 pub struct RateLimitService<S, I, A> {
     store: S,
     next: I, // Can we somehow omit the PhantomData
+    limiter: Option<RateLimiter<A>>,
     account_type: PhantomData<A>,
 }
 
@@ -82,9 +121,16 @@ where
         RateLimitService {
             store,
             next,
+            limiter: None,
             account_type: PhantomData,
         }
     }
+
+    /// Applies the limits with the in-process limiter instead of the store
+    pub fn with_limiter(mut self, limiter: RateLimiter<A>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
 }
 
 #[async_trait]
@@ -99,12 +145,27 @@ where
     /// 1. If no limits were hit forward the request
     ///     - If it succeeds, OK
     ///     - If the request forwarding failed, the client should not be charged towards their throughput limit, so they are refunded, and return a reject
-    /// 1. If the limit was hit, return a reject with the appropriate ErrorCode.
+    /// 1. If the limit was hit, return a reject with the appropriate ErrorCode and the time to retry after.
     async fn handle_request(&mut self, request: IncomingRequest<A>) -> IlpResult {
         let ilp_address = self.store.get_ilp_address();
         let account = request.from.clone();
         let account_clone = account.clone();
         let prepare_amount = request.prepare.amount();
+        if let Some(ref limiter) = self.limiter {
+            let destination = request.prepare.destination();
+            let prefix = limiter
+                .take(&account, &destination, prepare_amount)
+                .map_err(|(err, retry_after)| {
+                    rate_limited(&ilp_address, &account, err, Some(retry_after))
+                })?;
+            let packet = self.next.handle_request(request).await;
+            // Rejected packets do not count towards the amount limits
+            if packet.is_err() {
+                limiter.refund(&account, prefix.as_deref(), prepare_amount);
+            }
+            return packet;
+        }
+
         let has_throughput_limit = account.amount_per_minute_limit().is_some();
         // request.from and request.amount are used for apply_rate_limits, can't the previous service
         // always set the account to have None for both?
@@ -132,34 +193,67 @@ where
                 packet
             }
             Err(err) => {
-                let code = match err {
-                    RateLimitError::PacketLimitExceeded => {
-                        if let Some(limit) = account.packets_per_minute_limit() {
-                            warn!("Account {} was rate limited for sending too many packets. Limit is: {} per minute", account.id(), limit);
-                        }
-                        ErrorCode::T05_RATE_LIMITED
-                    }
-                    RateLimitError::ThroughputLimitExceeded => {
-                        if let Some(limit) = account.amount_per_minute_limit() {
-                            warn!("Account {} was throughput limited for trying to send too much money. Limit is: {} per minute", account.id(), limit);
-                        }
-                        ErrorCode::T04_INSUFFICIENT_LIQUIDITY
-                    }
-                    RateLimitError::StoreError => ErrorCode::T00_INTERNAL_ERROR,
-                };
-
-                let reject = RejectBuilder {
-                    code,
-                    triggered_by: Some(&ilp_address),
-                    message: &[],
-                    data: &[],
-                }
-                .build();
+                let retry_after = estimate_retry_after(&account, &err, prepare_amount);
+                Err(rate_limited(&ilp_address, &account, err, retry_after))
+            }
+        }
+    }
+}
 
-                Err(reject)
+/// The reject for a packet which exceeded a limit, telling the sender when to retry
+fn rate_limited<A: RateLimitAccount>(
+    ilp_address: &Address,
+    account: &A,
+    err: RateLimitError,
+    retry_after: Option<Duration>,
+) -> Reject {
+    let code = match err {
+        RateLimitError::PacketLimitExceeded => {
+            if let Some(limit) = account.packets_per_minute_limit() {
+                warn!("Account {} was rate limited for sending too many packets. Limit is: {} per minute", account.id(), limit);
+            }
+            ErrorCode::T05_RATE_LIMITED
+        }
+        RateLimitError::ThroughputLimitExceeded => {
+            if let Some(limit) = account.amount_per_minute_limit() {
+                warn!("Account {} was throughput limited for trying to send too much money. Limit is: {} per minute", account.id(), limit);
             }
+            ErrorCode::T04_INSUFFICIENT_LIQUIDITY
+        }
+        RateLimitError::StoreError => ErrorCode::T00_INTERNAL_ERROR,
+    };
+    let data = retry_after.map(|retry_after| RateLimitedDetails::new(retry_after).to_bytes());
+
+    RejectBuilder {
+        code,
+        triggered_by: Some(ilp_address),
+        message: &[],
+        data: data.as_ref().map(|data| &data[..]).unwrap_or(&[]),
+    }
+    .build()
+}
+
+/// The time the exceeded limit of the account takes to refill for the packet,
+/// since the store does not tell how much of it is left
+fn estimate_retry_after<A: RateLimitAccount>(
+    account: &A,
+    err: &RateLimitError,
+    prepare_amount: u64,
+) -> Option<Duration> {
+    let (needed, per_minute) = match err {
+        RateLimitError::PacketLimitExceeded => (1, u64::from(account.packets_per_minute_limit()?)),
+        RateLimitError::ThroughputLimitExceeded => {
+            (prepare_amount, account.amount_per_minute_limit()?)
         }
+        RateLimitError::StoreError => return None,
+    };
+    if per_minute == 0 {
+        return Some(Duration::from_millis(u64::MAX));
     }
+    let millis = u128::from(needed) * 60_000 / u128::from(per_minute);
+    Some(Duration::from_millis(
+        millis.min(u128::from(u64::MAX)) as u64
+    ))
 }
 
 #[cfg(test)]
@@ -174,6 +268,8 @@ mod tests {
     use std::sync::Arc;
     use uuid::Uuid;
 
+    static ID: Lazy<Uuid> = Lazy::new(Uuid::new_v4);
+
     #[tokio::test]
     async fn applies_rate_limit() {
         let next = incoming_service_fn(move |_| {
@@ -227,9 +323,60 @@ mod tests {
             .await
             .unwrap_err();
         assert_eq!(reject.code(), ErrorCode::T05_RATE_LIMITED);
+        // 100 packets per minute
+        assert_eq!(
+            RateLimitedDetails::from_bytes(reject.data())
+                .unwrap()
+                .retry_after(),
+            Duration::from_millis(600)
+        );
         assert_eq!(*store.was_refunded.read(), false);
     }
 
+    #[tokio::test]
+    async fn applies_in_process_limits() {
+        let next = incoming_service_fn(move |request| {
+            if request.prepare.amount() > 50 {
+                return Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build());
+            }
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"test data",
+            }
+            .build())
+        });
+        let store = TestStore::new(Err(RateLimitError::StoreError));
+        let mut service = RateLimitService::new(store.clone(), next)
+            .with_limiter(RateLimiter::new(&Default::default()));
+        let request = |amount| {
+            let mut request = TEST_REQUEST.clone();
+            request.prepare.set_amount(amount);
+            request
+        };
+
+        // The rejected packet is refunded, without going to the store
+        let reject = service.handle_request(request(100)).await.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F02_UNREACHABLE);
+        assert_eq!(*store.was_refunded.read(), false);
+        service.handle_request(request(50)).await.unwrap();
+        service.handle_request(request(50)).await.unwrap();
+
+        let reject = service.handle_request(request(1)).await.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T04_INSUFFICIENT_LIQUIDITY);
+        let retry_after = RateLimitedDetails::from_bytes(reject.data())
+            .unwrap()
+            .retry_after();
+        assert!(
+            retry_after > Duration::from_millis(500) && retry_after <= Duration::from_millis(600)
+        );
+    }
+
     #[tokio::test]
     async fn exceeded_throughput_limit() {
         let next = incoming_service_fn(move |_| {
@@ -277,7 +424,7 @@ mod tests {
 
     impl Account for TestAccount {
         fn id(&self) -> Uuid {
-            Uuid::new_v4()
+            *ID
         }
 
         fn username(&self) -> &Username {
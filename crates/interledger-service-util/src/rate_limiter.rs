@@ -0,0 +1,560 @@
+use super::rate_limit_service::{RateLimitAccount, RateLimitError, RateLimitStore};
+use interledger_packet::Address;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+const MINUTE_NANOS: u128 = 60_000_000_000;
+
+/// Token bucket limits on the packets and the amount sent by an account.
+///
+/// Each limit allows bursts of up to its burst size at once, and refills at its
+/// rate per minute. The burst defaults to the rate per minute.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct RateLimits {
+    /// Packets refilled per minute
+    #[serde(default)]
+    pub packets_per_minute: Option<u32>,
+    /// The most packets which can be sent at once
+    #[serde(default)]
+    pub packets_burst: Option<u32>,
+    /// Amount refilled per minute
+    #[serde(default)]
+    pub amount_per_minute: Option<u64>,
+    /// The largest amount which can be sent at once
+    #[serde(default)]
+    pub amount_burst: Option<u64>,
+}
+
+impl RateLimits {
+    /// The limits configured on the account
+    pub fn of_account<A: RateLimitAccount>(account: &A) -> Self {
+        RateLimits {
+            packets_per_minute: account.packets_per_minute_limit(),
+            packets_burst: account.packets_burst_limit(),
+            amount_per_minute: account.amount_per_minute_limit(),
+            amount_burst: account.amount_burst_limit(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.packets_per_minute.is_none() && self.amount_per_minute.is_none()
+    }
+}
+
+/// Configuration of the in-process [`RateLimiter`](./struct.RateLimiter.html)
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    /// Interval, in milliseconds, on which the limits of the accounts are synced with the store
+    #[serde(default = "RateLimitConfig::default_sync_interval")]
+    pub sync_interval: u64,
+    /// Limits on the packets each account sends to destinations starting with the
+    /// given ILP address prefixes, on top of the limits of the account. Only the
+    /// longest matching prefix applies.
+    #[serde(default)]
+    pub destinations: HashMap<String, RateLimits>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            sync_interval: Self::default_sync_interval(),
+            destinations: HashMap::new(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub(crate) fn default_sync_interval() -> u64 {
+        1000
+    }
+}
+
+/// Tokens which refill continuously, up to the capacity of the bucket
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    capacity: u64,
+    refill_per_minute: u64,
+    tokens: u64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    fn new(refill_per_minute: u64, capacity: u64, now: Instant) -> Self {
+        TokenBucket {
+            capacity,
+            refill_per_minute,
+            tokens: capacity,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if self.tokens >= self.capacity || self.refill_per_minute == 0 {
+            self.refilled_at = now;
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_nanos();
+        let refilled = elapsed * u128::from(self.refill_per_minute) / MINUTE_NANOS;
+        let missing = self.capacity - self.tokens;
+        if refilled >= u128::from(missing) {
+            self.tokens = self.capacity;
+            self.refilled_at = now;
+        } else if refilled > 0 {
+            self.tokens += refilled as u64;
+            // Only count the time it took to refill whole tokens, so that the
+            // progress towards the next token is not lost
+            let refill_time = refilled * MINUTE_NANOS / u128::from(self.refill_per_minute);
+            self.refilled_at += Duration::from_nanos(refill_time as u64);
+        }
+    }
+
+    /// How long until the bucket holds `amount` tokens, or until it is full if
+    /// `amount` is more than it can hold. The bucket must have been refilled at `now`.
+    fn wait_for(&self, amount: u64, now: Instant) -> Duration {
+        let missing = amount.min(self.capacity).saturating_sub(self.tokens);
+        if missing == 0 {
+            return Duration::from_secs(0);
+        }
+        if self.refill_per_minute == 0 {
+            return Duration::from_millis(u64::MAX);
+        }
+        let refill_per_minute = u128::from(self.refill_per_minute);
+        // Rounded up, so that the tokens are refilled after waiting
+        let refill_time = match u128::from(missing) * MINUTE_NANOS {
+            0 => 0,
+            missing => (missing - 1) / refill_per_minute + 1,
+        };
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_nanos();
+        let wait = refill_time.saturating_sub(elapsed);
+        if wait > u128::from(u64::MAX) {
+            Duration::from_millis(u64::MAX)
+        } else {
+            Duration::from_nanos(wait as u64)
+        }
+    }
+
+    fn give_back(&mut self, amount: u64) {
+        self.tokens = self.tokens.saturating_add(amount).min(self.capacity);
+    }
+}
+
+/// The buckets of one set of limits
+#[derive(Clone, Debug)]
+struct Buckets {
+    limits: RateLimits,
+    packets: Option<TokenBucket>,
+    amount: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(limits: RateLimits, now: Instant) -> Self {
+        Buckets {
+            limits,
+            packets: limits.packets_per_minute.map(|per_minute| {
+                let burst = limits.packets_burst.unwrap_or(per_minute);
+                TokenBucket::new(u64::from(per_minute), u64::from(burst), now)
+            }),
+            amount: limits.amount_per_minute.map(|per_minute| {
+                let burst = limits.amount_burst.unwrap_or(per_minute);
+                TokenBucket::new(per_minute, burst, now)
+            }),
+        }
+    }
+
+    /// Refills the buckets and returns how long to wait if they do not hold
+    /// one packet and the amount
+    fn check(&mut self, amount: u64, now: Instant) -> Result<(), (RateLimitError, Duration)> {
+        if let Some(ref mut packets) = self.packets {
+            packets.refill(now);
+            if packets.tokens < 1 {
+                return Err((
+                    RateLimitError::PacketLimitExceeded,
+                    packets.wait_for(1, now),
+                ));
+            }
+        }
+        if let Some(ref mut bucket) = self.amount {
+            bucket.refill(now);
+            if bucket.tokens < amount {
+                return Err((
+                    RateLimitError::ThroughputLimitExceeded,
+                    bucket.wait_for(amount, now),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes one packet and the amount, which must have been checked
+    fn take(&mut self, amount: u64) {
+        if let Some(ref mut packets) = self.packets {
+            packets.tokens -= 1;
+        }
+        if let Some(ref mut bucket) = self.amount {
+            bucket.tokens -= amount;
+        }
+    }
+
+    fn refund(&mut self, amount: u64) {
+        if let Some(ref mut bucket) = self.amount {
+            bucket.give_back(amount);
+        }
+    }
+}
+
+/// The buckets of an account and what it sent since the previous sync
+#[derive(Clone, Debug)]
+struct AccountBuckets<A> {
+    account: A,
+    buckets: Buckets,
+    unsynced_packets: u64,
+    unsynced_amount: i128,
+}
+
+struct Limiters<A> {
+    accounts: HashMap<Uuid, AccountBuckets<A>>,
+    destinations: HashMap<(Uuid, String), Buckets>,
+}
+
+/// Applies the rate limits of accounts in process, with token buckets, so that
+/// packets do not wait on the store.
+///
+/// What each account sent is charged to its limits in the store periodically,
+/// and the buckets are drained to what the store has left. This keeps the limits
+/// approximately consistent between nodes sharing the store. Limits per
+/// destination prefix are only applied in process.
+#[derive(Clone)]
+pub struct RateLimiter<A> {
+    destinations: Arc<Vec<(String, RateLimits)>>,
+    sync_interval: Duration,
+    limiters: Arc<Mutex<Limiters<A>>>,
+}
+
+impl<A> RateLimiter<A>
+where
+    A: RateLimitAccount + Send + Sync + 'static,
+{
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let mut destinations: Vec<(String, RateLimits)> = config
+            .destinations
+            .iter()
+            .map(|(prefix, limits)| (prefix.clone(), *limits))
+            .collect();
+        destinations.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        RateLimiter {
+            destinations: Arc::new(destinations),
+            sync_interval: Duration::from_millis(config.sync_interval),
+            limiters: Arc::new(Mutex::new(Limiters {
+                accounts: HashMap::new(),
+                destinations: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Takes a packet with the given amount from the limits of the account and of the
+    /// destination, returning the prefix whose limits applied. If any limit is exceeded,
+    /// nothing is taken and the time until the packet would be accepted is returned.
+    pub(crate) fn take(
+        &self,
+        account: &A,
+        destination: &Address,
+        amount: u64,
+    ) -> Result<Option<String>, (RateLimitError, Duration)> {
+        self.take_at(account, destination, amount, Instant::now())
+    }
+
+    fn take_at(
+        &self,
+        account: &A,
+        destination: &Address,
+        amount: u64,
+        now: Instant,
+    ) -> Result<Option<String>, (RateLimitError, Duration)> {
+        let mut limiters = self.limiters.lock();
+        let limiters = &mut *limiters;
+
+        let limits = RateLimits::of_account(account);
+        let account_buckets = if limits.is_empty() {
+            limiters.accounts.remove(&account.id());
+            None
+        } else {
+            let entry = limiters
+                .accounts
+                .entry(account.id())
+                .or_insert_with(|| AccountBuckets {
+                    account: account.clone(),
+                    buckets: Buckets::new(limits, now),
+                    unsynced_packets: 0,
+                    unsynced_amount: 0,
+                });
+            // The limits of the account were changed
+            if entry.buckets.limits != limits {
+                entry.buckets = Buckets::new(limits, now);
+            }
+            entry.account = account.clone();
+            entry.buckets.check(amount, now)?;
+            Some(entry)
+        };
+
+        let prefix = self
+            .destinations
+            .iter()
+            .find(|(prefix, _)| destination.starts_with(prefix.as_str()));
+        let destination_buckets = match prefix {
+            Some((prefix, limits)) => {
+                let buckets = limiters
+                    .destinations
+                    .entry((account.id(), prefix.clone()))
+                    .or_insert_with(|| Buckets::new(*limits, now));
+                buckets.check(amount, now)?;
+                Some(buckets)
+            }
+            None => None,
+        };
+
+        if let Some(entry) = account_buckets {
+            entry.buckets.take(amount);
+            entry.unsynced_packets += 1;
+            entry.unsynced_amount += i128::from(amount);
+        }
+        if let Some(buckets) = destination_buckets {
+            buckets.take(amount);
+        }
+        Ok(prefix.map(|(prefix, _)| prefix.clone()))
+    }
+
+    /// Gives back the amount of a packet which was rejected, so that rejected
+    /// packets do not count towards the amount limits
+    pub(crate) fn refund(&self, account: &A, prefix: Option<&str>, amount: u64) {
+        let mut limiters = self.limiters.lock();
+        if let Some(entry) = limiters.accounts.get_mut(&account.id()) {
+            entry.buckets.refund(amount);
+            entry.unsynced_amount -= i128::from(amount);
+        }
+        if let Some(prefix) = prefix {
+            if let Some(buckets) = limiters
+                .destinations
+                .get_mut(&(account.id(), prefix.to_string()))
+            {
+                buckets.refund(amount);
+            }
+        }
+    }
+
+    /// Charges what each account sent since the previous sync to its limits in the
+    /// store, and drains the buckets of the account to what the store has left
+    pub async fn sync<S>(&self, store: &S)
+    where
+        S: RateLimitStore<Account = A> + Sync,
+    {
+        let unsynced: Vec<(A, u64, i128)> = self
+            .limiters
+            .lock()
+            .accounts
+            .values_mut()
+            .map(|entry| {
+                let unsynced = (
+                    entry.account.clone(),
+                    entry.unsynced_packets,
+                    entry.unsynced_amount,
+                );
+                entry.unsynced_packets = 0;
+                entry.unsynced_amount = 0;
+                unsynced
+            })
+            .collect();
+
+        for (account, packets, amount) in unsynced {
+            let id = account.id();
+            let amount = amount.max(i64::MIN.into()).min(i64::MAX.into()) as i64;
+            let remaining = match store.sync_rate_limits(account, packets, amount).await {
+                Ok(Some(remaining)) => remaining,
+                Ok(None) => continue,
+                Err(err) => {
+                    error!("Error syncing rate limits of account {}: {:?}", id, err);
+                    continue;
+                }
+            };
+            if let Some(entry) = self.limiters.lock().accounts.get_mut(&id) {
+                if let (Some(bucket), Some(remaining)) =
+                    (entry.buckets.packets.as_mut(), remaining.packets)
+                {
+                    bucket.tokens = bucket.tokens.min(remaining);
+                }
+                if let (Some(bucket), Some(remaining)) =
+                    (entry.buckets.amount.as_mut(), remaining.amount)
+                {
+                    bucket.tokens = bucket.tokens.min(remaining);
+                }
+            }
+        }
+    }
+
+    /// Syncs the limits with the store on the configured interval
+    pub fn spawn_sync<S>(self, store: S)
+    where
+        S: RateLimitStore<Account = A> + Send + Sync + 'static,
+    {
+        debug!(
+            "Starting interval to sync rate limits with the store every {:?}",
+            self.sync_interval
+        );
+        let interval = async move {
+            let mut interval = tokio::time::interval(self.sync_interval);
+            loop {
+                interval.tick().await;
+                self.sync(&store).await;
+            }
+        };
+        tokio::spawn(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_service::{Account, Username};
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+
+    static ID: Lazy<Uuid> = Lazy::new(Uuid::new_v4);
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static EXAMPLE_ADDRESS: Lazy<Address> =
+        Lazy::new(|| Address::from_str("example.alice").unwrap());
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(RateLimits);
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            *ID
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &EXAMPLE_ADDRESS
+        }
+    }
+
+    impl RateLimitAccount for TestAccount {
+        fn packets_per_minute_limit(&self) -> Option<u32> {
+            self.0.packets_per_minute
+        }
+
+        fn packets_burst_limit(&self) -> Option<u32> {
+            self.0.packets_burst
+        }
+
+        fn amount_per_minute_limit(&self) -> Option<u64> {
+            self.0.amount_per_minute
+        }
+
+        fn amount_burst_limit(&self) -> Option<u64> {
+            self.0.amount_burst
+        }
+    }
+
+    fn address(address: &str) -> Address {
+        Address::from_str(address).unwrap()
+    }
+
+    #[test]
+    fn allows_bursts_and_refills() {
+        let limiter = RateLimiter::new(&RateLimitConfig::default());
+        let account = TestAccount(RateLimits {
+            packets_per_minute: Some(60),
+            packets_burst: Some(3),
+            ..Default::default()
+        });
+        let destination = address("example.bob");
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.take_at(&account, &destination, 100, start).is_ok());
+        }
+        assert_eq!(
+            limiter.take_at(&account, &destination, 100, start),
+            Err((RateLimitError::PacketLimitExceeded, Duration::from_secs(1)))
+        );
+        // One packet is refilled per second
+        let later = start + Duration::from_millis(1500);
+        assert!(limiter.take_at(&account, &destination, 100, later).is_ok());
+        assert_eq!(
+            limiter.take_at(&account, &destination, 100, later),
+            Err((
+                RateLimitError::PacketLimitExceeded,
+                Duration::from_millis(500)
+            ))
+        );
+    }
+
+    #[test]
+    fn limits_amount_and_refunds() {
+        let limiter = RateLimiter::new(&RateLimitConfig::default());
+        let account = TestAccount(RateLimits {
+            amount_per_minute: Some(600),
+            ..Default::default()
+        });
+        let destination = address("example.bob");
+        let now = Instant::now();
+        assert!(limiter.take_at(&account, &destination, 500, now).is_ok());
+        assert_eq!(
+            limiter.take_at(&account, &destination, 200, now),
+            Err((
+                RateLimitError::ThroughputLimitExceeded,
+                Duration::from_secs(10)
+            ))
+        );
+        limiter.refund(&account, None, 500);
+        assert!(limiter.take_at(&account, &destination, 600, now).is_ok());
+    }
+
+    #[test]
+    fn applies_longest_destination_prefix() {
+        let mut destinations = HashMap::new();
+        let one_packet = |packets_per_minute| RateLimits {
+            packets_per_minute: Some(packets_per_minute),
+            ..Default::default()
+        };
+        destinations.insert("example.".to_string(), one_packet(2));
+        destinations.insert("example.bob.".to_string(), one_packet(1));
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            destinations,
+            ..Default::default()
+        });
+        let account = TestAccount(RateLimits::default());
+        let now = Instant::now();
+
+        let bob = address("example.bob.1");
+        assert_eq!(
+            limiter.take_at(&account, &bob, 1, now),
+            Ok(Some("example.bob.".to_string()))
+        );
+        assert!(limiter.take_at(&account, &bob, 1, now).is_err());
+        let charlie = address("example.charlie");
+        assert_eq!(
+            limiter.take_at(&account, &charlie, 1, now),
+            Ok(Some("example.".to_string()))
+        );
+        assert_eq!(
+            limiter.take_at(&account, &address("test.bob"), 1, now),
+            Ok(None)
+        );
+    }
+}
@@ -8,7 +8,12 @@ pub mod backends_common;
 pub mod engines_api;
 
 mod settlement_client;
-pub use settlement_client::SettlementClient;
+pub use settlement_client::{SettlementClient, SettlementClientError};
+
+#[cfg(unix)]
+mod uds;
+#[cfg(unix)]
+pub use uds::HTTP_UNIX_SCHEME;
 
 /// Expose useful utilities for implementing idempotent functionalities
 pub mod idempotency;
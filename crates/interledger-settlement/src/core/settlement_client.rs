@@ -1,13 +1,53 @@
 use crate::core::types::Quantity;
+#[cfg(unix)]
+use crate::core::uds::{split_unix_url, UnixConnector};
 use futures_retry::{ErrorHandler, FutureRetry, RetryPolicy};
-use reqwest::Client;
+use http::StatusCode;
+use reqwest::{Client, RequestBuilder};
 use serde_json::json;
 use std::time::Duration;
+use thiserror::Error;
 use tracing::{debug, trace};
 use url::Url;
 use uuid::Uuid;
 
-type Response = Result<reqwest::Response, reqwest::Error>;
+type Response = Result<reqwest::Response, SettlementClientError>;
+
+/// Errors which may occur while talking to a settlement engine
+#[derive(Error, Debug)]
+pub enum SettlementClientError {
+    /// The request to the engine failed or the engine responded with an error status
+    #[error("{0}")]
+    Http(#[from] reqwest::Error),
+    /// The request to an engine listening on a Unix domain socket failed
+    #[error("{0}")]
+    Unix(#[from] hyper::Error),
+    /// The engine did not respond in time
+    #[error("request to settlement engine timed out")]
+    Timeout,
+    /// The request to the engine could not be built
+    #[error("invalid request to settlement engine: {0}")]
+    InvalidRequest(String),
+}
+
+impl SettlementClientError {
+    /// Returns true if the error was caused by the request timing out
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            SettlementClientError::Http(err) => err.is_timeout(),
+            SettlementClientError::Timeout => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the HTTP status code of the engine's response, if there was one
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            SettlementClientError::Http(err) => err.status(),
+            _ => None,
+        }
+    }
+}
 
 // The account creation endpoint set by the engines in the [RFC](https://github.com/interledger/rfcs/pull/536)
 static ACCOUNTS_ENDPOINT: &str = "accounts";
@@ -19,6 +59,7 @@ const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_millis(5000);
 pub struct SettlementClient {
     /// Asynchronous reqwest client
     client: Client,
+    timeout: Duration,
     max_retries: usize,
 }
 
@@ -27,6 +68,7 @@ impl SettlementClient {
     pub fn new(timeout: Duration, max_retries: usize) -> Self {
         SettlementClient {
             client: Client::builder().timeout(timeout).build().unwrap(),
+            timeout,
             max_retries,
         }
     }
@@ -65,13 +107,13 @@ impl SettlementClient {
             .push(&id.to_string())
             .push("messages");
         let idempotency_uuid = uuid::Uuid::new_v4().to_hyphenated().to_string();
-        self.client
+        let request = self
+            .client
             .post(settlement_engine_url.as_ref())
             .header("Content-Type", "application/octet-stream")
             .header("Idempotency-Key", idempotency_uuid.clone())
-            .body(message.clone())
-            .send()
-            .await
+            .body(message.clone());
+        self.send(&settlement_engine_url, request).await
     }
 
     /// Sends an idempotent settlement request to the engine (will retry if it fails)
@@ -104,12 +146,11 @@ impl SettlementClient {
             se_url.clone()
         );
 
-        Ok(self
+        let request = self
             .client
             .post(se_url.as_ref())
-            .json(&json!({ "id": id.to_string() }))
-            .send()
-            .await?)
+            .json(&json!({ "id": id.to_string() }));
+        self.send(&se_url, request).await
     }
 
     pub async fn send_settlement_once(
@@ -137,16 +178,68 @@ impl SettlementClient {
         let idempotency_uuid = Uuid::new_v4().to_hyphenated().to_string();
 
         // Make the POST request future
-        let response = self
+        let request = self
             .client
             .post(settlement_engine_url.as_ref())
             .header("Idempotency-Key", idempotency_uuid)
-            .json(&json!(Quantity::new(amount, asset_scale)))
-            .send()
-            .await?;
+            .json(&json!(Quantity::new(amount, asset_scale)));
+        let response = self.send(&settlement_engine_url, request).await?;
 
         Ok(response.error_for_status()?)
     }
+
+    /// Sends the request to the engine, either over TCP or over the Unix domain socket
+    /// given in the `http+unix` engine URL
+    async fn send(&self, engine_url: &Url, request: RequestBuilder) -> Response {
+        #[cfg(unix)]
+        {
+            if let Some((socket_path, uri)) = split_unix_url(engine_url) {
+                return self.send_unix(socket_path, uri, request).await;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = engine_url;
+
+        Ok(request.send().await?)
+    }
+
+    #[cfg(unix)]
+    async fn send_unix(
+        &self,
+        socket_path: std::path::PathBuf,
+        uri: hyper::Uri,
+        request: RequestBuilder,
+    ) -> Response {
+        // The request is built with reqwest so that both transports share the same code
+        // for headers and bodies, but reqwest cannot dial Unix domain sockets itself
+        let request = request.build()?;
+        let mut builder = http::Request::builder().method(request.method()).uri(uri);
+        for (name, value) in request.headers() {
+            builder = builder.header(name, value);
+        }
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(|bytes| hyper::Body::from(bytes.to_vec()))
+            .unwrap_or_else(hyper::Body::empty);
+        let request = builder
+            .body(body)
+            .map_err(|err| SettlementClientError::InvalidRequest(err.to_string()))?;
+
+        let client =
+            hyper::Client::builder().build::<_, hyper::Body>(UnixConnector::new(socket_path));
+        let exchange = async {
+            let response = client.request(request).await?;
+            let (parts, body) = response.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            Ok::<_, hyper::Error>(http::Response::from_parts(parts, body))
+        };
+        let response = tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| SettlementClientError::Timeout)??;
+
+        Ok(reqwest::Response::from(response))
+    }
 }
 
 struct RequestErrorHandler {
@@ -163,11 +256,11 @@ impl RequestErrorHandler {
     }
 }
 
-impl ErrorHandler<reqwest::Error> for RequestErrorHandler {
-    type OutError = reqwest::Error;
+impl ErrorHandler<SettlementClientError> for RequestErrorHandler {
+    type OutError = SettlementClientError;
 
     /// Handler of errors for the retry logic
-    fn handle(&mut self, e: reqwest::Error) -> RetryPolicy<reqwest::Error> {
+    fn handle(&mut self, e: SettlementClientError) -> RetryPolicy<SettlementClientError> {
         self.current_attempt += 1;
         if self.current_attempt > self.max_attempts {
             return RetryPolicy::ForwardError(e);
@@ -256,4 +349,39 @@ mod tests {
         m.assert();
         assert!(ret.is_err());
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn settlement_over_unix_socket() {
+        use warp::Filter;
+
+        let socket_path =
+            std::env::temp_dir().join(format!("settlement-engine-{}.sock", Uuid::new_v4()));
+        let mut listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+        let engine = warp::post()
+            .and(warp::path!("accounts" / Uuid / "settlements"))
+            .and(warp::header::<String>("Idempotency-Key"))
+            .and(warp::body::json())
+            .map(|_id: Uuid, _key: String, quantity: Quantity| {
+                assert_eq!(quantity, Quantity::new(100, 6));
+                warp::reply::json(&quantity)
+            });
+        tokio::spawn(async move { warp::serve(engine).run_incoming(listener.incoming()).await });
+
+        let engine_url = Url::parse(&format!(
+            "http+unix://{}",
+            percent_encoding::utf8_percent_encode(
+                socket_path.to_str().unwrap(),
+                percent_encoding::NON_ALPHANUMERIC
+            )
+        ))
+        .unwrap();
+        let client = SettlementClient::default();
+        let ret = client
+            .send_settlement(Uuid::new_v4(), engine_url, 100, 6)
+            .await;
+
+        let _ = std::fs::remove_file(&socket_path);
+        assert_eq!(ret.unwrap().status(), StatusCode::OK);
+    }
 }
@@ -0,0 +1,145 @@
+use futures::future::BoxFuture;
+use hyper::{
+    client::connect::{Connected, Connection},
+    service::Service,
+    Uri,
+};
+use std::{
+    io,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::UnixStream,
+};
+use url::Url;
+
+/// URL scheme used for settlement engines listening on a Unix domain socket.
+///
+/// The socket path is given percent-encoded in place of the host, for example
+/// `http+unix://%2Fvar%2Frun%2Fengine.sock/` for the socket at `/var/run/engine.sock`.
+pub const HTTP_UNIX_SCHEME: &str = "http+unix";
+
+/// Returns the socket path and the HTTP request URI for a `http+unix` URL, or `None`
+/// if the URL does not point to a Unix domain socket.
+pub(crate) fn split_unix_url(url: &Url) -> Option<(PathBuf, Uri)> {
+    if url.scheme() != HTTP_UNIX_SCHEME {
+        return None;
+    }
+    let host = url.host_str()?;
+    let socket_path = percent_encoding::percent_decode_str(host)
+        .decode_utf8()
+        .ok()?
+        .into_owned();
+    if socket_path.is_empty() {
+        return None;
+    }
+    let path_and_query = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    // The authority is only used for the Host header and connection pooling,
+    // the connector always dials the socket it was created with
+    let uri = format!("http://localhost{}", path_and_query).parse().ok()?;
+    Some((PathBuf::from(socket_path), uri))
+}
+
+/// Connector which makes `hyper` open its connections to a single Unix domain socket
+#[derive(Clone, Debug)]
+pub(crate) struct UnixConnector {
+    socket_path: PathBuf,
+}
+
+impl UnixConnector {
+    pub(crate) fn new(socket_path: PathBuf) -> Self {
+        UnixConnector { socket_path }
+    }
+}
+
+impl Service<Uri> for UnixConnector {
+    type Response = UnixConnection;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let socket_path = self.socket_path.clone();
+        Box::pin(async move {
+            let stream = UnixStream::connect(socket_path).await?;
+            Ok(UnixConnection(stream))
+        })
+    }
+}
+
+/// Wrapper around a `UnixStream` so that it can be used as a `hyper` connection
+pub(crate) struct UnixConnection(UnixStream);
+
+impl Connection for UnixConnection {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for UnixConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_unix_url() {
+        let url: Url = "http+unix://%2Ftmp%2Fengine.sock/accounts/1/settlements"
+            .parse()
+            .unwrap();
+        let (path, uri) = split_unix_url(&url).unwrap();
+        assert_eq!(path, PathBuf::from("/tmp/engine.sock"));
+        assert_eq!(uri.path(), "/accounts/1/settlements");
+        assert_eq!(uri.authority().unwrap().as_str(), "localhost");
+    }
+
+    #[test]
+    fn pushing_segments_keeps_socket_path() {
+        let mut url: Url = "http+unix://%2Ftmp%2Fengine.sock".parse().unwrap();
+        url.path_segments_mut().unwrap().push("accounts");
+        let (path, uri) = split_unix_url(&url).unwrap();
+        assert_eq!(path, PathBuf::from("/tmp/engine.sock"));
+        assert_eq!(uri.path(), "/accounts");
+    }
+
+    #[test]
+    fn ignores_other_schemes() {
+        let url: Url = "http://localhost:3000".parse().unwrap();
+        assert!(split_unix_url(&url).is_none());
+    }
+}
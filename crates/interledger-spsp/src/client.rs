@@ -2,7 +2,10 @@ use super::{Error, SpspResponse};
 use futures::TryFutureExt;
 use interledger_rates::ExchangeRateStore;
 use interledger_service::{Account, IncomingService};
-use interledger_stream::{send_money, StreamDelivery};
+use interledger_stream::{
+    send_money_fixed_delivery, send_money_with_options, PaymentAmount, PaymentHandle,
+    SendMoneyOptions, StreamDelivery, StreamQuote,
+};
 use reqwest::Client;
 use tracing::{debug, error, trace};
 
@@ -39,6 +42,33 @@ pub async fn pay<I, A, S>(
     source_amount: u64,
     slippage: f64,
 ) -> Result<StreamDelivery, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    pay_with_options(
+        service,
+        from_account,
+        store,
+        receiver,
+        source_amount,
+        slippage,
+        SendMoneyOptions::default(),
+    )
+    .await
+}
+
+/// Like [`pay`](./fn.pay.html), with the given options for sending the payment
+pub async fn pay_with_options<I, A, S>(
+    service: I,
+    from_account: A,
+    store: S,
+    receiver: &str,
+    source_amount: u64,
+    slippage: f64,
+    options: SendMoneyOptions,
+) -> Result<StreamDelivery, Error>
 where
     I: IncomingService<A> + Clone + Send + Sync + 'static,
     A: Account + Send + Sync + 'static,
@@ -49,7 +79,7 @@ where
     let addr = spsp.destination_account;
     debug!("Sending SPSP payment to address: {}", addr);
 
-    let receipt = send_money(
+    let receipt = send_money_with_options(
         service,
         &from_account,
         store,
@@ -57,6 +87,7 @@ where
         shared_secret,
         source_amount,
         slippage,
+        options,
     )
     .map_err(move |err| {
         error!("Error sending payment: {:?}", err);
@@ -68,6 +99,117 @@ where
     Ok(receipt)
 }
 
+/// Query the details of the given Payment Pointer and send a payment using the STREAM protocol
+/// which delivers exactly the given amount, in the receiver's asset's units.
+///
+/// The payment fails without sending more than the destination amount is worth at our
+/// exchange rate minus the slippage.
+pub async fn pay_fixed_delivery<I, A, S>(
+    service: I,
+    from_account: A,
+    store: S,
+    receiver: &str,
+    destination_amount: u64,
+    slippage: f64,
+    options: SendMoneyOptions,
+) -> Result<StreamDelivery, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    let spsp = query(receiver).await?;
+    let shared_secret = spsp.shared_secret;
+    let addr = spsp.destination_account;
+    debug!("Sending fixed-delivery SPSP payment to address: {}", addr);
+
+    let receipt = send_money_fixed_delivery(
+        service,
+        &from_account,
+        store,
+        addr,
+        shared_secret,
+        destination_amount,
+        slippage,
+        options,
+    )
+    .map_err(move |err| {
+        error!("Error sending payment: {:?}", err);
+        Error::StreamError(err)
+    })
+    .await?;
+
+    debug!("Sent SPSP payment. StreamDelivery: {:?}", receipt);
+    Ok(receipt)
+}
+
+/// Query the details of the given Payment Pointer and start sending a payment of the given
+/// amount in the background using the STREAM protocol.
+///
+/// The returned handle reports the progress of the payment and can cancel it.
+pub async fn start_payment<I, A, S>(
+    service: I,
+    from_account: A,
+    store: S,
+    receiver: &str,
+    amount: PaymentAmount,
+    slippage: f64,
+    options: SendMoneyOptions,
+) -> Result<PaymentHandle, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    let spsp = query(receiver).await?;
+    let addr = spsp.destination_account;
+    debug!("Starting SPSP payment to address: {}", addr);
+
+    Ok(interledger_stream::start_payment(
+        service,
+        &from_account,
+        store,
+        addr,
+        spsp.shared_secret,
+        amount,
+        slippage,
+        options,
+    ))
+}
+
+/// Query the details of the given Payment Pointer and quote a payment of the given source amount
+/// by probing the exchange rate with unfulfillable STREAM packets. No money is sent.
+pub async fn quote<I, A>(
+    service: I,
+    from_account: A,
+    receiver: &str,
+    source_amount: u64,
+) -> Result<StreamQuote, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    let spsp = query(receiver).await?;
+    let addr = spsp.destination_account;
+    debug!("Quoting SPSP payment to address: {}", addr);
+
+    let quote = interledger_stream::quote(
+        service,
+        &from_account,
+        addr,
+        spsp.shared_secret,
+        source_amount,
+    )
+    .map_err(move |err| {
+        error!("Error quoting payment: {:?}", err);
+        Error::StreamError(err)
+    })
+    .await?;
+
+    debug!("Quoted SPSP payment: {:?}", quote);
+    Ok(quote)
+}
+
 fn payment_pointer_to_url(payment_pointer: &str) -> String {
     let mut url: String = if let Some(suffix) = payment_pointer.strip_prefix("$") {
         let prefix = "https://";
@@ -14,8 +14,8 @@ mod client;
 /// An SPSP Server implementing an HTTP Service which generates ILP Addresses and Shared Secrets
 mod server;
 
-pub use client::{pay, query};
-pub use server::SpspResponder;
+pub use client::{pay, pay_fixed_delivery, pay_with_options, query, quote, start_payment};
+pub use server::{accepts_spsp, receipt_details_from_headers, SpspResponder};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -31,6 +31,10 @@ pub enum Error {
     ListenError(String),
     #[error("Invalid Payment Pointer: {0}")]
     InvalidPaymentPointerError(String),
+    #[error("Invalid receipt details: {0}")]
+    InvalidReceiptDetails(String),
+    #[error("Invalid connection tag: {0}")]
+    InvalidConnectionTag(String),
 }
 
 /// An SPSP Response returned by the SPSP server
@@ -42,6 +46,43 @@ pub struct SpspResponse {
     /// to be consumed for the STREAM connection
     #[serde(with = "serde_base64")]
     shared_secret: Vec<u8>,
+    /// Whether the receiver signs STREAM receipts for this connection
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    receipts_enabled: bool,
+    /// The asset of the receiving account, if the server shares it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    asset: Option<SpspAsset>,
+}
+
+/// The asset the receiver of an SPSP payment is denominated in
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SpspAsset {
+    /// Currency code, such as `USD`
+    pub code: String,
+    /// Number of decimal places of the amounts
+    pub scale: u8,
+}
+
+impl SpspResponse {
+    /// The ILP address to send the STREAM packets to
+    pub fn destination_account(&self) -> &Address {
+        &self.destination_account
+    }
+
+    /// The shared secret of the STREAM connection
+    pub fn shared_secret(&self) -> &[u8] {
+        &self.shared_secret
+    }
+
+    /// Whether the receiver signs STREAM receipts for the connection
+    pub fn receipts_enabled(&self) -> bool {
+        self.receipts_enabled
+    }
+
+    /// The asset of the receiving account, if the server shared it
+    pub fn asset(&self) -> Option<&SpspAsset> {
+        self.asset.as_ref()
+    }
 }
 
 // From https://github.com/serde-rs/json/issues/360#issuecomment-330095360
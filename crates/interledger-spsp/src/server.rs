@@ -1,8 +1,13 @@
-use super::SpspResponse;
+use super::{Error as SpspError, SpspAsset, SpspResponse};
 use bytes::Bytes;
-use hyper::{service::Service as HttpService, Body, Error, Request, Response};
+use hyper::{
+    header::{HeaderMap, ACCEPT},
+    service::Service as HttpService,
+    Body, Error, Request, Response,
+};
 use interledger_packet::Address;
-use interledger_stream::ConnectionGenerator;
+use interledger_stream::{ConnectionGenerator, ReceiptDetails};
+use std::convert::TryInto;
 use std::error::Error as StdError;
 use std::{
     fmt, str,
@@ -16,6 +21,7 @@ use tracing::debug;
 pub struct SpspResponder {
     ilp_address: Address,
     connection_generator: ConnectionGenerator,
+    asset: Option<SpspAsset>,
 }
 
 impl SpspResponder {
@@ -25,16 +31,73 @@ impl SpspResponder {
         SpspResponder {
             ilp_address,
             connection_generator,
+            asset: None,
         }
     }
 
+    /// Includes the asset code and scale of the receiving account in the responses
+    pub fn with_asset(mut self, code: String, scale: u8) -> Self {
+        self.asset = Some(SpspAsset { code, scale });
+        self
+    }
+
     /// Returns an HTTP Response containing the destination account
     /// and shared secret for this connection
     /// These fields are generated via [Stream's `ConnectionGenerator`](../interledger_stream/struct.ConnectionGenerator.html#method.generate_address_and_secret)
     pub fn generate_http_response(&self) -> Response<Body> {
+        self.generate_http_response_with_receipts(None)
+    }
+
+    /// Returns an HTTP Response like `generate_http_response`, for a connection whose
+    /// receiver signs [STREAM receipts](https://interledger.org/rfcs/0039-stream-receipts/)
+    /// with the given details
+    pub fn generate_http_response_with_receipts(
+        &self,
+        receipt_details: Option<&ReceiptDetails>,
+    ) -> Response<Body> {
+        let (destination_account, shared_secret) = match receipt_details {
+            Some(receipt_details) => self
+                .connection_generator
+                .generate_address_and_secret_with_receipts(&self.ilp_address, receipt_details),
+            None => self
+                .connection_generator
+                .generate_address_and_secret(&self.ilp_address),
+        };
+        self.http_response(
+            destination_account,
+            shared_secret,
+            receipt_details.is_some(),
+        )
+    }
+
+    /// Returns an HTTP Response like `generate_http_response_with_receipts`, for a connection
+    /// identified by the given [connection tag](../interledger_stream/struct.ConnectionGenerator.html#method.generate_address_and_secret_with_tag)
+    pub fn generate_http_response_with_tag(
+        &self,
+        connection_tag: &str,
+        receipt_details: Option<&ReceiptDetails>,
+    ) -> Result<Response<Body>, SpspError> {
         let (destination_account, shared_secret) = self
             .connection_generator
-            .generate_address_and_secret(&self.ilp_address);
+            .generate_address_and_secret_with_tag(
+                &self.ilp_address,
+                connection_tag,
+                receipt_details,
+            )
+            .map_err(|_| SpspError::InvalidConnectionTag(connection_tag.to_string()))?;
+        Ok(self.http_response(
+            destination_account,
+            shared_secret,
+            receipt_details.is_some(),
+        ))
+    }
+
+    fn http_response(
+        &self,
+        destination_account: Address,
+        shared_secret: [u8; 32],
+        receipts_enabled: bool,
+    ) -> Response<Body> {
         debug!(
             "Generated address and secret for: {:?}",
             destination_account
@@ -42,6 +105,8 @@ impl SpspResponder {
         let response = SpspResponse {
             destination_account,
             shared_secret: shared_secret.to_vec(),
+            receipts_enabled,
+            asset: self.asset.clone(),
         };
 
         Response::builder()
@@ -62,8 +127,80 @@ impl HttpService<Request<Body>> for SpspResponder {
         Ok(()).into()
     }
 
-    fn call(&mut self, _request: Request<Body>) -> Self::Future {
-        futures::future::ok(self.generate_http_response())
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        if !accepts_spsp(request.headers()) {
+            return futures::future::ok(
+                Response::builder()
+                    .status(406)
+                    .body(Body::from(
+                        "Only application/spsp4+json responses are served",
+                    ))
+                    .unwrap(),
+            );
+        }
+        let response = match receipt_details_from_headers(request.headers()) {
+            Ok(receipt_details) => {
+                self.generate_http_response_with_receipts(receipt_details.as_ref())
+            }
+            Err(err) => Response::builder()
+                .status(400)
+                .body(Body::from(err.to_string()))
+                .unwrap(),
+        };
+        futures::future::ok(response)
+    }
+}
+
+/// Whether the `Accept` header of an SPSP query allows an `application/spsp4+json` response.
+///
+/// Queries without the header are answered with SPSP as well.
+pub fn accepts_spsp(headers: &HeaderMap) -> bool {
+    let accept = match headers.get(ACCEPT).map(|value| value.to_str()) {
+        Some(Ok(accept)) => accept,
+        Some(Err(_)) => return false,
+        None => return true,
+    };
+    accept.split(',').any(|media_range| {
+        let media_type = media_range.split(';').next().unwrap_or_default().trim();
+        ["application/spsp4+json", "application/*", "*/*"]
+            .iter()
+            .any(|accepted| media_type.eq_ignore_ascii_case(accepted))
+    })
+}
+
+/// Reads the receipt details a verifier sends in the `Receipt-Nonce` and `Receipt-Secret`
+/// headers of the SPSP query, as base64-encoded 16 and 32 bytes.
+///
+/// Returns None if the query asked for no receipts.
+pub fn receipt_details_from_headers(
+    headers: &HeaderMap,
+) -> Result<Option<ReceiptDetails>, SpspError> {
+    let decode = |name: &str| -> Result<Option<Vec<u8>>, SpspError> {
+        match headers.get(name) {
+            Some(value) => {
+                let value = value
+                    .to_str()
+                    .map_err(|_| SpspError::InvalidReceiptDetails(format!("invalid {}", name)))?;
+                base64::decode(value.trim()).map(Some).map_err(|_| {
+                    SpspError::InvalidReceiptDetails(format!("{} is not base64", name))
+                })
+            }
+            None => Ok(None),
+        }
+    };
+    match (decode("Receipt-Nonce")?, decode("Receipt-Secret")?) {
+        (Some(nonce), Some(secret)) => Ok(Some(ReceiptDetails {
+            nonce: nonce[..].try_into().map_err(|_| {
+                SpspError::InvalidReceiptDetails("Receipt-Nonce must be 16 bytes".to_string())
+            })?,
+            secret: secret[..].try_into().map_err(|_| {
+                SpspError::InvalidReceiptDetails("Receipt-Secret must be 32 bytes".to_string())
+            })?,
+        })),
+        (None, None) => Ok(None),
+        _ => Err(SpspError::InvalidReceiptDetails(
+            "Receipt-Nonce and Receipt-Secret must be sent together".to_string(),
+        )),
     }
 }
 
@@ -112,4 +249,96 @@ mod spsp_server_test {
             "max-age=60"
         );
     }
+
+    #[tokio::test]
+    async fn negotiates_content_type() {
+        let addr = Address::from_str("example.receiver").unwrap();
+        let mut responder = SpspResponder::new(addr, Bytes::from(&[0; 32][..]));
+        for (accept, status) in &[
+            ("text/html, application/SPSP4+json; q=0.9", 200),
+            ("application/*", 200),
+            ("*/*", 200),
+            ("text/html", 406),
+            ("application/json", 406),
+        ] {
+            let response = responder
+                .call(
+                    Request::builder()
+                        .method("GET")
+                        .uri("http://example.com")
+                        .header("Accept", *accept)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), *status, "Accept: {}", accept);
+        }
+    }
+
+    #[tokio::test]
+    async fn includes_asset_details() {
+        let addr = Address::from_str("example.receiver").unwrap();
+        let responder =
+            SpspResponder::new(addr, Bytes::from(&[0; 32][..])).with_asset("XRP".to_string(), 9);
+        let body = hyper::body::to_bytes(responder.generate_http_response().into_body())
+            .await
+            .unwrap();
+        let response: SpspResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            response.asset(),
+            Some(&SpspAsset {
+                code: "XRP".to_string(),
+                scale: 9
+            })
+        );
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["asset"],
+            serde_json::json!({ "code": "XRP", "scale": 9 })
+        );
+    }
+
+    #[tokio::test]
+    async fn enables_receipts_when_asked() {
+        let addr = Address::from_str("example.receiver").unwrap();
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let mut responder = SpspResponder::new(addr, server_secret.clone());
+        let response = responder
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .uri("http://example.com")
+                    .header("Receipt-Nonce", base64::encode(&[1; 16]))
+                    .header("Receipt-Secret", base64::encode(&[2; 32]))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let response: SpspResponse = serde_json::from_slice(&body).unwrap();
+        assert!(response.receipts_enabled);
+        assert_eq!(
+            ConnectionGenerator::new(server_secret)
+                .rederive_receipt_details(&response.destination_account),
+            Some(ReceiptDetails {
+                nonce: [1; 16],
+                secret: [2; 32],
+            })
+        );
+
+        let response = responder
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .uri("http://example.com")
+                    .header("Receipt-Nonce", base64::encode(&[1; 15]))
+                    .header("Receipt-Secret", base64::encode(&[2; 32]))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+    }
 }
@@ -10,11 +10,13 @@ use interledger_service_util::{
     MaxPacketAmountAccount, RateLimitAccount, RoundTripTimeAccount, DEFAULT_ROUND_TRIP_TIME,
 };
 use interledger_settlement::core::types::{SettlementAccount, SettlementEngineDetails};
+use interledger_stream::{ReceiveLimitsAccount, DEFAULT_RECEIVE_PERIOD};
 use ring::aead;
 use secrecy::{ExposeSecret, SecretBytesMut, SecretString};
 use serde::Serializer;
 use serde::{Deserialize, Serialize};
 use std::str::{self, FromStr};
+use std::time::Duration;
 use tracing::error;
 use url::Url;
 use uuid::Uuid;
@@ -77,8 +79,20 @@ pub struct Account {
     pub(crate) round_trip_time: u32,
     /// The limit of packets the account can send per minute
     pub(crate) packets_per_minute_limit: Option<u32>,
+    /// The limit of packets the account can send at once
+    pub(crate) packets_burst_limit: Option<u32>,
     /// The maximum amount the account can send per minute
     pub(crate) amount_per_minute_limit: Option<u64>,
+    /// The maximum amount the account can send at once
+    pub(crate) amount_burst_limit: Option<u64>,
+    /// The maximum amount the account accepts over a single STREAM connection
+    pub(crate) receive_max_per_connection: Option<u64>,
+    /// The maximum amount the account accepts over STREAM within each receive period
+    pub(crate) receive_max_per_period: Option<u64>,
+    /// The length of the receive period
+    pub(crate) receive_period: Duration,
+    /// The minimum amount the account accepts in a single STREAM packet
+    pub(crate) receive_min_per_packet: Option<u64>,
     /// The account's settlement engine URL. If a global engine url is configured
     /// for the account's asset code,  that will be used instead (even if the account is
     /// configured with a specific one)
@@ -171,7 +185,16 @@ impl Account {
             routing_relation,
             round_trip_time: details.round_trip_time.unwrap_or(DEFAULT_ROUND_TRIP_TIME),
             packets_per_minute_limit: details.packets_per_minute_limit,
+            packets_burst_limit: details.packets_burst_limit,
             amount_per_minute_limit: details.amount_per_minute_limit,
+            amount_burst_limit: details.amount_burst_limit,
+            receive_max_per_connection: details.receive_max_per_connection,
+            receive_max_per_period: details.receive_max_per_period,
+            receive_period: details
+                .receive_period
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_RECEIVE_PERIOD),
+            receive_min_per_packet: details.receive_min_per_packet,
             settlement_engine_url,
         })
     }
@@ -345,6 +368,32 @@ impl RateLimitAccount for Account {
     fn packets_per_minute_limit(&self) -> Option<u32> {
         self.packets_per_minute_limit
     }
+
+    fn amount_burst_limit(&self) -> Option<u64> {
+        self.amount_burst_limit
+    }
+
+    fn packets_burst_limit(&self) -> Option<u32> {
+        self.packets_burst_limit
+    }
+}
+
+impl ReceiveLimitsAccount for Account {
+    fn receive_max_per_connection(&self) -> Option<u64> {
+        self.receive_max_per_connection
+    }
+
+    fn receive_max_per_period(&self) -> Option<u64> {
+        self.receive_max_per_period
+    }
+
+    fn receive_period(&self) -> Duration {
+        self.receive_period
+    }
+
+    fn receive_min_per_packet(&self) -> Option<u64> {
+        self.receive_min_per_packet
+    }
 }
 
 impl SettlementAccount for Account {
@@ -381,6 +430,12 @@ mod test {
         round_trip_time: Some(600),
         amount_per_minute_limit: None,
         packets_per_minute_limit: None,
+        amount_burst_limit: None,
+        packets_burst_limit: None,
+        receive_max_per_connection: None,
+        receive_max_per_period: None,
+        receive_period: None,
+        receive_min_per_packet: None,
         settlement_engine_url: None,
     });
 
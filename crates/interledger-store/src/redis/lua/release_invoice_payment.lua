@@ -0,0 +1,13 @@
+local invoice = KEYS[1]
+local amount = ARGV[1]
+
+if redis.call('EXISTS', invoice) == 0 then
+    return nil
+end
+
+local received = redis.call('HINCRBY', invoice, 'received', -amount)
+if received < tonumber(redis.call('HGET', invoice, 'amount')) then
+    redis.call('HSET', invoice, 'paid', 0)
+end
+
+return redis.call('HGETALL', invoice)
@@ -0,0 +1,19 @@
+local invoice = KEYS[1]
+local amount = tonumber(ARGV[1])
+
+if redis.call('EXISTS', invoice) == 0 then
+    return nil
+end
+
+-- Only add the amount if it does not exceed what is left to pay
+local total = tonumber(redis.call('HGET', invoice, 'amount'))
+if tonumber(redis.call('HGET', invoice, 'received')) + amount > total then
+    return {0, redis.call('HGETALL', invoice)}
+end
+
+local received = redis.call('HINCRBY', invoice, 'received', amount)
+if received >= total then
+    redis.call('HSET', invoice, 'paid', 1)
+end
+
+return {1, redis.call('HGETALL', invoice)}
@@ -0,0 +1,10 @@
+local accepted_rates = KEYS[1]
+local version = tonumber(ARGV[1])
+
+local saved_version = tonumber(redis.call('HGET', accepted_rates, 'version'))
+if saved_version and saved_version >= version then
+    return 0
+end
+
+redis.call('HMSET', accepted_rates, 'version', ARGV[1], 'rates', ARGV[2], 'pair_rates', ARGV[3])
+return 1
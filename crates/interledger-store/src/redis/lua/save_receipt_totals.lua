@@ -0,0 +1,18 @@
+local totals = KEYS[1]
+
+-- Compares the decimal strings rather than numbers, which cannot hold every u64
+local function greater(a, b)
+    if #a ~= #b then
+        return #a > #b
+    end
+    return a > b
+end
+
+for i = 1, #ARGV, 2 do
+    local stream_id = ARGV[i]
+    local total = ARGV[i + 1]
+    local saved = redis.call('HGET', totals, stream_id)
+    if not saved or greater(total, saved) then
+        redis.call('HSET', totals, stream_id, total)
+    end
+end
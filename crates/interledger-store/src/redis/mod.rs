@@ -3,6 +3,7 @@
 //   receive_routes_from    set         used for CCP routing
 //   next_account_id        string      unique ID for each new account
 //   rates:current          hash        exchange rates
+//   rates:accepted         hash        rates accepted by the rate change guard
 //   routes:current         hash        dynamic routing table
 //   routes:static          hash        static routing table
 //   accounts:<id>          hash        information for each account
@@ -20,29 +21,37 @@ mod reconnect;
 use reconnect::RedisReconnect;
 
 use super::account::{Account, AccountWithEncryptedTokens};
-use super::crypto::{encrypt_token, generate_keys, DecryptionKey, EncryptionKey};
+use super::crypto::{decrypt_token, encrypt_token, generate_keys, DecryptionKey, EncryptionKey};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use futures::channel::mpsc::UnboundedSender;
 use http::StatusCode;
-use interledger_api::{AccountDetails, AccountSettings, EncryptedAccountSettings, NodeStore};
+use interledger_api::{
+    AccountDetails, AccountSettings, EncryptedAccountSettings, NodeStore, OutgoingPayment,
+    OutgoingPaymentState, OutgoingPaymentStore,
+};
 use interledger_btp::BtpStore;
 use interledger_ccp::{CcpRoutingAccount, CcpRoutingStore, RoutingRelation};
 use interledger_errors::*;
 use interledger_http::HttpStore;
 use interledger_packet::Address;
-use interledger_rates::ExchangeRateStore;
+use interledger_rates::{
+    AcceptedRates, AcceptedRatesStore, ExchangeRate, ExchangeRateStore, PairRate,
+};
 use interledger_router::RouterStore;
 use interledger_service::{Account as AccountTrait, AccountStore, AddressStore, Username};
 use interledger_service_util::{
-    BalanceStore, RateLimitError, RateLimitStore, DEFAULT_ROUND_TRIP_TIME,
+    BalanceStore, RateLimitError, RateLimitStore, RemainingRateLimits, DEFAULT_ROUND_TRIP_TIME,
 };
 use interledger_settlement::core::{
     idempotency::{IdempotentData, IdempotentStore},
     scale_with_precision_loss,
     types::{Convert, ConvertDetails, LeftoversStore, SettlementStore},
 };
-use interledger_stream::{PaymentNotification, StreamNotificationsStore};
+use interledger_stream::{
+    Invoice, InvoiceStore, PaymentNotification, ReceiptTotalsStore, StreamDataNotification,
+    StreamNotificationsStore, DEFAULT_RECEIVE_PERIOD,
+};
 use num_bigint::BigUint;
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
@@ -62,7 +71,7 @@ use uuid::Uuid;
 use zeroize::Zeroize;
 
 const DEFAULT_POLL_INTERVAL: u64 = 30000; // 30 seconds
-const ACCOUNT_DETAILS_FIELDS: usize = 21;
+const ACCOUNT_DETAILS_FIELDS: usize = 25;
 const DEFAULT_DB_PREFIX: &str = "";
 
 static PARENT_ILP_KEY: &str = "parent_node_account_address";
@@ -76,6 +85,11 @@ static ACCOUNTS_KEY: &str = "accounts";
 static SEND_ROUTES_KEY: &str = "send_routes_to";
 static RECEIVE_ROUTES_FROM_KEY: &str = "receive_routes_from";
 static BPT_OUTGOING: &str = "btp_outgoing";
+static UNFINISHED_OUTGOING_PAYMENTS_KEY: &str = "outgoing_payments:unfinished";
+static ACCEPTED_RATES_KEY: &str = "rates:accepted";
+
+/// How long finished outgoing payments are kept, in seconds
+const FINISHED_OUTGOING_PAYMENT_EXPIRY: usize = 86400;
 
 /// Domain separator for leftover amounts
 fn uncredited_amount_key(prefix: &str, account_id: impl ToString) -> String {
@@ -95,6 +109,37 @@ fn prefixed_idempotency_key(prefix: &str, idempotency_key: &str) -> String {
     .into_owned()
 }
 
+/// Domain separator for outgoing payments
+fn outgoing_payment_key(prefix: &str, id: Uuid) -> String {
+    prefixed_key(prefix, &format!("outgoing_payments:{}", id)).into_owned()
+}
+
+/// Domain separator for invoices
+fn invoice_key(prefix: &str, id: Uuid) -> String {
+    prefixed_key(prefix, &format!("invoices:{}", id)).into_owned()
+}
+
+/// Domain separator for the totals received on the streams of a connection signing receipts
+fn receipt_totals_key(prefix: &str, connection: &Address) -> String {
+    prefixed_key(prefix, &format!("receipt_totals:{}", connection)).into_owned()
+}
+
+/// Domain separator for the idempotency keys of outgoing payments, which are unique per account
+fn outgoing_payment_idempotency_key(
+    prefix: &str,
+    account_id: Uuid,
+    idempotency_key: &str,
+) -> String {
+    prefixed_key(
+        prefix,
+        &format!(
+            "outgoing_payments:idempotency:{}:{}",
+            account_id, idempotency_key
+        ),
+    )
+    .into_owned()
+}
+
 fn prefixed_key<'a>(prefix: &str, key: &'a str) -> Cow<'a, str> {
     if prefix.is_empty() {
         Cow::Borrowed(key)
@@ -154,6 +199,24 @@ static REFUND_SETTLEMENT: Lazy<Script> =
 static PROCESS_INCOMING_SETTLEMENT: Lazy<Script> =
     Lazy::new(|| Script::new(include_str!("lua/process_incoming_settlement.lua")));
 
+/// Lua script which adds an amount received for an invoice unless it exceeds the amount
+/// left to pay, and marks the invoice as paid once the full amount arrived
+static RESERVE_INVOICE_PAYMENT: Lazy<Script> =
+    Lazy::new(|| Script::new(include_str!("lua/reserve_invoice_payment.lua")));
+
+/// Lua script which takes back an amount reserved for an invoice
+static RELEASE_INVOICE_PAYMENT: Lazy<Script> =
+    Lazy::new(|| Script::new(include_str!("lua/release_invoice_payment.lua")));
+
+/// Lua script which saves the totals of a connection's streams unless the saved ones are higher
+static SAVE_RECEIPT_TOTALS: Lazy<Script> =
+    Lazy::new(|| Script::new(include_str!("lua/save_receipt_totals.lua")));
+
+/// Lua script which saves the rates accepted by the rate change guard unless
+/// the saved rates are newer
+static SAVE_ACCEPTED_RATES: Lazy<Script> =
+    Lazy::new(|| Script::new(include_str!("lua/save_accepted_rates.lua")));
+
 /// Builder for the Redis Store
 pub struct RedisStoreBuilder {
     redis_url: ConnectionInfo,
@@ -244,8 +307,10 @@ impl RedisStoreBuilder {
             ilp_address: Arc::new(RwLock::new(node_ilp_address)),
             connection,
             subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            data_subscriptions: Arc::new(Mutex::new(HashMap::new())),
             payment_publisher: all_payment_publisher,
             exchange_rates: Arc::new(RwLock::new(HashMap::new())),
+            pair_rates: Arc::new(RwLock::new(HashMap::new())),
             routes: Arc::new(RwLock::new(Arc::new(HashMap::new()))),
             encryption_key: Arc::new(encryption_key),
             decryption_key: Arc::new(decryption_key),
@@ -368,7 +433,11 @@ pub struct RedisStore {
     subscriptions: Arc<Mutex<HashMap<Uuid, Vec<UnboundedSender<PaymentNotification>>>>>,
     /// A subscriber to all payment notifications, exposed via a WebSocket
     payment_publisher: broadcast::Sender<PaymentNotification>,
-    exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
+    /// WebSocket senders which publish data received over STREAM
+    data_subscriptions: Arc<Mutex<HashMap<Uuid, Vec<UnboundedSender<StreamDataNotification>>>>>,
+    exchange_rates: Arc<RwLock<HashMap<String, ExchangeRate>>>,
+    /// Direct rates between pairs of assets, keyed by `BASE/QUOTE`
+    pair_rates: Arc<RwLock<HashMap<String, PairRate>>>,
     /// The store keeps the routing table in memory so that it can be returned
     /// synchronously while the Router is processing packets.
     /// The outer `Arc<RwLock>` is used so that we can update the stored routing
@@ -837,6 +906,38 @@ impl StreamNotificationsStore for RedisStore {
     fn all_payment_subscription(&self) -> broadcast::Receiver<PaymentNotification> {
         self.payment_publisher.subscribe()
     }
+
+    fn add_stream_data_subscription(
+        &self,
+        id: Uuid,
+        sender: UnboundedSender<StreamDataNotification>,
+    ) {
+        trace!("Added stream data listener for {}", id);
+        self.data_subscriptions
+            .lock()
+            .entry(id)
+            .or_default()
+            .push(sender);
+    }
+
+    /// Unlike payment notifications, the data is not published over Redis because the
+    /// STREAM receiver keeps the state of the connection in the memory of this node.
+    fn publish_stream_data_notification(&self, id: Uuid, notification: StreamDataNotification) {
+        match self.data_subscriptions.lock().get_mut(&id) {
+            Some(senders) => senders.retain(|sender| {
+                if let Err(err) = sender.unbounded_send(notification.clone()) {
+                    debug!("Failed to send stream data: {}", err);
+                    false
+                } else {
+                    true
+                }
+            }),
+            None => trace!(
+                "Ignoring stream data for account {} because there were no open subscriptions",
+                id
+            ),
+        }
+    }
 }
 
 #[async_trait]
@@ -950,8 +1051,11 @@ impl BalanceStore for RedisStore {
 }
 
 impl ExchangeRateStore for RedisStore {
-    fn get_exchange_rates(&self, asset_codes: &[&str]) -> Result<Vec<f64>, ExchangeRateStoreError> {
-        let rates: Vec<f64> = asset_codes
+    fn get_exchange_rates(
+        &self,
+        asset_codes: &[&str],
+    ) -> Result<Vec<ExchangeRate>, ExchangeRateStoreError> {
+        let rates: Vec<ExchangeRate> = asset_codes
             .iter()
             .filter_map(|code| (*self.exchange_rates.read()).get(*code).cloned())
             .collect();
@@ -966,18 +1070,98 @@ impl ExchangeRateStore for RedisStore {
         }
     }
 
-    fn get_all_exchange_rates(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+    fn get_all_exchange_rates(
+        &self,
+    ) -> Result<HashMap<String, ExchangeRate>, ExchangeRateStoreError> {
         Ok((*self.exchange_rates.read()).clone())
     }
 
     fn set_exchange_rates(
         &self,
-        rates: HashMap<String, f64>,
+        rates: HashMap<String, ExchangeRate>,
     ) -> Result<(), ExchangeRateStoreError> {
         // TODO publish rate updates through a pubsub mechanism to support horizontally scaling nodes
         (*self.exchange_rates.write()) = rates;
         Ok(())
     }
+
+    fn set_pair_rates(
+        &self,
+        rates: HashMap<String, PairRate>,
+    ) -> Result<(), ExchangeRateStoreError> {
+        (*self.pair_rates.write()) = rates;
+        Ok(())
+    }
+
+    fn get_all_pair_rates(&self) -> Result<HashMap<String, PairRate>, ExchangeRateStoreError> {
+        Ok((*self.pair_rates.read()).clone())
+    }
+
+    fn get_pair_rate(
+        &self,
+        base: &str,
+        quote: &str,
+    ) -> Result<Option<PairRate>, ExchangeRateStoreError> {
+        Ok((*self.pair_rates.read())
+            .get(&format!("{}/{}", base, quote))
+            .cloned())
+    }
+}
+
+#[async_trait]
+impl AcceptedRatesStore for RedisStore {
+    async fn load_accepted_rates(&self) -> Result<Option<AcceptedRates>, ExchangeRateStoreError> {
+        let (version, rates, pair_rates): (Option<u64>, Option<String>, Option<String>) =
+            cmd("HMGET")
+                .arg(&*prefixed_key(&self.db_prefix, ACCEPTED_RATES_KEY))
+                .arg("version")
+                .arg("rates")
+                .arg("pair_rates")
+                .query_async(&mut self.connection.clone())
+                .await?;
+        match (version, rates) {
+            (Some(version), Some(rates)) => {
+                let rates = serde_json::from_str(&rates)
+                    .map_err(|err| ExchangeRateStoreError::Other(Box::new(err)))?;
+                // Rates saved by older versions have no pair rates
+                let pair_rates = match pair_rates {
+                    Some(pair_rates) => serde_json::from_str(&pair_rates)
+                        .map_err(|err| ExchangeRateStoreError::Other(Box::new(err)))?,
+                    None => HashMap::new(),
+                };
+                Ok(Some(AcceptedRates {
+                    version,
+                    rates,
+                    pair_rates,
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn save_accepted_rates(
+        &self,
+        accepted: AcceptedRates,
+    ) -> Result<(), ExchangeRateStoreError> {
+        let rates = serde_json::to_string(&accepted.rates)
+            .map_err(|err| ExchangeRateStoreError::Other(Box::new(err)))?;
+        let pair_rates = serde_json::to_string(&accepted.pair_rates)
+            .map_err(|err| ExchangeRateStoreError::Other(Box::new(err)))?;
+        let saved: bool = SAVE_ACCEPTED_RATES
+            .key(&*prefixed_key(&self.db_prefix, ACCEPTED_RATES_KEY))
+            .arg(accepted.version)
+            .arg(rates)
+            .arg(pair_rates)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        if !saved {
+            debug!(
+                "Not saving accepted rates version {}, newer rates are saved",
+                accepted.version
+            );
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -1571,28 +1755,19 @@ impl RateLimitStore for RedisStore {
             let packet_limit = account.packets_per_minute_limit.is_some();
             let amount_limit = account.amount_per_minute_limit.is_some();
 
-            if let Some(limit) = account.packets_per_minute_limit {
-                let limit = limit - 1;
-                let packets_limit =
-                    prefixed_key(&self.db_prefix, &format!("limit:packets:{}", account.id))
-                        .into_owned();
+            if let Some((max_burst, limit)) = packets_throttle(&account) {
                 pipe.cmd("CL.THROTTLE")
-                    .arg(&packets_limit)
-                    .arg(limit)
+                    .arg(self.packets_limit_key(&account))
+                    .arg(max_burst)
                     .arg(limit)
                     .arg(60)
                     .arg(1);
             }
 
-            if let Some(limit) = account.amount_per_minute_limit {
-                let limit = limit - 1;
-                let throughput_limit =
-                    prefixed_key(&self.db_prefix, &format!("limit:throughput:{}", account.id))
-                        .into_owned();
+            if let Some((max_burst, limit)) = throughput_throttle(&account) {
                 pipe.cmd("CL.THROTTLE")
-                    .arg(&throughput_limit)
-                    // TODO allow separate configuration for burst limit
-                    .arg(limit)
+                    .arg(self.throughput_limit_key(&account))
+                    .arg(max_burst)
                     .arg(limit)
                     .arg(60)
                     .arg(prepare_amount);
@@ -1631,18 +1806,13 @@ impl RateLimitStore for RedisStore {
         account: Account,
         prepare_amount: u64,
     ) -> Result<(), RateLimitError> {
-        if let Some(limit) = account.amount_per_minute_limit {
-            let limit = limit - 1;
-            let throughput_limit =
-                prefixed_key(&self.db_prefix, &format!("limit:throughput:{}", account.id))
-                    .into_owned();
+        if let Some((max_burst, limit)) = throughput_throttle(&account) {
             cmd("CL.THROTTLE")
-                .arg(&throughput_limit)
-                .arg(limit)
+                .arg(self.throughput_limit_key(&account))
+                .arg(max_burst)
                 .arg(limit)
                 .arg(60)
-                // TODO make sure this doesn't overflow
-                .arg(0i64 - (prepare_amount as i64))
+                .arg(0i64.saturating_sub(prepare_amount.min(i64::MAX as u64) as i64))
                 .query_async(&mut self.connection.clone())
                 .map_err(|_| RateLimitError::StoreError)
                 .await?;
@@ -1650,6 +1820,90 @@ impl RateLimitStore for RedisStore {
 
         Ok(())
     }
+
+    /// Charges the limits with redis-cell like `apply_rate_limits`. A charge which exceeds
+    /// what is left of a limit is refused by redis-cell, in which case the limit is
+    /// reported as used up.
+    async fn sync_rate_limits(
+        &self,
+        account: Account,
+        packets: u64,
+        amount: i64,
+    ) -> Result<Option<RemainingRateLimits>, RateLimitError> {
+        let packets_throttle = packets_throttle(&account);
+        let throughput_throttle = throughput_throttle(&account);
+        if packets_throttle.is_none() && throughput_throttle.is_none() {
+            return Ok(None);
+        }
+
+        let mut pipe = redis_crate::pipe();
+        if let Some((max_burst, limit)) = packets_throttle {
+            pipe.cmd("CL.THROTTLE")
+                .arg(self.packets_limit_key(&account))
+                .arg(max_burst)
+                .arg(limit)
+                .arg(60)
+                .arg(packets);
+        }
+        if let Some((max_burst, limit)) = throughput_throttle {
+            pipe.cmd("CL.THROTTLE")
+                .arg(self.throughput_limit_key(&account))
+                .arg(max_burst)
+                .arg(limit)
+                .arg(60)
+                .arg(amount);
+        }
+        let results: Vec<Vec<i64>> = pipe
+            .query_async(&mut self.connection.clone())
+            .map_err(|err| {
+                error!("Error syncing rate limits: {:?}", err);
+                RateLimitError::StoreError
+            })
+            .await?;
+
+        // Each result is [limited, total limit, remaining, retry after, reset after]
+        let mut results = results.into_iter().map(|result| {
+            if result.first() == Some(&1) {
+                0
+            } else {
+                result.get(2).cloned().unwrap_or_default().max(0) as u64
+            }
+        });
+        let packets = if packets_throttle.is_some() {
+            results.next()
+        } else {
+            None
+        };
+        Ok(Some(RemainingRateLimits {
+            packets,
+            amount: results.next(),
+        }))
+    }
+}
+
+/// The max burst and the rate per minute of the packet limit of the account, as
+/// arguments of `CL.THROTTLE`. redis-cell allows one more than the max burst at once.
+fn packets_throttle(account: &Account) -> Option<(u32, u32)> {
+    let limit = account.packets_per_minute_limit?;
+    let burst = account.packets_burst_limit.unwrap_or(limit);
+    Some((burst.saturating_sub(1), limit))
+}
+
+/// The max burst and the rate per minute of the throughput limit of the account
+fn throughput_throttle(account: &Account) -> Option<(u64, u64)> {
+    let limit = account.amount_per_minute_limit?;
+    let burst = account.amount_burst_limit.unwrap_or(limit);
+    Some((burst.saturating_sub(1), limit))
+}
+
+impl RedisStore {
+    fn packets_limit_key(&self, account: &Account) -> String {
+        prefixed_key(&self.db_prefix, &format!("limit:packets:{}", account.id)).into_owned()
+    }
+
+    fn throughput_limit_key(&self, account: &Account) -> String {
+        prefixed_key(&self.db_prefix, &format!("limit:throughput:{}", account.id)).into_owned()
+    }
 }
 
 #[async_trait]
@@ -1717,6 +1971,333 @@ impl IdempotentStore for RedisStore {
     }
 }
 
+impl RedisStore {
+    /// Serializes the payment with its shared secret encrypted
+    fn encode_outgoing_payment(
+        &self,
+        payment: &OutgoingPayment,
+    ) -> Result<String, OutgoingPaymentStoreError> {
+        let mut payment = payment.clone();
+        payment.shared_secret = encrypt_token(
+            &self.encryption_key.expose_secret().0,
+            &payment.shared_secret,
+        )
+        .to_vec();
+        serde_json::to_string(&payment)
+            .map_err(|err| OutgoingPaymentStoreError::Other(Box::new(err)))
+    }
+
+    /// Deserializes a payment saved with `encode_outgoing_payment`
+    fn decode_outgoing_payment(
+        &self,
+        encoded: &str,
+    ) -> Result<OutgoingPayment, OutgoingPaymentStoreError> {
+        let mut payment: OutgoingPayment = serde_json::from_str(encoded)
+            .map_err(|err| OutgoingPaymentStoreError::Other(Box::new(err)))?;
+        let shared_secret = decrypt_token(
+            &self.decryption_key.expose_secret().0,
+            &payment.shared_secret,
+        )
+        .map_err(|err| OutgoingPaymentStoreError::Other(Box::new(err)))?;
+        payment.shared_secret = shared_secret.expose_secret().to_vec();
+        Ok(payment)
+    }
+}
+
+#[async_trait]
+impl OutgoingPaymentStore for RedisStore {
+    async fn insert_outgoing_payment(
+        &self,
+        payment: OutgoingPayment,
+    ) -> Result<OutgoingPayment, OutgoingPaymentStoreError> {
+        let mut connection = self.connection.clone();
+        let key = outgoing_payment_key(&self.db_prefix, payment.id);
+        let unfinished_key = prefixed_key(&self.db_prefix, UNFINISHED_OUTGOING_PAYMENTS_KEY);
+
+        // Save the payment before claiming the idempotency key, so that the payment
+        // a claimed key points to always exists
+        let mut pipe = redis_crate::pipe();
+        pipe.atomic()
+            .set(&key, self.encode_outgoing_payment(&payment)?)
+            .ignore()
+            .sadd(&*unfinished_key, RedisPaymentId(payment.id))
+            .ignore();
+        pipe.query_async(&mut connection).await?;
+
+        if let Some(idempotency_key) = &payment.idempotency_key {
+            let idempotency_key = outgoing_payment_idempotency_key(
+                &self.db_prefix,
+                payment.account_id,
+                idempotency_key,
+            );
+            let claimed: bool = connection
+                .set_nx(&idempotency_key, RedisPaymentId(payment.id))
+                .await?;
+            if !claimed {
+                let mut pipe = redis_crate::pipe();
+                pipe.atomic()
+                    .del(&key)
+                    .ignore()
+                    .srem(&*unfinished_key, RedisPaymentId(payment.id))
+                    .ignore();
+                pipe.query_async(&mut connection).await?;
+
+                let RedisPaymentId(existing) = connection.get(&idempotency_key).await?;
+                debug!(
+                    "Payment {} already exists with the same idempotency key",
+                    existing
+                );
+                return self.get_outgoing_payment(existing).await;
+            }
+        }
+
+        trace!("Inserted outgoing payment {}", payment.id);
+        Ok(payment)
+    }
+
+    async fn update_outgoing_payment(
+        &self,
+        payment: OutgoingPayment,
+    ) -> Result<(), OutgoingPaymentStoreError> {
+        let mut connection = self.connection.clone();
+        let key = outgoing_payment_key(&self.db_prefix, payment.id);
+        let exists: bool = connection.exists(&key).await?;
+        if !exists {
+            return Err(OutgoingPaymentStoreError::PaymentNotFound(
+                payment.id.to_string(),
+            ));
+        }
+
+        let mut pipe = redis_crate::pipe();
+        pipe.atomic()
+            .set(&key, self.encode_outgoing_payment(&payment)?)
+            .ignore();
+        if payment.state != OutgoingPaymentState::Sending {
+            pipe.srem(
+                &*prefixed_key(&self.db_prefix, UNFINISHED_OUTGOING_PAYMENTS_KEY),
+                payment.id.to_string(),
+            )
+            .ignore()
+            .expire(&key, FINISHED_OUTGOING_PAYMENT_EXPIRY)
+            .ignore();
+            if let Some(idempotency_key) = &payment.idempotency_key {
+                pipe.expire(
+                    outgoing_payment_idempotency_key(
+                        &self.db_prefix,
+                        payment.account_id,
+                        idempotency_key,
+                    ),
+                    FINISHED_OUTGOING_PAYMENT_EXPIRY,
+                )
+                .ignore();
+            }
+        }
+        pipe.query_async(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn get_outgoing_payment(
+        &self,
+        id: Uuid,
+    ) -> Result<OutgoingPayment, OutgoingPaymentStoreError> {
+        let mut connection = self.connection.clone();
+        let encoded: Option<String> = connection
+            .get(outgoing_payment_key(&self.db_prefix, id))
+            .await?;
+        match encoded {
+            Some(encoded) => self.decode_outgoing_payment(&encoded),
+            None => Err(OutgoingPaymentStoreError::PaymentNotFound(id.to_string())),
+        }
+    }
+
+    async fn get_outgoing_payment_by_idempotency_key(
+        &self,
+        account_id: Uuid,
+        idempotency_key: &str,
+    ) -> Result<Option<OutgoingPayment>, OutgoingPaymentStoreError> {
+        let mut connection = self.connection.clone();
+        let id: Option<RedisPaymentId> = connection
+            .get(outgoing_payment_idempotency_key(
+                &self.db_prefix,
+                account_id,
+                idempotency_key,
+            ))
+            .await?;
+        let id = match id {
+            Some(RedisPaymentId(id)) => id,
+            None => return Ok(None),
+        };
+        match self.get_outgoing_payment(id).await {
+            Ok(payment) => Ok(Some(payment)),
+            // The payment expired just before its idempotency key
+            Err(OutgoingPaymentStoreError::PaymentNotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn get_unfinished_outgoing_payments(
+        &self,
+    ) -> Result<Vec<OutgoingPayment>, OutgoingPaymentStoreError> {
+        let mut connection = self.connection.clone();
+        let ids: Vec<RedisPaymentId> = connection
+            .smembers(&*prefixed_key(
+                &self.db_prefix,
+                UNFINISHED_OUTGOING_PAYMENTS_KEY,
+            ))
+            .await?;
+        let mut payments = Vec::with_capacity(ids.len());
+        for RedisPaymentId(id) in ids {
+            payments.push(self.get_outgoing_payment(id).await?);
+        }
+        Ok(payments)
+    }
+}
+
+#[async_trait]
+impl InvoiceStore for RedisStore {
+    async fn insert_invoice(&self, invoice: Invoice) -> Result<(), InvoiceStoreError> {
+        let metadata = serde_json::to_string(&invoice.metadata)
+            .map_err(|err| InvoiceStoreError::Other(Box::new(err)))?;
+        let mut connection = self.connection.clone();
+        connection
+            .hset_multiple(
+                invoice_key(&self.db_prefix, invoice.id),
+                &[
+                    ("account_id", invoice.account_id.to_string()),
+                    ("amount", invoice.amount.to_string()),
+                    ("received", invoice.received.to_string()),
+                    ("metadata", metadata),
+                    ("paid", (invoice.paid as u8).to_string()),
+                ],
+            )
+            .await?;
+        trace!("Inserted invoice {}", invoice.id);
+        Ok(())
+    }
+
+    async fn get_invoice(&self, id: Uuid) -> Result<Invoice, InvoiceStoreError> {
+        let mut connection = self.connection.clone();
+        let fields: HashMap<String, String> =
+            connection.hgetall(invoice_key(&self.db_prefix, id)).await?;
+        invoice_from_fields(id, fields)
+    }
+
+    async fn reserve_invoice_payment(
+        &self,
+        id: Uuid,
+        amount: u64,
+    ) -> Result<(bool, Invoice), InvoiceStoreError> {
+        let result: Option<(bool, HashMap<String, String>)> = RESERVE_INVOICE_PAYMENT
+            .key(invoice_key(&self.db_prefix, id))
+            .arg(amount)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        let (reserved, fields) = result.unwrap_or_default();
+        let invoice = invoice_from_fields(id, fields)?;
+        if reserved {
+            trace!(
+                "Received {} for invoice {}, {} of {} received in total",
+                amount,
+                id,
+                invoice.received,
+                invoice.amount
+            );
+        } else {
+            trace!(
+                "Not adding {} to invoice {}, only {} is left to pay",
+                amount,
+                id,
+                invoice.remaining()
+            );
+        }
+        Ok((reserved, invoice))
+    }
+
+    async fn release_invoice_payment(
+        &self,
+        id: Uuid,
+        amount: u64,
+    ) -> Result<Invoice, InvoiceStoreError> {
+        let fields: Option<HashMap<String, String>> = RELEASE_INVOICE_PAYMENT
+            .key(invoice_key(&self.db_prefix, id))
+            .arg(amount)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        let invoice = invoice_from_fields(id, fields.unwrap_or_default())?;
+        trace!(
+            "Released {} of invoice {}, {} of {} received in total",
+            amount,
+            id,
+            invoice.received,
+            invoice.amount
+        );
+        Ok(invoice)
+    }
+}
+
+/// Parses the fields of an invoice's hash
+fn invoice_from_fields(
+    id: Uuid,
+    fields: HashMap<String, String>,
+) -> Result<Invoice, InvoiceStoreError> {
+    if fields.is_empty() {
+        return Err(InvoiceStoreError::InvoiceNotFound(id.to_string()));
+    }
+    let field = |name: &str| {
+        fields.get(name).map(String::as_str).ok_or_else(|| {
+            InvoiceStoreError::Other(Box::new(RedisError::from((
+                ErrorKind::TypeError,
+                "Invoice is missing a field",
+                name.to_string(),
+            ))))
+        })
+    };
+    let invalid = || {
+        InvoiceStoreError::Other(Box::new(RedisError::from((
+            ErrorKind::TypeError,
+            "Invalid invoice field",
+        ))))
+    };
+    Ok(Invoice {
+        id,
+        account_id: Uuid::from_str(field("account_id")?).map_err(|_| invalid())?,
+        amount: field("amount")?.parse().map_err(|_| invalid())?,
+        received: field("received")?.parse().map_err(|_| invalid())?,
+        metadata: serde_json::from_str(field("metadata")?).map_err(|_| invalid())?,
+        paid: field("paid")? == "1",
+    })
+}
+
+#[async_trait]
+impl ReceiptTotalsStore for RedisStore {
+    async fn get_receipt_totals(
+        &self,
+        connection: &Address,
+    ) -> Result<HashMap<u64, u64>, ReceiptStoreError> {
+        let mut redis = self.connection.clone();
+        let totals: HashMap<u64, u64> = redis
+            .hgetall(receipt_totals_key(&self.db_prefix, connection))
+            .await?;
+        Ok(totals)
+    }
+
+    async fn save_receipt_totals(
+        &self,
+        connection: &Address,
+        totals: &[(u64, u64)],
+    ) -> Result<(), ReceiptStoreError> {
+        let mut script = SAVE_RECEIPT_TOTALS.key(receipt_totals_key(&self.db_prefix, connection));
+        for (stream_id, total_received) in totals {
+            script.arg(*stream_id).arg(*total_received);
+        }
+        script
+            .invoke_async::<_, ()>(&mut self.connection.clone())
+            .await?;
+        trace!("Saved receipt totals {:?} of {}", totals, connection);
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl SettlementStore for RedisStore {
     type Account = Account;
@@ -2036,6 +2617,25 @@ impl FromRedisValue for RedisAccountId {
     }
 }
 
+/// Outgoing payment ids, wrapped for the same reason as account ids
+#[derive(Debug, Copy, Clone)]
+struct RedisPaymentId(Uuid);
+
+impl ToRedisArgs for RedisPaymentId {
+    fn write_redis_args<W: RedisWrite + ?Sized>(&self, out: &mut W) {
+        out.write_arg(self.0.to_hyphenated().to_string().as_bytes().as_ref());
+    }
+}
+
+impl FromRedisValue for RedisPaymentId {
+    fn from_redis_value(v: &Value) -> Result<Self, RedisError> {
+        let payment_id = String::from_redis_value(v)?;
+        let id = Uuid::from_str(&payment_id)
+            .map_err(|_| RedisError::from((ErrorKind::TypeError, "Invalid payment id string")))?;
+        Ok(RedisPaymentId(id))
+    }
+}
+
 impl ToRedisArgs for &AccountWithEncryptedTokens {
     fn write_redis_args<W: RedisWrite + ?Sized>(&self, out: &mut W) {
         let mut rv = Vec::with_capacity(ACCOUNT_DETAILS_FIELDS * 2);
@@ -2122,6 +2722,28 @@ impl ToRedisArgs for &AccountWithEncryptedTokens {
             "amount_per_minute_limit".write_redis_args(&mut rv);
             limit.write_redis_args(&mut rv);
         }
+        if let Some(limit) = account.packets_burst_limit {
+            "packets_burst_limit".write_redis_args(&mut rv);
+            limit.write_redis_args(&mut rv);
+        }
+        if let Some(limit) = account.amount_burst_limit {
+            "amount_burst_limit".write_redis_args(&mut rv);
+            limit.write_redis_args(&mut rv);
+        }
+        if let Some(max) = account.receive_max_per_connection {
+            "receive_max_per_connection".write_redis_args(&mut rv);
+            max.write_redis_args(&mut rv);
+        }
+        if let Some(max) = account.receive_max_per_period {
+            "receive_max_per_period".write_redis_args(&mut rv);
+            max.write_redis_args(&mut rv);
+        }
+        "receive_period".write_redis_args(&mut rv);
+        account.receive_period.as_secs().write_redis_args(&mut rv);
+        if let Some(min) = account.receive_min_per_packet {
+            "receive_min_per_packet".write_redis_args(&mut rv);
+            min.write_redis_args(&mut rv);
+        }
         if let Some(min_balance) = account.min_balance {
             "min_balance".write_redis_args(&mut rv);
             min_balance.write_redis_args(&mut rv);
@@ -2157,6 +2779,11 @@ impl FromRedisValue for AccountWithEncryptedTokens {
         let round_trip_time: Option<u32> = get_value_option("round_trip_time", &hash)?;
         let round_trip_time: u32 = round_trip_time.unwrap_or(DEFAULT_ROUND_TRIP_TIME);
 
+        let receive_period: Option<u64> = get_value_option("receive_period", &hash)?;
+        let receive_period = receive_period
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RECEIVE_PERIOD);
+
         let rid: RedisAccountId = get_value("id", &hash)?;
 
         Ok(AccountWithEncryptedTokens {
@@ -2196,6 +2823,12 @@ impl FromRedisValue for AccountWithEncryptedTokens {
                 round_trip_time,
                 packets_per_minute_limit: get_value_option("packets_per_minute_limit", &hash)?,
                 amount_per_minute_limit: get_value_option("amount_per_minute_limit", &hash)?,
+                packets_burst_limit: get_value_option("packets_burst_limit", &hash)?,
+                amount_burst_limit: get_value_option("amount_burst_limit", &hash)?,
+                receive_max_per_connection: get_value_option("receive_max_per_connection", &hash)?,
+                receive_max_per_period: get_value_option("receive_max_per_period", &hash)?,
+                receive_period,
+                receive_min_per_packet: get_value_option("receive_min_per_packet", &hash)?,
                 settlement_engine_url: get_url_option("settlement_engine_url", &hash)?,
             },
         })
@@ -0,0 +1,62 @@
+use super::store_helpers::*;
+use interledger_errors::InvoiceStoreError;
+use interledger_service::Account as AccountTrait;
+use interledger_stream::{Invoice, InvoiceStore};
+use serde_json::json;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn saves_and_loads_invoices() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let invoice = Invoice::new(accs[0].id(), 1000, json!({ "order": "1234" }));
+    store.insert_invoice(invoice.clone()).await.unwrap();
+
+    let loaded = store.get_invoice(invoice.id).await.unwrap();
+    assert_eq!(loaded, invoice);
+
+    let result = store.get_invoice(Uuid::new_v4()).await;
+    assert!(matches!(result, Err(InvoiceStoreError::InvoiceNotFound(_))));
+}
+
+#[tokio::test]
+async fn marks_invoice_paid_once_fully_received() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let invoice = Invoice::new(accs[0].id(), 1000, json!(null));
+    store.insert_invoice(invoice.clone()).await.unwrap();
+
+    let (reserved, updated) = store.reserve_invoice_payment(invoice.id, 600).await.unwrap();
+    assert!(reserved);
+    assert_eq!(updated.received, 600);
+    assert!(!updated.paid);
+
+    let (reserved, updated) = store.reserve_invoice_payment(invoice.id, 400).await.unwrap();
+    assert!(reserved);
+    assert_eq!(updated.received, 1000);
+    assert!(updated.paid);
+    assert_eq!(store.get_invoice(invoice.id).await.unwrap(), updated);
+
+    let result = store.reserve_invoice_payment(Uuid::new_v4(), 100).await;
+    assert!(matches!(result, Err(InvoiceStoreError::InvoiceNotFound(_))));
+}
+
+#[tokio::test]
+async fn does_not_reserve_more_than_is_left_to_pay() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let invoice = Invoice::new(accs[0].id(), 1000, json!(null));
+    store.insert_invoice(invoice.clone()).await.unwrap();
+
+    let (reserved, _) = store.reserve_invoice_payment(invoice.id, 600).await.unwrap();
+    assert!(reserved);
+    let (reserved, updated) = store.reserve_invoice_payment(invoice.id, 600).await.unwrap();
+    assert!(!reserved);
+    assert_eq!(updated.received, 600);
+
+    // Released amounts can be paid again
+    let updated = store.release_invoice_payment(invoice.id, 600).await.unwrap();
+    assert_eq!(updated.received, 0);
+    let (reserved, updated) = store.reserve_invoice_payment(invoice.id, 1000).await.unwrap();
+    assert!(reserved);
+    assert!(updated.paid);
+    let updated = store.release_invoice_payment(invoice.id, 1000).await.unwrap();
+    assert!(!updated.paid);
+}
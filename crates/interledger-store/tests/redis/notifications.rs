@@ -45,6 +45,7 @@ async fn notifications_on_multitenant_config() {
         timestamp: String::from("2021-04-04T12:11:11.987+00:00"),
         sequence: 2,
         connection_closed: false,
+        invoice_id: None,
     };
 
     let second_pmt = PaymentNotification {
@@ -55,6 +56,7 @@ async fn notifications_on_multitenant_config() {
         timestamp: String::from("2021-04-04T12:11:10.987+00:00"),
         sequence: 1,
         connection_closed: false,
+        invoice_id: None,
     };
 
     // do the test in a loop since sometimes the psubscribe functionality just isn't ready
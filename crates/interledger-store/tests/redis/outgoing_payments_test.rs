@@ -0,0 +1,107 @@
+use super::store_helpers::*;
+use interledger_api::{OutgoingPayment, OutgoingPaymentState, OutgoingPaymentStore};
+use interledger_errors::OutgoingPaymentStoreError;
+use interledger_packet::Address;
+use interledger_service::Account as AccountTrait;
+use interledger_stream::{PaymentAmount, PaymentCheckpoint, SendMoneyOptions, StreamDelivery};
+use std::str::FromStr;
+use uuid::Uuid;
+
+fn payment(account: &impl AccountTrait, idempotency_key: Option<&str>) -> OutgoingPayment {
+    OutgoingPayment {
+        id: Uuid::new_v4(),
+        account_id: account.id(),
+        idempotency_key: idempotency_key.map(|key| key.to_string()),
+        receiver: "$example.com/bob".to_string(),
+        shared_secret: vec![7; 32],
+        amount: PaymentAmount::FixedSource(1000),
+        slippage: 0.015,
+        options: SendMoneyOptions::default(),
+        state: OutgoingPaymentState::Sending,
+        checkpoint: PaymentCheckpoint {
+            receipt: StreamDelivery::new(
+                account,
+                Address::from_str("example.bob.connection").unwrap(),
+                1000,
+            ),
+            next_sequence: 1,
+        },
+        error: None,
+    }
+}
+
+#[tokio::test]
+async fn saves_and_loads_payments() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let inserted = store
+        .insert_outgoing_payment(payment(&accs[0], None))
+        .await
+        .unwrap();
+
+    let loaded = store.get_outgoing_payment(inserted.id).await.unwrap();
+    assert_eq!(loaded.shared_secret, vec![7; 32]);
+    assert_eq!(loaded.amount, PaymentAmount::FixedSource(1000));
+    assert_eq!(loaded.checkpoint, inserted.checkpoint);
+
+    let result = store.get_outgoing_payment(Uuid::new_v4()).await;
+    assert!(matches!(
+        result,
+        Err(OutgoingPaymentStoreError::PaymentNotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn returns_existing_payment_for_idempotency_key() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let first = store
+        .insert_outgoing_payment(payment(&accs[0], Some("key")))
+        .await
+        .unwrap();
+    let second = store
+        .insert_outgoing_payment(payment(&accs[0], Some("key")))
+        .await
+        .unwrap();
+    assert_eq!(first.id, second.id);
+
+    let found = store
+        .get_outgoing_payment_by_idempotency_key(accs[0].id(), "key")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(found.id, first.id);
+
+    // Idempotency keys are unique per account
+    let other = store
+        .insert_outgoing_payment(payment(&accs[1], Some("key")))
+        .await
+        .unwrap();
+    assert_ne!(other.id, first.id);
+}
+
+#[tokio::test]
+async fn only_unfinished_payments_are_resumed() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let sending = store
+        .insert_outgoing_payment(payment(&accs[0], None))
+        .await
+        .unwrap();
+    let mut completed = store
+        .insert_outgoing_payment(payment(&accs[0], None))
+        .await
+        .unwrap();
+
+    completed.state = OutgoingPaymentState::Completed;
+    completed.checkpoint.receipt.sent_amount = 1000;
+    store
+        .update_outgoing_payment(completed.clone())
+        .await
+        .unwrap();
+
+    let unfinished = store.get_unfinished_outgoing_payments().await.unwrap();
+    assert_eq!(unfinished.len(), 1);
+    assert_eq!(unfinished[0].id, sending.id);
+
+    let loaded = store.get_outgoing_payment(completed.id).await.unwrap();
+    assert_eq!(loaded.state, OutgoingPaymentState::Completed);
+    assert_eq!(loaded.receipt().sent_amount, 1000);
+}
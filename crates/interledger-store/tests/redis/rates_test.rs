@@ -1,6 +1,9 @@
 use super::store_helpers::*;
 
-use interledger_rates::ExchangeRateStore;
+use interledger_rates::{
+    AcceptedRates, AcceptedRatesStore, ExchangeRate, ExchangeRateStore, PairRate,
+};
+use std::str::FromStr;
 
 #[tokio::test]
 async fn set_rates() {
@@ -9,10 +12,12 @@ async fn set_rates() {
     assert!(rates.is_err());
     store
         .set_exchange_rates(
-            [("ABC".to_string(), 500.0), ("XYZ".to_string(), 0.005)]
-                .iter()
-                .cloned()
-                .collect(),
+            vec![
+                ("ABC".to_string(), ExchangeRate::from_str("500").unwrap()),
+                ("XYZ".to_string(), ExchangeRate::from_str("0.005").unwrap()),
+            ]
+            .into_iter()
+            .collect(),
         )
         .unwrap();
 
@@ -20,3 +25,54 @@ async fn set_rates() {
     assert_eq!(rates[0].to_string(), "0.005");
     assert_eq!(rates[1].to_string(), "500");
 }
+
+#[tokio::test]
+async fn set_pair_rates() {
+    let (store, _context, _) = test_store().await.unwrap();
+    assert_eq!(store.get_pair_rate("XRP", "EUR").unwrap(), None);
+    let pair_rate = PairRate::new(
+        ExchangeRate::from_str("0.2").unwrap(),
+        ExchangeRate::from_str("0.21").unwrap(),
+    )
+    .unwrap();
+    store
+        .set_pair_rates(
+            vec![("XRP/EUR".to_string(), pair_rate.clone())]
+                .into_iter()
+                .collect(),
+        )
+        .unwrap();
+
+    assert_eq!(store.get_pair_rate("XRP", "EUR").unwrap(), Some(pair_rate));
+    assert_eq!(store.get_pair_rate("EUR", "XRP").unwrap(), None);
+    assert_eq!(store.get_all_pair_rates().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn saves_newer_accepted_rates_only() {
+    let (store, _context, _) = test_store().await.unwrap();
+    assert_eq!(store.load_accepted_rates().await.unwrap(), None);
+    let accepted = |version, rate| AcceptedRates {
+        version,
+        rates: vec![("ABC".to_string(), ExchangeRate::from_str(rate).unwrap())]
+            .into_iter()
+            .collect(),
+        pair_rates: vec![(
+            "ABC/DEF".to_string(),
+            PairRate::mid(ExchangeRate::from_str(rate).unwrap()).unwrap(),
+        )]
+        .into_iter()
+        .collect(),
+    };
+    store.save_accepted_rates(accepted(2, "1/3")).await.unwrap();
+    store.save_accepted_rates(accepted(1, "0.5")).await.unwrap();
+    assert_eq!(
+        store.load_accepted_rates().await.unwrap(),
+        Some(accepted(2, "1/3"))
+    );
+    store.save_accepted_rates(accepted(3, "0.5")).await.unwrap();
+    assert_eq!(
+        store.load_accepted_rates().await.unwrap(),
+        Some(accepted(3, "0.5"))
+    );
+}
@@ -0,0 +1,37 @@
+use super::store_helpers::*;
+use interledger_packet::Address;
+use interledger_stream::ReceiptTotalsStore;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+#[tokio::test]
+async fn only_raises_saved_receipt_totals() {
+    let (store, _context, _) = test_store().await.unwrap();
+    let connection = Address::from_str("example.receiver.connection").unwrap();
+    assert!(store
+        .get_receipt_totals(&connection)
+        .await
+        .unwrap()
+        .is_empty());
+
+    store
+        .save_receipt_totals(&connection, &[(1, 100), (3, u64::MAX)])
+        .await
+        .unwrap();
+    store
+        .save_receipt_totals(&connection, &[(1, 99), (3, 5)])
+        .await
+        .unwrap();
+    store
+        .save_receipt_totals(&connection, &[(1, 150), (5, 7)])
+        .await
+        .unwrap();
+
+    let expected: HashMap<u64, u64> = vec![(1, 150), (3, u64::MAX), (5, 7)].into_iter().collect();
+    assert_eq!(
+        store.get_receipt_totals(&connection).await.unwrap(),
+        expected
+    );
+    let other = Address::from_str("example.receiver.other").unwrap();
+    assert!(store.get_receipt_totals(&other).await.unwrap().is_empty());
+}
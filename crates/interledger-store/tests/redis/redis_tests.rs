@@ -2,9 +2,12 @@ mod accounts_test;
 mod balances_test;
 mod btp_test;
 mod http_test;
+mod invoices_test;
 mod notifications;
+mod outgoing_payments_test;
 mod rate_limiting_test;
 mod rates_test;
+mod receipt_totals_test;
 mod routing_test;
 mod settlement_test;
 
@@ -37,6 +40,12 @@ mod fixtures {
         round_trip_time: None,
         amount_per_minute_limit: Some(1000),
         packets_per_minute_limit: Some(2),
+        amount_burst_limit: None,
+        packets_burst_limit: None,
+        receive_max_per_connection: None,
+        receive_max_per_period: None,
+        receive_period: None,
+        receive_min_per_packet: None,
         settlement_engine_url: Some("http://settlement.example".to_string()),
     });
     pub static ACCOUNT_DETAILS_1: Lazy<AccountDetails> = Lazy::new(|| AccountDetails {
@@ -59,6 +68,12 @@ mod fixtures {
         round_trip_time: None,
         amount_per_minute_limit: Some(1000),
         packets_per_minute_limit: Some(20),
+        amount_burst_limit: None,
+        packets_burst_limit: None,
+        receive_max_per_connection: None,
+        receive_max_per_period: None,
+        receive_period: None,
+        receive_min_per_packet: None,
         settlement_engine_url: None,
     });
     pub static ACCOUNT_DETAILS_2: Lazy<AccountDetails> = Lazy::new(|| AccountDetails {
@@ -80,6 +95,12 @@ mod fixtures {
         round_trip_time: None,
         amount_per_minute_limit: None,
         packets_per_minute_limit: None,
+        amount_burst_limit: None,
+        packets_burst_limit: None,
+        receive_max_per_connection: None,
+        receive_max_per_period: None,
+        receive_period: None,
+        receive_min_per_packet: None,
         settlement_engine_url: None,
     });
 }
@@ -51,6 +51,12 @@ async fn polls_for_route_updates() {
             round_trip_time: None,
             amount_per_minute_limit: None,
             packets_per_minute_limit: None,
+            amount_burst_limit: None,
+            packets_burst_limit: None,
+            receive_max_per_connection: None,
+            receive_max_per_period: None,
+            receive_period: None,
+            receive_min_per_packet: None,
             settlement_engine_url: None,
         })
         .await
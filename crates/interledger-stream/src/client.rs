@@ -1,13 +1,18 @@
-use super::congestion::CongestionController;
+use super::congestion::{CongestionControl, CongestionController, CongestionStrategy};
 use super::crypto::*;
 use super::error::Error;
 use super::packet::*;
+use super::receipt::Receipt;
+use super::server::split_amount;
+use async_trait::async_trait;
 use bytes::Bytes;
 use bytes::BytesMut;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::stream::{FuturesUnordered, StreamExt};
+use interledger_errors::OutgoingPaymentStoreError;
 use interledger_packet::{
     Address, ErrorClass, ErrorCode as IlpErrorCode, PacketType as IlpPacketType, PrepareBuilder,
-    Reject,
+    Reject, RejectBuilder,
 };
 use interledger_rates::ExchangeRateStore;
 use interledger_service::*;
@@ -18,20 +23,23 @@ use num::traits::ops::checked::CheckedDiv;
 use num::traits::pow::pow;
 use num::BigInt;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
 use tokio::time::timeout_at;
 use tokio::time::{Duration, Instant};
 use tracing::{debug, error, warn};
 
 use std::cmp::{max, min};
+use std::future::Future;
 use std::marker::{Send, Sync};
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::SystemTime;
 
 /// Maximum time we should wait since last fulfill before we error out to avoid
 /// getting into an infinite loop of sending packets and effectively DoSing ourselves
-const MAX_TIME_SINCE_LAST_FULFILL: Duration = Duration::from_secs(30);
+pub(crate) const MAX_TIME_SINCE_LAST_FULFILL: Duration = Duration::from_secs(30);
 
 /// Minimum number of packet attempts before defaulting to failure rate
 const FAIL_FAST_MINIMUM_PACKET_ATTEMPTS: u64 = 200;
@@ -39,6 +47,10 @@ const FAIL_FAST_MINIMUM_PACKET_ATTEMPTS: u64 = 200;
 /// Minimum rate of rejected packets in order to terminate the payment
 const FAIL_FAST_MINIMUM_FAILURE_RATE: f64 = 0.99;
 
+/// Highest stream ID we open until the recipient tells us its limit. This allows
+/// 10 streams, like the default of other STREAM implementations.
+const DEFAULT_MAX_STREAM_ID: u64 = 20;
+
 /// Receipt for STREAM payment to account for how much and what assets were sent & delivered
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct StreamDelivery {
@@ -50,8 +62,13 @@ pub struct StreamDelivery {
     pub source_asset_scale: u8,
     /// Asset code of sender
     pub source_asset_code: String,
-    /// Total amount *intended* to be sent, in source units
+    /// Total amount *intended* to be sent, in source units.
+    /// For fixed-delivery payments this is the most we are willing to send, known after
+    /// the exchange rate was probed.
     pub source_amount: u64,
+    /// Amount the receiver should get in fixed-delivery payments, in destination units
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination_amount: Option<u64>,
     /// Amount fulfilled or currently in-flight, in source units
     pub sent_amount: u64,
     /// Amount in-flight (yet to be fulfilled or rejected), in source units
@@ -64,6 +81,10 @@ pub struct StreamDelivery {
     /// Receiver's asset code
     /// Updated after we received a `ConnectionAssetDetails` frame.
     pub destination_asset_code: Option<String>,
+    /// Base64-encoded [STREAM receipt](https://interledger.org/rfcs/0039-stream-receipts/) with
+    /// the highest total, if the receiver signs receipts for this connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<String>,
 }
 
 impl StreamDelivery {
@@ -74,21 +95,136 @@ impl StreamDelivery {
             source_asset_scale: from_account.asset_scale(),
             source_asset_code: from_account.asset_code().to_string(),
             source_amount,
+            destination_amount: None,
             sent_amount: 0,
             in_flight_amount: 0,
             destination_asset_scale: None,
             destination_asset_code: None,
             delivered_amount: 0,
+            receipt: None,
+        }
+    }
+
+    /// Keeps the receipt if it attests to a higher total than the one we have
+    fn apply_receipt(&mut self, receipt: &[u8]) {
+        let total_received = |receipt: &[u8]| {
+            Receipt::from_bytes(receipt)
+                .map(|receipt| receipt.total_received)
+                .ok()
+        };
+        let new_total = match total_received(receipt) {
+            Some(total) => total,
+            None => {
+                warn!("Ignoring invalid STREAM receipt: {:?}", receipt);
+                return;
+            }
+        };
+        let current_total = self
+            .receipt
+            .as_ref()
+            .and_then(|current| base64::decode(current).ok())
+            .and_then(|current| total_received(&current));
+        if current_total.map(|total| new_total > total).unwrap_or(true) {
+            self.receipt = Some(base64::encode(receipt));
+        }
+    }
+}
+
+/// Range the exchange rate to the receiver is known to be in, from the amounts the receiver
+/// reported for our packets. The rate is in destination units per source unit.
+struct RateBounds {
+    /// Lower bound (inclusive)
+    lower: BigRational,
+    /// Upper bound (exclusive), unknown until the receiver replied to a packet
+    upper: Option<BigRational>,
+}
+
+impl Default for RateBounds {
+    fn default() -> Self {
+        RateBounds {
+            lower: BigRational::zero(),
+            upper: None,
+        }
+    }
+}
+
+impl RateBounds {
+    /// Narrows the bounds with a packet of the source amount for which the receiver got
+    /// the destination amount. Connectors round down, so the rate is at least
+    /// `received / sent` and less than `(received + 1) / sent`.
+    fn update(&mut self, source_amount: u64, destination_amount: u64) {
+        let lower = BigRational::new(destination_amount.into(), source_amount.into());
+        let upper = BigRational::new(
+            BigInt::from(destination_amount) + 1,
+            BigInt::from(source_amount),
+        );
+        match &self.upper {
+            // If the rate moved, the packets we saw before no longer tell us anything
+            Some(current_upper) if lower < *current_upper && upper > self.lower => {
+                self.lower = max(self.lower.clone(), lower);
+                self.upper = Some(min(current_upper.clone(), upper));
+            }
+            _ => {
+                self.lower = lower;
+                self.upper = Some(upper);
+            }
         }
     }
 }
 
+/// Approximate the rate as a float, for reporting it
+fn rate_to_f64(rate: &BigRational) -> f64 {
+    rate.numer().to_f64().unwrap_or(0.0) / rate.denom().to_f64().unwrap_or(1.0)
+}
+
+/// State of a payment which delivers a fixed amount to the receiver
+struct FixedDelivery {
+    /// Amount the receiver should get, in destination units
+    destination_amount: u64,
+    /// Minimum acceptable rate, computed from our exchange rates and the slippage
+    /// once we know the receiver's asset
+    min_rate: Option<BigRational>,
+    /// Source amount of the next probe, which grows while too little arrives to tell the rate
+    probe_amount: u64,
+    /// How much more the receiver is willing to accept, per its last StreamMaxMoney frame
+    receive_max: Option<u64>,
+}
+
+/// One of several amounts sent on its own stream of a connection
+struct MoneyStream {
+    /// Stream ID, odd like every stream opened by the client
+    id: u64,
+    /// Amounts sent & delivered on this stream, with its STREAM receipt
+    receipt: StreamDelivery,
+    /// Did the recipient fulfill a packet telling it that the stream is closed?
+    closed: bool,
+}
+
+impl MoneyStream {
+    /// Amount of the stream which is neither fulfilled nor in flight
+    fn get_amount_available_to_send(&self) -> u64 {
+        self.receipt
+            .source_amount
+            .saturating_sub(self.receipt.sent_amount)
+    }
+
+    /// Was the full amount of the stream fulfilled?
+    fn is_complete(&self) -> bool {
+        self.receipt
+            .sent_amount
+            .saturating_sub(self.receipt.in_flight_amount)
+            >= self.receipt.source_amount
+    }
+}
+
 /// Stream payment mutable state: amounts & assets sent and received, sequence, packet counts, and flow control parameters
 struct StreamPayment {
-    /// The [congestion controller](./../congestion/struct.CongestionController.html) to adjust flow control and the in-flight amount
-    congestion_controller: CongestionController,
+    /// The [congestion controller](./../congestion/trait.CongestionControl.html) to adjust flow control and the in-flight amount
+    congestion_controller: Box<dyn CongestionControl>,
     /// The [StreamDelivery](./struct.StreamDelivery.html) receipt to account for the delivered amounts
     receipt: StreamDelivery,
+    /// Exchange rate to the receiver, as observed from its replies
+    rate: RateBounds,
     /// Do we need to send our source account information to the recipient?
     should_send_source_account: bool,
     /// Monotonically increaing sequence number for this STREAM payment
@@ -101,13 +237,234 @@ struct StreamPayment {
     fail_fast_rejects: u64,
     /// Timestamp when a packet was last fulfilled for this payment
     last_fulfill_time: Instant,
+    /// Set for payments which deliver a fixed destination amount instead of sending a fixed source amount
+    fixed_delivery: Option<FixedDelivery>,
+    /// Where to report the outcome of each packet, for payments started with a [`PaymentHandle`](./struct.PaymentHandle.html)
+    progress: Option<UnboundedSender<PaymentProgress>>,
+    /// Set once the recipient closed the connection, such as when it reached a receive limit
+    receiver_closed: Option<String>,
+    /// Streams of a payment sending several amounts at once. If empty, the whole payment
+    /// is sent on stream 1.
+    streams: Vec<MoneyStream>,
+    /// Highest stream ID the recipient lets us open
+    max_stream_id: u64,
+    /// Set when resumed from a checkpoint, to the amount which was in flight when it was
+    /// taken. The receiver needs to be asked what it got before sending anything else.
+    resumed: Option<u64>,
+    /// Where the checkpoints of a resumable payment are saved
+    checkpoints: Option<Arc<dyn PaymentCheckpointStore>>,
+    /// Set once a checkpoint could not be saved, which stops the payment
+    checkpoint_error: Option<String>,
 }
 
 impl StreamPayment {
+    /// New payment of the given amount to the destination, not yet started
+    fn new<A: Account>(
+        from_account: &A,
+        destination_account: Address,
+        amount: PaymentAmount,
+        options: SendMoneyOptions,
+    ) -> Self {
+        let mut congestion_controller = options.congestion_control.build();
+        let (receipt, fixed_delivery) = match amount {
+            PaymentAmount::FixedSource(source_amount) => {
+                congestion_controller.set_payment_amount(source_amount);
+                (
+                    StreamDelivery::new(from_account, destination_account, source_amount),
+                    None,
+                )
+            }
+            PaymentAmount::FixedDelivery(destination_amount) => {
+                // The payment amount is set once the probed rate tells us how much we will send
+                let mut receipt = StreamDelivery::new(from_account, destination_account, 0);
+                receipt.destination_amount = Some(destination_amount);
+                let fixed_delivery = FixedDelivery {
+                    destination_amount,
+                    min_rate: None,
+                    // Start by probing with one unit of the source asset
+                    probe_amount: 10u64.saturating_pow(u32::from(from_account.asset_scale())),
+                    receive_max: None,
+                };
+                (receipt, Some(fixed_delivery))
+            }
+        };
+        StreamPayment {
+            congestion_controller,
+            receipt,
+            rate: RateBounds::default(),
+            should_send_source_account: true,
+            sequence: 1,
+            fulfilled_packets: 0,
+            rejected_packets: 0,
+            fail_fast_rejects: 0,
+            last_fulfill_time: Instant::now(),
+            fixed_delivery,
+            progress: None,
+            receiver_closed: None,
+            streams: Vec::new(),
+            max_stream_id: DEFAULT_MAX_STREAM_ID,
+            resumed: None,
+            checkpoints: None,
+            checkpoint_error: None,
+        }
+    }
+
+    /// Send the payment as the given amounts, each on its own stream, instead of on stream 1.
+    /// The amounts must add up to the source amount of the payment.
+    fn set_streams(&mut self, amounts: &[u64]) {
+        let receipt = &self.receipt;
+        self.streams = amounts
+            .iter()
+            .zip((1..).step_by(2))
+            .map(|(amount, id)| MoneyStream {
+                id,
+                receipt: StreamDelivery {
+                    source_amount: *amount,
+                    ..receipt.clone()
+                },
+                closed: false,
+            })
+            .collect();
+    }
+
+    /// Split the source amount of a packet between the streams which can take more money,
+    /// filling them in the order of their IDs, and account for it.
+    /// Returns the amount for each stream.
+    fn allocate_to_streams(&mut self, source_amount: u64) -> Vec<(u64, u64)> {
+        let max_stream_id = self.max_stream_id;
+        let mut amount_left = source_amount;
+        let mut allocations = Vec::new();
+        for stream in self
+            .streams
+            .iter_mut()
+            .filter(|stream| stream.id <= max_stream_id)
+        {
+            let amount = min(amount_left, stream.get_amount_available_to_send());
+            if amount > 0 {
+                stream.receipt.sent_amount += amount;
+                stream.receipt.in_flight_amount += amount;
+                allocations.push((stream.id, amount));
+                amount_left -= amount;
+            }
+        }
+        allocations
+    }
+
+    /// Account for a fulfilled packet on the streams its amount was split between.
+    /// The recipient splits the amount it got in proportion to the amounts, as we do here.
+    fn apply_streams_fulfill(&mut self, allocations: &[(u64, u64)], destination_amount: u64) {
+        let delivered = split_amount(destination_amount, allocations);
+        for ((stream_id, source_amount), (_, destination_amount)) in
+            allocations.iter().zip(delivered)
+        {
+            if let Some(stream) = self.streams.iter_mut().find(|s| s.id == *stream_id) {
+                stream.receipt.in_flight_amount = stream
+                    .receipt
+                    .in_flight_amount
+                    .saturating_sub(*source_amount);
+                stream.receipt.delivered_amount = stream
+                    .receipt
+                    .delivered_amount
+                    .saturating_add(destination_amount);
+            }
+        }
+    }
+
+    /// Account for a rejected packet on the streams its amount was split between
+    fn apply_streams_reject(&mut self, allocations: &[(u64, u64)]) {
+        for (stream_id, source_amount) in allocations {
+            if let Some(stream) = self.streams.iter_mut().find(|s| s.id == *stream_id) {
+                stream.receipt.sent_amount =
+                    stream.receipt.sent_amount.saturating_sub(*source_amount);
+                stream.receipt.in_flight_amount = stream
+                    .receipt
+                    .in_flight_amount
+                    .saturating_sub(*source_amount);
+            }
+        }
+    }
+
+    /// First stream with money left to send whose ID is above the recipient's limit
+    fn get_blocked_stream_id(&self) -> Option<u64> {
+        self.streams
+            .iter()
+            .find(|stream| {
+                stream.id > self.max_stream_id && stream.get_amount_available_to_send() > 0
+            })
+            .map(|stream| stream.id)
+    }
+
+    /// Stream which cannot be opened because of the recipient's limit, if nothing else
+    /// can be sent until it is
+    fn get_blocking_stream_id(&self) -> Option<u64> {
+        if self.receipt.in_flight_amount == 0 && self.get_amount_available_to_send() == 0 {
+            self.get_blocked_stream_id()
+        } else {
+            None
+        }
+    }
+
+    /// Pick up where an interrupted payment left off, counting the packets which were
+    /// in flight as sent but not delivered until the receiver tells us what it got
+    fn resume(&mut self, checkpoint: PaymentCheckpoint) {
+        let PaymentCheckpoint {
+            receipt,
+            next_sequence,
+        } = checkpoint;
+        self.receipt.sent_amount = receipt.sent_amount;
+        self.receipt.delivered_amount = receipt.delivered_amount;
+        self.receipt.destination_asset_scale = receipt.destination_asset_scale;
+        self.receipt.destination_asset_code = receipt.destination_asset_code;
+        self.receipt.receipt = receipt.receipt;
+        self.sequence = max(self.sequence, next_sequence);
+        self.resumed = Some(receipt.in_flight_amount);
+        if self.fixed_delivery.is_none() {
+            self.congestion_controller
+                .set_payment_amount(self.get_amount_available_to_send());
+        }
+    }
+
+    /// Where the payment is at
+    fn checkpoint(&self) -> PaymentCheckpoint {
+        PaymentCheckpoint {
+            receipt: self.receipt.clone(),
+            next_sequence: self.sequence,
+        }
+    }
+
+    /// Save where the payment is at, if it has a checkpoint store. The returned future
+    /// does not borrow the payment, which is not `Sync`.
+    fn save_checkpoint(&self) -> impl Future<Output = Result<(), OutgoingPaymentStoreError>> {
+        let checkpoints = self.checkpoints.clone();
+        let checkpoint = self.checkpoint();
+        async move {
+            match checkpoints {
+                Some(checkpoints) => checkpoints.save_payment_checkpoint(checkpoint).await,
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// Account for the total the receiver reported, which includes the packets
+    /// that were delivered after the checkpoint was taken
+    fn apply_total_received(&mut self, total_received: u64) {
+        if total_received > self.receipt.delivered_amount {
+            debug!(
+                "Receiver got {} rather than the {} of the checkpoint",
+                total_received, self.receipt.delivered_amount
+            );
+            self.receipt.delivered_amount = total_received;
+        }
+    }
+
     /// Determine amount to load in next Prepare and account for it.
     /// Return the source packet amount and minimum destination amount
     #[inline]
     fn apply_prepare<S: ExchangeRateStore>(&mut self, store: &S, slippage: f64) -> (u64, u64) {
+        if self.fixed_delivery.is_some() {
+            return self.apply_fixed_delivery_prepare();
+        }
+
         // Determine scaled rate with slippage used for enforcing minimum destination amount
         // and computing its corresponding minimum source amount,
         // where source_amount * scaled_rate = dest_amount.
@@ -174,16 +531,212 @@ impl StreamPayment {
         // (1) Amount available to send, subtracting fulfilled and in-fligth amounts
         source_amount = min(source_amount, self.get_amount_available_to_send());
 
-        // Account for the prepare
-        self.congestion_controller.prepare(source_amount);
-        self.receipt.sent_amount = self.receipt.sent_amount.saturating_add(source_amount);
-        self.receipt.in_flight_amount = self.receipt.in_flight_amount.saturating_add(source_amount);
+        self.account_for_prepare(source_amount);
 
         // Compute the minimum destination amount using the same rate
         let min_destination_amount = convert(source_amount, rate).unwrap_or(0);
         (source_amount, min_destination_amount)
     }
 
+    /// Determine the amount of the next Prepare of a fixed-delivery payment, based on the
+    /// probed exchange rate, and account for it.
+    /// Return the source packet amount and minimum destination amount
+    fn apply_fixed_delivery_prepare(&mut self) -> (u64, u64) {
+        let (rate, min_rate) = match &self.fixed_delivery {
+            Some(fixed) => (
+                self.rate.lower.clone(),
+                fixed.min_rate.clone().unwrap_or_else(BigRational::zero),
+            ),
+            None => return (0, 0),
+        };
+
+        // Source amount which delivers the rest of the payment at the probed rate.
+        // Rounding up may deliver a fraction of a unit more than the target,
+        // but never less since connectors round down.
+        let amount_for_rest = BigRational::from_u64(self.get_destination_amount_left_to_send())
+            .and_then(|amount| amount.checked_div(&rate))
+            .and_then(|amount| amount.ceil().to_integer().to_u64())
+            .unwrap_or(u64::MAX);
+
+        let source_amount = min(
+            min(
+                amount_for_rest,
+                self.congestion_controller.get_amount_left_in_window(),
+            ),
+            min(
+                self.congestion_controller.get_max_packet_amount(),
+                self.get_amount_available_to_send(),
+            ),
+        );
+
+        self.account_for_prepare(source_amount);
+
+        // Round down, since the receiver must only get at least the amount the minimum rate allows.
+        // The packet needs a non-zero minimum, otherwise it would be unfulfillable.
+        let min_destination_amount = BigRational::from_u64(source_amount)
+            .map(|amount| amount * min_rate)
+            .and_then(|amount| amount.floor().to_integer().to_u64())
+            .unwrap_or(0);
+        (source_amount, max(min_destination_amount, 1))
+    }
+
+    /// Determine the amount of the next unfulfillable Prepare which probes the exchange rate
+    /// of a fixed-delivery payment, and account for it.
+    fn apply_probe(&mut self) -> (u64, u64) {
+        let probe_amount = self
+            .fixed_delivery
+            .as_ref()
+            .map(|fixed| fixed.probe_amount)
+            .unwrap_or(0);
+        let source_amount = min(
+            probe_amount,
+            self.congestion_controller.get_max_packet_amount(),
+        );
+        self.account_for_prepare(source_amount);
+        // A zero minimum destination amount makes the packet unfulfillable
+        (source_amount, 0)
+    }
+
+    /// Account for a Prepare of the given source amount
+    #[inline]
+    fn account_for_prepare(&mut self, source_amount: u64) {
+        self.congestion_controller.prepare(source_amount);
+        self.receipt.sent_amount = self.receipt.sent_amount.saturating_add(source_amount);
+        self.receipt.in_flight_amount = self.receipt.in_flight_amount.saturating_add(source_amount);
+    }
+
+    /// Narrow down the exchange rate with the amount the receiver got for a packet, and grow the
+    /// probe amount of a fixed-delivery payment if it was too small to get anything through
+    fn apply_exchange_rate(&mut self, source_amount: u64, destination_amount: u64) {
+        if source_amount > 0 {
+            self.rate.update(source_amount, destination_amount);
+        }
+        if let Some(fixed) = self.fixed_delivery.as_mut() {
+            if destination_amount == 0 && source_amount >= fixed.probe_amount {
+                fixed.probe_amount = source_amount.saturating_mul(10);
+            }
+        }
+    }
+
+    /// Save how much more the receiver is willing to accept
+    fn set_receive_max(&mut self, receive_max: u64, total_received: u64) {
+        if let Some(fixed) = self.fixed_delivery.as_mut() {
+            fixed.receive_max = Some(receive_max.saturating_sub(total_received));
+        }
+    }
+
+    /// Is a fixed-delivery payment still probing the exchange rate?
+    #[inline]
+    fn is_probing(&self) -> bool {
+        self.fixed_delivery.is_some() && self.rate.lower.is_zero()
+    }
+
+    /// Check that a fixed-delivery payment can still deliver its amount at an acceptable rate.
+    ///
+    /// Once the exchange rate was probed, this also fixes the maximum source amount of the
+    /// payment, so that we never send more than the minimum rate allows.
+    fn check_fixed_delivery<S: ExchangeRateStore>(
+        &mut self,
+        store: &S,
+        slippage: f64,
+    ) -> Result<(), Error> {
+        let (rate_upper, has_min_rate) = match &self.fixed_delivery {
+            Some(fixed) => (self.rate.upper.clone(), fixed.min_rate.is_some()),
+            None => return Ok(()),
+        };
+
+        if self.is_probing() {
+            // If even the largest packet we can send delivers less than a unit,
+            // the payment cannot get anything through
+            let max_packet_amount =
+                BigRational::from_u64(self.congestion_controller.get_max_packet_amount());
+            return match (rate_upper, max_packet_amount) {
+                (Some(upper), Some(max_packet_amount))
+                    if &upper * &max_packet_amount <= BigRational::one() =>
+                {
+                    Err(Error::ExchangeRateTooLow)
+                }
+                _ => Ok(()),
+            };
+        }
+
+        if !has_min_rate {
+            let min_rate = get_rate(
+                store,
+                self.receipt.source_asset_scale,
+                &self.receipt.source_asset_code,
+                self.receipt.destination_asset_scale,
+                self.receipt.destination_asset_code.as_deref(),
+                slippage,
+            )
+            .filter(|rate| !rate.is_zero())
+            .ok_or(Error::UnknownExchangeRate)?;
+
+            let destination_amount = self.get_destination_amount_left_to_send();
+            let max_source_amount = BigRational::from_u64(destination_amount)
+                .and_then(|amount| amount.checked_div(&min_rate))
+                .and_then(|amount| amount.ceil().to_integer().to_u64())
+                .unwrap_or(u64::MAX);
+            debug!(
+                "Probed exchange rate, sending at most {} to deliver {}",
+                max_source_amount, destination_amount
+            );
+            // A resumed payment may have sent some already
+            self.receipt.source_amount = self.receipt.sent_amount.saturating_add(max_source_amount);
+            self.congestion_controller
+                .set_payment_amount(max_source_amount);
+            if let Some(fixed) = self.fixed_delivery.as_mut() {
+                fixed.min_rate = Some(min_rate);
+            }
+        }
+
+        let fixed = match &self.fixed_delivery {
+            Some(fixed) => fixed,
+            None => return Ok(()),
+        };
+        if let (Some(upper), Some(min_rate)) = (&self.rate.upper, &fixed.min_rate) {
+            if upper <= min_rate {
+                return Err(Error::ExchangeRateTooLow);
+            }
+        }
+
+        let amount_left = fixed
+            .destination_amount
+            .saturating_sub(self.receipt.delivered_amount);
+        if let Some(receive_max) = fixed.receive_max {
+            if receive_max < amount_left {
+                return Err(Error::ReceiveMaxExceeded(receive_max));
+            }
+        }
+
+        // We sent as much as the minimum rate allows, but the receiver did not get enough
+        if amount_left > 0
+            && self.receipt.in_flight_amount == 0
+            && self.get_amount_available_to_send() == 0
+        {
+            return Err(Error::ExchangeRateTooLow);
+        }
+
+        Ok(())
+    }
+
+    /// Amount the receiver still needs to get in a fixed-delivery payment, less the estimated
+    /// destination amount of the packets in flight, in destination units
+    fn get_destination_amount_left_to_send(&self) -> u64 {
+        let fixed = match &self.fixed_delivery {
+            Some(fixed) => fixed,
+            None => return 0,
+        };
+        let in_flight = BigRational::from_u64(self.receipt.in_flight_amount)
+            .map(|amount| amount * self.rate.lower.clone())
+            .and_then(|amount| amount.floor().to_integer().to_u64())
+            .unwrap_or(u64::MAX);
+        fixed
+            .destination_amount
+            .saturating_sub(self.receipt.delivered_amount)
+            .saturating_sub(in_flight)
+    }
+
     /// Account for a fulfilled packet and update flow control
     #[inline]
     fn apply_fulfill(&mut self, source_amount: u64, destination_amount: u64) {
@@ -197,6 +750,11 @@ impl StreamPayment {
 
         self.last_fulfill_time = Instant::now();
         self.fulfilled_packets += 1;
+
+        self.report_progress(
+            source_amount,
+            PacketOutcome::Fulfilled { destination_amount },
+        );
     }
 
     /// Account for a rejected packet and update flow control
@@ -220,6 +778,33 @@ impl StreamPayment {
         if apply_to_fail_fast {
             self.fail_fast_rejects += 1;
         }
+
+        self.report_progress(
+            amount,
+            PacketOutcome::Rejected {
+                code: reject.code().to_string(),
+            },
+        );
+    }
+
+    /// Tell the holder of the payment's handle, if any, what happened to a packet
+    fn report_progress(&self, source_amount: u64, packet: PacketOutcome) {
+        if let Some(progress) = &self.progress {
+            let exchange_rate = Some(&self.rate.lower)
+                .filter(|rate| !rate.is_zero())
+                .map(rate_to_f64);
+            // The handle may have been dropped, which is not a reason to stop paying
+            let _ = progress.unbounded_send(PaymentProgress {
+                packet,
+                source_amount,
+                sent_amount: self.receipt.sent_amount,
+                in_flight_amount: self.receipt.in_flight_amount,
+                delivered_amount: self.receipt.delivered_amount,
+                fulfilled_packets: self.fulfilled_packets,
+                rejected_packets: self.rejected_packets,
+                exchange_rate,
+            });
+        }
     }
 
     /// Save the recipient's destination asset details for calculating minimum exchange rates
@@ -237,66 +822,692 @@ impl StreamPayment {
         seq
     }
 
-    /// Amount of money fulfilled in source units
-    #[inline]
-    fn get_fulfilled_amount(&self) -> u64 {
-        self.receipt
-            .sent_amount
-            .saturating_sub(self.receipt.in_flight_amount)
+    /// Amount of money fulfilled in source units
+    #[inline]
+    fn get_fulfilled_amount(&self) -> u64 {
+        self.receipt
+            .sent_amount
+            .saturating_sub(self.receipt.in_flight_amount)
+    }
+
+    // Get remaining amount that must be fulfilled for the payment to complete
+    #[inline]
+    fn get_remaining_amount(&self) -> u64 {
+        self.receipt
+            .source_amount
+            .saturating_sub(self.get_fulfilled_amount())
+    }
+
+    /// Has the entire intended source amount been fulfilled by the recipient?
+    /// (Or, for fixed-delivery payments, has the recipient received the destination amount?)
+    #[inline]
+    fn is_complete(&self) -> bool {
+        match &self.fixed_delivery {
+            Some(fixed) => self.receipt.delivered_amount >= fixed.destination_amount,
+            None => self.get_remaining_amount() == 0,
+        }
+    }
+
+    /// Return the amount of money available to be sent in the payment (amount remaining minus in-flight)
+    #[inline]
+    fn get_amount_available_to_send(&self) -> u64 {
+        // Sent amount also includes the amount in-flight, which should be subtracted from the amount available
+        let available = self
+            .receipt
+            .source_amount
+            .saturating_sub(self.receipt.sent_amount);
+        if self.streams.is_empty() {
+            return available;
+        }
+        // Streams above the recipient's limit have to wait until it raises the limit
+        let available_on_open_streams = self
+            .streams
+            .iter()
+            .filter(|stream| stream.id <= self.max_stream_id)
+            .map(MoneyStream::get_amount_available_to_send)
+            .sum();
+        min(available, available_on_open_streams)
+    }
+
+    /// Is as much money as possible in-flight?
+    /// (If so, the intended source amount may be fulfilled or in-flight, or the congestion controller
+    /// has temporarily limited sending more money)
+    #[inline]
+    fn is_max_in_flight(&self) -> bool {
+        if self.is_probing() {
+            // Probe one packet at a time
+            return self.receipt.in_flight_amount > 0;
+        }
+        self.congestion_controller.get_amount_left_in_window() == 0
+            || self.get_amount_available_to_send() == 0
+            || (self.fixed_delivery.is_some() && self.get_destination_amount_left_to_send() == 0)
+    }
+
+    /// Given we've attempted sending enough packets, does the rate of rejects
+    /// that count towards fail-fast indicate the payment is failing?
+    #[inline]
+    fn is_failing(&self) -> bool {
+        let num_packets = self.fulfilled_packets + self.rejected_packets;
+        num_packets >= FAIL_FAST_MINIMUM_PACKET_ATTEMPTS
+            && (self.fail_fast_rejects as f64 / num_packets as f64) > FAIL_FAST_MINIMUM_FAILURE_RATE
+    }
+}
+
+/// Send the given source amount with packetized Interledger payments using the STREAM transport protocol
+/// Returns the receipt with sent & delivered amounts, asset & account details
+pub async fn send_money<I, A, S>(
+    service: I,
+    from_account: &A,
+    store: S,
+    destination_account: Address,
+    shared_secret: Vec<u8>,
+    source_amount: u64,
+    slippage: f64,
+) -> Result<StreamDelivery, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    send_money_with_options(
+        service,
+        from_account,
+        store,
+        destination_account,
+        shared_secret,
+        source_amount,
+        slippage,
+        SendMoneyOptions::default(),
+    )
+    .await
+}
+
+/// Options for sending STREAM payments
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SendMoneyOptions {
+    /// How to split the payment into packets and how much to have in flight at once
+    #[serde(default)]
+    pub congestion_control: CongestionStrategy,
+}
+
+/// Send the given source amount like [`send_money`](./fn.send_money.html), with the given options
+#[allow(clippy::too_many_arguments)]
+pub async fn send_money_with_options<I, A, S>(
+    service: I,
+    from_account: &A,
+    store: S,
+    destination_account: Address,
+    shared_secret: Vec<u8>,
+    source_amount: u64,
+    slippage: f64,
+    options: SendMoneyOptions,
+) -> Result<StreamDelivery, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    let payment = StreamPayment::new(
+        from_account,
+        destination_account,
+        PaymentAmount::FixedSource(source_amount),
+        options,
+    );
+    run_payment(
+        service,
+        from_account,
+        store,
+        shared_secret,
+        slippage,
+        Arc::new(Mutex::new(payment)),
+        PaymentCanceller::default(),
+    )
+    .await
+}
+
+/// Send several amounts to the same receiver, each on its own stream of one STREAM connection,
+/// like [`send_money_with_options`](./fn.send_money_with_options.html).
+///
+/// The streams share the connection's packets, congestion window and exchange rate, so a batch of
+/// payouts to one receiver only needs a single connection. The amounts are sent on stream IDs
+/// 1, 3, 5 and so on, opening only as many streams at once as the receiver allows.
+///
+/// Returns a receipt for each amount, in the same order, with the amounts sent & delivered
+/// on its stream and the stream's STREAM receipt.
+#[allow(clippy::too_many_arguments)]
+pub async fn send_money_streams<I, A, S>(
+    service: I,
+    from_account: &A,
+    store: S,
+    destination_account: Address,
+    shared_secret: Vec<u8>,
+    amounts: &[u64],
+    slippage: f64,
+    options: SendMoneyOptions,
+) -> Result<Vec<StreamDelivery>, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    let source_amount = amounts
+        .iter()
+        .try_fold(0u64, |total, amount| total.checked_add(*amount))
+        .ok_or(Error::AmountOverflow)?;
+    let mut payment = StreamPayment::new(
+        from_account,
+        destination_account,
+        PaymentAmount::FixedSource(source_amount),
+        options,
+    );
+    payment.set_streams(amounts);
+    let payment = Arc::new(Mutex::new(payment));
+    let receipt = run_payment(
+        service,
+        from_account,
+        store,
+        shared_secret,
+        slippage,
+        payment.clone(),
+        PaymentCanceller::default(),
+    )
+    .await?;
+
+    let payment = payment.lock().await;
+    Ok(payment
+        .streams
+        .iter()
+        .map(|stream| StreamDelivery {
+            from: receipt.from.clone(),
+            to: receipt.to.clone(),
+            destination_asset_scale: receipt.destination_asset_scale,
+            destination_asset_code: receipt.destination_asset_code.clone(),
+            ..stream.receipt.clone()
+        })
+        .collect())
+}
+
+/// Send packetized Interledger payments using the STREAM transport protocol until the receiver got
+/// exactly the given destination amount.
+///
+/// The exchange rate is first probed with unfulfillable packets, which also tells us the receiver's
+/// asset. We never send more than the destination amount is worth at the minimum acceptable rate
+/// (our exchange rate minus the slippage): if the rate drops below it, the payment fails without
+/// delivering more than the receiver got up to then.
+/// Returns the receipt with sent & delivered amounts, asset & account details
+#[allow(clippy::too_many_arguments)]
+pub async fn send_money_fixed_delivery<I, A, S>(
+    service: I,
+    from_account: &A,
+    store: S,
+    destination_account: Address,
+    shared_secret: Vec<u8>,
+    destination_amount: u64,
+    slippage: f64,
+    options: SendMoneyOptions,
+) -> Result<StreamDelivery, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    let payment = StreamPayment::new(
+        from_account,
+        destination_account,
+        PaymentAmount::FixedDelivery(destination_amount),
+        options,
+    );
+    run_payment(
+        service,
+        from_account,
+        store,
+        shared_secret,
+        slippage,
+        Arc::new(Mutex::new(payment)),
+        PaymentCanceller::default(),
+    )
+    .await
+}
+
+/// Amount of a payment started with [`start_payment`](./fn.start_payment.html)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentAmount {
+    /// Send the given amount, in source units, like [`send_money`](./fn.send_money.html)
+    FixedSource(u64),
+    /// Deliver the given amount, in destination units, like
+    /// [`send_money_fixed_delivery`](./fn.send_money_fixed_delivery.html)
+    FixedDelivery(u64),
+}
+
+/// What happened to a packet of a payment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PacketOutcome {
+    /// The packet was fulfilled and the receiver got the given amount, in destination units
+    Fulfilled { destination_amount: u64 },
+    /// The packet was rejected with the given ILP error code
+    Rejected { code: String },
+}
+
+/// Progress of a payment started with [`start_payment`](./fn.start_payment.html),
+/// reported each time one of its packets is fulfilled or rejected
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaymentProgress {
+    /// What happened to the packet
+    pub packet: PacketOutcome,
+    /// Amount of the packet, in source units
+    pub source_amount: u64,
+    /// Amount fulfilled or in-flight so far, in source units
+    pub sent_amount: u64,
+    /// Amount in-flight, in source units
+    pub in_flight_amount: u64,
+    /// Amount the receiver got so far, in destination units
+    pub delivered_amount: u64,
+    /// Number of packets fulfilled so far
+    pub fulfilled_packets: u64,
+    /// Number of packets rejected so far
+    pub rejected_packets: u64,
+    /// Exchange rate to the receiver observed so far, in destination units per source unit,
+    /// once the receiver told us what it got
+    pub exchange_rate: Option<f64>,
+}
+
+/// Where a payment is at, which is enough to resume it on the same connection with
+/// [`resume_payment`](./fn.resume_payment.html), e.g. after a restart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaymentCheckpoint {
+    /// Receipt with the amounts sent & delivered so far
+    pub receipt: StreamDelivery,
+    /// Sequence number of the next packet on the connection
+    pub next_sequence: u64,
+}
+
+/// Store for the checkpoints of a payment sent with [`resume_payment`](./fn.resume_payment.html)
+#[async_trait]
+pub trait PaymentCheckpointStore: Send + Sync + 'static {
+    /// Saves where the payment is at, replacing its previous checkpoint
+    async fn save_payment_checkpoint(
+        &self,
+        checkpoint: PaymentCheckpoint,
+    ) -> Result<(), OutgoingPaymentStoreError>;
+}
+
+/// Stops a payment started with [`start_payment`](./fn.start_payment.html).
+/// Clones stop the same payment.
+#[derive(Clone, Default)]
+pub struct PaymentCanceller {
+    cancelled: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl PaymentCanceller {
+    /// Stop sending new packets. The payment waits for the packets in flight,
+    /// closes the connection and fails with [`Error::Cancelled`](./enum.Error.html#variant.Cancelled)
+    /// carrying the partial receipt.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify();
+    }
+
+    /// Was the payment cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Announces a new address of the sender to the recipient of a payment started with
+/// [`start_payment`](./fn.start_payment.html), e.g. after our node got a new address over ILDCP.
+/// Clones update the same payment.
+#[derive(Clone)]
+pub struct SourceAddressUpdater {
+    payment: Arc<Mutex<StreamPayment>>,
+}
+
+impl SourceAddressUpdater {
+    /// Send the next packets from the given address and tell the recipient about it
+    /// with a `ConnectionNewAddress` frame
+    pub async fn update(&self, address: Address) {
+        let mut payment = self.payment.lock().await;
+        if payment.receipt.from != address {
+            debug!("Announcing new source address {} to the recipient", address);
+            payment.receipt.from = address;
+            payment.should_send_source_account = true;
+        }
+    }
+}
+
+/// Handle to a payment sent in the background, to follow its progress and cancel it
+pub struct PaymentHandle {
+    /// Outcome of each packet, until the payment ends
+    progress: UnboundedReceiver<PaymentProgress>,
+    canceller: PaymentCanceller,
+    /// Payment state, shared with the task sending the packets
+    payment: Arc<Mutex<StreamPayment>>,
+    result: JoinHandle<Result<StreamDelivery, Error>>,
+}
+
+impl PaymentHandle {
+    /// Wait for the next packet to be fulfilled or rejected.
+    /// Returns `None` once the payment completed, failed or was cancelled.
+    pub async fn next_progress(&mut self) -> Option<PaymentProgress> {
+        self.progress.next().await
+    }
+
+    /// Receipt with the amounts sent & delivered so far
+    pub async fn receipt(&self) -> StreamDelivery {
+        self.payment.lock().await.receipt.clone()
     }
 
-    // Get remaining amount that must be fulfilled for the payment to complete
-    #[inline]
-    fn get_remaining_amount(&self) -> u64 {
-        self.receipt
-            .source_amount
-            .saturating_sub(self.get_fulfilled_amount())
+    /// Where the payment is at, for resuming it if it gets interrupted
+    pub async fn checkpoint(&self) -> PaymentCheckpoint {
+        self.payment.lock().await.checkpoint()
     }
 
-    /// Has the entire intended source amount been fulfilled by the recipient?
-    #[inline]
-    fn is_complete(&self) -> bool {
-        self.get_remaining_amount() == 0
+    /// Stop the payment gracefully, see [`PaymentCanceller::cancel`](./struct.PaymentCanceller.html#method.cancel)
+    pub fn cancel(&self) {
+        self.canceller.cancel()
     }
 
-    /// Return the amount of money available to be sent in the payment (amount remaining minus in-flight)
-    #[inline]
-    fn get_amount_available_to_send(&self) -> u64 {
-        // Sent amount also includes the amount in-flight, which should be subtracted from the amount available
-        self.receipt
-            .source_amount
-            .saturating_sub(self.receipt.sent_amount)
+    /// Canceller for stopping the payment from elsewhere
+    pub fn canceller(&self) -> PaymentCanceller {
+        self.canceller.clone()
     }
 
-    /// Is as much money as possible in-flight?
-    /// (If so, the intended source amount may be fulfilled or in-flight, or the congestion controller
-    /// has temporarily limited sending more money)
-    #[inline]
-    fn is_max_in_flight(&self) -> bool {
-        self.congestion_controller.get_amount_left_in_window() == 0
-            || self.get_amount_available_to_send() == 0
+    /// Updater for announcing a new address of ours from elsewhere
+    pub fn source_address_updater(&self) -> SourceAddressUpdater {
+        SourceAddressUpdater {
+            payment: self.payment.clone(),
+        }
     }
 
-    /// Given we've attempted sending enough packets, does the rate of rejects
-    /// that count towards fail-fast indicate the payment is failing?
-    #[inline]
-    fn is_failing(&self) -> bool {
-        let num_packets = self.fulfilled_packets + self.rejected_packets;
-        num_packets >= FAIL_FAST_MINIMUM_PACKET_ATTEMPTS
-            && (self.fail_fast_rejects as f64 / num_packets as f64) > FAIL_FAST_MINIMUM_FAILURE_RATE
+    /// Wait for the payment to end and return its final receipt
+    pub async fn finish(self) -> Result<StreamDelivery, Error> {
+        self.result
+            .await
+            .unwrap_or_else(|err| Err(Error::ConnectionClosed(err.to_string())))
     }
 }
 
-/// Send the given source amount with packetized Interledger payments using the STREAM transport protocol
-/// Returns the receipt with sent & delivered amounts, asset & account details
-pub async fn send_money<I, A, S>(
+/// Start sending a payment in the background with packetized Interledger payments using the
+/// STREAM transport protocol, like [`send_money_with_options`](./fn.send_money_with_options.html)
+/// or [`send_money_fixed_delivery`](./fn.send_money_fixed_delivery.html) depending on the amount.
+///
+/// The returned handle reports the outcome of each packet and can cancel the payment.
+/// This must be called from within a Tokio runtime.
+#[allow(clippy::too_many_arguments)]
+pub fn start_payment<I, A, S>(
+    service: I,
+    from_account: &A,
+    store: S,
+    destination_account: Address,
+    shared_secret: Vec<u8>,
+    amount: PaymentAmount,
+    slippage: f64,
+    options: SendMoneyOptions,
+) -> PaymentHandle
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    let payment = StreamPayment::new(from_account, destination_account, amount, options);
+    spawn_payment(
+        service,
+        from_account,
+        store,
+        shared_secret,
+        slippage,
+        payment,
+    )
+}
+
+/// Resume a payment which was interrupted, e.g. by a restart, on the same connection.
+/// The amount, slippage and options should be the ones the payment was started with.
+///
+/// The payment saves its checkpoints to `checkpoints` as it goes. Each packet which can be
+/// fulfilled is only sent once a checkpoint accounting for it was saved, and the payment
+/// fails with [`Error::CheckpointNotSaved`](./enum.Error.html#variant.CheckpointNotSaved)
+/// if that is not possible. When resumed from the last saved checkpoint, the packets which
+/// were in flight are counted as sent, so a payment of a fixed source amount never sends
+/// more than it should. Before sending anything else, the receiver is asked how much it got,
+/// and the payment continues from that total if it is more than the checkpoint's delivered
+/// amount. Receivers only remember the totals of connections they saw recently.
+///
+/// A fixed-delivery payment whose checkpoint had packets in flight fails with
+/// [`Error::UnconfirmedDelivery`](./enum.Error.html#variant.UnconfirmedDelivery) if the receiver
+/// cannot confirm it got more than the checkpoint's delivered amount, rather than delivering
+/// the amount in flight twice. Once it is confirmed otherwise what was delivered, the payment
+/// can be resumed from a checkpoint with that delivered amount and no amount in flight.
+#[allow(clippy::too_many_arguments)]
+pub fn resume_payment<I, A, S, C>(
+    service: I,
+    from_account: &A,
+    store: S,
+    shared_secret: Vec<u8>,
+    amount: PaymentAmount,
+    slippage: f64,
+    options: SendMoneyOptions,
+    checkpoint: PaymentCheckpoint,
+    checkpoints: C,
+) -> PaymentHandle
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+    C: PaymentCheckpointStore,
+{
+    let mut payment =
+        StreamPayment::new(from_account, checkpoint.receipt.to.clone(), amount, options);
+    payment.resume(checkpoint);
+    payment.checkpoints = Some(Arc::new(checkpoints));
+    spawn_payment(
+        service,
+        from_account,
+        store,
+        shared_secret,
+        slippage,
+        payment,
+    )
+}
+
+/// Send the packets of the payment in a task, returning its handle
+fn spawn_payment<I, A, S>(
     service: I,
     from_account: &A,
     store: S,
+    shared_secret: Vec<u8>,
+    slippage: f64,
+    mut payment: StreamPayment,
+) -> PaymentHandle
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    let (progress_tx, progress_rx) = unbounded();
+
+    payment.progress = Some(progress_tx);
+    let payment = Arc::new(Mutex::new(payment));
+    let canceller = PaymentCanceller::default();
+
+    let from_account = from_account.clone();
+    let task_payment = payment.clone();
+    let task_canceller = canceller.clone();
+    let result = tokio::spawn(async move {
+        let result = run_payment(
+            service,
+            &from_account,
+            store,
+            shared_secret,
+            slippage,
+            task_payment.clone(),
+            task_canceller,
+        )
+        .await;
+        // Ends the progress stream
+        task_payment.lock().await.progress.take();
+        result
+    });
+
+    PaymentHandle {
+        progress: progress_rx,
+        canceller,
+        payment,
+        result,
+    }
+}
+
+/// Quote for a STREAM payment, probed with unfulfillable packets
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StreamQuote {
+    /// Sender's ILP Address
+    pub from: Address,
+    /// Receiver's ILP Address
+    pub to: Address,
+    /// Asset scale of sender
+    pub source_asset_scale: u8,
+    /// Asset code of sender
+    pub source_asset_code: String,
+    /// Amount the quote is for, in source units
+    pub source_amount: u64,
+    /// Receiver's asset scale, if the receiver told us
+    pub destination_asset_scale: Option<u8>,
+    /// Receiver's asset code, if the receiver told us
+    pub destination_asset_code: Option<String>,
+    /// Probed exchange rate of the path, in destination units per source unit
+    /// (including the asset scales, like the amounts)
+    pub exchange_rate: f64,
+    /// Largest packet the path allows, in source units, if a connector told us
+    pub max_packet_amount: Option<u64>,
+    /// Amount the receiver would get for the source amount at the probed rate, in destination units
+    pub estimated_delivery_amount: u64,
+}
+
+/// Number of magnitudes of the source amount the quote probes with (e.g. 1000, 100, 10 and 1)
+const QUOTE_PROBE_MAGNITUDES: usize = 4;
+
+/// Quote a payment of the given source amount by sending unfulfillable test packets
+/// of several magnitudes over a STREAM connection.
+///
+/// This tells the probed exchange rate, the max packet amount of the path and the receiver's
+/// asset details without moving any money.
+pub async fn quote<I, A>(
+    service: I,
+    from_account: &A,
     destination_account: Address,
     shared_secret: Vec<u8>,
     source_amount: u64,
+) -> Result<StreamQuote, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    let mut sender = StreamSender {
+        next: service,
+        from_account: from_account.clone(),
+        shared_secret: Bytes::from(shared_secret),
+        // Quotes do not enforce a minimum rate
+        store: (),
+        slippage: 0.0,
+        payment: Arc::new(Mutex::new(StreamPayment {
+            congestion_controller: Box::new(CongestionController::new(source_amount, 0, 2.0)),
+            receipt: StreamDelivery::new(from_account, destination_account, source_amount),
+            rate: RateBounds::default(),
+            should_send_source_account: true,
+            sequence: 1,
+            fulfilled_packets: 0,
+            rejected_packets: 0,
+            fail_fast_rejects: 0,
+            last_fulfill_time: Instant::now(),
+            fixed_delivery: None,
+            progress: None,
+            receiver_closed: None,
+            streams: Vec::new(),
+            max_stream_id: DEFAULT_MAX_STREAM_ID,
+            resumed: None,
+            checkpoints: None,
+            checkpoint_error: None,
+        })),
+    };
+
+    let mut probed: Vec<u64> = Vec::new();
+    let mut magnitude = source_amount;
+    while magnitude > 0 && probed.len() < QUOTE_PROBE_MAGNITUDES {
+        // Once a connector told us the max packet amount, probe with that instead of larger amounts
+        let amount = {
+            let mut payment = sender.payment.lock().await;
+            let amount = min(
+                magnitude,
+                payment.congestion_controller.get_max_packet_amount(),
+            );
+            if probed.contains(&amount) {
+                magnitude = amount / 10;
+                continue;
+            }
+            payment.account_for_prepare(amount);
+            amount
+        };
+        probed.push(amount);
+        // A zero minimum destination amount makes the packet unfulfillable
+        sender.send_money_packet(amount, 0).await?;
+
+        // After an F08, probe again with the max packet amount before going down in magnitude
+        let max_packet_amount = sender
+            .payment
+            .lock()
+            .await
+            .congestion_controller
+            .get_max_packet_amount();
+        if max_packet_amount >= amount {
+            magnitude = amount / 10;
+        }
+    }
+
+    sender.try_send_connection_close().await;
+
+    let payment = sender.payment.lock().await;
+    if payment.rate.upper.is_none() {
+        // None of the probes reached the receiver
+        return Err(Error::UnknownExchangeRate);
+    }
+    let rate = payment.rate.lower.clone();
+    let estimated_delivery_amount = BigRational::from_u64(source_amount)
+        .map(|amount| amount * rate.clone())
+        .and_then(|amount| amount.floor().to_integer().to_u64())
+        .unwrap_or(u64::MAX);
+    let max_packet_amount = Some(payment.congestion_controller.get_max_packet_amount())
+        .filter(|amount| *amount < u64::MAX);
+    debug!(
+        "Probed exchange rate {} with {} packets",
+        rate,
+        probed.len()
+    );
+
+    let receipt = &payment.receipt;
+    Ok(StreamQuote {
+        from: receipt.from.clone(),
+        to: receipt.to.clone(),
+        source_asset_scale: receipt.source_asset_scale,
+        source_asset_code: receipt.source_asset_code.clone(),
+        source_amount,
+        destination_asset_scale: receipt.destination_asset_scale,
+        destination_asset_code: receipt.destination_asset_code.clone(),
+        exchange_rate: rate_to_f64(&rate),
+        max_packet_amount,
+        estimated_delivery_amount,
+    })
+}
+
+/// Send the packets of the payment until it completes or fails
+async fn run_payment<I, A, S>(
+    service: I,
+    from_account: &A,
+    store: S,
+    shared_secret: Vec<u8>,
     slippage: f64,
+    payment: Arc<Mutex<StreamPayment>>,
+    canceller: PaymentCanceller,
 ) -> Result<StreamDelivery, Error>
 where
     I: IncomingService<A> + Clone + Send + Sync + 'static,
@@ -306,6 +1517,7 @@ where
     let shared_secret = Bytes::from(shared_secret);
 
     let from = from_account.ilp_address();
+    let destination_account = payment.lock().await.receipt.to.clone();
     if from.scheme() != destination_account.scheme() {
         warn!(
             "Destination ILP address starts with a different scheme prefix (\"{}\') than ours (\"{}\'), this probably won't work",
@@ -320,23 +1532,30 @@ where
         shared_secret,
         store,
         slippage,
-        payment: Arc::new(Mutex::new(StreamPayment {
-            // TODO Make configurable to get money flowing ASAP vs as much as possible per-packet
-            congestion_controller: CongestionController::new(
-                source_amount,
-                source_amount / 10,
-                2.0,
-            ),
-            receipt: StreamDelivery::new(from_account, destination_account, source_amount),
-            should_send_source_account: true,
-            sequence: 1,
-            fulfilled_packets: 0,
-            rejected_packets: 0,
-            fail_fast_rejects: 0,
-            last_fulfill_time: Instant::now(),
-        })),
+        payment,
     };
 
+    // Packets which were in flight when the payment was interrupted may have been delivered
+    let resumed = sender.payment.lock().await.resumed.take();
+    if let Some(in_flight_amount) = resumed {
+        let total_received = sender.query_total_received().await;
+        let mut payment = sender.payment.lock().await;
+        match total_received {
+            Some(total_received) if total_received > payment.receipt.delivered_amount => {
+                payment.apply_total_received(total_received)
+            }
+            // The receiver forgets the totals of connections it closed or did not see for a
+            // while, so delivering the amount again could pay it twice
+            _ if payment.fixed_delivery.is_some() && in_flight_amount > 0 => {
+                return Err(Error::UnconfirmedDelivery(Box::new(
+                    payment.receipt.clone(),
+                )));
+            }
+            Some(_) => {}
+            None => warn!("Receiver did not tell us how much it got, resuming from the checkpoint"),
+        }
+    }
+
     let mut pending_requests = FuturesUnordered::new();
 
     /// Actions corresponding to the state of the payment
@@ -351,6 +1570,12 @@ where
         Timeout,
         /// Too many packets are rejected, such as if the exchange rate is too low: terminate the payment
         FailFast,
+        /// Fixed-delivery payment cannot deliver its amount: terminate the payment
+        Fail(Error),
+        /// Payment was cancelled through its handle: close the connection and return the partial receipt
+        Cancel,
+        /// Only streams above the recipient's limit are left: ask it to raise the limit
+        StreamIdBlocked(u64),
     }
 
     loop {
@@ -361,14 +1586,27 @@ where
                 PaymentEvent::Timeout
             } else if payment.is_failing() {
                 PaymentEvent::FailFast
+            } else if let Some(error) = payment.checkpoint_error.clone() {
+                PaymentEvent::Fail(Error::CheckpointNotSaved(error))
+            } else if let Err(error) = payment.check_fixed_delivery(&sender.store, sender.slippage)
+            {
+                PaymentEvent::Fail(error)
             } else if payment.is_complete() {
                 PaymentEvent::CloseConnection
+            } else if let Some(reason) = payment.receiver_closed.clone() {
+                PaymentEvent::Fail(Error::ConnectionClosed(reason))
+            } else if canceller.is_cancelled() {
+                PaymentEvent::Cancel
+            } else if let Some(stream_id) = payment.get_blocking_stream_id() {
+                PaymentEvent::StreamIdBlocked(stream_id)
             } else if payment.is_max_in_flight() {
                 let deadline = payment
                     .last_fulfill_time
                     .checked_add(MAX_TIME_SINCE_LAST_FULFILL)
                     .unwrap();
                 PaymentEvent::MaxInFlight(deadline)
+            } else if payment.is_probing() {
+                PaymentEvent::SendMoney(payment.apply_probe())
             } else {
                 PaymentEvent::SendMoney(payment.apply_prepare(&sender.store, sender.slippage))
             }
@@ -383,8 +1621,12 @@ where
             }
             PaymentEvent::MaxInFlight(deadline) => {
                 // Wait for any request to complete, or if after reach deadline since last fulfill,
-                // run loop again, which should timeout the payment
-                let result = timeout_at(deadline, pending_requests.select_next_some()).await;
+                // run loop again, which should timeout the payment.
+                // If the payment is cancelled meanwhile, run the loop again to stop it.
+                let result = tokio::select! {
+                    result = timeout_at(deadline, pending_requests.select_next_some()) => result,
+                    _ = canceller.notify.notified() => continue,
+                };
 
                 if let Ok(Ok(Err(error))) = result {
                     error!("Send money stopped because of error: {:?}", error);
@@ -419,6 +1661,34 @@ where
                     payment.rejected_packets,
                 ));
             }
+            PaymentEvent::Fail(error) => {
+                // Let the packets in flight finish so they are not fulfilled after we return
+                pending_requests.map(|_| ()).collect::<()>().await;
+                sender.try_send_connection_close().await;
+                return Err(error);
+            }
+            PaymentEvent::StreamIdBlocked(stream_id) => {
+                // Nothing is in flight, so tell the recipient with a packet carrying no money.
+                // It may raise the limit in its reply, otherwise the rest cannot be sent.
+                sender.send_money_packet(0, 0).await?;
+                if sender.payment.lock().await.max_stream_id < stream_id {
+                    sender.try_send_connection_close().await;
+                    return Err(Error::StreamIdBlocked(stream_id));
+                }
+            }
+            PaymentEvent::Cancel => {
+                pending_requests.map(|_| ()).collect::<()>().await;
+                sender.try_send_connection_close().await;
+
+                let payment = sender.payment.lock().await;
+                debug!(
+                    "Payment cancelled. Delivered: {} ({} packets fulfilled, {} packets rejected)",
+                    payment.receipt.delivered_amount,
+                    payment.fulfilled_packets,
+                    payment.rejected_packets,
+                );
+                return Err(Error::Cancelled(Box::new(payment.receipt.clone())));
+            }
         }
     }
 }
@@ -444,7 +1714,6 @@ impl<I, A, S> StreamSender<I, A, S>
 where
     I: IncomingService<A>,
     A: Account,
-    S: ExchangeRateStore,
 {
     /// Send a Prepare for the given source amount and apply the resulting Fulfill or Reject
     #[inline]
@@ -453,20 +1722,62 @@ where
         source_amount: u64,
         min_destination_amount: u64,
     ) -> Result<(), Error> {
-        let (prepare, sequence) = {
+        let (prepare, sequence, announced_address, allocations, closing) = {
             let mut payment = self.payment.lock().await;
 
             // Build the STREAM packet
             let sequence = payment.next_sequence();
-            let mut frames = vec![Frame::StreamMoney(StreamMoneyFrame {
-                stream_id: 1,
-                shares: 1,
-            })];
-            if payment.should_send_source_account {
+            let (mut frames, allocations) = if payment.streams.is_empty() {
+                let frames = vec![Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 1,
+                    shares: 1,
+                })];
+                (frames, Vec::new())
+            } else {
+                // The recipient splits the amount between the streams in proportion to their shares
+                let allocations = payment.allocate_to_streams(source_amount);
+                let frames = allocations
+                    .iter()
+                    .map(|(stream_id, amount)| {
+                        Frame::StreamMoney(StreamMoneyFrame {
+                            stream_id: *stream_id,
+                            shares: *amount,
+                        })
+                    })
+                    .collect();
+                (frames, allocations)
+            };
+            // Close the streams which got all their money, which lets the recipient
+            // accept new ones in their place. They are only closed once the recipient
+            // fulfills a packet saying so, until then every packet repeats it.
+            let closing: Vec<u64> = payment
+                .streams
+                .iter()
+                .filter(|stream| stream.is_complete() && !stream.closed)
+                .map(|stream| stream.id)
+                .collect();
+            frames.extend(closing.iter().map(|stream_id| {
+                Frame::StreamClose(StreamCloseFrame {
+                    stream_id: *stream_id,
+                    code: ErrorCode::NoError,
+                    message: "",
+                })
+            }));
+            if let Some(stream_id) = payment.get_blocked_stream_id() {
+                frames.push(Frame::ConnectionStreamIdBlocked(
+                    ConnectionStreamIdBlockedFrame {
+                        max_stream_id: stream_id,
+                    },
+                ));
+            }
+            let announced_address = if payment.should_send_source_account {
                 frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
                     source_account: payment.receipt.from.clone(),
                 }));
-            }
+                Some(payment.receipt.from.clone())
+            } else {
+                None
+            };
             let stream_request_packet = StreamPacketBuilder {
                 ilp_packet_type: IlpPacketType::Prepare,
                 prepare_amount: min_destination_amount,
@@ -501,10 +1812,33 @@ where
             }
             .build();
 
-            (prepare, sequence)
+            // Save the packet before it goes out, so that a payment interrupted before the
+            // reply arrives counts it as sent when it is resumed
+            if min_destination_amount > 0 {
+                if let Err(err) = payment.save_checkpoint().await {
+                    error!(
+                        "Unable to save checkpoint before sending packet {}: {}",
+                        sequence, err
+                    );
+                    let reject = RejectBuilder {
+                        code: IlpErrorCode::T00_INTERNAL_ERROR,
+                        message: b"Unable to save checkpoint",
+                        triggered_by: None,
+                        data: &[],
+                    }
+                    .build();
+                    payment.apply_reject(source_amount, &reject);
+                    payment.apply_streams_reject(&allocations);
+                    payment.checkpoint_error = Some(err.to_string());
+                    return Err(Error::CheckpointNotSaved(err.to_string()));
+                }
+            }
+
+            (prepare, sequence, announced_address, allocations, closing)
         };
 
         // Send it!
+        let sent_at = Instant::now();
         let reply = self
             .next
             .handle_request(IncomingRequest {
@@ -512,6 +1846,7 @@ where
                 prepare,
             })
             .await;
+        let round_trip = sent_at.elapsed();
 
         let (packet_type, reply_data) = match &reply {
             Ok(fulfill) => (IlpPacketType::Fulfill, fulfill.data()),
@@ -541,8 +1876,11 @@ where
                     warn!("Discarding STREAM packet (received Fulfill, but recipient said they sent a Reject)");
                     0
                 } else {
-                    // Since we decrypted the response, the recipient read the request packet and knows our account
-                    payment.should_send_source_account = false;
+                    // Since we decrypted the response, the recipient read the request packet and knows our account,
+                    // unless our address changed since we sent it
+                    if announced_address.as_ref() == Some(&payment.receipt.from) {
+                        payment.should_send_source_account = false;
+                    }
 
                     // Update the destination asset scale & code
                     // https://github.com/interledger/rfcs/pull/551 ensures that this won't change
@@ -560,6 +1898,50 @@ where
                         }
                     }
 
+                    if packet_type == IlpPacketType::Fulfill {
+                        for frame in stream_reply_packet.frames() {
+                            if let Frame::StreamReceipt(frame) = frame {
+                                match payment
+                                    .streams
+                                    .iter_mut()
+                                    .find(|stream| stream.id == frame.stream_id)
+                                {
+                                    Some(stream) => stream.receipt.apply_receipt(frame.receipt),
+                                    None => payment.receipt.apply_receipt(frame.receipt),
+                                }
+                            }
+                        }
+                    }
+
+                    for frame in stream_reply_packet.frames() {
+                        match frame {
+                            Frame::StreamMaxMoney(frame) => {
+                                payment.set_receive_max(frame.receive_max, frame.total_received);
+                            }
+                            Frame::ConnectionMaxStreamId(frame) => {
+                                payment.max_stream_id =
+                                    max(payment.max_stream_id, frame.max_stream_id);
+                            }
+                            // The recipient moved, so send the next packets to its new address
+                            Frame::ConnectionNewAddress(frame)
+                                if frame.source_account != payment.receipt.to =>
+                            {
+                                debug!("Recipient moved to new address {}", frame.source_account);
+                                payment.receipt.to = frame.source_account;
+                            }
+                            Frame::ConnectionClose(frame) => {
+                                debug!(
+                                    "Recipient closed the connection: {:?} {}",
+                                    frame.code, frame.message
+                                );
+                                payment.receiver_closed = Some(frame.message.to_string());
+                            }
+                            _ => {}
+                        }
+                    }
+                    payment
+                        .apply_exchange_rate(source_amount, stream_reply_packet.prepare_amount());
+
                     stream_reply_packet.prepare_amount()
                 }
             }
@@ -572,7 +1954,9 @@ where
             }
         };
 
-        match reply {
+        payment.congestion_controller.round_trip(round_trip);
+
+        let result = match reply {
             // Handle ILP Fulfill
             Ok(_) => {
                 // Delivered amount must be *at least* the minimum acceptable amount we told the receiver
@@ -580,6 +1964,14 @@ where
                 let delivered_amount = max(min_destination_amount, claimed_amount);
 
                 payment.apply_fulfill(source_amount, delivered_amount);
+                payment.apply_streams_fulfill(&allocations, delivered_amount);
+                for stream in payment
+                    .streams
+                    .iter_mut()
+                    .filter(|stream| closing.contains(&stream.id))
+                {
+                    stream.closed = true;
+                }
 
                 debug!(
                     "Prepare {} with amount {} was fulfilled ({} left to send)",
@@ -593,6 +1985,7 @@ where
             // Handle ILP Reject
             Err(reject) => {
                 payment.apply_reject(source_amount, &reject);
+                payment.apply_streams_reject(&allocations);
 
                 debug!(
                     "Prepare {} with amount {} was rejected with code: {} ({} left to send)",
@@ -616,7 +2009,92 @@ where
                     )),
                 }
             }
+        };
+
+        // Not saving the outcome is no reason to stop, the last checkpoint counts the packet as sent
+        if min_destination_amount > 0 {
+            if let Err(err) = payment.save_checkpoint().await {
+                warn!(
+                    "Unable to save checkpoint after the reply to packet {}: {}",
+                    sequence, err
+                );
+            }
         }
+        result
+    }
+
+    /// Ask the receiver how much it got on the streams of the payment, with an unfulfillable
+    /// Prepare it answers with a StreamMaxMoney frame per stream
+    async fn query_total_received(&mut self) -> Option<u64> {
+        let (prepare, sequence, stream_ids) = {
+            let mut payment = self.payment.lock().await;
+            let sequence = payment.next_sequence();
+            let stream_ids: Vec<u64> = if payment.streams.is_empty() {
+                vec![1]
+            } else {
+                // Opening streams above the recipient's limit would close the connection
+                let max_stream_id = payment.max_stream_id;
+                payment
+                    .streams
+                    .iter()
+                    .map(|stream| stream.id)
+                    .filter(|stream_id| *stream_id <= max_stream_id)
+                    .collect()
+            };
+            let frames: Vec<Frame> = stream_ids
+                .iter()
+                .map(|stream_id| {
+                    Frame::StreamMoney(StreamMoneyFrame {
+                        stream_id: *stream_id,
+                        shares: 1,
+                    })
+                })
+                .collect();
+            let data = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence,
+                frames: &frames,
+            }
+            .build()
+            .into_encrypted(&self.shared_secret);
+            let prepare = PrepareBuilder {
+                destination: payment.receipt.to.clone(),
+                amount: 0,
+                execution_condition: &random_condition(),
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &data[..],
+            }
+            .build();
+            (prepare, sequence, stream_ids)
+        };
+
+        // Packet will always be rejected since the condition is random
+        let reply = self
+            .next
+            .handle_request(IncomingRequest {
+                from: self.from_account.clone(),
+                prepare,
+            })
+            .await;
+        let reply_data = match &reply {
+            Ok(fulfill) => fulfill.data(),
+            Err(reject) => reject.data(),
+        };
+        StreamPacket::from_encrypted(&self.shared_secret, BytesMut::from(reply_data))
+            .ok()
+            .filter(|packet| packet.sequence() == sequence)
+            .map(|packet| {
+                packet
+                    .frames()
+                    .filter_map(|frame| match frame {
+                        Frame::StreamMaxMoney(frame) if stream_ids.contains(&frame.stream_id) => {
+                            Some(frame.total_received)
+                        }
+                        _ => None,
+                    })
+                    .fold(0, u64::saturating_add)
+            })
     }
 
     /// Send an unfulfillable Prepare with a ConnectionClose frame to the peer
@@ -670,7 +2148,7 @@ where
 /// fetching from the provider's exchange rates, subtracting slippage, and adjusting scales.
 /// Returns None if destination asset details are unknown or rate cannot be calculated.
 #[inline]
-fn get_rate<S: ExchangeRateStore>(
+pub(crate) fn get_rate<S: ExchangeRateStore>(
     store: &S,
     source_scale: u8,
     source_code: &str,
@@ -685,7 +2163,9 @@ fn get_rate<S: ExchangeRateStore>(
     let rate: BigRational = if source_code == dest_code {
         BigRational::one()
     } else if let Ok(prices) = store.get_exchange_rates(&[&source_code, &dest_code]) {
-        BigRational::from_f64(prices[0])? / BigRational::from_f64(prices[1])?
+        prices[0]
+            .as_rational()
+            .checked_div(prices[1].as_rational())?
     } else {
         return None;
     };
@@ -708,7 +2188,7 @@ fn get_rate<S: ExchangeRateStore>(
 ///
 /// Returns `Some` when the value can after conversion be represented with an u64.
 #[inline]
-fn convert(source_amount: u64, rate: BigRational) -> Option<u64> {
+pub(crate) fn convert(source_amount: u64, rate: BigRational) -> Option<u64> {
     // First, convert scaled source amount to base unit
     let source_amount = BigRational::from_u64(source_amount)?;
 
@@ -913,6 +2393,42 @@ mod send_money_tests {
         assert_eq!(num_requests_in_flight.load(Ordering::Relaxed), 5);
     }
 
+    #[test]
+    fn opens_streams_up_to_the_max_stream_id() {
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.sender").unwrap(),
+            max_packet_amount: None,
+        };
+        let mut payment = StreamPayment::new(
+            &account,
+            Address::from_str("example.receiver").unwrap(),
+            PaymentAmount::FixedSource(120),
+            SendMoneyOptions::default(),
+        );
+        payment.set_streams(&[10; 12]);
+
+        // Streams 21 and 23 are above the default limit
+        assert_eq!(payment.get_amount_available_to_send(), 100);
+        let allocations = payment.allocate_to_streams(1000);
+        assert_eq!(allocations.len(), 10);
+        assert_eq!(allocations.last(), Some(&(19, 10)));
+        assert_eq!(payment.get_blocked_stream_id(), Some(21));
+
+        payment.apply_streams_reject(&allocations[5..]);
+        payment.apply_streams_fulfill(&allocations[..5], 25);
+        assert_eq!(payment.streams[0].receipt.delivered_amount, 5);
+        assert_eq!(payment.streams[4].receipt.delivered_amount, 5);
+        assert!(payment.streams[4].is_complete());
+        assert!(!payment.streams[5].is_complete());
+
+        payment.max_stream_id = 23;
+        assert_eq!(payment.get_blocked_stream_id(), None);
+        assert_eq!(payment.allocate_to_streams(1000).len(), 7);
+    }
+
     #[tokio::test]
     async fn computes_min_destination_amount() {
         struct TestData<'a> {
@@ -1,14 +1,151 @@
 use interledger_packet::{ErrorCode, MaxPacketAmountDetails, Reject};
 #[cfg(test)]
 use once_cell::sync::Lazy;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 use std::cmp::{max, min};
+use std::time::{Duration, Instant};
 use tracing::{debug, warn};
 
+/// Decides how much money the [stream client](./client/fn.send_money.html) puts in each
+/// packet and how much it may have in flight at once
+pub trait CongestionControl: Send {
+    /// Sets the total source amount of the payment. For fixed-delivery payments this is
+    /// only known once the exchange rate was probed.
+    fn set_payment_amount(&mut self, amount: u64);
+
+    /// Maximium allowed packet amount allowed to send in a packet per F08s
+    fn get_max_packet_amount(&self) -> u64;
+
+    /// The maximum amount availble to be sent is the maximum amount in flight minus the current amount in flight
+    fn get_amount_left_in_window(&self) -> u64;
+
+    /// Increments the amount in flight by the provided amount
+    fn prepare(&mut self, amount: u64);
+
+    /// Decrements the amount in flight by the provided amount
+    fn fulfill(&mut self, prepare_amount: u64);
+
+    /// Decrements the amount in flight by the provided amount
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject);
+
+    /// Reports how long it took to get the reply to a packet,
+    /// right before the packet is fulfilled or rejected
+    fn round_trip(&mut self, _round_trip: Duration) {}
+}
+
+/// Congestion control strategies the [stream client](./client/fn.send_money.html) can use
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum CongestionStrategy {
+    /// [AIMD](./struct.CongestionController.html), starting with the whole payment in flight
+    Aimd,
+    /// Packets of a fixed source amount, with at most the given number of packets in flight
+    FixedPacketSize {
+        packet_amount: u64,
+        #[serde(default = "default_packets_in_flight")]
+        packets_in_flight: u64,
+    },
+    /// AIMD which also backs off when replies take longer than the given factor times the
+    /// fastest round trip seen so far. The factor must be greater than 1.
+    LatencyAware {
+        #[serde(
+            default = "default_latency_factor",
+            deserialize_with = "deserialize_latency_factor"
+        )]
+        latency_factor: f64,
+    },
+    /// Splits the payment into the given number of packets which are all sent right away,
+    /// so money starts arriving as soon as possible
+    Asap {
+        #[serde(default = "default_asap_packets")]
+        packets: u64,
+    },
+}
+
+// Deriving this needs `#[default]` on the variant, which is not available on older compilers
+#[allow(clippy::derivable_impls)]
+impl Default for CongestionStrategy {
+    fn default() -> Self {
+        CongestionStrategy::Aimd
+    }
+}
+
+fn default_packets_in_flight() -> u64 {
+    1
+}
+
+fn default_latency_factor() -> f64 {
+    2.0
+}
+
+/// Replies are never faster than the fastest one, so a factor of 1 or less would
+/// decrease the window on almost every packet
+fn deserialize_latency_factor<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let latency_factor = f64::deserialize(deserializer)?;
+    if latency_factor > 1.0 && latency_factor.is_finite() {
+        Ok(latency_factor)
+    } else {
+        Err(D::Error::custom(format!(
+            "latency_factor must be greater than 1, got {}",
+            latency_factor
+        )))
+    }
+}
+
+fn default_asap_packets() -> u64 {
+    10
+}
+
+/// Divide `max_in_flight` by this factor per reject with code for insufficient liquidity
+const DEFAULT_DECREASE_FACTOR: f64 = 2.0;
+
+impl CongestionStrategy {
+    /// Constructs the congestion controller of the strategy.
+    /// Its window is set once the payment amount is known.
+    pub fn build(&self) -> Box<dyn CongestionControl> {
+        match *self {
+            CongestionStrategy::Aimd => {
+                Box::new(CongestionController::new(0, 0, DEFAULT_DECREASE_FACTOR))
+            }
+            CongestionStrategy::FixedPacketSize {
+                packet_amount,
+                packets_in_flight,
+            } => Box::new(FixedPacketSize::new(packet_amount, packets_in_flight)),
+            CongestionStrategy::LatencyAware { latency_factor } => {
+                Box::new(LatencyAware::new(latency_factor))
+            }
+            CongestionStrategy::Asap { packets } => Box::new(Asap::new(packets)),
+        }
+    }
+}
+
+/// Lowers the max packet amount per the details of an F08 Amount Too Large reject,
+/// or by the decrease factor if the reject has none
+fn max_packet_amount_after_f08(
+    max_packet_amount: Option<u64>,
+    prepare_amount: u64,
+    reject: &Reject,
+    decrease_factor: f64,
+) -> Option<u64> {
+    if let Ok(details) = MaxPacketAmountDetails::from_bytes(reject.data()) {
+        let new_max_packet_amount: u64 =
+            prepare_amount * details.max_amount() / details.amount_received();
+        if let Some(max_packet_amount) = max_packet_amount {
+            Some(min(max_packet_amount, new_max_packet_amount))
+        } else {
+            Some(new_max_packet_amount)
+        }
+    } else {
+        warn!("Got F08: Amount Too Large Error without max packet amount details attached");
+        max_packet_amount
+            .map(|max_packet_amount| (max_packet_amount as f64 / decrease_factor) as u64)
+    }
+}
+
 /// A basic congestion controller that implements an
 /// Additive Increase, Multiplicative Decrease (AIMD) algorithm.
 ///
-/// Future implementations of this will use more advanced congestion
-/// control algorithms.
+/// This is the default [strategy](./enum.CongestionStrategy.html).
 pub struct CongestionController {
     state: CongestionState,
     /// Amount which is added to `max_in_flight` per fulfill
@@ -44,18 +181,38 @@ impl CongestionController {
         }
     }
 
-    /// Maximium allowed packet amount allowed to send in a packet per F08s
-    pub fn get_max_packet_amount(&self) -> u64 {
+    /// Divides the max in flight amount by the decrease factor and stops doubling it
+    fn decrease(&mut self) {
+        self.state = CongestionState::AvoidCongestion;
+        self.max_in_flight = max(
+            (self.max_in_flight as f64 / self.decrease_factor).floor() as u64,
+            1,
+        );
+    }
+
+    #[cfg(test)]
+    fn set_max_packet_amount(&mut self, max_packet_amount: u64) {
+        self.max_packet_amount = Some(max_packet_amount)
+    }
+}
+
+impl CongestionControl for CongestionController {
+    /// Starts with the whole payment in flight, adding a tenth of it per fulfill once
+    /// congestion was detected
+    fn set_payment_amount(&mut self, amount: u64) {
+        self.max_in_flight = amount;
+        self.increase_amount = amount / 10;
+    }
+
+    fn get_max_packet_amount(&self) -> u64 {
         self.max_packet_amount.unwrap_or(u64::max_value())
     }
 
-    /// The maximum amount availble to be sent is the maximum amount in flight minus the current amount in flight
-    pub fn get_amount_left_in_window(&self) -> u64 {
+    fn get_amount_left_in_window(&self) -> u64 {
         self.max_in_flight.saturating_sub(self.amount_in_flight)
     }
 
-    /// Increments the amount in flight by the provided amount
-    pub fn prepare(&mut self, amount: u64) {
+    fn prepare(&mut self, amount: u64) {
         if amount > 0 {
             self.amount_in_flight += amount;
             debug!(
@@ -67,7 +224,7 @@ impl CongestionController {
 
     /// Decrements the amount in flight by the provided amount
     /// Increases the allowed max in flight amount cap
-    pub fn fulfill(&mut self, prepare_amount: u64) {
+    fn fulfill(&mut self, prepare_amount: u64) {
         self.amount_in_flight -= prepare_amount;
 
         // Before we know how much we should be sending at a time,
@@ -101,45 +258,238 @@ impl CongestionController {
 
     /// Decrements the amount in flight by the provided amount
     /// Decreases the allowed max in flight amount cap
-    pub fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
         self.amount_in_flight -= prepare_amount;
 
         match reject.code() {
             ErrorCode::T04_INSUFFICIENT_LIQUIDITY => {
-                self.state = CongestionState::AvoidCongestion;
-                self.max_in_flight = max(
-                    (self.max_in_flight as f64 / self.decrease_factor).floor() as u64,
-                    1,
-                );
+                self.decrease();
                 debug!("Rejected packet with T04 error. Amount in flight was: {}, decreasing max in flight to: {}", self.amount_in_flight + prepare_amount, self.max_in_flight);
             }
             ErrorCode::F08_AMOUNT_TOO_LARGE => {
-                if let Ok(details) = MaxPacketAmountDetails::from_bytes(reject.data()) {
-                    let new_max_packet_amount: u64 =
-                        prepare_amount * details.max_amount() / details.amount_received();
-                    if let Some(max_packet_amount) = self.max_packet_amount {
-                        self.max_packet_amount =
-                            Some(min(max_packet_amount, new_max_packet_amount));
-                    } else {
-                        self.max_packet_amount = Some(new_max_packet_amount);
-                    }
-                } else {
-                    warn!("Got F08: Amount Too Large Error without max packet amount details attached");
-                    if let Some(max_packet_amount) = self.max_packet_amount {
-                        self.max_packet_amount =
-                            Some((max_packet_amount as f64 / self.decrease_factor) as u64);
-                    }
-                }
+                self.max_packet_amount = max_packet_amount_after_f08(
+                    self.max_packet_amount,
+                    prepare_amount,
+                    reject,
+                    self.decrease_factor,
+                );
             }
             _ => {
                 // No special treatment for other errors
             }
         }
     }
+}
 
-    #[cfg(test)]
-    fn set_max_packet_amount(&mut self, max_packet_amount: u64) {
-        self.max_packet_amount = Some(max_packet_amount)
+/// Sends packets of a fixed amount (or less, if a connector's max packet amount is lower),
+/// with at most a fixed number of them in flight
+pub struct FixedPacketSize {
+    packet_amount: u64,
+    packets_in_flight: u64,
+    /// The maximum amount the path allows in a packet, set by F08 rejects
+    max_packet_amount: Option<u64>,
+    amount_in_flight: u64,
+}
+
+impl FixedPacketSize {
+    pub fn new(packet_amount: u64, packets_in_flight: u64) -> Self {
+        FixedPacketSize {
+            packet_amount: max(packet_amount, 1),
+            packets_in_flight: max(packets_in_flight, 1),
+            max_packet_amount: None,
+            amount_in_flight: 0,
+        }
+    }
+}
+
+impl CongestionControl for FixedPacketSize {
+    fn set_payment_amount(&mut self, _amount: u64) {}
+
+    fn get_max_packet_amount(&self) -> u64 {
+        min(
+            self.packet_amount,
+            self.max_packet_amount.unwrap_or(u64::MAX),
+        )
+    }
+
+    fn get_amount_left_in_window(&self) -> u64 {
+        self.get_max_packet_amount()
+            .saturating_mul(self.packets_in_flight)
+            .saturating_sub(self.amount_in_flight)
+    }
+
+    fn prepare(&mut self, amount: u64) {
+        self.amount_in_flight += amount;
+    }
+
+    fn fulfill(&mut self, prepare_amount: u64) {
+        self.amount_in_flight -= prepare_amount;
+    }
+
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
+        self.amount_in_flight -= prepare_amount;
+        if reject.code() == ErrorCode::F08_AMOUNT_TOO_LARGE {
+            self.max_packet_amount = max_packet_amount_after_f08(
+                self.max_packet_amount,
+                prepare_amount,
+                reject,
+                DEFAULT_DECREASE_FACTOR,
+            );
+        }
+    }
+}
+
+/// AIMD which treats replies slower than a factor of the fastest round trip seen so far as
+/// a sign of congestion (such as connectors queueing packets), like it treats T04 rejects.
+///
+/// The window is decreased at most once per round trip: replies to packets which were sent
+/// before the last decrease do not decrease it again.
+pub struct LatencyAware {
+    aimd: CongestionController,
+    /// A reply this many times slower than the fastest one decreases the window
+    latency_factor: f64,
+    min_round_trip: Option<Duration>,
+    /// When the packet whose reply is being handled was sent
+    reply_sent_at: Option<Instant>,
+    /// When the window was last decreased
+    decreased_at: Option<Instant>,
+}
+
+impl LatencyAware {
+    /// Factors of 1 or less are replaced by the default factor of 2
+    pub fn new(latency_factor: f64) -> Self {
+        let latency_factor = if latency_factor > 1.0 && latency_factor.is_finite() {
+            latency_factor
+        } else {
+            warn!(
+                "Latency factor must be greater than 1, using {} instead of {}",
+                default_latency_factor(),
+                latency_factor
+            );
+            default_latency_factor()
+        };
+        LatencyAware {
+            aimd: CongestionController::new(0, 0, DEFAULT_DECREASE_FACTOR),
+            latency_factor,
+            min_round_trip: None,
+            reply_sent_at: None,
+            decreased_at: None,
+        }
+    }
+
+    /// Decreases the window, unless it was already decreased after the packet was sent
+    fn decrease(&mut self) -> bool {
+        if let (Some(sent_at), Some(decreased_at)) = (self.reply_sent_at, self.decreased_at) {
+            if sent_at < decreased_at {
+                return false;
+            }
+        }
+        self.aimd.decrease();
+        self.decreased_at = Some(Instant::now());
+        true
+    }
+}
+
+impl CongestionControl for LatencyAware {
+    fn set_payment_amount(&mut self, amount: u64) {
+        self.aimd.set_payment_amount(amount);
+    }
+
+    fn get_max_packet_amount(&self) -> u64 {
+        self.aimd.get_max_packet_amount()
+    }
+
+    fn get_amount_left_in_window(&self) -> u64 {
+        self.aimd.get_amount_left_in_window()
+    }
+
+    fn prepare(&mut self, amount: u64) {
+        self.aimd.prepare(amount);
+    }
+
+    fn fulfill(&mut self, prepare_amount: u64) {
+        self.aimd.fulfill(prepare_amount);
+    }
+
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
+        if reject.code() == ErrorCode::T04_INSUFFICIENT_LIQUIDITY {
+            self.aimd.amount_in_flight -= prepare_amount;
+            if self.decrease() {
+                debug!(
+                    "Rejected packet with T04 error, decreasing max in flight to: {}",
+                    self.aimd.max_in_flight
+                );
+            }
+        } else {
+            self.aimd.reject(prepare_amount, reject);
+        }
+    }
+
+    fn round_trip(&mut self, round_trip: Duration) {
+        self.reply_sent_at = Instant::now().checked_sub(round_trip);
+        match self.min_round_trip {
+            Some(min_round_trip) if round_trip > min_round_trip.mul_f64(self.latency_factor) => {
+                if self.decrease() {
+                    debug!(
+                        "Round trip of {:?} is much slower than the fastest one of {:?}, decreasing max in flight to: {}",
+                        round_trip, min_round_trip, self.aimd.max_in_flight
+                    );
+                }
+            }
+            Some(min_round_trip) if round_trip >= min_round_trip => {}
+            _ => self.min_round_trip = Some(round_trip),
+        }
+    }
+}
+
+/// Splits the payment into a number of equal packets which are all sent right away, instead of
+/// starting with one packet of the whole amount. Money starts arriving with the first fulfill,
+/// and a failing packet only holds up its share of the payment.
+pub struct Asap {
+    aimd: CongestionController,
+    packets: u64,
+    packet_amount: u64,
+}
+
+impl Asap {
+    pub fn new(packets: u64) -> Self {
+        Asap {
+            aimd: CongestionController::new(0, 0, DEFAULT_DECREASE_FACTOR),
+            packets: max(packets, 1),
+            packet_amount: u64::MAX,
+        }
+    }
+}
+
+impl CongestionControl for Asap {
+    fn set_payment_amount(&mut self, amount: u64) {
+        self.aimd.set_payment_amount(amount);
+        // Round up so that the last packet is not a tiny remainder
+        let mut packet_amount = amount / self.packets;
+        if packet_amount.saturating_mul(self.packets) < amount {
+            packet_amount += 1;
+        }
+        self.packet_amount = max(packet_amount, 1);
+    }
+
+    fn get_max_packet_amount(&self) -> u64 {
+        min(self.packet_amount, self.aimd.get_max_packet_amount())
+    }
+
+    fn get_amount_left_in_window(&self) -> u64 {
+        self.aimd.get_amount_left_in_window()
+    }
+
+    fn prepare(&mut self, amount: u64) {
+        self.aimd.prepare(amount);
+    }
+
+    fn fulfill(&mut self, prepare_amount: u64) {
+        self.aimd.fulfill(prepare_amount);
+    }
+
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
+        self.aimd.reject(prepare_amount, reject);
     }
 }
 
@@ -359,4 +709,152 @@ mod tests {
             assert_eq!(max_amount, 1000 - 600 - 100);
         }
     }
+
+    mod strategies {
+        use super::*;
+        use interledger_packet::RejectBuilder;
+
+        #[test]
+        fn fixed_packet_size() {
+            let mut controller = CongestionStrategy::FixedPacketSize {
+                packet_amount: 100,
+                packets_in_flight: 2,
+            }
+            .build();
+            controller.set_payment_amount(1000);
+            assert_eq!(controller.get_max_packet_amount(), 100);
+            assert_eq!(controller.get_amount_left_in_window(), 200);
+
+            controller.prepare(100);
+            controller.prepare(100);
+            assert_eq!(controller.get_amount_left_in_window(), 0);
+            controller.fulfill(100);
+            assert_eq!(controller.get_amount_left_in_window(), 100);
+
+            // A connector's max packet amount shrinks the packets
+            controller.reject(
+                100,
+                &RejectBuilder {
+                    code: ErrorCode::F08_AMOUNT_TOO_LARGE,
+                    message: &[],
+                    triggered_by: None,
+                    data: &MaxPacketAmountDetails::new(100, 50).to_bytes(),
+                }
+                .build(),
+            );
+            assert_eq!(controller.get_max_packet_amount(), 50);
+            assert_eq!(controller.get_amount_left_in_window(), 100);
+        }
+
+        #[test]
+        fn latency_aware_backs_off_on_slow_replies() {
+            let mut controller = CongestionStrategy::LatencyAware {
+                latency_factor: 2.0,
+            }
+            .build();
+            controller.set_payment_amount(1000);
+
+            controller.prepare(1000);
+            controller.round_trip(Duration::from_millis(100));
+            controller.fulfill(1000);
+            assert_eq!(controller.get_amount_left_in_window(), 2000);
+
+            controller.prepare(1000);
+            controller.round_trip(Duration::from_millis(150));
+            controller.fulfill(1000);
+            assert_eq!(controller.get_amount_left_in_window(), 4000);
+
+            // More than twice as slow as the fastest reply
+            controller.prepare(1000);
+            controller.round_trip(Duration::from_millis(250));
+            controller.fulfill(1000);
+            // Halved, and then increased by a tenth of the payment
+            assert_eq!(controller.get_amount_left_in_window(), 2100);
+        }
+
+        #[test]
+        fn latency_aware_decreases_once_per_round_trip() {
+            let mut controller = LatencyAware::new(2.0);
+            controller.set_payment_amount(1000);
+            controller.prepare(1000);
+            controller.round_trip(Duration::from_millis(10));
+            controller.fulfill(1000);
+            assert_eq!(controller.get_amount_left_in_window(), 2000);
+
+            // Both packets were sent before the first slow reply arrived
+            controller.prepare(1000);
+            controller.prepare(1000);
+            controller.round_trip(Duration::from_secs(1));
+            controller.fulfill(1000);
+            assert_eq!(controller.get_amount_left_in_window(), 1000 + 100 - 1000);
+            controller.round_trip(Duration::from_secs(1));
+            controller.reject(
+                1000,
+                &RejectBuilder {
+                    code: ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+                    message: &[],
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build(),
+            );
+            assert_eq!(controller.get_amount_left_in_window(), 1100);
+
+            // A packet sent after the decrease may decrease the window again
+            std::thread::sleep(Duration::from_millis(50));
+            controller.prepare(1000);
+            controller.round_trip(Duration::from_millis(30));
+            controller.fulfill(1000);
+            assert_eq!(controller.get_amount_left_in_window(), 550 + 100);
+        }
+
+        #[test]
+        fn latency_aware_ignores_invalid_factors() {
+            assert_eq!(LatencyAware::new(0.5).latency_factor, 2.0);
+            assert_eq!(LatencyAware::new(f64::NAN).latency_factor, 2.0);
+            assert_eq!(LatencyAware::new(1.5).latency_factor, 1.5);
+        }
+
+        #[test]
+        fn asap_splits_the_payment() {
+            let mut controller = CongestionStrategy::Asap { packets: 3 }.build();
+            controller.set_payment_amount(1000);
+            assert_eq!(controller.get_max_packet_amount(), 334);
+            // All packets can be sent right away
+            assert_eq!(controller.get_amount_left_in_window(), 1000);
+        }
+
+        #[test]
+        fn deserializes_strategies() {
+            assert_eq!(
+                serde_json::from_str::<CongestionStrategy>(r#"{"strategy":"aimd"}"#).unwrap(),
+                CongestionStrategy::Aimd
+            );
+            assert_eq!(
+                serde_json::from_str::<CongestionStrategy>(
+                    r#"{"strategy":"fixed_packet_size","packet_amount":10}"#
+                )
+                .unwrap(),
+                CongestionStrategy::FixedPacketSize {
+                    packet_amount: 10,
+                    packets_in_flight: 1,
+                }
+            );
+            assert_eq!(
+                serde_json::from_str::<CongestionStrategy>(r#"{"strategy":"asap"}"#).unwrap(),
+                CongestionStrategy::Asap { packets: 10 }
+            );
+            assert_eq!(
+                serde_json::from_str::<CongestionStrategy>(r#"{"strategy":"latency_aware"}"#)
+                    .unwrap(),
+                CongestionStrategy::LatencyAware {
+                    latency_factor: 2.0
+                }
+            );
+            assert!(serde_json::from_str::<CongestionStrategy>(
+                r#"{"strategy":"latency_aware","latency_factor":1}"#
+            )
+            .is_err());
+        }
+    }
 }
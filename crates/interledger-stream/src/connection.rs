@@ -0,0 +1,1277 @@
+use super::client::{convert, get_rate, MAX_TIME_SINCE_LAST_FULFILL};
+use super::congestion::{CongestionControl, CongestionController};
+use super::crypto::*;
+use super::data::{IncomingStream, DEFAULT_STREAM_RECEIVE_WINDOW};
+use super::error::Error;
+use super::packet::{ErrorCode as StreamErrorCode, *};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::future::poll_fn;
+use futures::StreamExt;
+use interledger_packet::{
+    Address, ErrorClass, ErrorCode, FulfillBuilder, PacketType as IlpPacketType, Prepare,
+    PrepareBuilder, RejectBuilder,
+};
+use interledger_rates::ExchangeRateStore;
+use interledger_service::{Account, IlpResult, IncomingRequest, IncomingService};
+use parking_lot::Mutex;
+use std::cmp::min;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+/// Maximum number of bytes of stream data sent in a single Prepare
+const MAX_DATA_PER_PACKET: usize = 16_384;
+
+/// Number of bytes written to a stream which are buffered before writes wait
+/// for the data to be sent
+const SEND_BUFFER_SIZE: usize = 65_536;
+
+/// Amount of data we assume the other endpoint accepts on each stream until it tells us otherwise
+const DEFAULT_REMOTE_RECEIVE_WINDOW: u64 = 16_384;
+
+/// Expiry of the Prepare packets sent over a connection
+const PACKET_EXPIRY: Duration = Duration::from_secs(30);
+
+/// Bounds of the backoff applied when packets are rejected with temporary errors
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// How often an idle sender checks whether the connection timed out
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why a connection was closed
+#[derive(Debug, Clone)]
+enum CloseReason {
+    /// Closed by the application on this side
+    Local,
+    /// Closed by the other endpoint
+    Remote(StreamErrorCode, String),
+    /// The other endpoint violated the flow control limits
+    FlowControl,
+    /// No packet was fulfilled within `MAX_TIME_SINCE_LAST_FULFILL`
+    Timeout,
+    /// No packets were exchanged within the idle timeout of an accepted connection
+    Idle,
+    /// A packet was rejected with a final error
+    Rejected(ErrorCode, String),
+}
+
+impl CloseReason {
+    fn to_error(&self) -> Error {
+        match self {
+            CloseReason::Local => Error::ConnectionClosed("closed locally".to_string()),
+            CloseReason::Remote(code, message) => Error::ConnectionClosed(format!(
+                "closed by the remote endpoint ({:?}: {})",
+                code, message
+            )),
+            CloseReason::FlowControl => {
+                Error::ConnectionClosed("the remote endpoint sent too much data".to_string())
+            }
+            CloseReason::Timeout => Error::Timeout,
+            CloseReason::Idle => Error::ConnectionClosed("idle for too long".to_string()),
+            CloseReason::Rejected(code, message) => {
+                Error::UnexpectedRejection(*code, message.clone())
+            }
+        }
+    }
+}
+
+/// State of one stream of a connection
+struct StreamState {
+    /// Data written by the application which has not been sent yet
+    send_buffer: BytesMut,
+    /// Offset of the first byte in `send_buffer`
+    send_offset: u64,
+    /// Data which was sent in a packet the other endpoint did not process
+    retransmit: VecDeque<(u64, Bytes)>,
+    /// Number of bytes in the packet currently in flight
+    unacked: usize,
+    /// Offset up to which the other endpoint is willing to receive data
+    remote_max_offset: u64,
+    /// The application will not write more data
+    write_closed: bool,
+    /// The other endpoint acknowledged our StreamClose frame
+    close_sent: bool,
+
+    /// Reassembles the data sent by the other endpoint
+    incoming: IncomingStream,
+    /// In-order data which the application has not read yet
+    read_buffer: BytesMut,
+    /// Max offset we last told the other endpoint about
+    advertised_max_offset: u64,
+    /// The other endpoint closed the stream
+    remote_closed: bool,
+
+    /// Total amount the application asked to send, in our units
+    send_max: u64,
+    /// Amount sent in fulfilled packets, in our units
+    sent: u64,
+    /// Amount the other endpoint received, in its units
+    delivered: u64,
+    /// Limits the other endpoint put on the money it receives on this stream, in its units
+    remote_receive_max: u64,
+    remote_received: u64,
+
+    /// Amount received, in our units
+    received: u64,
+    /// Maximum amount we accept on this stream, in our units
+    receive_max: u64,
+    /// The receive max changed and the other endpoint needs to be told about it
+    advertise_receive_max: bool,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        StreamState {
+            send_buffer: BytesMut::new(),
+            send_offset: 0,
+            retransmit: VecDeque::new(),
+            unacked: 0,
+            remote_max_offset: DEFAULT_REMOTE_RECEIVE_WINDOW,
+            write_closed: false,
+            close_sent: false,
+            incoming: IncomingStream::default(),
+            read_buffer: BytesMut::new(),
+            advertised_max_offset: DEFAULT_STREAM_RECEIVE_WINDOW,
+            remote_closed: false,
+            send_max: 0,
+            sent: 0,
+            delivered: 0,
+            remote_receive_max: u64::MAX,
+            remote_received: 0,
+            received: 0,
+            receive_max: u64::MAX,
+            advertise_receive_max: false,
+        }
+    }
+
+    /// Offset up to which the application has read the data
+    fn read_offset(&self) -> u64 {
+        self.incoming.delivered() - self.read_buffer.len() as u64
+    }
+
+    fn max_offset(&self) -> u64 {
+        self.read_offset()
+            .saturating_add(DEFAULT_STREAM_RECEIVE_WINDOW)
+    }
+
+    fn money_to_send(&self) -> u64 {
+        if self.remote_closed || self.remote_received >= self.remote_receive_max {
+            0
+        } else {
+            self.send_max - self.sent
+        }
+    }
+
+    fn has_data_to_send(&self) -> bool {
+        !self.send_buffer.is_empty() || !self.retransmit.is_empty() || self.unacked > 0
+    }
+
+    /// Anything the application asked for that the other endpoint has not acknowledged yet
+    fn is_pending(&self) -> bool {
+        self.has_data_to_send()
+            || self.money_to_send() > 0
+            || (self.write_closed && !self.close_sent && !self.remote_closed)
+    }
+
+    /// Stops sending on a stream which the other endpoint closed
+    fn remote_close(&mut self) {
+        self.remote_closed = true;
+        self.send_buffer.clear();
+        self.retransmit.clear();
+        self.send_max = self.sent;
+    }
+}
+
+struct ConnectionState {
+    /// Clients use odd and servers even stream IDs
+    is_client: bool,
+    next_stream_id: u64,
+    streams: BTreeMap<u64, StreamState>,
+    /// Streams opened by the other endpoint, waiting to be accepted
+    new_streams: UnboundedSender<DataMoneyStream>,
+    remote_address: Option<Address>,
+    remote_asset: Option<(String, u8)>,
+    /// We have not told the other endpoint our address yet
+    send_address: bool,
+    sequence: u64,
+    /// The application asked to close the connection once everything is sent
+    closing: bool,
+    closed: Option<CloseReason>,
+    /// Last time the other endpoint sent us a packet or processed one of ours
+    last_progress: Instant,
+    /// Tasks waiting for the state to change
+    wakers: Vec<Waker>,
+}
+
+impl ConnectionState {
+    fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn close(&mut self, reason: CloseReason) {
+        if self.closed.is_none() {
+            debug!("Closing STREAM connection: {:?}", reason);
+            self.closed = Some(reason);
+            self.new_streams.close_channel();
+        }
+        self.wake_all();
+    }
+
+    fn is_pending(&self) -> bool {
+        self.streams.values().any(StreamState::is_pending)
+    }
+}
+
+/// The state of a connection shared by its handles, its streams and the task sending its packets
+pub(crate) struct Connection {
+    local_address: Address,
+    shared_secret: Bytes,
+    asset_code: String,
+    asset_scale: u8,
+    state: Mutex<ConnectionState>,
+    /// Wakes up the task sending the packets
+    notify: Notify,
+}
+
+/// A packet being sent, along with what it carries so the response can be applied
+struct OutgoingPacket {
+    sequence: u64,
+    source_amount: u64,
+    min_destination_amount: u64,
+    /// Source amount sent on each stream
+    money: Vec<(u64, u64)>,
+    /// Stream ID, offset and the data sent on each stream
+    data: Vec<(u64, u64, Bytes)>,
+    closes: Vec<u64>,
+    max_data: Vec<StreamMaxDataFrame>,
+    max_money: Vec<u64>,
+    connection_close: bool,
+}
+
+impl Connection {
+    pub(crate) fn new(
+        local_address: Address,
+        remote_address: Option<Address>,
+        shared_secret: Bytes,
+        asset_code: String,
+        asset_scale: u8,
+    ) -> (Arc<Self>, UnboundedReceiver<DataMoneyStream>) {
+        let (new_streams, incoming_streams) = futures::channel::mpsc::unbounded();
+        let is_client = remote_address.is_some();
+        let connection = Connection {
+            local_address,
+            shared_secret,
+            asset_code,
+            asset_scale,
+            state: Mutex::new(ConnectionState {
+                is_client,
+                next_stream_id: if is_client { 1 } else { 2 },
+                streams: BTreeMap::new(),
+                new_streams,
+                send_address: is_client,
+                remote_address,
+                remote_asset: None,
+                sequence: 0,
+                closing: false,
+                closed: None,
+                last_progress: Instant::now(),
+                wakers: Vec::new(),
+            }),
+            notify: Notify::new(),
+        };
+        (Arc::new(connection), incoming_streams)
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state.lock().closed.is_some()
+    }
+
+    /// Handles a Prepare sent to this connection's address.
+    ///
+    /// Returns None if the packet could not be decrypted, i.e. it was not meant for this connection.
+    pub(crate) fn handle_prepare(self: &Arc<Self>, prepare: &Prepare) -> Option<IlpResult> {
+        let request =
+            StreamPacket::from_encrypted(&self.shared_secret[..], BytesMut::from(prepare.data()))
+                .ok()?;
+        if request.ilp_packet_type() != IlpPacketType::Prepare {
+            warn!("Ignoring STREAM packet which does not claim to be a Prepare");
+            return None;
+        }
+
+        let fulfillment = generate_fulfillment(&self.shared_secret[..], prepare.data());
+        let mut is_fulfillable = hash_sha256(&fulfillment) == prepare.execution_condition()
+            && prepare.amount() >= request.prepare_amount();
+
+        let mut state = self.state.lock();
+        let mut response_frames: Vec<Frame> = Vec::new();
+        let mut money_frames = Vec::new();
+        let mut data_frames = Vec::new();
+
+        if state.closed.is_some() {
+            is_fulfillable = false;
+            response_frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+                code: StreamErrorCode::NoError,
+                message: "",
+            }));
+        } else {
+            state.last_progress = Instant::now();
+            for frame in request.frames() {
+                match frame {
+                    Frame::ConnectionNewAddress(frame) => {
+                        state.remote_address = Some(frame.source_account);
+                        response_frames.push(Frame::ConnectionAssetDetails(
+                            ConnectionAssetDetailsFrame {
+                                source_asset_code: &self.asset_code,
+                                source_asset_scale: self.asset_scale,
+                            },
+                        ));
+                    }
+                    Frame::ConnectionAssetDetails(frame) => {
+                        state.remote_asset = Some((
+                            frame.source_asset_code.to_string(),
+                            frame.source_asset_scale,
+                        ));
+                    }
+                    Frame::ConnectionClose(frame) => {
+                        is_fulfillable = false;
+                        state.close(CloseReason::Remote(frame.code, frame.message.to_string()));
+                    }
+                    Frame::StreamMaxMoney(frame) => {
+                        if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                            stream.remote_receive_max = frame.receive_max;
+                            stream.remote_received =
+                                stream.remote_received.max(frame.total_received);
+                        }
+                    }
+                    Frame::StreamMaxData(frame) => {
+                        if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                            stream.remote_max_offset =
+                                stream.remote_max_offset.max(frame.max_offset);
+                        }
+                    }
+                    Frame::StreamClose(frame) => {
+                        if let Some(stream) = self.get_or_open_stream(&mut state, frame.stream_id) {
+                            stream.remote_close();
+                        }
+                    }
+                    Frame::StreamMoney(frame) => money_frames.push(frame),
+                    Frame::StreamData(frame) => data_frames.push(frame),
+                    _ => {}
+                }
+            }
+        }
+
+        // Split the amount between the streams according to their shares and
+        // only accept it if every stream is willing to receive its part
+        let mut amounts = Vec::new();
+        if state.closed.is_none() && !money_frames.is_empty() {
+            let total_shares: u128 = money_frames.iter().map(|f| u128::from(f.shares)).sum();
+            let mut remaining = prepare.amount();
+            for (i, frame) in money_frames.iter().enumerate() {
+                let amount = if i == money_frames.len() - 1 {
+                    remaining
+                } else {
+                    (u128::from(prepare.amount()) * u128::from(frame.shares))
+                        .checked_div(total_shares)
+                        .unwrap_or(0) as u64
+                };
+                remaining -= amount;
+                match self.get_or_open_stream(&mut state, frame.stream_id) {
+                    Some(stream) => {
+                        if stream.received.saturating_add(amount) > stream.receive_max {
+                            is_fulfillable = false;
+                        }
+                        amounts.push((frame.stream_id, amount));
+                    }
+                    None => is_fulfillable = false,
+                }
+            }
+            if is_fulfillable {
+                for (stream_id, amount) in amounts.iter() {
+                    if let Some(stream) = state.streams.get_mut(stream_id) {
+                        stream.received += amount;
+                    }
+                }
+            }
+            for (stream_id, _) in amounts.iter() {
+                if let Some(stream) = state.streams.get_mut(stream_id) {
+                    stream.advertise_receive_max = false;
+                    response_frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                        stream_id: *stream_id,
+                        receive_max: stream.receive_max,
+                        total_received: stream.received,
+                    }));
+                }
+            }
+        }
+
+        // Buffer the data for the application to read
+        if state.closed.is_none() {
+            let mut flow_control_violated = false;
+            for frame in data_frames.iter() {
+                let stream = match self.get_or_open_stream(&mut state, frame.stream_id) {
+                    Some(stream) => stream,
+                    None => {
+                        // Data on one of our streams we never opened cannot be delivered
+                        is_fulfillable = false;
+                        continue;
+                    }
+                };
+                if frame.offset.saturating_add(frame.data.len() as u64) > stream.max_offset() {
+                    flow_control_violated = true;
+                    break;
+                }
+                // The max offset already bounds the data buffered for the stream
+                if let Some(Some(data)) = stream.incoming.push(frame.offset, frame.data, u64::MAX) {
+                    stream.read_buffer.extend_from_slice(&data);
+                }
+                let max_offset = stream.max_offset();
+                stream.advertised_max_offset = max_offset;
+                if !response_frames.iter().any(|response| {
+                    matches!(response, Frame::StreamMaxData(f) if f.stream_id == frame.stream_id)
+                }) {
+                    response_frames.push(Frame::StreamMaxData(StreamMaxDataFrame {
+                        stream_id: frame.stream_id,
+                        max_offset,
+                    }));
+                }
+            }
+            if flow_control_violated {
+                is_fulfillable = false;
+                state.close(CloseReason::FlowControl);
+                response_frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+                    code: StreamErrorCode::FlowControlError,
+                    message: "Stream data exceeds the advertised max offset",
+                }));
+            }
+        }
+
+        state.wake_all();
+        drop(state);
+        self.notify.notify();
+
+        let response = StreamPacketBuilder {
+            sequence: request.sequence(),
+            ilp_packet_type: if is_fulfillable {
+                IlpPacketType::Fulfill
+            } else {
+                IlpPacketType::Reject
+            },
+            prepare_amount: prepare.amount(),
+            frames: &response_frames,
+        }
+        .build()
+        .into_encrypted(&self.shared_secret[..]);
+
+        Some(if is_fulfillable {
+            Ok(FulfillBuilder {
+                fulfillment: &fulfillment,
+                data: &response[..],
+            }
+            .build())
+        } else {
+            Err(RejectBuilder {
+                code: ErrorCode::F99_APPLICATION_ERROR,
+                message: &[],
+                triggered_by: Some(&self.local_address),
+                data: &response[..],
+            }
+            .build())
+        })
+    }
+
+    /// Returns the stream, opening it if it is a new stream of the other endpoint.
+    ///
+    /// Streams are never forgotten, so any unknown stream ID of the other endpoint is a new
+    /// stream, even if its packets arrive after those of a stream it opened later.
+    fn get_or_open_stream<'a>(
+        self: &Arc<Self>,
+        state: &'a mut ConnectionState,
+        stream_id: u64,
+    ) -> Option<&'a mut StreamState> {
+        let is_remote_stream = (stream_id % 2 == 1) != state.is_client;
+        if is_remote_stream && !state.streams.contains_key(&stream_id) {
+            state.streams.insert(stream_id, StreamState::new());
+            let stream = DataMoneyStream {
+                id: stream_id,
+                connection: self.clone(),
+            };
+            if state.new_streams.unbounded_send(stream).is_err() {
+                debug!(
+                    "Stream {} was opened after the connection closed",
+                    stream_id
+                );
+            }
+        }
+        state.streams.get_mut(&stream_id)
+    }
+
+    /// Decides what the next packet carries, or returns None if there is nothing to send
+    fn next_packet<S: ExchangeRateStore>(
+        &self,
+        state: &mut ConnectionState,
+        congestion_controller: &mut Option<CongestionController>,
+        store: &S,
+        slippage: f64,
+    ) -> Option<(OutgoingPacket, Prepare)> {
+        let destination = state.remote_address.clone()?;
+
+        // Money: fill the streams in order of their IDs
+        let money_to_send: u64 = state
+            .streams
+            .values()
+            .map(StreamState::money_to_send)
+            .fold(0, u64::saturating_add);
+        let mut source_amount = 0;
+        let mut money = Vec::new();
+        if money_to_send > 0 {
+            let congestion_controller = congestion_controller.get_or_insert_with(|| {
+                CongestionController::new(money_to_send, money_to_send / 10, 2.0)
+            });
+            source_amount = min(
+                money_to_send,
+                min(
+                    congestion_controller.get_amount_left_in_window(),
+                    congestion_controller.get_max_packet_amount(),
+                ),
+            );
+            let mut left = source_amount;
+            for (stream_id, stream) in state.streams.iter() {
+                let amount = min(left, stream.money_to_send());
+                if amount > 0 {
+                    money.push((*stream_id, amount));
+                    left -= amount;
+                }
+            }
+        }
+        let (remote_code, remote_scale) = match state.remote_asset {
+            Some((ref code, scale)) => (Some(code.as_str()), Some(scale)),
+            None => (None, None),
+        };
+        let min_destination_amount = get_rate(
+            store,
+            self.asset_scale,
+            &self.asset_code,
+            remote_scale,
+            remote_code,
+            slippage,
+        )
+        .and_then(|rate| convert(source_amount, rate))
+        .unwrap_or(0);
+
+        // Data: resend what was lost before sending new data
+        let mut data = Vec::new();
+        let mut closes = Vec::new();
+        let mut budget = MAX_DATA_PER_PACKET;
+        for (stream_id, stream) in state.streams.iter_mut() {
+            while budget > 0 {
+                match stream.retransmit.front() {
+                    Some((_, chunk)) if chunk.len() <= budget => {
+                        let (offset, chunk) = stream.retransmit.pop_front().unwrap();
+                        budget -= chunk.len();
+                        stream.unacked += chunk.len();
+                        data.push((*stream_id, offset, chunk));
+                    }
+                    _ => break,
+                }
+            }
+            if stream.retransmit.is_empty() {
+                let allowed = stream.remote_max_offset.saturating_sub(stream.send_offset);
+                let len = min(
+                    min(budget as u64, allowed) as usize,
+                    stream.send_buffer.len(),
+                );
+                if len > 0 {
+                    let chunk = stream.send_buffer.split_to(len).freeze();
+                    data.push((*stream_id, stream.send_offset, chunk));
+                    stream.send_offset += len as u64;
+                    stream.unacked += len;
+                    budget -= len;
+                }
+            }
+            // Streams are closed after all their money was sent, so the StreamClose
+            // frame can't be accepted while the money in the same packet is rejected
+            if stream.write_closed
+                && !stream.close_sent
+                && !stream.remote_closed
+                && stream.send_buffer.is_empty()
+                && stream.retransmit.is_empty()
+                && stream.money_to_send() == 0
+            {
+                closes.push(*stream_id);
+            }
+        }
+
+        // Flow control updates are only worth a packet of their own once
+        // the window opened up by at least half
+        let mut max_data = Vec::new();
+        let mut max_money = Vec::new();
+        let mut urgent_updates = false;
+        for (stream_id, stream) in state.streams.iter() {
+            if stream.remote_closed {
+                continue;
+            }
+            let max_offset = stream.max_offset();
+            if max_offset > stream.advertised_max_offset {
+                urgent_updates |=
+                    max_offset - stream.advertised_max_offset >= DEFAULT_STREAM_RECEIVE_WINDOW / 2;
+                max_data.push(StreamMaxDataFrame {
+                    stream_id: *stream_id,
+                    max_offset,
+                });
+            }
+            if stream.advertise_receive_max {
+                urgent_updates = true;
+                max_money.push(*stream_id);
+            }
+        }
+
+        let connection_close = state.closing && !state.is_pending() && closes.is_empty();
+
+        if source_amount == 0
+            && data.is_empty()
+            && closes.is_empty()
+            && !urgent_updates
+            && !state.send_address
+            && !connection_close
+        {
+            return None;
+        }
+
+        state.sequence += 1;
+        let packet = OutgoingPacket {
+            sequence: state.sequence,
+            source_amount,
+            min_destination_amount,
+            money,
+            data,
+            closes,
+            max_data,
+            max_money,
+            connection_close,
+        };
+
+        let mut frames: Vec<Frame> = Vec::new();
+        if state.send_address {
+            frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                source_account: self.local_address.clone(),
+            }));
+            frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                source_asset_code: &self.asset_code,
+                source_asset_scale: self.asset_scale,
+            }));
+        }
+        for (stream_id, amount) in packet.money.iter() {
+            frames.push(Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: *stream_id,
+                shares: *amount,
+            }));
+        }
+        for (stream_id, offset, chunk) in packet.data.iter() {
+            frames.push(Frame::StreamData(StreamDataFrame {
+                stream_id: *stream_id,
+                offset: *offset,
+                data: &chunk[..],
+            }));
+        }
+        frames.extend(packet.max_data.iter().cloned().map(Frame::StreamMaxData));
+        for stream_id in packet.max_money.iter() {
+            let stream = &state.streams[stream_id];
+            frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                stream_id: *stream_id,
+                receive_max: stream.receive_max,
+                total_received: stream.received,
+            }));
+        }
+        for stream_id in packet.closes.iter() {
+            frames.push(Frame::StreamClose(StreamCloseFrame {
+                stream_id: *stream_id,
+                code: StreamErrorCode::NoError,
+                message: "",
+            }));
+        }
+        if packet.connection_close {
+            frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+                code: StreamErrorCode::NoError,
+                message: "",
+            }));
+        }
+
+        let data = StreamPacketBuilder {
+            sequence: packet.sequence,
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: packet.min_destination_amount,
+            frames: &frames,
+        }
+        .build()
+        .into_encrypted(&self.shared_secret[..]);
+
+        // If we can't calculate a minimum destination amount (e.g. we don't know the
+        // asset details yet), the packet must be unfulfillable so no money is at risk
+        let execution_condition = if packet.source_amount > 0 && packet.min_destination_amount > 0 {
+            generate_condition(&self.shared_secret[..], &data)
+        } else {
+            random_condition()
+        };
+        let prepare = PrepareBuilder {
+            destination,
+            amount: packet.source_amount,
+            execution_condition: &execution_condition,
+            expires_at: SystemTime::now() + PACKET_EXPIRY,
+            data: &data[..],
+        }
+        .build();
+
+        if let Some(congestion_controller) = congestion_controller {
+            congestion_controller.prepare(packet.source_amount);
+        }
+        Some((packet, prepare))
+    }
+
+    /// Applies the Fulfill or Reject of a packet.
+    ///
+    /// Returns true if the packet was rejected with a temporary error or the receiver refused
+    /// its money, in which case the sender should back off before sending the next one.
+    fn apply_result(
+        &self,
+        state: &mut ConnectionState,
+        congestion_controller: &mut Option<CongestionController>,
+        packet: OutgoingPacket,
+        result: IlpResult,
+    ) -> bool {
+        let (is_fulfill, data) = match result {
+            Ok(ref fulfill) => (true, fulfill.data()),
+            Err(ref reject) => (false, reject.data()),
+        };
+        let response = StreamPacket::from_encrypted(&self.shared_secret[..], BytesMut::from(data))
+            .ok()
+            .filter(|response| {
+                response.sequence() == packet.sequence
+                    && !(is_fulfill && response.ilp_packet_type() == IlpPacketType::Reject)
+            });
+
+        if let Some(ref response) = response {
+            // The other endpoint processed our packet
+            state.send_address = false;
+            state.last_progress = Instant::now();
+            for frame in response.frames() {
+                match frame {
+                    Frame::ConnectionAssetDetails(frame) => {
+                        state.remote_asset = Some((
+                            frame.source_asset_code.to_string(),
+                            frame.source_asset_scale,
+                        ));
+                    }
+                    Frame::ConnectionClose(frame) => {
+                        state.close(CloseReason::Remote(frame.code, frame.message.to_string()))
+                    }
+                    Frame::StreamMaxMoney(frame) => {
+                        if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                            stream.remote_receive_max = frame.receive_max;
+                            stream.remote_received =
+                                stream.remote_received.max(frame.total_received);
+                        }
+                    }
+                    Frame::StreamMaxData(frame) => {
+                        if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                            stream.remote_max_offset =
+                                stream.remote_max_offset.max(frame.max_offset);
+                        }
+                    }
+                    Frame::StreamClose(frame) => {
+                        if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                            stream.remote_close();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Data counts as delivered once the other endpoint decrypted the packet,
+        // even if it rejected it (which it does for packets without money)
+        for (stream_id, offset, chunk) in packet.data.into_iter().rev() {
+            if let Some(stream) = state.streams.get_mut(&stream_id) {
+                stream.unacked -= chunk.len();
+                if response.is_none() && !stream.remote_closed {
+                    stream.retransmit.push_front((offset, chunk));
+                }
+            }
+        }
+        if response.is_some() {
+            for stream_id in packet.closes.iter() {
+                if let Some(stream) = state.streams.get_mut(stream_id) {
+                    stream.close_sent = true;
+                }
+            }
+            for frame in packet.max_data.iter() {
+                if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                    stream.advertised_max_offset = frame.max_offset;
+                }
+            }
+            for stream_id in packet.max_money.iter() {
+                if let Some(stream) = state.streams.get_mut(stream_id) {
+                    stream.advertise_receive_max = false;
+                }
+            }
+        }
+        if packet.connection_close {
+            state.close(CloseReason::Local);
+        }
+
+        let mut retry = false;
+        match result {
+            Ok(_) => {
+                let claimed_amount = response.map(|r| r.prepare_amount()).unwrap_or(0);
+                let delivered = claimed_amount.max(packet.min_destination_amount);
+                if let (Some(congestion_controller), true) =
+                    (congestion_controller.as_mut(), packet.source_amount > 0)
+                {
+                    congestion_controller.fulfill(packet.source_amount);
+                }
+                let mut delivered_left = delivered;
+                let streams = packet.money.len();
+                for (i, (stream_id, amount)) in packet.money.into_iter().enumerate() {
+                    let share = if i == streams - 1 {
+                        delivered_left
+                    } else {
+                        (u128::from(delivered) * u128::from(amount)
+                            / u128::from(packet.source_amount)) as u64
+                    };
+                    delivered_left -= share;
+                    if let Some(stream) = state.streams.get_mut(&stream_id) {
+                        stream.sent += amount;
+                        stream.delivered += share;
+                    }
+                }
+                debug!(
+                    "Packet {} sending {} was fulfilled ({} delivered)",
+                    packet.sequence, packet.source_amount, delivered
+                );
+            }
+            Err(reject) => {
+                if let (Some(congestion_controller), true) =
+                    (congestion_controller.as_mut(), packet.source_amount > 0)
+                {
+                    congestion_controller.reject(packet.source_amount, &reject);
+                }
+                debug!(
+                    "Packet {} sending {} was rejected with code: {}",
+                    packet.sequence,
+                    packet.source_amount,
+                    reject.code()
+                );
+                match (reject.code().class(), reject.code()) {
+                    (ErrorClass::Temporary, _) => retry = true,
+                    (_, ErrorCode::F08_AMOUNT_TOO_LARGE) => {}
+                    // The receiver refused the money, e.g. because of its receive max or
+                    // the exchange rate, so don't keep sending it at full speed
+                    (_, ErrorCode::F99_APPLICATION_ERROR) => retry = packet.source_amount > 0,
+                    (_, ErrorCode::R01_INSUFFICIENT_SOURCE_AMOUNT) => {}
+                    _ => state.close(CloseReason::Rejected(
+                        reject.code(),
+                        String::from_utf8_lossy(reject.message()).into_owned(),
+                    )),
+                }
+            }
+        }
+
+        state.wake_all();
+        retry
+    }
+}
+
+/// Sends the packets of a connection, one at a time, until the connection is closed.
+///
+/// Accepted connections are closed once no packets were exchanged within the idle timeout.
+pub(crate) async fn run_sender<I, A, S>(
+    connection: Arc<Connection>,
+    mut next: I,
+    from_account: A,
+    store: S,
+    slippage: f64,
+    idle_timeout: Duration,
+) where
+    I: IncomingService<A>,
+    A: Account,
+    S: ExchangeRateStore,
+{
+    let mut congestion_controller = None;
+    let mut retry_delay = MIN_RETRY_DELAY;
+    loop {
+        let packet = {
+            let mut state = connection.state.lock();
+            if state.closed.is_some() {
+                break;
+            }
+            if state.is_pending() && state.last_progress.elapsed() >= MAX_TIME_SINCE_LAST_FULFILL {
+                state.close(CloseReason::Timeout);
+                break;
+            }
+            if !state.is_client && state.last_progress.elapsed() >= idle_timeout {
+                state.close(CloseReason::Idle);
+                break;
+            }
+            if state.closing && state.remote_address.is_none() {
+                // The other endpoint never told us where to send packets
+                state.close(CloseReason::Local);
+                break;
+            }
+            connection.next_packet(&mut state, &mut congestion_controller, &store, slippage)
+        };
+
+        let (packet, prepare) = match packet {
+            Some(packet) => packet,
+            None => {
+                let _ =
+                    tokio::time::timeout(IDLE_CHECK_INTERVAL, connection.notify.notified()).await;
+                continue;
+            }
+        };
+
+        let result = next
+            .handle_request(IncomingRequest {
+                from: from_account.clone(),
+                prepare,
+            })
+            .await;
+
+        let retry = {
+            let mut state = connection.state.lock();
+            connection.apply_result(&mut state, &mut congestion_controller, packet, result)
+        };
+        if retry {
+            tokio::time::delay_for(retry_delay).await;
+            retry_delay = min(retry_delay * 2, MAX_RETRY_DELAY);
+        } else {
+            retry_delay = MIN_RETRY_DELAY;
+        }
+    }
+}
+
+/// A STREAM connection, which carries any number of streams of money and data in both directions.
+///
+/// Connections are created by a [`StreamListener`](./struct.StreamListener.html), either by
+/// connecting to another endpoint or by accepting the connections other endpoints open.
+/// Handles can be cloned freely; the connection stays open until either side closes it.
+#[derive(Clone)]
+pub struct StreamConnection {
+    connection: Arc<Connection>,
+    incoming_streams: Arc<tokio::sync::Mutex<UnboundedReceiver<DataMoneyStream>>>,
+}
+
+impl StreamConnection {
+    pub(crate) fn new(
+        connection: Arc<Connection>,
+        incoming_streams: UnboundedReceiver<DataMoneyStream>,
+    ) -> Self {
+        StreamConnection {
+            connection,
+            incoming_streams: Arc::new(tokio::sync::Mutex::new(incoming_streams)),
+        }
+    }
+
+    pub(crate) fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+
+    /// The address packets for this connection are sent to
+    pub fn local_address(&self) -> &Address {
+        &self.connection.local_address
+    }
+
+    /// The address of the other endpoint, once it is known
+    pub fn remote_address(&self) -> Option<Address> {
+        self.connection.state.lock().remote_address.clone()
+    }
+
+    /// Opens a new stream
+    pub fn open_stream(&self) -> DataMoneyStream {
+        let mut state = self.connection.state.lock();
+        let id = state.next_stream_id;
+        state.next_stream_id += 2;
+        state.streams.insert(id, StreamState::new());
+        DataMoneyStream {
+            id,
+            connection: self.connection.clone(),
+        }
+    }
+
+    /// Waits for the other endpoint to open a stream.
+    ///
+    /// Returns None once the connection is closed.
+    pub async fn accept_stream(&self) -> Option<DataMoneyStream> {
+        self.incoming_streams.lock().await.next().await
+    }
+
+    /// Closes the connection once all the data and money written to its streams has been sent
+    pub async fn close(&self) -> Result<(), Error> {
+        self.connection.state.lock().closing = true;
+        self.connection.notify.notify();
+        self.closed().await
+    }
+
+    /// Waits for the connection to be closed by either side
+    pub async fn closed(&self) -> Result<(), Error> {
+        poll_fn(|cx| {
+            let mut state = self.connection.state.lock();
+            match state.closed {
+                Some(CloseReason::Local) => Poll::Ready(Ok(())),
+                Some(CloseReason::Remote(StreamErrorCode::NoError, _)) => Poll::Ready(Ok(())),
+                Some(ref reason) => Poll::Ready(Err(reason.to_error())),
+                None => {
+                    state.register(cx.waker());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.connection.is_closed()
+    }
+}
+
+/// A single stream of a [`StreamConnection`](./struct.StreamConnection.html).
+///
+/// Data is written and read through the `AsyncWrite` and `AsyncRead` implementations. Writes
+/// are sent in order and as fast as the other endpoint's receive window allows, while reads
+/// return the data in order and open up the receive window again. Shutting down the writing
+/// side closes the stream once all the data has been sent.
+///
+/// Money is sent with `send_money` and received up to the limit set with `set_receive_max`.
+pub struct DataMoneyStream {
+    id: u64,
+    connection: Arc<Connection>,
+}
+
+impl DataMoneyStream {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Sends the amount (in the units of our account) and waits until it was delivered
+    pub async fn send_money(&self, amount: u64) -> Result<(), Error> {
+        let target = {
+            let mut state = self.connection.state.lock();
+            if let Some(ref reason) = state.closed {
+                return Err(reason.to_error());
+            }
+            let stream = self.stream(&mut state)?;
+            if stream.remote_closed {
+                return Err(Error::ConnectionClosed(format!(
+                    "stream {} was closed by the remote endpoint",
+                    self.id
+                )));
+            }
+            stream.send_max += amount;
+            stream.send_max
+        };
+        self.connection.notify.notify();
+
+        poll_fn(|cx| {
+            let mut state = self.connection.state.lock();
+            let sent = match state.streams.get(&self.id) {
+                Some(stream) if stream.sent >= target => return Poll::Ready(Ok(())),
+                Some(stream) => stream.sent,
+                None => 0,
+            };
+            if let Some(ref reason) = state.closed {
+                return Poll::Ready(Err(reason.to_error()));
+            }
+            match state.streams.get(&self.id) {
+                Some(stream) if !stream.remote_closed => {
+                    state.register(cx.waker());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(Err(Error::ConnectionClosed(format!(
+                    "stream {} was closed by the remote endpoint after sending {}",
+                    self.id, sent
+                )))),
+            }
+        })
+        .await
+    }
+
+    /// Waits until the total amount received on the stream reaches the given amount,
+    /// and returns the total received
+    pub async fn receive_money(&self, amount: u64) -> Result<u64, Error> {
+        poll_fn(|cx| {
+            let mut state = self.connection.state.lock();
+            let received = state
+                .streams
+                .get(&self.id)
+                .map(|stream| stream.received)
+                .unwrap_or(0);
+            if received >= amount {
+                Poll::Ready(Ok(received))
+            } else if let Some(ref reason) = state.closed {
+                Poll::Ready(Err(reason.to_error()))
+            } else {
+                state.register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Sets the total amount (in the units of our account) we accept on this stream
+    pub fn set_receive_max(&self, receive_max: u64) {
+        let mut state = self.connection.state.lock();
+        if let Ok(stream) = self.stream(&mut state) {
+            stream.receive_max = receive_max;
+            stream.advertise_receive_max = true;
+        }
+        drop(state);
+        self.connection.notify.notify();
+    }
+
+    /// Total amount received on this stream, in the units of our account
+    pub fn total_received(&self) -> u64 {
+        self.read_stream(|stream| stream.received)
+    }
+
+    /// Total amount sent on this stream, in the units of our account
+    pub fn total_sent(&self) -> u64 {
+        self.read_stream(|stream| stream.sent)
+    }
+
+    /// Total amount the other endpoint received on this stream, in the units of its account
+    pub fn total_delivered(&self) -> u64 {
+        self.read_stream(|stream| stream.delivered)
+    }
+
+    fn read_stream(&self, read: impl Fn(&StreamState) -> u64) -> u64 {
+        self.connection
+            .state
+            .lock()
+            .streams
+            .get(&self.id)
+            .map(read)
+            .unwrap_or(0)
+    }
+
+    fn stream<'a>(&self, state: &'a mut ConnectionState) -> Result<&'a mut StreamState, Error> {
+        let id = self.id;
+        state
+            .streams
+            .get_mut(&id)
+            .ok_or_else(|| Error::ConnectionClosed(format!("stream {} does not exist", id)))
+    }
+}
+
+fn broken_pipe(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, message)
+}
+
+impl AsyncRead for DataMoneyStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.connection.state.lock();
+        let is_closed = state.closed.is_some();
+        let stream = match state.streams.get_mut(&self.id) {
+            Some(stream) => stream,
+            None => return Poll::Ready(Ok(0)),
+        };
+        if !stream.read_buffer.is_empty() {
+            let len = min(buf.len(), stream.read_buffer.len());
+            buf[..len].copy_from_slice(&stream.read_buffer[..len]);
+            stream.read_buffer.advance(len);
+            let window_opened = stream.max_offset() - stream.advertised_max_offset
+                >= DEFAULT_STREAM_RECEIVE_WINDOW / 2;
+            drop(state);
+            if window_opened {
+                self.connection.notify.notify();
+            }
+            Poll::Ready(Ok(len))
+        } else if stream.remote_closed || is_closed {
+            Poll::Ready(Ok(0))
+        } else {
+            state.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+impl AsyncWrite for DataMoneyStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.connection.state.lock();
+        if state.closed.is_some() {
+            return Poll::Ready(Err(broken_pipe("the connection is closed")));
+        }
+        let stream = match state.streams.get_mut(&self.id) {
+            Some(stream) if !stream.write_closed && !stream.remote_closed => stream,
+            _ => return Poll::Ready(Err(broken_pipe("the stream is closed"))),
+        };
+        if stream.send_buffer.len() >= SEND_BUFFER_SIZE {
+            state.register(cx.waker());
+            return Poll::Pending;
+        }
+        let len = min(buf.len(), SEND_BUFFER_SIZE - stream.send_buffer.len());
+        stream.send_buffer.extend_from_slice(&buf[..len]);
+        drop(state);
+        self.connection.notify.notify();
+        Poll::Ready(Ok(len))
+    }
+
+    /// Waits until the other endpoint received all the data written so far
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.connection.state.lock();
+        let is_closed = state.closed.is_some();
+        match state.streams.get(&self.id) {
+            Some(stream) if stream.has_data_to_send() && !stream.remote_closed => {
+                if is_closed {
+                    Poll::Ready(Err(broken_pipe("the connection is closed")))
+                } else {
+                    state.register(cx.waker());
+                    Poll::Pending
+                }
+            }
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Closes the stream once all the data written to it has been sent
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.connection.state.lock();
+        let is_closed = state.closed.is_some();
+        let stream = match state.streams.get_mut(&self.id) {
+            Some(stream) => stream,
+            None => return Poll::Ready(Ok(())),
+        };
+        if !stream.write_closed {
+            stream.write_closed = true;
+            self.connection.notify.notify();
+        }
+        if stream.close_sent || stream.remote_closed || is_closed {
+            Poll::Ready(Ok(()))
+        } else {
+            state.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,533 @@
+use super::packet::{StreamDataFrame, StreamMaxDataFrame};
+use bytes::{Bytes, BytesMut};
+use interledger_packet::Address;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Number of bytes the receiver is willing to buffer for each stream
+/// beyond the data which has already been delivered
+pub(crate) const DEFAULT_STREAM_RECEIVE_WINDOW: u64 = 65_536;
+
+/// Number of bytes the receiver is willing to buffer ahead of the delivered
+/// data across all the streams of a connection
+pub(crate) const DEFAULT_CONNECTION_BUFFER: u64 = 1_048_576;
+
+/// Number of streams a sender may have open at once on a connection. Every stream
+/// it closes lets it open another one.
+pub(crate) const DEFAULT_MAX_OPEN_STREAMS: u64 = 10;
+
+/// State of connections which have not received a packet within this
+/// time is dropped (for example, because the sender disappeared without closing them)
+pub(crate) const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// In-order data of a stream which is ready to be delivered to the application
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct DataChunk {
+    pub stream_id: u64,
+    /// Position of the first byte of `data` in the stream
+    pub offset: u64,
+    pub data: Bytes,
+    /// Set on the last chunk of a stream which the sender closed
+    pub stream_closed: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum DataError {
+    /// The sender sent data past the max offset we advertised for the stream
+    FlowControl { stream_id: u64, max_offset: u64 },
+    /// The connection already buffers as much out-of-order data as allowed
+    BufferFull { stream_id: u64 },
+}
+
+/// Reassembles the data of a single stream
+#[derive(Default)]
+pub(crate) struct IncomingStream {
+    /// Offset up to which the data has been delivered
+    delivered: u64,
+    /// Non-overlapping fragments received ahead of the delivered offset, indexed by their offset
+    fragments: BTreeMap<u64, Bytes>,
+    /// Total length of the fragments
+    buffered: u64,
+}
+
+impl IncomingStream {
+    /// Offset up to which the data has been delivered
+    pub fn delivered(&self) -> u64 {
+        self.delivered
+    }
+
+    /// Number of bytes buffered ahead of the delivered offset
+    pub fn buffered(&self) -> u64 {
+        self.buffered
+    }
+
+    /// The ranges of `offset..end` which were neither delivered nor buffered yet
+    fn missing(&self, offset: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut missing = Vec::new();
+        let mut cursor = offset.max(self.delivered);
+        if let Some((start, fragment)) = self.fragments.range(..cursor).next_back() {
+            cursor = cursor.max(start + fragment.len() as u64);
+        }
+        if cursor >= end {
+            return missing;
+        }
+        for (start, fragment) in self.fragments.range(cursor..end) {
+            if *start > cursor {
+                missing.push((cursor, *start));
+            }
+            cursor = cursor.max(start + fragment.len() as u64);
+        }
+        if cursor < end {
+            missing.push((cursor, end));
+        }
+        missing
+    }
+
+    /// Buffers a fragment of the stream and returns the data which is now
+    /// in order, if any. The caller is responsible for enforcing the window.
+    ///
+    /// Only the parts of the fragment which were neither delivered nor buffered yet
+    /// are kept, so that overlapping retransmissions cannot change the data or take
+    /// up more memory. Returns `None` without buffering anything if that would leave
+    /// more than `max_buffered` bytes ahead of the delivered offset.
+    pub fn push(&mut self, offset: u64, data: &[u8], max_buffered: u64) -> Option<Option<Bytes>> {
+        let end = offset.saturating_add(data.len() as u64);
+        let missing = self.missing(offset, end);
+        // Data at the delivered offset is delivered right away rather than buffered
+        let buffered: u64 = missing
+            .iter()
+            .filter(|(start, _)| *start != self.delivered)
+            .map(|(start, end)| end - start)
+            .sum();
+        if self.buffered.saturating_add(buffered) > max_buffered {
+            return None;
+        }
+        for (start, end) in missing {
+            let fragment = &data[(start - offset) as usize..(end - offset) as usize];
+            self.fragments
+                .insert(start, Bytes::copy_from_slice(fragment));
+            self.buffered += end - start;
+        }
+
+        let mut in_order = BytesMut::new();
+        while let Some(fragment) = self.fragments.remove(&self.delivered) {
+            self.delivered += fragment.len() as u64;
+            self.buffered -= fragment.len() as u64;
+            in_order.extend_from_slice(&fragment);
+        }
+        if in_order.is_empty() {
+            Some(None)
+        } else {
+            Some(Some(in_order.freeze()))
+        }
+    }
+}
+
+struct ConnectionData {
+    streams: HashMap<u64, IncomingStream>,
+    /// Total amount received on each stream, for signing receipts and telling the sender
+    received: HashMap<u64, u64>,
+    /// Whether the totals saved for the connection were merged into `received`
+    received_restored: bool,
+    /// Streams the sender opened and did not close yet
+    opened_streams: HashSet<u64>,
+    /// Streams the sender closed, which it may open new ones in place of
+    closed_streams: HashSet<u64>,
+    last_seen: Instant,
+}
+
+/// Reassembles the data sent over each STREAM connection and enforces
+/// the stream-level flow control limits. It also keeps the totals received on
+/// each stream, which the receipts attest to and the sender is told about.
+///
+/// Connections are identified by the destination address of their packets.
+pub(crate) struct IncomingData {
+    connections: Mutex<HashMap<Address, ConnectionData>>,
+    window: u64,
+    /// Out-of-order bytes each connection may buffer across its streams
+    max_buffered: u64,
+    /// Streams each connection may have open at once
+    max_open_streams: u64,
+}
+
+impl IncomingData {
+    pub fn new(window: u64, max_buffered: u64, max_open_streams: u64) -> Self {
+        IncomingData {
+            connections: Mutex::new(HashMap::new()),
+            window,
+            max_buffered,
+            max_open_streams,
+        }
+    }
+
+    /// Highest ID of a stream the sender may open on the connection. Streams opened by
+    /// the sender have odd IDs, so each open stream it is allowed takes up two IDs.
+    pub fn max_stream_id(&self, connection: &Address) -> u64 {
+        let connections = self.connections.lock();
+        self.max_stream_id_of(connections.get(connection))
+    }
+
+    fn max_stream_id_of(&self, connection: Option<&ConnectionData>) -> u64 {
+        let closed = connection
+            .map(|connection| connection.closed_streams.len() as u64)
+            .unwrap_or(0);
+        self.max_open_streams
+            .saturating_add(closed)
+            .saturating_mul(2)
+    }
+
+    /// Records the streams the sender sent money on as opened, so that it can close them.
+    /// Only the streams the sender may open count, which have odd IDs up to the max stream ID.
+    pub fn open_streams(&self, connection: &Address, stream_ids: impl IntoIterator<Item = u64>) {
+        let mut connections = self.connections.lock();
+        let connection = Self::connection(&mut connections, connection);
+        let max_stream_id = self.max_stream_id_of(Some(connection));
+        connection.opened_streams.extend(
+            stream_ids
+                .into_iter()
+                .filter(|stream_id| stream_id % 2 == 1 && *stream_id <= max_stream_id),
+        );
+    }
+
+    /// Buffers the data frames of a single STREAM packet.
+    ///
+    /// Returns the data that is now in order and can be delivered, along with a
+    /// StreamMaxData frame for every stream that the packet carried data for.
+    pub fn receive(
+        &self,
+        connection: &Address,
+        frames: &[StreamDataFrame],
+    ) -> Result<(Vec<DataChunk>, Vec<StreamMaxDataFrame>), DataError> {
+        let mut connections = self.connections.lock();
+        let connection = Self::connection(&mut connections, connection);
+
+        let mut chunks = Vec::new();
+        let mut max_data: Vec<StreamMaxDataFrame> = Vec::new();
+        for frame in frames {
+            if frame.stream_id % 2 == 1 {
+                connection.opened_streams.insert(frame.stream_id);
+            }
+            let buffered: u64 = connection
+                .streams
+                .values()
+                .map(|stream| stream.buffered())
+                .sum();
+            let stream = connection.streams.entry(frame.stream_id).or_default();
+            let max_offset = stream.delivered.saturating_add(self.window);
+            if frame.offset.saturating_add(frame.data.len() as u64) > max_offset {
+                return Err(DataError::FlowControl {
+                    stream_id: frame.stream_id,
+                    max_offset,
+                });
+            }
+
+            // The stream may use what the other streams of the connection leave
+            let max_buffered = self
+                .max_buffered
+                .saturating_sub(buffered - stream.buffered());
+            let offset = stream.delivered;
+            let data = stream.push(frame.offset, frame.data, max_buffered).ok_or(
+                DataError::BufferFull {
+                    stream_id: frame.stream_id,
+                },
+            )?;
+            if let Some(data) = data {
+                chunks.push(DataChunk {
+                    stream_id: frame.stream_id,
+                    offset,
+                    data,
+                    stream_closed: false,
+                });
+            }
+
+            let max_offset = stream.delivered.saturating_add(self.window);
+            match max_data
+                .iter_mut()
+                .find(|max_data| max_data.stream_id == frame.stream_id)
+            {
+                Some(max_data) => max_data.max_offset = max_offset,
+                None => max_data.push(StreamMaxDataFrame {
+                    stream_id: frame.stream_id,
+                    max_offset,
+                }),
+            }
+        }
+
+        Ok((chunks, max_data))
+    }
+
+    /// Adds the amount to the total received on the stream and returns the new total
+    pub fn add_received(&self, connection: &Address, stream_id: u64, amount: u64) -> u64 {
+        let mut connections = self.connections.lock();
+        let total = Self::connection(&mut connections, connection)
+            .received
+            .entry(stream_id)
+            .or_default();
+        *total = total.saturating_add(amount);
+        *total
+    }
+
+    /// Whether the totals saved for the connection still need to be merged in with
+    /// `restore_received`, because the connection is new or was forgotten
+    pub fn needs_restored_received(&self, connection: &Address) -> bool {
+        self.connections
+            .lock()
+            .get(connection)
+            .map(|connection| !connection.received_restored)
+            .unwrap_or(true)
+    }
+
+    /// Merges the totals saved for the connection into the ones received since,
+    /// keeping the larger total of each stream
+    pub fn restore_received(&self, connection: &Address, totals: HashMap<u64, u64>) {
+        let mut connections = self.connections.lock();
+        let connection = Self::connection(&mut connections, connection);
+        for (stream_id, saved) in totals {
+            let total = connection.received.entry(stream_id).or_default();
+            *total = (*total).max(saved);
+        }
+        connection.received_restored = true;
+    }
+
+    fn connection<'a>(
+        connections: &'a mut HashMap<Address, ConnectionData>,
+        connection: &Address,
+    ) -> &'a mut ConnectionData {
+        if !connections.contains_key(connection) {
+            connections.retain(|_, data| data.last_seen.elapsed() < IDLE_CONNECTION_TIMEOUT);
+        }
+        let connection = connections
+            .entry(connection.clone())
+            .or_insert_with(|| ConnectionData {
+                streams: HashMap::new(),
+                received: HashMap::new(),
+                received_restored: false,
+                opened_streams: HashSet::new(),
+                closed_streams: HashSet::new(),
+                last_seen: Instant::now(),
+            });
+        connection.last_seen = Instant::now();
+        connection
+    }
+
+    /// Forgets the state of a stream which the sender closed, which lets it open
+    /// another stream in its place if the sender had opened it.
+    ///
+    /// Returns the chunk which signals the end of the stream to the application,
+    /// if any data was received for it.
+    pub fn close_stream(&self, connection: &Address, stream_id: u64) -> Option<DataChunk> {
+        let mut connections = self.connections.lock();
+        let connection = Self::connection(&mut connections, connection);
+        if connection.opened_streams.remove(&stream_id) {
+            connection.closed_streams.insert(stream_id);
+        }
+        let stream = connection.streams.remove(&stream_id)?;
+        Some(DataChunk {
+            stream_id,
+            offset: stream.delivered,
+            data: Bytes::new(),
+            stream_closed: true,
+        })
+    }
+
+    /// Forgets the state of a connection, for example after it was closed.
+    ///
+    /// Returns the end-of-stream chunks for every stream which had received data.
+    pub fn close_connection(&self, connection: &Address) -> Vec<DataChunk> {
+        let mut streams: Vec<_> = self
+            .connections
+            .lock()
+            .remove(connection)
+            .map(|connection| connection.streams.into_iter().collect())
+            .unwrap_or_default();
+        streams.sort_by_key(|(stream_id, _)| *stream_id);
+        streams
+            .into_iter()
+            .map(|(stream_id, stream)| DataChunk {
+                stream_id,
+                offset: stream.delivered,
+                data: Bytes::new(),
+                stream_closed: true,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn data_frame(stream_id: u64, offset: u64, data: &[u8]) -> StreamDataFrame<'_> {
+        StreamDataFrame {
+            stream_id,
+            offset,
+            data,
+        }
+    }
+
+    #[test]
+    fn reassembles_out_of_order_data() {
+        let connection = Address::from_str("example.receiver.conn").unwrap();
+        let incoming = IncomingData::new(100, 1000, DEFAULT_MAX_OPEN_STREAMS);
+
+        let (chunks, max_data) = incoming
+            .receive(&connection, &[data_frame(1, 5, b"world")])
+            .unwrap();
+        assert!(chunks.is_empty());
+        assert_eq!(
+            max_data,
+            vec![StreamMaxDataFrame {
+                stream_id: 1,
+                max_offset: 100
+            }]
+        );
+
+        let (chunks, max_data) = incoming
+            .receive(&connection, &[data_frame(1, 0, b"hello")])
+            .unwrap();
+        assert_eq!(
+            chunks,
+            vec![DataChunk {
+                stream_id: 1,
+                offset: 0,
+                data: Bytes::from_static(b"helloworld"),
+                stream_closed: false,
+            }]
+        );
+        assert_eq!(max_data[0].max_offset, 110);
+
+        // Retransmitted frames are not delivered twice
+        let (chunks, _) = incoming
+            .receive(&connection, &[data_frame(1, 0, b"hello")])
+            .unwrap();
+        assert!(chunks.is_empty());
+    }
+
+    #[test]
+    fn enforces_the_receive_window() {
+        let connection = Address::from_str("example.receiver.conn").unwrap();
+        let incoming = IncomingData::new(10, 1000, DEFAULT_MAX_OPEN_STREAMS);
+
+        assert_eq!(
+            incoming
+                .receive(&connection, &[data_frame(1, 8, b"abc")])
+                .unwrap_err(),
+            DataError::FlowControl {
+                stream_id: 1,
+                max_offset: 10
+            }
+        );
+        let (chunks, _) = incoming
+            .receive(&connection, &[data_frame(1, 0, b"0123456789")])
+            .unwrap();
+        assert_eq!(chunks[0].data.len(), 10);
+        // The window moves along with the delivered data
+        assert!(incoming
+            .receive(&connection, &[data_frame(1, 10, b"0123456789")])
+            .is_ok());
+    }
+
+    #[test]
+    fn trims_overlapping_fragments() {
+        let connection = Address::from_str("example.receiver.conn").unwrap();
+        let incoming = IncomingData::new(100, 1000, DEFAULT_MAX_OPEN_STREAMS);
+
+        incoming
+            .receive(&connection, &[data_frame(1, 4, b"efgh")])
+            .unwrap();
+        // Only the bytes which were not received yet are kept, so the
+        // overlapping fragments cannot replace the data received first
+        incoming
+            .receive(
+                &connection,
+                &[data_frame(1, 2, b"XXXXXX"), data_frame(1, 6, b"YYYY")],
+            )
+            .unwrap();
+        let (chunks, _) = incoming
+            .receive(&connection, &[data_frame(1, 0, b"abZZ")])
+            .unwrap();
+        assert_eq!(chunks[0].data, Bytes::from_static(b"abXXefghYY"));
+
+        // Data which was already delivered is dropped
+        let (chunks, _) = incoming
+            .receive(&connection, &[data_frame(1, 5, b"fghYYk")])
+            .unwrap();
+        assert_eq!((chunks[0].offset, &chunks[0].data[..]), (10, &b"k"[..]));
+    }
+
+    #[test]
+    fn caps_the_buffered_data() {
+        let connection = Address::from_str("example.receiver.conn").unwrap();
+        let incoming = IncomingData::new(100, 8, DEFAULT_MAX_OPEN_STREAMS);
+
+        incoming
+            .receive(&connection, &[data_frame(1, 2, b"cdef")])
+            .unwrap();
+        // Resending the same range does not take up more of the buffer
+        incoming
+            .receive(&connection, &[data_frame(1, 2, b"cdef")])
+            .unwrap();
+        assert_eq!(
+            incoming
+                .receive(&connection, &[data_frame(3, 10, b"abcde")])
+                .unwrap_err(),
+            DataError::BufferFull { stream_id: 3 }
+        );
+        incoming
+            .receive(&connection, &[data_frame(3, 10, b"abcd")])
+            .unwrap();
+        // Data which can be delivered right away is not buffered
+        let (chunks, _) = incoming
+            .receive(&connection, &[data_frame(1, 0, b"ab")])
+            .unwrap();
+        assert_eq!(chunks[0].data, Bytes::from_static(b"abcdef"));
+        assert!(incoming
+            .receive(&connection, &[data_frame(3, 20, b"abcd")])
+            .is_ok());
+    }
+
+    #[test]
+    fn closing_signals_the_end_of_streams() {
+        let connection = Address::from_str("example.receiver.conn").unwrap();
+        let incoming = IncomingData::new(100, 1000, DEFAULT_MAX_OPEN_STREAMS);
+        incoming
+            .receive(
+                &connection,
+                &[data_frame(1, 0, b"one"), data_frame(3, 0, b"three")],
+            )
+            .unwrap();
+
+        let chunk = incoming.close_stream(&connection, 1).unwrap();
+        assert_eq!((chunk.stream_id, chunk.offset), (1, 3));
+        assert!(chunk.stream_closed);
+        assert!(incoming.close_stream(&connection, 1).is_none());
+
+        let chunks = incoming.close_connection(&connection);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].stream_id, chunks[0].offset), (3, 5));
+        assert!(incoming.close_connection(&connection).is_empty());
+    }
+
+    #[test]
+    fn closing_streams_lets_the_sender_open_more() {
+        let connection = Address::from_str("example.receiver.conn").unwrap();
+        let incoming = IncomingData::new(100, 1000, 2);
+        assert_eq!(incoming.max_stream_id(&connection), 4);
+
+        // Closing streams which were never opened, the receiver's own (even) streams
+        // or streams above the limit does not let the sender open more
+        assert!(incoming.close_stream(&connection, 1).is_none());
+        incoming.open_streams(&connection, vec![2, 5]);
+        assert!(incoming.close_stream(&connection, 2).is_none());
+        assert!(incoming.close_stream(&connection, 5).is_none());
+        assert_eq!(incoming.max_stream_id(&connection), 4);
+
+        // Streams without data count too, and closing one again does not
+        incoming.open_streams(&connection, vec![1]);
+        assert!(incoming.close_stream(&connection, 1).is_none());
+        assert!(incoming.close_stream(&connection, 1).is_none());
+        assert_eq!(incoming.max_stream_id(&connection), 6);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::client::StreamDelivery;
 use interledger_packet::{AddressError, ErrorCode, PacketTypeError as IlpPacketTypeError};
 /// Stream Errors
 use std::str::Utf8Error;
@@ -12,6 +13,24 @@ pub enum Error {
         "Error maximum time exceeded: Time since last fulfill exceeded the maximum time limit"
     )]
     Timeout,
+    #[error("Connection closed: {0}")]
+    ConnectionClosed(String),
+    #[error("Exchange rate to the receiver is below the minimum acceptable rate")]
+    ExchangeRateTooLow,
+    #[error("Unable to determine the minimum acceptable exchange rate to the receiver")]
+    UnknownExchangeRate,
+    #[error("Receiver only accepts {0} more, less than the amount left to deliver")]
+    ReceiveMaxExceeded(u64),
+    #[error("Payment was cancelled after delivering {}", .0.delivered_amount)]
+    Cancelled(Box<StreamDelivery>),
+    #[error("Receiver does not allow opening stream {0}")]
+    StreamIdBlocked(u64),
+    #[error("Amounts of the payment add up to more than the largest possible amount")]
+    AmountOverflow,
+    #[error("Receiver could not confirm what the packets in flight delivered, after delivering {}", .0.delivered_amount)]
+    UnconfirmedDelivery(Box<StreamDelivery>),
+    #[error("Unable to save the payment checkpoint: {0}")]
+    CheckpointNotSaved(String),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -39,3 +58,15 @@ pub enum StreamPacketError {
     )]
     NonRoundtrippableSaturatingAmount,
 }
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ReceiptError {
+    #[error("Invalid receipt: expected {expected} bytes but got {actual}")]
+    InvalidLength { expected: usize, actual: usize },
+    #[error("Unsupported receipt version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Invalid receipt: unable to read the stream ID")]
+    InvalidStreamId,
+    #[error("Receipt was not signed with the given secret")]
+    InvalidSignature,
+}
@@ -0,0 +1,79 @@
+use async_trait::async_trait;
+use interledger_errors::InvoiceStoreError;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// An amount an account expects to receive over STREAM.
+///
+/// The connections for paying an invoice are generated with the invoice ID as their
+/// connection tag, so that the receiver can tie incoming packets to the invoice.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Invoice {
+    /// The ID of the invoice, used as the connection tag
+    pub id: Uuid,
+    /// The account receiving the payment
+    pub account_id: Uuid,
+    /// The expected amount, denominated in the account's asset
+    pub amount: u64,
+    /// The amount received so far
+    pub received: u64,
+    /// Arbitrary data attached to the invoice by its creator, such as an order number
+    #[serde(default)]
+    pub metadata: serde_json::Value,
+    /// Whether the full amount was received
+    pub paid: bool,
+}
+
+impl Invoice {
+    /// Creates a new unpaid invoice for the given account
+    pub fn new(account_id: Uuid, amount: u64, metadata: serde_json::Value) -> Self {
+        Invoice {
+            id: Uuid::new_v4(),
+            account_id,
+            amount,
+            received: 0,
+            metadata,
+            paid: false,
+        }
+    }
+
+    /// The amount which still needs to be received
+    pub fn remaining(&self) -> u64 {
+        self.amount.saturating_sub(self.received)
+    }
+
+    /// The connection tag of the connections paying this invoice
+    pub fn connection_tag(&self) -> String {
+        self.id.to_string()
+    }
+}
+
+/// Store for the invoices that the STREAM receiver tracks incoming payments for
+#[async_trait]
+pub trait InvoiceStore {
+    /// Saves a new invoice
+    async fn insert_invoice(&self, invoice: Invoice) -> Result<(), InvoiceStoreError>;
+
+    /// Loads an invoice by its ID
+    async fn get_invoice(&self, id: Uuid) -> Result<Invoice, InvoiceStoreError>;
+
+    /// Atomically adds an amount received for the invoice, unless it exceeds the amount left
+    /// to pay, and marks the invoice as paid once the full amount has been received.
+    ///
+    /// This is called before the packet carrying the amount is fulfilled, so that concurrent
+    /// packets cannot overpay the invoice. Returns whether the amount was added, along with
+    /// the updated invoice.
+    async fn reserve_invoice_payment(
+        &self,
+        id: Uuid,
+        amount: u64,
+    ) -> Result<(bool, Invoice), InvoiceStoreError>;
+
+    /// Takes back an amount reserved for a packet which was rejected after all.
+    /// Returns the updated invoice.
+    async fn release_invoice_payment(
+        &self,
+        id: Uuid,
+        amount: u64,
+    ) -> Result<Invoice, InvoiceStoreError>;
+}
@@ -6,21 +6,48 @@
 
 /// Stream client
 mod client;
-/// Congestion controller consumed by the [stream client](./client/fn.send_money.html)
+/// Congestion control strategies consumed by the [stream client](./client/fn.send_money.html)
 mod congestion;
+/// Full-duplex STREAM connections carrying streams of money and data
+mod connection;
 /// Cryptographic utilities for generating fulfillments and encrypting/decrypting STREAM packets
 mod crypto;
+/// Reassembly and flow control of the data received over STREAM connections
+mod data;
 /// Stream errors
 mod error;
+/// Invoices tying incoming STREAM payments to an expected amount via connection tags
+mod invoice;
+/// Limits on the money accounts accept over STREAM
+mod limits;
+/// Terminates full-duplex STREAM connections for an account
+mod listener;
 /// Stream Packet implementation, [as specified in the RFC](https://interledger.org/rfcs/0029-stream/#5-packet-and-frame-specification)
 mod packet;
+/// Signing and verification of [STREAM receipts](https://interledger.org/rfcs/0039-stream-receipts/)
+mod receipt;
 /// A stream server implementing an [Outgoing Service](../interledger_service/trait.OutgoingService.html) for receiving STREAM payments from peers
 mod server;
 
-pub use client::{send_money, StreamDelivery};
-pub use error::{Error, StreamPacketError};
+pub use client::{
+    quote, resume_payment, send_money, send_money_fixed_delivery, send_money_streams,
+    send_money_with_options, start_payment, PacketOutcome, PaymentAmount, PaymentCanceller,
+    PaymentCheckpoint, PaymentCheckpointStore, PaymentHandle, PaymentProgress, SendMoneyOptions,
+    SourceAddressUpdater, StreamDelivery, StreamQuote,
+};
+pub use congestion::{
+    Asap, CongestionControl, CongestionController, CongestionStrategy, FixedPacketSize,
+    LatencyAware,
+};
+pub use connection::{DataMoneyStream, StreamConnection};
+pub use error::{Error, ReceiptError, StreamPacketError};
+pub use invoice::{Invoice, InvoiceStore};
+pub use limits::{ReceiveLimitsAccount, DEFAULT_RECEIVE_PERIOD};
+pub use listener::StreamListener;
+pub use receipt::{verify_receipt, Receipt, ReceiptDetails, ReceiptTotalsStore};
 pub use server::{
-    ConnectionGenerator, PaymentNotification, StreamNotificationsStore, StreamReceiverService,
+    ConnectionGenerator, PaymentNotification, StreamDataNotification, StreamNotificationsStore,
+    StreamReceiverService,
 };
 
 #[cfg(fuzzing)]
@@ -44,9 +71,12 @@ pub mod test_helpers {
     use super::*;
     use async_trait::async_trait;
     use futures::channel::mpsc::UnboundedSender;
-    use interledger_errors::{AccountStoreError, AddressStoreError, ExchangeRateStoreError};
+    use interledger_errors::{
+        AccountStoreError, AddressStoreError, ExchangeRateStoreError, InvoiceStoreError,
+        ReceiptStoreError,
+    };
     use interledger_packet::Address;
-    use interledger_rates::ExchangeRateStore;
+    use interledger_rates::{ExchangeRate, ExchangeRateStore, PairRate};
     use interledger_router::RouterStore;
     use interledger_service::{Account, AccountStore, AddressStore, Username};
     use interledger_service_util::MaxPacketAmountAccount;
@@ -94,6 +124,8 @@ pub mod test_helpers {
         }
     }
 
+    impl ReceiveLimitsAccount for TestAccount {}
+
     impl MaxPacketAmountAccount for TestAccount {
         fn max_packet_amount(&self) -> u64 {
             self.max_packet_amount.unwrap_or(std::u64::MAX)
@@ -118,6 +150,65 @@ pub mod test_helpers {
         fn all_payment_subscription(&self) -> broadcast::Receiver<PaymentNotification> {
             broadcast::channel(0).1
         }
+
+        fn add_stream_data_subscription(
+            &self,
+            _account_id: Uuid,
+            _sender: UnboundedSender<StreamDataNotification>,
+        ) {
+        }
+
+        fn publish_stream_data_notification(
+            &self,
+            _account_id: Uuid,
+            _notification: StreamDataNotification,
+        ) {
+        }
+    }
+
+    #[async_trait]
+    impl InvoiceStore for DummyStore {
+        async fn insert_invoice(&self, _invoice: Invoice) -> Result<(), InvoiceStoreError> {
+            Ok(())
+        }
+
+        async fn get_invoice(&self, id: Uuid) -> Result<Invoice, InvoiceStoreError> {
+            Err(InvoiceStoreError::InvoiceNotFound(id.to_string()))
+        }
+
+        async fn reserve_invoice_payment(
+            &self,
+            id: Uuid,
+            _amount: u64,
+        ) -> Result<(bool, Invoice), InvoiceStoreError> {
+            Err(InvoiceStoreError::InvoiceNotFound(id.to_string()))
+        }
+
+        async fn release_invoice_payment(
+            &self,
+            id: Uuid,
+            _amount: u64,
+        ) -> Result<Invoice, InvoiceStoreError> {
+            Err(InvoiceStoreError::InvoiceNotFound(id.to_string()))
+        }
+    }
+
+    #[async_trait]
+    impl ReceiptTotalsStore for DummyStore {
+        async fn get_receipt_totals(
+            &self,
+            _connection: &Address,
+        ) -> Result<HashMap<u64, u64>, ReceiptStoreError> {
+            Ok(HashMap::new())
+        }
+
+        async fn save_receipt_totals(
+            &self,
+            _connection: &Address,
+            _totals: &[(u64, u64)],
+        ) -> Result<(), ReceiptStoreError> {
+            Ok(())
+        }
     }
 
     #[derive(Clone)]
@@ -179,9 +270,15 @@ pub mod test_helpers {
 
     #[async_trait]
     impl ExchangeRateStore for TestStore {
-        fn get_exchange_rates(&self, codes: &[&str]) -> Result<Vec<f64>, ExchangeRateStoreError> {
+        fn get_exchange_rates(
+            &self,
+            codes: &[&str],
+        ) -> Result<Vec<ExchangeRate>, ExchangeRateStoreError> {
             match (self.price_1, self.price_2) {
-                (Some(price_1), Some(price_2)) => Ok(vec![price_1, price_2]),
+                (Some(price_1), Some(price_2)) => Ok(vec![
+                    ExchangeRate::from_f64(price_1).unwrap(),
+                    ExchangeRate::from_f64(price_2).unwrap(),
+                ]),
                 _ => Err(ExchangeRateStoreError::PairNotFound {
                     from: codes[0].to_string(),
                     to: codes[1].to_string(),
@@ -191,28 +288,47 @@ pub mod test_helpers {
 
         fn set_exchange_rates(
             &self,
-            _rates: HashMap<String, f64>,
+            _rates: HashMap<String, ExchangeRate>,
         ) -> Result<(), ExchangeRateStoreError> {
             unimplemented!("Cannot set exchange rates")
         }
 
-        fn get_all_exchange_rates(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+        fn get_all_exchange_rates(
+            &self,
+        ) -> Result<HashMap<String, ExchangeRate>, ExchangeRateStoreError> {
             unimplemented!("Cannot get all exchange rates")
         }
+
+        fn set_pair_rates(
+            &self,
+            _rates: HashMap<String, PairRate>,
+        ) -> Result<(), ExchangeRateStoreError> {
+            unimplemented!("Cannot set pair rates")
+        }
+
+        fn get_all_pair_rates(&self) -> Result<HashMap<String, PairRate>, ExchangeRateStoreError> {
+            Ok(HashMap::new())
+        }
     }
 }
 
 #[cfg(test)]
 mod send_money_to_receiver {
+    use super::packet::*;
     use super::test_helpers::*;
     use super::*;
-    use bytes::Bytes;
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use interledger_errors::OutgoingPaymentStoreError;
     use interledger_packet::Address;
-    use interledger_packet::{ErrorCode, RejectBuilder};
+    use interledger_packet::{ErrorCode, FulfillBuilder, PrepareBuilder, RejectBuilder};
     use interledger_router::Router;
-    use interledger_service::outgoing_service_fn;
-    use interledger_service_util::ExchangeRateService;
+    use interledger_service::{outgoing_service_fn, IlpResult, IncomingRequest, IncomingService};
+    use interledger_service_util::{ExchangeRateService, MaxPacketAmountService};
+    use parking_lot::Mutex;
     use std::str::FromStr;
+    use std::sync::Arc;
+    use tokio::sync::Notify;
     use uuid::Uuid;
 
     #[tokio::test]
@@ -342,4 +458,916 @@ mod send_money_to_receiver {
             _ => panic!("Payment should fail fast due to poor exchange rates"),
         }
     }
+
+    fn cross_currency_setup(
+        spread: f64,
+        max_packet_amount: Option<u64>,
+    ) -> (
+        TestAccount,
+        TestStore,
+        impl IncomingService<TestAccount> + Clone,
+        Address,
+        Bytes,
+    ) {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let destination_address = Address::from_str("example.receiver").unwrap();
+
+        let sender_account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: Address::from_str("example.sender").unwrap(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount,
+        };
+
+        let recipient_account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: destination_address.clone(),
+            asset_code: "ABC".to_string(),
+            asset_scale: 2,
+            max_packet_amount: None,
+        };
+
+        let store = TestStore {
+            route: Some((destination_address.to_string(), recipient_account)),
+            price_1: Some(2.0),
+            price_2: Some(1.0),
+        };
+
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let server = StreamReceiverService::new(
+            server_secret,
+            DummyStore,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let server = ExchangeRateService::new(spread, store.clone(), server);
+        let server = Router::new(store.clone(), server);
+        let server = MaxPacketAmountService::new(store.clone(), server);
+
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address);
+        (
+            sender_account,
+            store,
+            server,
+            destination_account,
+            Bytes::copy_from_slice(&shared_secret[..]),
+        )
+    }
+
+    #[tokio::test]
+    async fn delivers_fixed_destination_amount() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            // Requires at least 7 packets
+            cross_currency_setup(0.0, Some(1_000_000_000));
+
+        let receipt = send_money_fixed_delivery(
+            server,
+            &sender_account,
+            store,
+            destination_account,
+            shared_secret.to_vec(),
+            1235,
+            0.01,
+            SendMoneyOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(receipt.delivered_amount, 1235);
+        assert_eq!(receipt.destination_amount, Some(1235));
+        assert_eq!(receipt.destination_asset_code, Some("ABC".to_string()));
+        // 1 XYZ is worth 2 ABC, so we only need to send ~6.175 XYZ,
+        // but we would have sent up to what the slippage allows
+        assert_eq!(receipt.sent_amount, 6_175_000_000);
+        assert!(receipt.source_amount > receipt.sent_amount);
+        assert_eq!(receipt.in_flight_amount, 0);
+    }
+
+    #[tokio::test]
+    async fn quotes_by_probing_the_rate() {
+        let (sender_account, _, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, Some(1_000_000_000));
+
+        let quote = quote(
+            server,
+            &sender_account,
+            destination_account,
+            shared_secret.to_vec(),
+            6_175_000_000,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(quote.max_packet_amount, Some(1_000_000_000));
+        assert_eq!(quote.destination_asset_code, Some("ABC".to_string()));
+        assert_eq!(quote.destination_asset_scale, Some(2));
+        assert!((quote.exchange_rate - 2e-7).abs() < 1e-12);
+        assert_eq!(quote.estimated_delivery_amount, 1235);
+    }
+
+    #[tokio::test]
+    async fn fixed_delivery_fails_if_large_spread() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.02, None);
+
+        let result = send_money_fixed_delivery(
+            server,
+            &sender_account,
+            store,
+            destination_account,
+            shared_secret.to_vec(),
+            1235,
+            0.014,
+            SendMoneyOptions::default(),
+        )
+        .await;
+
+        // The probe shows the connector takes a 2% spread, so nothing is sent
+        match result {
+            Err(Error::ExchangeRateTooLow) => {}
+            other => panic!(
+                "Payment should fail due to poor exchange rates: {:?}",
+                other
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_progress_of_started_payment() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, Some(1_000_000_000));
+
+        let mut handle = start_payment(
+            server,
+            &sender_account,
+            store,
+            destination_account,
+            shared_secret.to_vec(),
+            PaymentAmount::FixedSource(5_000_000_000),
+            0.01,
+            SendMoneyOptions::default(),
+        );
+
+        let mut events = Vec::new();
+        while let Some(progress) = handle.next_progress().await {
+            events.push(progress);
+        }
+        let receipt = handle.finish().await.unwrap();
+
+        let last = events.last().unwrap();
+        assert_eq!(last.delivered_amount, receipt.delivered_amount);
+        assert_eq!(last.sent_amount, 5_000_000_000);
+        assert_eq!(last.in_flight_amount, 0);
+        assert_eq!(
+            last.fulfilled_packets + last.rejected_packets,
+            events.len() as u64
+        );
+        assert!(events
+            .iter()
+            .any(|event| matches!(event.packet, PacketOutcome::Fulfilled { .. })));
+        assert!((last.exchange_rate.unwrap() - 2e-7).abs() < 1e-12);
+    }
+
+    #[tokio::test]
+    async fn cancelled_payment_keeps_partial_receipt() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, None);
+
+        let mut handle = start_payment(
+            server,
+            &sender_account,
+            store,
+            destination_account,
+            shared_secret.to_vec(),
+            PaymentAmount::FixedSource(100_000_000_000),
+            0.01,
+            SendMoneyOptions {
+                congestion_control: CongestionStrategy::FixedPacketSize {
+                    packet_amount: 1_000_000_000,
+                    packets_in_flight: 1,
+                },
+            },
+        );
+
+        while let Some(progress) = handle.next_progress().await {
+            if let PacketOutcome::Fulfilled { .. } = progress.packet {
+                handle.cancel();
+                break;
+            }
+        }
+
+        match handle.finish().await {
+            Err(Error::Cancelled(receipt)) => {
+                assert!(receipt.delivered_amount > 0);
+                assert!(receipt.sent_amount < 100_000_000_000);
+                assert_eq!(receipt.in_flight_amount, 0);
+            }
+            other => panic!("Payment should be cancelled: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_payment() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, None);
+        let options = SendMoneyOptions {
+            congestion_control: CongestionStrategy::FixedPacketSize {
+                packet_amount: 1_000_000_000,
+                packets_in_flight: 1,
+            },
+        };
+
+        let mut handle = start_payment(
+            server.clone(),
+            &sender_account,
+            store.clone(),
+            destination_account,
+            shared_secret.to_vec(),
+            PaymentAmount::FixedSource(100_000_000_000),
+            0.01,
+            options.clone(),
+        );
+        while let Some(progress) = handle.next_progress().await {
+            if let PacketOutcome::Fulfilled { .. } = progress.packet {
+                handle.cancel();
+            }
+        }
+        let checkpoint = handle.checkpoint().await;
+        assert!(handle.finish().await.is_err());
+        assert!(checkpoint.receipt.delivered_amount > 0);
+        assert!(checkpoint.receipt.delivered_amount < 20_000);
+
+        let receipt = resume_payment(
+            server,
+            &sender_account,
+            store,
+            shared_secret.to_vec(),
+            PaymentAmount::FixedSource(100_000_000_000),
+            0.01,
+            options,
+            checkpoint,
+            TestCheckpoints::default(),
+        )
+        .finish()
+        .await
+        .unwrap();
+
+        assert_eq!(receipt.sent_amount, 100_000_000_000);
+        assert_eq!(receipt.delivered_amount, 20_000);
+    }
+
+    /// Keeps the last checkpoint a payment saved
+    #[derive(Clone, Default)]
+    struct TestCheckpoints(Arc<Mutex<Option<PaymentCheckpoint>>>);
+
+    impl TestCheckpoints {
+        fn last(&self) -> Option<PaymentCheckpoint> {
+            self.0.lock().clone()
+        }
+    }
+
+    #[async_trait]
+    impl PaymentCheckpointStore for TestCheckpoints {
+        async fn save_payment_checkpoint(
+            &self,
+            checkpoint: PaymentCheckpoint,
+        ) -> Result<(), OutgoingPaymentStoreError> {
+            *self.0.lock() = Some(checkpoint);
+            Ok(())
+        }
+    }
+
+    /// Never replies once the receiver fulfilled the given number of packets, as if the
+    /// sender was interrupted while the last one was in flight
+    #[derive(Clone)]
+    struct InterruptedAfterFulfills<S> {
+        next: S,
+        fulfills_left: Arc<Mutex<usize>>,
+        interrupted: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl<S> IncomingService<TestAccount> for InterruptedAfterFulfills<S>
+    where
+        S: IncomingService<TestAccount> + Send,
+    {
+        async fn handle_request(&mut self, request: IncomingRequest<TestAccount>) -> IlpResult {
+            let result = self.next.handle_request(request).await;
+            if result.is_ok() {
+                let interrupted = {
+                    let mut fulfills_left = self.fulfills_left.lock();
+                    *fulfills_left = fulfills_left.saturating_sub(1);
+                    *fulfills_left == 0
+                };
+                if interrupted {
+                    self.interrupted.notify();
+                    futures::future::pending::<()>().await;
+                }
+            }
+            result
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_payment_interrupted_with_a_packet_in_flight() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, None);
+        let options = SendMoneyOptions {
+            congestion_control: CongestionStrategy::FixedPacketSize {
+                packet_amount: 1_000_000_000,
+                packets_in_flight: 1,
+            },
+        };
+        let checkpoints = TestCheckpoints::default();
+        let interrupted = Arc::new(Notify::new());
+
+        let _handle = resume_payment(
+            InterruptedAfterFulfills {
+                next: server.clone(),
+                fulfills_left: Arc::new(Mutex::new(3)),
+                interrupted: interrupted.clone(),
+            },
+            &sender_account,
+            store.clone(),
+            shared_secret.to_vec(),
+            PaymentAmount::FixedSource(5_000_000_000),
+            0.01,
+            options.clone(),
+            PaymentCheckpoint {
+                receipt: StreamDelivery::new(&sender_account, destination_account, 5_000_000_000),
+                next_sequence: 1,
+            },
+            checkpoints.clone(),
+        );
+        interrupted.notified().await;
+
+        // The receiver got the third packet, which the checkpoint counts as sent
+        let checkpoint = checkpoints.last().unwrap();
+        assert_eq!(checkpoint.receipt.sent_amount, 3_000_000_000);
+        assert_eq!(checkpoint.receipt.in_flight_amount, 1_000_000_000);
+        assert_eq!(checkpoint.receipt.delivered_amount, 400);
+
+        let receipt = resume_payment(
+            server,
+            &sender_account,
+            store,
+            shared_secret.to_vec(),
+            PaymentAmount::FixedSource(5_000_000_000),
+            0.01,
+            options,
+            checkpoint,
+            TestCheckpoints::default(),
+        )
+        .finish()
+        .await
+        .unwrap();
+
+        // The packet in flight is not sent again
+        assert_eq!(receipt.sent_amount, 5_000_000_000);
+        assert_eq!(receipt.delivered_amount, 1000);
+    }
+
+    #[derive(Clone)]
+    struct FailingCheckpoints;
+
+    #[async_trait]
+    impl PaymentCheckpointStore for FailingCheckpoints {
+        async fn save_payment_checkpoint(
+            &self,
+            _checkpoint: PaymentCheckpoint,
+        ) -> Result<(), OutgoingPaymentStoreError> {
+            Err(OutgoingPaymentStoreError::PaymentNotFound(
+                "payment".to_string(),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_if_checkpoints_cannot_be_saved() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, None);
+
+        let mut handle = resume_payment(
+            server,
+            &sender_account,
+            store,
+            shared_secret.to_vec(),
+            PaymentAmount::FixedSource(5_000_000_000),
+            0.01,
+            SendMoneyOptions::default(),
+            PaymentCheckpoint {
+                receipt: StreamDelivery::new(&sender_account, destination_account, 5_000_000_000),
+                next_sequence: 1,
+            },
+            FailingCheckpoints,
+        );
+        while let Some(progress) = handle.next_progress().await {
+            assert!(!matches!(progress.packet, PacketOutcome::Fulfilled { .. }));
+        }
+        match handle.finish().await {
+            Err(Error::CheckpointNotSaved(_)) => {}
+            other => panic!("Payment should fail to save its checkpoint: {:?}", other),
+        }
+    }
+
+    /// Loses the packets which close the connection, as if the sender was interrupted
+    /// before it could tell the receiver
+    #[derive(Clone)]
+    struct LosesConnectionClose<S> {
+        next: S,
+        shared_secret: Bytes,
+    }
+
+    #[async_trait]
+    impl<S> IncomingService<TestAccount> for LosesConnectionClose<S>
+    where
+        S: IncomingService<TestAccount> + Send,
+    {
+        async fn handle_request(&mut self, request: IncomingRequest<TestAccount>) -> IlpResult {
+            let closes = StreamPacket::from_encrypted(
+                &self.shared_secret,
+                BytesMut::from(request.prepare.data()),
+            )
+            .map(|packet| {
+                packet
+                    .frames()
+                    .any(|frame| matches!(frame, Frame::ConnectionClose(_)))
+            })
+            .unwrap_or(false);
+            if closes {
+                return Err(RejectBuilder {
+                    code: ErrorCode::T00_INTERNAL_ERROR,
+                    message: b"Lost",
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build());
+            }
+            self.next.handle_request(request).await
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_from_what_the_receiver_got() {
+        let (sender_account, store, server, destination_account, _) =
+            cross_currency_setup(0.0, None);
+        let receipt_details = ReceiptDetails {
+            nonce: [1; 16],
+            secret: [2; 32],
+        };
+        let (destination_account, shared_secret) =
+            ConnectionGenerator::new(Bytes::from(&[0; 32][..]))
+                .generate_address_and_secret_with_receipts(&destination_account, &receipt_details);
+        let server = LosesConnectionClose {
+            next: server,
+            shared_secret: Bytes::copy_from_slice(&shared_secret[..]),
+        };
+        let options = SendMoneyOptions {
+            congestion_control: CongestionStrategy::FixedPacketSize {
+                packet_amount: 1_000_000_000,
+                packets_in_flight: 1,
+            },
+        };
+
+        let mut handle = start_payment(
+            server.clone(),
+            &sender_account,
+            store.clone(),
+            destination_account,
+            shared_secret.to_vec(),
+            PaymentAmount::FixedDelivery(20_000),
+            0.01,
+            options.clone(),
+        );
+        // The checkpoint is older than the packets the receiver got
+        let checkpoint = handle.checkpoint().await;
+        while let Some(progress) = handle.next_progress().await {
+            if let PacketOutcome::Fulfilled { .. } = progress.packet {
+                handle.cancel();
+            }
+        }
+        assert!(handle.finish().await.is_err());
+        assert_eq!(checkpoint.receipt.delivered_amount, 0);
+
+        let receipt = resume_payment(
+            server,
+            &sender_account,
+            store,
+            shared_secret.to_vec(),
+            PaymentAmount::FixedDelivery(20_000),
+            0.01,
+            options,
+            checkpoint,
+            TestCheckpoints::default(),
+        )
+        .finish()
+        .await
+        .unwrap();
+
+        // The receiver got the amount once, not on top of what it got before the interruption
+        assert_eq!(receipt.delivered_amount, 20_000);
+        let stream_receipt = base64::decode(receipt.receipt.as_ref().unwrap()).unwrap();
+        let stream_receipt = verify_receipt(&stream_receipt, &receipt_details.secret).unwrap();
+        assert_eq!(stream_receipt.total_received, 20_000);
+    }
+
+    #[tokio::test]
+    async fn does_not_resume_when_the_receiver_lost_its_totals() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, None);
+        let options = SendMoneyOptions {
+            congestion_control: CongestionStrategy::FixedPacketSize {
+                packet_amount: 1_000_000_000,
+                packets_in_flight: 1,
+            },
+        };
+
+        let mut handle = start_payment(
+            server,
+            &sender_account,
+            store.clone(),
+            destination_account,
+            shared_secret.to_vec(),
+            PaymentAmount::FixedDelivery(20_000),
+            0.01,
+            options.clone(),
+        );
+        while let Some(progress) = handle.next_progress().await {
+            if let PacketOutcome::Fulfilled { .. } = progress.packet {
+                handle.cancel();
+            }
+        }
+        let mut checkpoint = handle.checkpoint().await;
+        assert!(handle.finish().await.is_err());
+        let delivered_amount = checkpoint.receipt.delivered_amount;
+        assert!(delivered_amount > 0);
+        // Interrupted while a packet was in flight
+        checkpoint.receipt.sent_amount += 1_000_000_000;
+        checkpoint.receipt.in_flight_amount = 1_000_000_000;
+
+        // A receiver which restarted since, with the same secret
+        let (_, _, restarted_server, _, _) = cross_currency_setup(0.0, None);
+        let resume = |checkpoint: PaymentCheckpoint| {
+            resume_payment(
+                restarted_server.clone(),
+                &sender_account,
+                store.clone(),
+                shared_secret.to_vec(),
+                PaymentAmount::FixedDelivery(20_000),
+                0.01,
+                options.clone(),
+                checkpoint,
+                TestCheckpoints::default(),
+            )
+            .finish()
+        };
+        match resume(checkpoint.clone()).await {
+            Err(Error::UnconfirmedDelivery(receipt)) => {
+                assert_eq!(receipt.delivered_amount, delivered_amount);
+            }
+            other => panic!("Resuming should need a confirmation: {:?}", other),
+        }
+
+        // Once it is confirmed that the packet in flight was not delivered
+        checkpoint.receipt.sent_amount -= 1_000_000_000;
+        checkpoint.receipt.in_flight_amount = 0;
+        let receipt = resume(checkpoint).await.unwrap();
+        assert_eq!(receipt.delivered_amount, 20_000);
+    }
+
+    #[tokio::test]
+    async fn sends_amounts_on_separate_streams() {
+        let (sender_account, store, server, destination_account, _) =
+            cross_currency_setup(0.0, Some(1_000_000_000));
+        let receipt_details = ReceiptDetails {
+            nonce: [1; 16],
+            secret: [2; 32],
+        };
+        let (destination_account, shared_secret) =
+            ConnectionGenerator::new(Bytes::from(&[0; 32][..]))
+                .generate_address_and_secret_with_receipts(&destination_account, &receipt_details);
+
+        let receipts = send_money_streams(
+            server,
+            &sender_account,
+            store,
+            destination_account,
+            shared_secret.to_vec(),
+            &[5_000_000_000, 2_000_000_000, 500_000_000],
+            0.01,
+            SendMoneyOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let delivered: Vec<u64> = receipts
+            .iter()
+            .map(|receipt| receipt.delivered_amount)
+            .collect();
+        assert_eq!(delivered, vec![1000, 400, 100]);
+        for (receipt, stream_id) in receipts.iter().zip(&[1, 3, 5]) {
+            assert_eq!(receipt.destination_asset_code.as_deref(), Some("ABC"));
+            let stream_receipt = base64::decode(receipt.receipt.as_ref().unwrap()).unwrap();
+            let stream_receipt = verify_receipt(&stream_receipt, &receipt_details.secret).unwrap();
+            assert_eq!(stream_receipt.stream_id, *stream_id);
+            assert_eq!(stream_receipt.total_received, receipt.delivered_amount);
+        }
+    }
+
+    #[tokio::test]
+    async fn opens_more_streams_than_the_default_limit() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, None);
+
+        let receipts = send_money_streams(
+            server,
+            &sender_account,
+            store,
+            destination_account,
+            shared_secret.to_vec(),
+            &[1_000_000_000; 12],
+            0.01,
+            SendMoneyOptions {
+                congestion_control: CongestionStrategy::FixedPacketSize {
+                    packet_amount: 1_000_000_000,
+                    packets_in_flight: 1,
+                },
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(receipts.len(), 12);
+        assert!(receipts
+            .iter()
+            .all(|receipt| receipt.delivered_amount == 200));
+    }
+
+    /// Loses the first packet closing streams and records the streams closed by the
+    /// packets which reach the receiver
+    #[derive(Clone)]
+    struct LosesFirstStreamClose<S> {
+        next: S,
+        shared_secret: Bytes,
+        lost: Arc<Mutex<Vec<u64>>>,
+        closed: Arc<Mutex<Vec<u64>>>,
+    }
+
+    #[async_trait]
+    impl<S> IncomingService<TestAccount> for LosesFirstStreamClose<S>
+    where
+        S: IncomingService<TestAccount> + Send,
+    {
+        async fn handle_request(&mut self, request: IncomingRequest<TestAccount>) -> IlpResult {
+            let closes: Vec<u64> = StreamPacket::from_encrypted(
+                &self.shared_secret,
+                BytesMut::from(request.prepare.data()),
+            )
+            .map(|packet| {
+                packet
+                    .frames()
+                    .filter_map(|frame| match frame {
+                        Frame::StreamClose(frame) => Some(frame.stream_id),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+            if !closes.is_empty() {
+                let mut lost = self.lost.lock();
+                if lost.is_empty() {
+                    lost.extend(closes);
+                    return Err(RejectBuilder {
+                        code: ErrorCode::T00_INTERNAL_ERROR,
+                        message: b"Lost",
+                        triggered_by: None,
+                        data: &[],
+                    }
+                    .build());
+                }
+            }
+            self.closed.lock().extend(closes);
+            self.next.handle_request(request).await
+        }
+    }
+
+    #[tokio::test]
+    async fn repeats_stream_close_until_it_is_fulfilled() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, None);
+        let lost = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let server = LosesFirstStreamClose {
+            next: server,
+            shared_secret: Bytes::copy_from_slice(&shared_secret[..]),
+            lost: lost.clone(),
+            closed: closed.clone(),
+        };
+
+        let receipts = send_money_streams(
+            server,
+            &sender_account,
+            store,
+            destination_account,
+            shared_secret.to_vec(),
+            &[1_000_000_000; 12],
+            0.01,
+            SendMoneyOptions {
+                congestion_control: CongestionStrategy::FixedPacketSize {
+                    packet_amount: 1_000_000_000,
+                    packets_in_flight: 1,
+                },
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(receipts.len(), 12);
+        let lost = lost.lock();
+        assert!(!lost.is_empty());
+        let closed = closed.lock();
+        assert!(lost.iter().all(|stream_id| closed.contains(stream_id)));
+    }
+
+    /// Sits in front of the receiver to move it to another address and to record
+    /// the source addresses the sender announces
+    #[derive(Clone)]
+    struct MovingReceiver<I> {
+        next: I,
+        shared_secret: Bytes,
+        address: Address,
+        /// Once set, the receiver tells the sender it moved here
+        new_address: Option<Address>,
+        destinations: Arc<Mutex<Vec<Address>>>,
+        announced_addresses: Arc<Mutex<Vec<Address>>>,
+    }
+
+    #[async_trait]
+    impl<I> IncomingService<TestAccount> for MovingReceiver<I>
+    where
+        I: IncomingService<TestAccount> + Send,
+    {
+        async fn handle_request(&mut self, request: IncomingRequest<TestAccount>) -> IlpResult {
+            let prepare = request.prepare;
+            let destination = prepare.destination();
+            self.destinations.lock().push(destination.clone());
+            let packet =
+                StreamPacket::from_encrypted(&self.shared_secret, BytesMut::from(prepare.data()))
+                    .unwrap();
+            self.announced_addresses
+                .lock()
+                .extend(packet.frames().filter_map(|frame| match frame {
+                    Frame::ConnectionNewAddress(frame) => Some(frame.source_account),
+                    _ => None,
+                }));
+
+            let mut execution_condition = [0; 32];
+            execution_condition.copy_from_slice(prepare.execution_condition());
+            let prepare = PrepareBuilder {
+                destination: self.address.clone(),
+                amount: prepare.amount(),
+                expires_at: prepare.expires_at(),
+                execution_condition: &execution_condition,
+                data: prepare.data(),
+            }
+            .build();
+            let fulfill = self
+                .next
+                .handle_request(IncomingRequest {
+                    from: request.from,
+                    prepare,
+                })
+                .await?;
+            let new_address = match &self.new_address {
+                Some(new_address) if destination != *new_address => new_address.clone(),
+                _ => return Ok(fulfill),
+            };
+
+            let reply =
+                StreamPacket::from_encrypted(&self.shared_secret, BytesMut::from(fulfill.data()))
+                    .unwrap();
+            let mut frames: Vec<Frame> = reply.frames().collect();
+            frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                source_account: new_address,
+            }));
+            let data = StreamPacketBuilder {
+                sequence: reply.sequence(),
+                ilp_packet_type: reply.ilp_packet_type(),
+                prepare_amount: reply.prepare_amount(),
+                frames: &frames,
+            }
+            .build()
+            .into_encrypted(&self.shared_secret);
+            let mut fulfillment = [0; 32];
+            fulfillment.copy_from_slice(fulfill.fulfillment());
+            Ok(FulfillBuilder {
+                fulfillment: &fulfillment,
+                data: &data[..],
+            }
+            .build())
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_receiver_to_new_address() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, Some(1_000_000_000));
+        let new_address = Address::from_str("example.receiver.moved").unwrap();
+        let receiver = MovingReceiver {
+            next: server,
+            shared_secret: shared_secret.clone(),
+            address: destination_account.clone(),
+            new_address: Some(new_address.clone()),
+            destinations: Arc::new(Mutex::new(Vec::new())),
+            announced_addresses: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let receipt = send_money_with_options(
+            receiver.clone(),
+            &sender_account,
+            store,
+            destination_account.clone(),
+            shared_secret.to_vec(),
+            5_000_000_000,
+            0.01,
+            SendMoneyOptions {
+                congestion_control: CongestionStrategy::FixedPacketSize {
+                    packet_amount: 1_000_000_000,
+                    packets_in_flight: 1,
+                },
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(receipt.delivered_amount, 1000);
+        assert_eq!(receipt.to, new_address);
+        let destinations = receiver.destinations.lock();
+        assert_eq!(destinations[0], destination_account);
+        assert_eq!(destinations.last(), Some(&new_address));
+    }
+
+    #[tokio::test]
+    async fn announces_new_source_address() {
+        let (sender_account, store, server, destination_account, shared_secret) =
+            cross_currency_setup(0.0, None);
+        let receiver = MovingReceiver {
+            next: server,
+            shared_secret: shared_secret.clone(),
+            address: destination_account.clone(),
+            new_address: None,
+            destinations: Arc::new(Mutex::new(Vec::new())),
+            announced_addresses: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let mut handle = start_payment(
+            receiver.clone(),
+            &sender_account,
+            store,
+            destination_account,
+            shared_secret.to_vec(),
+            PaymentAmount::FixedSource(100_000_000_000),
+            0.01,
+            SendMoneyOptions {
+                congestion_control: CongestionStrategy::FixedPacketSize {
+                    packet_amount: 1_000_000_000,
+                    packets_in_flight: 1,
+                },
+            },
+        );
+        let new_source = Address::from_str("example.new-parent.sender").unwrap();
+        let mut updated = false;
+        while let Some(progress) = handle.next_progress().await {
+            if let (PacketOutcome::Fulfilled { .. }, false) = (&progress.packet, updated) {
+                handle
+                    .source_address_updater()
+                    .update(new_source.clone())
+                    .await;
+                updated = true;
+            }
+        }
+        let receipt = handle.finish().await.unwrap();
+
+        assert_eq!(receipt.from, new_source);
+        assert_eq!(receipt.delivered_amount, 20_000);
+        let announced = receiver.announced_addresses.lock();
+        assert_eq!(announced[0], sender_account.ilp_address);
+        // Announced once, since the packet in flight alone carries it
+        assert_eq!(
+            announced
+                .iter()
+                .filter(|address| **address == new_source)
+                .count(),
+            1
+        );
+    }
 }
@@ -0,0 +1,385 @@
+use interledger_packet::Address;
+use interledger_service::Account;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Period `receive_max_per_period` applies to if the account doesn't configure one
+pub const DEFAULT_RECEIVE_PERIOD: Duration = Duration::from_secs(86400);
+
+/// Extension trait for [Account](../interledger_service/trait.Account.html) with the limits
+/// on the money the account accepts over STREAM. Amounts are denominated in the account's asset.
+pub trait ReceiveLimitsAccount: Account {
+    /// Maximum amount accepted over a single STREAM connection. The total of a connection
+    /// is kept until it did not receive anything for a `receive_period`.
+    fn receive_max_per_connection(&self) -> Option<u64> {
+        None
+    }
+
+    /// Maximum amount accepted over all STREAM connections within each `receive_period`.
+    /// The totals are kept in the memory of each node, so they start over when the node
+    /// restarts and are not shared between the nodes of a cluster.
+    fn receive_max_per_period(&self) -> Option<u64> {
+        None
+    }
+
+    /// Length of the period `receive_max_per_period` applies to
+    fn receive_period(&self) -> Duration {
+        DEFAULT_RECEIVE_PERIOD
+    }
+
+    /// Packets carrying less than this amount are rejected
+    fn receive_min_per_packet(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// The receiver accepts at most `max - received` more money
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct MoneyLimit {
+    pub received: u64,
+    pub max: u64,
+}
+
+impl MoneyLimit {
+    pub fn remaining(&self) -> u64 {
+        self.max.saturating_sub(self.received)
+    }
+}
+
+/// An amount added to the totals by `ReceivedAmounts::reserve`, which `release` takes back
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Reservation {
+    amount: u64,
+    /// Start of the period the amount was added to, if the account has a period limit
+    period_start: Option<Instant>,
+}
+
+struct PeriodTotal {
+    start: Instant,
+    received: u64,
+}
+
+struct ConnectionTotal {
+    received: u64,
+    last_seen: Instant,
+    /// How long the total is kept after the connection was last seen
+    retention: Duration,
+}
+
+#[derive(Default)]
+struct Totals {
+    connections: HashMap<Address, ConnectionTotal>,
+    periods: HashMap<Uuid, PeriodTotal>,
+}
+
+/// Keeps the amounts received per connection and per account in each period, which
+/// the `ReceiveLimitsAccount` limits apply to. Amounts are only tracked for the
+/// accounts which have the corresponding limit. The totals only live in memory: they
+/// are lost on restart and each node of a cluster keeps its own.
+#[derive(Default)]
+pub(crate) struct ReceivedAmounts {
+    totals: Mutex<Totals>,
+}
+
+impl ReceivedAmounts {
+    /// Adds the amount of a packet to the totals if it fits within all of the account's
+    /// limits, which is checked under the same lock so that concurrent packets cannot
+    /// exceed them. Returns the limits as they were before the packet and, if the amount
+    /// was added, its reservation. Amounts of packets which are not fulfilled after all
+    /// must be given back with `release`.
+    pub fn reserve<A: ReceiveLimitsAccount>(
+        &self,
+        account: &A,
+        connection: &Address,
+        amount: u64,
+    ) -> (Vec<MoneyLimit>, Option<Reservation>) {
+        let mut totals = self.totals.lock();
+        let mut limits = Vec::new();
+        let connection_max = account.receive_max_per_connection();
+        if let Some(max) = connection_max {
+            let received = match totals.connections.get(connection) {
+                Some(total) if total.last_seen.elapsed() < total.retention => total.received,
+                _ => 0,
+            };
+            limits.push(MoneyLimit { received, max });
+        }
+        let period_max = account.receive_max_per_period();
+        if let Some(max) = period_max {
+            let received = match totals.periods.get(&account.id()) {
+                Some(total) if total.start.elapsed() < account.receive_period() => total.received,
+                _ => 0,
+            };
+            limits.push(MoneyLimit { received, max });
+        }
+
+        let fits = limits.iter().all(|limit| amount <= limit.remaining());
+        if amount == 0 || !fits {
+            return (limits, None);
+        }
+        if connection_max.is_some() {
+            if !totals.connections.contains_key(connection) {
+                totals
+                    .connections
+                    .retain(|_, total| total.last_seen.elapsed() < total.retention);
+            }
+            let total = totals
+                .connections
+                .entry(connection.clone())
+                .or_insert_with(|| ConnectionTotal {
+                    received: 0,
+                    last_seen: Instant::now(),
+                    retention: account.receive_period(),
+                });
+            if total.last_seen.elapsed() >= total.retention {
+                total.received = 0;
+            }
+            total.received = total.received.saturating_add(amount);
+            total.last_seen = Instant::now();
+            total.retention = account.receive_period();
+        }
+        let mut period_start = None;
+        if period_max.is_some() {
+            let total = totals
+                .periods
+                .entry(account.id())
+                .or_insert_with(|| PeriodTotal {
+                    start: Instant::now(),
+                    received: 0,
+                });
+            if total.start.elapsed() >= account.receive_period() {
+                total.start = Instant::now();
+                total.received = 0;
+            }
+            total.received = total.received.saturating_add(amount);
+            period_start = Some(total.start);
+        }
+        (
+            limits,
+            Some(Reservation {
+                amount,
+                period_start,
+            }),
+        )
+    }
+
+    /// Takes back an amount added by `reserve` for a packet which was rejected. If a new
+    /// period started since, the amount is not part of its total and is not taken back.
+    pub fn release<A: ReceiveLimitsAccount>(
+        &self,
+        account: &A,
+        connection: &Address,
+        reservation: &Reservation,
+    ) {
+        let mut totals = self.totals.lock();
+        if let Some(total) = totals.connections.get_mut(connection) {
+            total.received = total.received.saturating_sub(reservation.amount);
+        }
+        if let Some(total) = totals.periods.get_mut(&account.id()) {
+            if Some(total.start) == reservation.period_start {
+                total.received = total.received.saturating_sub(reservation.amount);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::ALICE;
+    use interledger_service::Username;
+    use std::str::FromStr;
+
+    #[derive(Clone, Debug)]
+    struct LimitedAccount {
+        id: Uuid,
+        address: Address,
+        period: Duration,
+    }
+
+    impl Account for LimitedAccount {
+        fn id(&self) -> Uuid {
+            self.id
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &self.address
+        }
+    }
+
+    impl ReceiveLimitsAccount for LimitedAccount {
+        fn receive_max_per_connection(&self) -> Option<u64> {
+            Some(100)
+        }
+
+        fn receive_max_per_period(&self) -> Option<u64> {
+            Some(150)
+        }
+
+        fn receive_period(&self) -> Duration {
+            self.period
+        }
+    }
+
+    fn account(period: Duration) -> LimitedAccount {
+        LimitedAccount {
+            id: Uuid::new_v4(),
+            address: Address::from_str("example.alice").unwrap(),
+            period,
+        }
+    }
+
+    #[test]
+    fn tracks_connection_and_period_totals() {
+        let account = account(DEFAULT_RECEIVE_PERIOD);
+        let first = Address::from_str("example.alice.first").unwrap();
+        let second = Address::from_str("example.alice.second").unwrap();
+        let received = ReceivedAmounts::default();
+        assert_eq!(
+            received.reserve(&account, &first, 0),
+            (
+                vec![
+                    MoneyLimit {
+                        received: 0,
+                        max: 100
+                    },
+                    MoneyLimit {
+                        received: 0,
+                        max: 150
+                    },
+                ],
+                None
+            )
+        );
+
+        assert!(received.reserve(&account, &first, 90).1.is_some());
+        assert!(received.reserve(&account, &second, 40).1.is_some());
+        let remaining = |connection| {
+            received
+                .reserve(&account, connection, 0)
+                .0
+                .iter()
+                .map(MoneyLimit::remaining)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(remaining(&first), vec![10, 20]);
+        assert_eq!(remaining(&second), vec![60, 20]);
+    }
+
+    #[test]
+    fn reserves_only_what_fits_and_releases_it() {
+        let account = account(DEFAULT_RECEIVE_PERIOD);
+        let connection = Address::from_str("example.alice.first").unwrap();
+        let received = ReceivedAmounts::default();
+        let remaining = || {
+            received
+                .reserve(&account, &connection, 0)
+                .0
+                .iter()
+                .map(MoneyLimit::remaining)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(received.reserve(&account, &connection, 60).1.is_some());
+        // The connection only has 40 left, so nothing is added
+        let (limits, reservation) = received.reserve(&account, &connection, 50);
+        assert!(reservation.is_none());
+        assert_eq!(
+            limits.iter().map(MoneyLimit::remaining).collect::<Vec<_>>(),
+            vec![40, 90]
+        );
+        assert_eq!(remaining(), vec![40, 90]);
+
+        // The amount of a rejected packet is given back
+        let reservation = received.reserve(&account, &connection, 40).1.unwrap();
+        assert_eq!(remaining(), vec![0, 50]);
+        received.release(&account, &connection, &reservation);
+        assert_eq!(remaining(), vec![40, 90]);
+    }
+
+    #[test]
+    fn starts_new_period() {
+        let account = account(Duration::from_millis(0));
+        let connection = Address::from_str("example.alice.first").unwrap();
+        let received = ReceivedAmounts::default();
+        assert!(received.reserve(&account, &connection, 90).1.is_some());
+        assert_eq!(
+            received.reserve(&account, &connection, 0).0[1],
+            MoneyLimit {
+                received: 0,
+                max: 150
+            }
+        );
+    }
+
+    #[test]
+    fn releases_nothing_from_a_new_period() {
+        let account = account(DEFAULT_RECEIVE_PERIOD);
+        let connection = Address::from_str("example.alice.first").unwrap();
+        let other = Address::from_str("example.alice.second").unwrap();
+        let received = ReceivedAmounts::default();
+        let reservation = received.reserve(&account, &connection, 90).1.unwrap();
+        // The period ends while the packet is in flight, and another one is received
+        received
+            .totals
+            .lock()
+            .periods
+            .get_mut(&account.id)
+            .unwrap()
+            .start -= DEFAULT_RECEIVE_PERIOD;
+        assert!(received.reserve(&account, &other, 60).1.is_some());
+
+        received.release(&account, &connection, &reservation);
+        assert_eq!(received.reserve(&account, &other, 0).0[1].received, 60);
+    }
+
+    #[test]
+    fn keeps_connection_totals_for_the_receive_period() {
+        let account = account(DEFAULT_RECEIVE_PERIOD);
+        let connection = Address::from_str("example.alice.first").unwrap();
+        let received = ReceivedAmounts::default();
+        assert!(received.reserve(&account, &connection, 90).1.is_some());
+        // Idle for longer than the STREAM receiver keeps the state of a connection
+        received
+            .totals
+            .lock()
+            .connections
+            .get_mut(&connection)
+            .unwrap()
+            .last_seen -= Duration::from_secs(600);
+        let other = Address::from_str("example.alice.second").unwrap();
+        assert!(received.reserve(&account, &other, 10).1.is_some());
+
+        let (limits, reservation) = received.reserve(&account, &connection, 20);
+        assert!(reservation.is_none());
+        assert_eq!(limits[0].remaining(), 10);
+    }
+
+    #[test]
+    fn ignores_accounts_without_limits() {
+        let account = crate::test_helpers::TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: Address::from_str("example.alice").unwrap(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+        let received = ReceivedAmounts::default();
+        let (limits, _) = received.reserve(&account, account.ilp_address(), 90);
+        assert!(limits.is_empty());
+        assert!(received.totals.lock().connections.is_empty());
+    }
+}
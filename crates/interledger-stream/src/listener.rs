@@ -0,0 +1,544 @@
+use super::connection::{run_sender, Connection, StreamConnection};
+use super::data::IDLE_CONNECTION_TIMEOUT;
+use super::server::ConnectionGenerator;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use interledger_packet::{Address, Prepare};
+use interledger_rates::ExchangeRateStore;
+use interledger_service::{Account, IlpResult, IncomingService, OutgoingRequest, OutgoingService};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+/// Maximum acceptable slippage below the exchange rate when sending money over connections
+const DEFAULT_SLIPPAGE: f64 = 0.015;
+
+/// An [Outgoing Service](../interledger_service/trait.OutgoingService.html) which terminates
+/// full-duplex STREAM connections for a single account.
+///
+/// Packets sent to the account's connections are handled here, while all other packets are
+/// passed to the next service. The packets of the connections are sent through the given
+/// [Incoming Service](../interledger_service/trait.IncomingService.html), as if the account
+/// had sent them.
+///
+/// Connections are opened with `connect` (using the destination address and shared secret
+/// the other endpoint generated, e.g. with SPSP) or accepted with `accept` (after handing out
+/// the details from `generate_address_and_secret`). Connections should be closed when they are
+/// no longer needed, as they are only forgotten once closed. Accepted connections are also
+/// closed once they were idle for longer than the idle timeout.
+#[derive(Clone)]
+pub struct StreamListener<O, I, A, S> {
+    next: O,
+    sender: I,
+    account: A,
+    store: S,
+    slippage: f64,
+    idle_timeout: Duration,
+    connection_generator: ConnectionGenerator,
+    connections: Arc<Mutex<HashMap<Address, StreamConnection>>>,
+    new_connections: UnboundedSender<StreamConnection>,
+    incoming_connections: Arc<tokio::sync::Mutex<UnboundedReceiver<StreamConnection>>>,
+}
+
+impl<O, I, A, S> StreamListener<O, I, A, S>
+where
+    O: OutgoingService<A>,
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Clone + Send + Sync + 'static,
+{
+    pub fn new(server_secret: Bytes, account: A, sender: I, store: S, next: O) -> Self {
+        let (new_connections, incoming_connections) = unbounded();
+        StreamListener {
+            next,
+            sender,
+            account,
+            store,
+            slippage: DEFAULT_SLIPPAGE,
+            idle_timeout: IDLE_CONNECTION_TIMEOUT,
+            connection_generator: ConnectionGenerator::new(server_secret),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            new_connections,
+            incoming_connections: Arc::new(tokio::sync::Mutex::new(incoming_connections)),
+        }
+    }
+
+    /// Sets the maximum acceptable slippage below the exchange rate when sending money
+    pub fn slippage(&mut self, slippage: f64) -> &mut Self {
+        self.slippage = slippage;
+        self
+    }
+
+    /// Sets how long accepted connections may go without exchanging packets before they are closed
+    pub fn idle_timeout(&mut self, idle_timeout: Duration) -> &mut Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Generates the destination address and shared secret another endpoint uses to
+    /// open a connection to this listener
+    pub fn generate_address_and_secret(&self) -> (Address, [u8; 32]) {
+        self.connection_generator
+            .generate_address_and_secret(self.account.ilp_address())
+    }
+
+    /// Opens a connection to the given destination
+    pub fn connect(&self, destination: Address, shared_secret: &[u8]) -> StreamConnection {
+        // Packets from the other endpoint are sent to an address of our own
+        let (local_address, _) = self.generate_address_and_secret();
+        self.open(
+            local_address,
+            Some(destination),
+            Bytes::copy_from_slice(shared_secret),
+        )
+    }
+
+    /// Waits for another endpoint to open a connection
+    pub async fn accept(&self) -> Option<StreamConnection> {
+        self.incoming_connections.lock().await.next().await
+    }
+
+    fn open(
+        &self,
+        local_address: Address,
+        remote_address: Option<Address>,
+        shared_secret: Bytes,
+    ) -> StreamConnection {
+        let (connection, incoming_streams) = Connection::new(
+            local_address.clone(),
+            remote_address,
+            shared_secret,
+            self.account.asset_code().to_string(),
+            self.account.asset_scale(),
+        );
+        let handle = StreamConnection::new(connection, incoming_streams);
+        self.connections
+            .lock()
+            .insert(local_address, handle.clone());
+        self.spawn_sender(&handle);
+        handle
+    }
+
+    /// Passes the Prepare to the connection it was sent to, accepting a new connection if needed.
+    ///
+    /// Returns None if the Prepare does not belong to any connection.
+    fn handle_prepare(&self, destination: Address, prepare: &Prepare) -> Option<IlpResult> {
+        let mut connections = self.connections.lock();
+        if let Some(connection) = connections.get(&destination) {
+            // A closed connection which was not forgotten yet does not stop the other
+            // endpoint from connecting again
+            if !connection.is_closed() {
+                return connection.connection().handle_prepare(prepare);
+            }
+        }
+
+        let shared_secret = self.connection_generator.rederive_secret(&destination);
+        let (connection, incoming_streams) = Connection::new(
+            destination.clone(),
+            None,
+            Bytes::copy_from_slice(&shared_secret[..]),
+            self.account.asset_code().to_string(),
+            self.account.asset_scale(),
+        );
+        // Only packets we can decrypt open new connections
+        let result = connection.handle_prepare(prepare)?;
+        let handle = StreamConnection::new(connection, incoming_streams);
+        connections.insert(destination, handle.clone());
+        drop(connections);
+
+        self.spawn_sender(&handle);
+        if self.new_connections.unbounded_send(handle).is_err() {
+            debug!("Accepted a STREAM connection nobody is listening for");
+        }
+        Some(result)
+    }
+
+    /// Sends the packets of the connection until it is closed, and then forgets it
+    fn spawn_sender(&self, handle: &StreamConnection) {
+        let local_address = handle.local_address().clone();
+        let sender = run_sender(
+            handle.connection().clone(),
+            self.sender.clone(),
+            self.account.clone(),
+            self.store.clone(),
+            self.slippage,
+            self.idle_timeout,
+        );
+        let connections = self.connections.clone();
+        let connection = handle.connection().clone();
+        tokio::spawn(async move {
+            sender.await;
+            debug!("STREAM connection {} closed", local_address);
+            let mut connections = connections.lock();
+            // The address may already belong to a new connection
+            match connections.get(&local_address) {
+                Some(handle) if Arc::ptr_eq(handle.connection(), &connection) => {
+                    connections.remove(&local_address);
+                }
+                _ => {}
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl<O, I, A, S> OutgoingService<A> for StreamListener<O, I, A, S>
+where
+    O: OutgoingService<A> + Send + Sync + Clone,
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Clone + Send + Sync + 'static,
+{
+    /// Handle the request if it is for one of the connections of this listener,
+    /// or pass it to the next outgoing handler if not.
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        let destination = request.prepare.destination();
+        let to_address = self.account.ilp_address();
+        let dest: &[u8] = destination.as_ref();
+        if request.to.id() == self.account.id() && dest.starts_with(to_address.as_ref()) {
+            if let Some(result) = self.handle_prepare(destination, &request.prepare) {
+                return result;
+            }
+        }
+
+        self.next.send_request(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_condition;
+    use crate::packet::{Frame, StreamDataFrame, StreamPacketBuilder};
+    use crate::test_helpers::{TestAccount, TestStore};
+    use interledger_packet::{ErrorCode, PacketType, PrepareBuilder, RejectBuilder};
+    use interledger_service::IncomingRequest;
+    use std::str::FromStr;
+    use std::time::SystemTime;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+    use uuid::Uuid;
+
+    #[derive(Clone)]
+    struct Unreachable;
+
+    #[async_trait]
+    impl OutgoingService<TestAccount> for Unreachable {
+        async fn send_request(&mut self, request: OutgoingRequest<TestAccount>) -> IlpResult {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: b"No route",
+                triggered_by: Some(&request.to.ilp_address),
+                data: &[],
+            }
+            .build())
+        }
+    }
+
+    type Listener = StreamListener<Unreachable, Network, TestAccount, TestStore>;
+
+    /// Delivers the packets sent by each listener to the listener of the destination account
+    #[derive(Clone, Default)]
+    struct Network {
+        listeners: Arc<Mutex<Vec<(TestAccount, Listener)>>>,
+    }
+
+    #[async_trait]
+    impl IncomingService<TestAccount> for Network {
+        async fn handle_request(&mut self, request: IncomingRequest<TestAccount>) -> IlpResult {
+            let destination = request.prepare.destination();
+            let (to, mut listener) = self
+                .listeners
+                .lock()
+                .iter()
+                .find(|(account, _)| {
+                    let dest: &[u8] = destination.as_ref();
+                    dest.starts_with(account.ilp_address.as_ref())
+                })
+                .cloned()
+                .expect("Packet sent to an unknown account");
+            listener
+                .send_request(OutgoingRequest {
+                    from: request.from,
+                    to,
+                    original_amount: request.prepare.amount(),
+                    prepare: request.prepare,
+                })
+                .await
+        }
+    }
+
+    impl Network {
+        fn listener(&self, address: &str) -> Listener {
+            self.listener_with_idle_timeout(address, IDLE_CONNECTION_TIMEOUT)
+        }
+
+        fn listener_with_idle_timeout(&self, address: &str, idle_timeout: Duration) -> Listener {
+            let account = TestAccount {
+                id: Uuid::new_v4(),
+                ilp_address: Address::from_str(address).unwrap(),
+                asset_code: "XYZ".to_string(),
+                asset_scale: 9,
+                max_packet_amount: None,
+            };
+            let mut listener = StreamListener::new(
+                Bytes::from(vec![address.len() as u8; 32]),
+                account.clone(),
+                self.clone(),
+                TestStore {
+                    route: None,
+                    price_1: None,
+                    price_2: None,
+                },
+                Unreachable,
+            );
+            listener.idle_timeout(idle_timeout);
+            self.listeners.lock().push((account, listener.clone()));
+            listener
+        }
+    }
+
+    /// Opens a connection from the first to the second listener
+    async fn connect(
+        network: &Network,
+    ) -> (StreamConnection, StreamConnection, Listener, Listener) {
+        let alice = network.listener("example.alice");
+        let bob = network.listener("example.bobby");
+        let (destination, shared_secret) = bob.generate_address_and_secret();
+        let client = alice.connect(destination, &shared_secret[..]);
+        let server = timeout(Duration::from_secs(5), bob.accept())
+            .await
+            .expect("Connection was not accepted")
+            .unwrap();
+        assert_eq!(
+            server.local_address(),
+            client.remote_address().as_ref().unwrap()
+        );
+        (client, server, alice, bob)
+    }
+
+    /// Sends a packet with the given frames to the destination, as the other endpoint would
+    async fn send_frames(
+        network: &Network,
+        destination: &Address,
+        shared_secret: &[u8],
+        sequence: u64,
+        frames: &[Frame<'_>],
+    ) -> IlpResult {
+        let data = StreamPacketBuilder {
+            sequence,
+            ilp_packet_type: PacketType::Prepare,
+            prepare_amount: 0,
+            frames,
+        }
+        .build()
+        .into_encrypted(shared_secret);
+        let prepare = PrepareBuilder {
+            amount: 0,
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            execution_condition: &generate_condition(shared_secret, &data[..]),
+            destination: destination.clone(),
+            data: &data[..],
+        }
+        .build();
+        let from = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: Address::from_str("example.sender").unwrap(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+        network
+            .clone()
+            .handle_request(IncomingRequest { from, prepare })
+            .await
+    }
+
+    #[tokio::test]
+    async fn accepts_streams_whose_packets_arrive_out_of_order() {
+        let network = Network::default();
+        let bob = network.listener("example.bobby");
+        let (destination, shared_secret) = bob.generate_address_and_secret();
+
+        // Stream 3 arrives before stream 1, which the sender opened first
+        for (sequence, stream_id, data) in [(2, 3, b"world"), (1, 1, b"hello")].iter() {
+            let frames = [Frame::StreamData(StreamDataFrame {
+                stream_id: *stream_id,
+                offset: 0,
+                data: &data[..],
+            })];
+            send_frames(
+                &network,
+                &destination,
+                &shared_secret[..],
+                *sequence,
+                &frames,
+            )
+            .await
+            .expect("Packet was rejected");
+        }
+
+        let server = bob.accept().await.unwrap();
+        let mut third = server.accept_stream().await.unwrap();
+        let mut first = server.accept_stream().await.unwrap();
+        assert_eq!((third.id(), first.id()), (3, 1));
+        let mut buf = [0; 5];
+        first.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        third.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn rejects_data_on_streams_it_did_not_open() {
+        let network = Network::default();
+        let bob = network.listener("example.bobby");
+        let (destination, shared_secret) = bob.generate_address_and_secret();
+
+        // Even stream IDs belong to the accepting side, which has not opened any
+        let frames = [Frame::StreamData(StreamDataFrame {
+            stream_id: 2,
+            offset: 0,
+            data: b"hello",
+        })];
+        let reject = send_frames(&network, &destination, &shared_secret[..], 1, &frames)
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+    }
+
+    #[tokio::test]
+    async fn closes_idle_accepted_connections_and_accepts_them_again() {
+        let network = Network::default();
+        let alice = network.listener("example.alice");
+        let bob = network.listener_with_idle_timeout("example.bobby", Duration::from_millis(100));
+        let (destination, shared_secret) = bob.generate_address_and_secret();
+
+        let client = alice.connect(destination.clone(), &shared_secret[..]);
+        let server = timeout(Duration::from_secs(5), bob.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        timeout(Duration::from_secs(5), server.closed())
+            .await
+            .expect("Idle connection was not closed")
+            .unwrap_err();
+        // Only accepted connections are closed when they are idle
+        assert!(!client.is_closed());
+
+        let reconnected = alice.connect(destination, &shared_secret[..]);
+        let mut client_stream = reconnected.open_stream();
+        client_stream.write_all(b"hello").await.unwrap();
+        client_stream.flush().await.unwrap();
+        let server = timeout(Duration::from_secs(5), bob.accept())
+            .await
+            .expect("Connection was not accepted again")
+            .unwrap();
+        assert_eq!(
+            server.local_address(),
+            client.remote_address().as_ref().unwrap()
+        );
+        let mut server_stream = server.accept_stream().await.unwrap();
+        let mut buf = [0; 5];
+        timeout(Duration::from_secs(5), server_stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn exchanges_data_in_both_directions() {
+        let network = Network::default();
+        let (client, server, _, _) = connect(&network).await;
+
+        let mut client_stream = client.open_stream();
+        client_stream.write_all(b"hello").await.unwrap();
+        client_stream.flush().await.unwrap();
+
+        let mut server_stream = timeout(Duration::from_secs(5), server.accept_stream())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(server_stream.id(), client_stream.id());
+        let mut buf = [0; 5];
+        server_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        server_stream.write_all(b"world").await.unwrap();
+        server_stream.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        timeout(
+            Duration::from_secs(5),
+            client_stream.read_to_end(&mut response),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(response, b"world");
+
+        client.close().await.unwrap();
+        timeout(Duration::from_secs(5), server.closed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(server.accept_stream().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn transfers_more_data_than_the_receive_window() {
+        let network = Network::default();
+        let (client, server, _, _) = connect(&network).await;
+
+        let data: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+        let mut client_stream = client.open_stream();
+        let sent = data.clone();
+        tokio::spawn(async move {
+            client_stream.write_all(&sent).await.unwrap();
+            client_stream.shutdown().await.unwrap();
+        });
+
+        let mut server_stream = server.accept_stream().await.unwrap();
+        let mut received = Vec::new();
+        timeout(
+            Duration::from_secs(10),
+            server_stream.read_to_end(&mut received),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn sends_money_on_streams() {
+        let network = Network::default();
+        let (client, server, _, _) = connect(&network).await;
+
+        let client_stream = client.open_stream();
+        timeout(Duration::from_secs(5), client_stream.send_money(1000))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(client_stream.total_sent(), 1000);
+        assert_eq!(client_stream.total_delivered(), 1000);
+
+        let server_stream = server.accept_stream().await.unwrap();
+        assert_eq!(server_stream.receive_money(1000).await.unwrap(), 1000);
+
+        // Money flows the other way too, up to the receive max
+        client_stream.set_receive_max(300);
+        timeout(Duration::from_secs(5), server_stream.send_money(200))
+            .await
+            .unwrap()
+            .unwrap();
+        let result = timeout(Duration::from_millis(500), server_stream.send_money(500)).await;
+        assert!(result.is_err(), "sending past the receive max completed");
+        assert_eq!(client_stream.total_received(), 200);
+        assert_eq!(server_stream.total_sent(), 200);
+    }
+}
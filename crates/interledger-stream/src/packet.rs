@@ -101,6 +101,10 @@ impl<'a> StreamPacketBuilder<'a> {
                     buffer_unencrypted.put_u8(FrameType::StreamDataBlocked as u8);
                     frame.put_contents(&mut contents);
                 }
+                Frame::StreamReceipt(ref frame) => {
+                    buffer_unencrypted.put_u8(FrameType::StreamReceipt as u8);
+                    frame.put_contents(&mut contents);
+                }
                 Frame::Unknown(ref unknown_frame) => {
                     // The frame type u8 was stored and handled by UnknownFrameData
                     buffer_unencrypted.put_u8(unknown_frame.frame_type);
@@ -308,6 +312,9 @@ impl<'a> FrameIterator<'a> {
             FrameType::StreamDataBlocked => {
                 Frame::StreamDataBlocked(StreamDataBlockedFrame::read_contents(&contents)?)
             }
+            FrameType::StreamReceipt => {
+                Frame::StreamReceipt(StreamReceiptFrame::read_contents(contents)?)
+            }
             FrameType::Unknown => {
                 warn!(
                     "Ignoring unknown frame of type {}: {:x?}",
@@ -371,6 +378,7 @@ pub enum Frame<'a> {
     StreamData(StreamDataFrame<'a>),
     StreamMaxData(StreamMaxDataFrame),
     StreamDataBlocked(StreamDataBlockedFrame),
+    StreamReceipt(StreamReceiptFrame<'a>),
     Unknown(UnknownFrameData<'a>),
 }
 
@@ -391,6 +399,7 @@ impl<'a> fmt::Debug for Frame<'a> {
             Frame::StreamData(frame) => write!(f, "{:?}", frame),
             Frame::StreamMaxData(frame) => write!(f, "{:?}", frame),
             Frame::StreamDataBlocked(frame) => write!(f, "{:?}", frame),
+            Frame::StreamReceipt(frame) => write!(f, "{:?}", frame),
             Frame::Unknown(unknown_data) => write!(f, "{:?}", unknown_data),
         }
     }
@@ -414,6 +423,7 @@ pub enum FrameType {
     StreamData = 0x14,
     StreamMaxData = 0x15,
     StreamDataBlocked = 0x16,
+    StreamReceipt = 0x17,
     Unknown,
 }
 
@@ -434,6 +444,7 @@ impl From<u8> for FrameType {
             0x14 => FrameType::StreamData,
             0x15 => FrameType::StreamMaxData,
             0x16 => FrameType::StreamDataBlocked,
+            0x17 => FrameType::StreamReceipt,
             _ => FrameType::Unknown,
         }
     }
@@ -937,6 +948,31 @@ impl<'a> SerializableFrame<'a> for StreamDataBlockedFrame {
     }
 }
 
+/// A [STREAM receipt](https://interledger.org/rfcs/0039-stream-receipts/) proving the total
+/// amount the receiver got on this stream
+#[derive(Debug, PartialEq, Clone)]
+pub struct StreamReceiptFrame<'a> {
+    /// Identifier of the stream this frame refers to.
+    pub stream_id: u64,
+    /// The receipt, signed by the receiver with the secret given by the verifier.
+    pub receipt: &'a [u8],
+}
+
+impl<'a> SerializableFrame<'a> for StreamReceiptFrame<'a> {
+    fn read_contents(mut reader: &'a [u8]) -> Result<Self, StreamPacketError> {
+        let stream_id = reader.read_var_uint()?;
+        let receipt = reader.read_var_octet_string()?;
+        ensure_no_inner_trailing_bytes(reader)?;
+
+        Ok(StreamReceiptFrame { stream_id, receipt })
+    }
+
+    fn put_contents(&self, buf: &mut impl MutBufOerExt) {
+        buf.put_var_uint(self.stream_id);
+        buf.put_var_octet_string(self.receipt);
+    }
+}
+
 /// See: https://github.com/interledger/rfcs/blob/master/0029-stream/0029-stream.md#514-maximum-varuint-size
 fn saturating_read_var_uint<'a>(reader: &mut impl BufOerExt<'a>) -> Result<u64, StreamPacketError> {
     if reader.peek_var_octet_string()?.len() > 8 {
@@ -1248,6 +1284,28 @@ mod serialization {
         );
     }
 
+    #[test]
+    fn it_roundtrips_receipt_frames() {
+        let packet = StreamPacketBuilder {
+            sequence: 1,
+            ilp_packet_type: IlpPacketType::Fulfill,
+            prepare_amount: 99,
+            frames: &[Frame::StreamReceipt(StreamReceiptFrame {
+                stream_id: 1,
+                receipt: &[1, 2, 3],
+            })],
+        }
+        .build();
+        assert_eq!(
+            &packet.buffer_unencrypted[..],
+            &[1, 13, 1, 1, 1, 99, 1, 1, 23, 6, 1, 1, 3, 1, 2, 3][..]
+        );
+        assert_eq!(
+            StreamPacket::from_bytes_unencrypted(packet.buffer_unencrypted.clone()).unwrap(),
+            packet
+        );
+    }
+
     #[test]
     fn it_serializes_to_same_as_javascript() {
         assert_eq!(PACKET.buffer_unencrypted, *SERIALIZED);
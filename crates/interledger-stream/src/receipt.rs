@@ -0,0 +1,170 @@
+use super::crypto::{hmac_sha256, verify_hmac_sha256};
+use super::error::ReceiptError;
+use async_trait::async_trait;
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use interledger_errors::ReceiptStoreError;
+use interledger_packet::oer::{BufOerExt, MutBufOerExt};
+use interledger_packet::Address;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+/// Version of the receipt format
+const RECEIPT_VERSION: u8 = 1;
+const NONCE_LENGTH: usize = 16;
+const HMAC_LENGTH: usize = 32;
+
+/// The nonce and secret a verifier gives the receiver (usually through SPSP) so that
+/// the receiver can sign the receipts of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiptDetails {
+    pub nonce: [u8; 16],
+    pub secret: [u8; 32],
+}
+
+/// A [STREAM receipt](https://interledger.org/rfcs/0039-stream-receipts/), which proves
+/// the total amount the receiver got on a stream of a connection.
+///
+/// Receipts are cumulative, so a verifier only needs to keep the one with the highest total.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    /// The nonce of the connection, which the verifier uses to tell connections apart
+    pub nonce: [u8; 16],
+    pub stream_id: u64,
+    /// Total amount received on the stream, in the units of the receiver's account
+    pub total_received: u64,
+}
+
+impl Receipt {
+    /// Serializes and signs the receipt with the secret
+    pub fn sign(&self, secret: &[u8]) -> Bytes {
+        let mut buf = BytesMut::with_capacity(NONCE_LENGTH + 2 * HMAC_LENGTH);
+        buf.put_u8(RECEIPT_VERSION);
+        buf.put_slice(&self.nonce[..]);
+        buf.put_var_uint(self.stream_id);
+        buf.put_u64(self.total_received);
+        let hmac = hmac_sha256(secret, &buf[..]);
+        buf.put_slice(&hmac[..]);
+        buf.freeze()
+    }
+
+    /// Parses a receipt without checking its signature, which only the verifier can do
+    pub fn from_bytes(receipt: &[u8]) -> Result<Self, ReceiptError> {
+        Self::parse(receipt).map(|(receipt, _)| receipt)
+    }
+
+    /// Returns the receipt along with the length of its signed part
+    fn parse(mut reader: &[u8]) -> Result<(Self, usize), ReceiptError> {
+        let length = reader.len();
+        let invalid_length = |expected: usize| ReceiptError::InvalidLength {
+            expected,
+            actual: length,
+        };
+        // The smallest receipt has a stream ID of a single byte
+        let min_length = 1 + NONCE_LENGTH + 2 + 8 + HMAC_LENGTH;
+        if length < min_length {
+            return Err(invalid_length(min_length));
+        }
+
+        let version = reader.read_u8().unwrap();
+        if version != RECEIPT_VERSION {
+            return Err(ReceiptError::UnsupportedVersion(version));
+        }
+        let nonce = reader[..NONCE_LENGTH].try_into().unwrap();
+        reader = &reader[NONCE_LENGTH..];
+        let stream_id = reader
+            .read_var_uint()
+            .map_err(|_| ReceiptError::InvalidStreamId)?;
+        let total_received = reader
+            .read_u64::<BigEndian>()
+            .map_err(|_| invalid_length(length + 8))?;
+        if reader.len() != HMAC_LENGTH {
+            return Err(invalid_length(length - reader.len() + HMAC_LENGTH));
+        }
+
+        let receipt = Receipt {
+            nonce,
+            stream_id,
+            total_received,
+        };
+        Ok((receipt, length - HMAC_LENGTH))
+    }
+}
+
+/// Parses the receipt and checks that it was signed with the given secret
+pub fn verify_receipt(receipt: &[u8], secret: &[u8]) -> Result<Receipt, ReceiptError> {
+    let (parsed, signed_length) = Receipt::parse(receipt)?;
+    if verify_hmac_sha256(secret, &receipt[..signed_length], &receipt[signed_length..]) {
+        Ok(parsed)
+    } else {
+        Err(ReceiptError::InvalidSignature)
+    }
+}
+
+/// Store for the totals received on the streams of connections whose receiver signs receipts.
+///
+/// Verifiers expect the total of a stream to only go up, so the receiver keeps signing
+/// receipts for the saved totals after it forgot a connection, e.g. because it was closed,
+/// idle or the node restarted.
+#[async_trait]
+pub trait ReceiptTotalsStore {
+    /// Loads the totals received on each stream of the connection, by stream ID
+    async fn get_receipt_totals(
+        &self,
+        connection: &Address,
+    ) -> Result<HashMap<u64, u64>, ReceiptStoreError>;
+
+    /// Saves the new totals of the given `(stream_id, total_received)` pairs. Totals below
+    /// the ones already saved are ignored.
+    async fn save_receipt_totals(
+        &self,
+        connection: &Address,
+        totals: &[(u64, u64)],
+    ) -> Result<(), ReceiptStoreError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SECRET: [u8; 32] = [7; 32];
+
+    fn receipt() -> Receipt {
+        Receipt {
+            nonce: [1; 16],
+            stream_id: 1,
+            total_received: 500,
+        }
+    }
+
+    #[test]
+    fn signs_and_verifies_receipts() {
+        let signed = receipt().sign(&SECRET[..]);
+        assert_eq!(signed.len(), 59);
+        assert_eq!(verify_receipt(&signed, &SECRET[..]), Ok(receipt()));
+        assert_eq!(Receipt::from_bytes(&signed), Ok(receipt()));
+    }
+
+    #[test]
+    fn rejects_tampered_receipts() {
+        let mut signed = BytesMut::from(&receipt().sign(&SECRET[..])[..]);
+        assert_eq!(
+            verify_receipt(&signed, &[8; 32]),
+            Err(ReceiptError::InvalidSignature)
+        );
+
+        // Bump the total received
+        signed[25] += 1;
+        assert_eq!(
+            verify_receipt(&signed, &SECRET[..]),
+            Err(ReceiptError::InvalidSignature)
+        );
+
+        signed[0] = 2;
+        assert_eq!(
+            verify_receipt(&signed, &SECRET[..]),
+            Err(ReceiptError::UnsupportedVersion(2))
+        );
+        assert!(verify_receipt(&signed[..40], &SECRET[..]).is_err());
+    }
+}
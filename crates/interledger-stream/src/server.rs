@@ -1,19 +1,29 @@
 use super::crypto::*;
-use super::packet::*;
+use super::data::{
+    DataChunk, DataError, IncomingData, DEFAULT_CONNECTION_BUFFER, DEFAULT_MAX_OPEN_STREAMS,
+    DEFAULT_STREAM_RECEIVE_WINDOW,
+};
+use super::invoice::InvoiceStore;
+use super::limits::{MoneyLimit, ReceiveLimitsAccount, ReceivedAmounts};
+use super::packet::{ErrorCode as StreamErrorCode, *};
+use super::receipt::{Receipt, ReceiptDetails, ReceiptTotalsStore};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use chrono::{DateTime, Utc};
 use futures::channel::mpsc::UnboundedSender;
+use interledger_errors::InvoiceStoreError;
 use interledger_packet::{
-    hex::HexString, Address, ErrorCode, Fulfill, FulfillBuilder, PacketType as IlpPacketType,
-    Prepare, Reject, RejectBuilder,
+    hex::HexString, Address, AddressError, ErrorCode, Fulfill, FulfillBuilder,
+    PacketType as IlpPacketType, Prepare, Reject, RejectBuilder,
 };
 use interledger_service::{Account, IlpResult, OutgoingRequest, OutgoingService, Username};
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
 use std::marker::PhantomData;
+use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::sync::broadcast;
-use tracing::debug;
+use tracing::{debug, error};
 use uuid::Uuid;
 
 // Note we are using the same magic bytes as the Javascript
@@ -25,6 +35,16 @@ use uuid::Uuid;
 // this string is.
 const STREAM_SERVER_SECRET_GENERATOR: &[u8] = b"ilp_stream_shared_secret";
 
+/// Used for deriving the key which encrypts the receipt details into the destination address
+const RECEIPT_DETAILS_KEY_GENERATOR: &[u8] = b"ilp_stream_receipt_details";
+
+/// Length of the random part of the generated tokens
+const TOKEN_LENGTH: usize = 18;
+
+/// Separates the connection tag from the token in the destination address, as in the
+/// Javascript implementation. The base64-url encoded token never contains it.
+const CONNECTION_TAG_SEPARATOR: char = '~';
+
 /// A STREAM connection generator that creates `destination_account` and `shared_secret` values
 /// based on a single root secret.
 ///
@@ -33,6 +53,7 @@ const STREAM_SERVER_SECRET_GENERATOR: &[u8] = b"ilp_stream_shared_secret";
 #[derive(Clone)]
 pub struct ConnectionGenerator {
     secret_generator: [u8; 32],
+    receipt_details_key: [u8; 32],
 }
 
 impl ConnectionGenerator {
@@ -43,6 +64,7 @@ impl ConnectionGenerator {
 
         ConnectionGenerator {
             secret_generator: secret,
+            receipt_details_key: hmac_sha256(&server_secret[..], RECEIPT_DETAILS_KEY_GENERATOR),
         }
     }
 
@@ -51,7 +73,83 @@ impl ConnectionGenerator {
     /// The `destination_account` is generated such that the `shared_secret` can be re-derived
     /// from a Prepare packet's destination and the same server secret.
     pub fn generate_address_and_secret(&self, base_address: &Address) -> (Address, [u8; 32]) {
-        let token = base64::encode_config(&generate_token(), base64::URL_SAFE_NO_PAD);
+        self.address_and_secret(base_address, &generate_token())
+    }
+
+    /// Generate the STREAM parameters for a connection whose receiver signs
+    /// [STREAM receipts](https://interledger.org/rfcs/0039-stream-receipts/) with the given details.
+    ///
+    /// The receipt details are encrypted into the `destination_account`, so the receiver
+    /// does not need to store them.
+    pub fn generate_address_and_secret_with_receipts(
+        &self,
+        base_address: &Address,
+        receipt_details: &ReceiptDetails,
+    ) -> (Address, [u8; 32]) {
+        self.address_and_secret(base_address, &self.receipts_token(receipt_details))
+    }
+
+    /// Generate the STREAM parameters for a connection identified by the given tag,
+    /// optionally signing receipts with the given details.
+    ///
+    /// The tag is appended to the `destination_account` and is covered by the `shared_secret`,
+    /// so senders cannot change it. It can be read back with `connection_tag` to tie the
+    /// incoming packets to, for example, an invoice. It may only contain the characters
+    /// `a-zA-Z0-9_~-`.
+    pub fn generate_address_and_secret_with_tag(
+        &self,
+        base_address: &Address,
+        connection_tag: &str,
+        receipt_details: Option<&ReceiptDetails>,
+    ) -> Result<(Address, [u8; 32]), AddressError> {
+        let valid_tag = !connection_tag.is_empty()
+            && connection_tag
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'~' || c == b'-');
+        if !valid_tag {
+            return Err(AddressError::InvalidFormat);
+        }
+        let token = match receipt_details {
+            Some(receipt_details) => self.receipts_token(receipt_details),
+            None => generate_token().to_vec(),
+        };
+        let token = format!(
+            "{}{}{}",
+            base64::encode_config(&token, base64::URL_SAFE_NO_PAD),
+            CONNECTION_TAG_SEPARATOR,
+            connection_tag
+        );
+        Ok(self.encoded_address_and_secret(base_address, token))
+    }
+
+    /// Read the connection tag from a `destination_account` generated with
+    /// `generate_address_and_secret_with_tag`.
+    pub fn connection_tag(destination_account: &Address) -> Option<&str> {
+        let local_part = destination_account.segments().next_back()?;
+        let separator = local_part.find(CONNECTION_TAG_SEPARATOR)?;
+        Some(&local_part[separator + 1..])
+    }
+
+    /// Random token followed by the encrypted receipt details
+    fn receipts_token(&self, receipt_details: &ReceiptDetails) -> Vec<u8> {
+        let mut details = BytesMut::with_capacity(48);
+        details.extend_from_slice(&receipt_details.nonce[..]);
+        details.extend_from_slice(&receipt_details.secret[..]);
+        let mut token = generate_token().to_vec();
+        token.extend_from_slice(&encrypt(&self.receipt_details_key[..], details));
+        token
+    }
+
+    fn address_and_secret(&self, base_address: &Address, token: &[u8]) -> (Address, [u8; 32]) {
+        let token = base64::encode_config(token, base64::URL_SAFE_NO_PAD);
+        self.encoded_address_and_secret(base_address, token)
+    }
+
+    fn encoded_address_and_secret(
+        &self,
+        base_address: &Address,
+        token: String,
+    ) -> (Address, [u8; 32]) {
         // Note the shared secret is generated from the base64-encoded version of the token,
         // rather than from the unencoded bytes
         let shared_secret = hmac_sha256(&self.secret_generator[..], token.as_bytes());
@@ -82,6 +180,35 @@ impl ConnectionGenerator {
         // rather than decoding the base64 first.
         hmac_sha256(&self.secret_generator[..], local_part.as_bytes())
     }
+
+    /// Recover the receipt details encrypted into a `destination_account`, if the
+    /// connection was generated with them.
+    pub fn rederive_receipt_details(
+        &self,
+        destination_account: &Address,
+    ) -> Option<ReceiptDetails> {
+        let local_part = destination_account.segments().next_back()?;
+        let local_part = local_part
+            .split(CONNECTION_TAG_SEPARATOR)
+            .next()
+            .unwrap_or(local_part);
+        let token = base64::decode_config(local_part, base64::URL_SAFE_NO_PAD).ok()?;
+        if token.len() <= TOKEN_LENGTH {
+            return None;
+        }
+        let details = decrypt(
+            &self.receipt_details_key[..],
+            BytesMut::from(&token[TOKEN_LENGTH..]),
+        )
+        .ok()?;
+        if details.len() != 48 {
+            return None;
+        }
+        Some(ReceiptDetails {
+            nonce: details[..16].try_into().unwrap(),
+            secret: details[16..].try_into().unwrap(),
+        })
+    }
 }
 
 /// Notification that STREAM fulfilled a packet and received a single Interledger payment, used by Pubsub API consumers
@@ -103,12 +230,45 @@ pub struct PaymentNotification {
     /// In that case, the PaymentNotification will have `amount: 0`
     /// and `connection_closed: true`.
     pub connection_closed: bool,
+    /// The invoice the payment was for, if the connection was generated for one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invoice_id: Option<Uuid>,
+}
+
+/// Notification that data was received over a STREAM connection, used by Pubsub API consumers.
+///
+/// The data of each stream is delivered in order and exactly once.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StreamDataNotification {
+    /// The username of the account that received the data
+    pub to_username: Username,
+    /// The username of the account that routed the data to this node
+    pub from_username: Username,
+    /// The ILP Address of the STREAM connection the data was sent to
+    pub destination: Address,
+    /// The stream the data was sent on
+    pub stream_id: u64,
+    /// Position of the first byte of `data` in the stream
+    pub offset: u64,
+    /// The received bytes, base64-encoded
+    pub data: String,
+    /// Whether the stream was closed. No more data will be received for it
+    /// after a notification with `stream_closed: true`.
+    pub stream_closed: bool,
+    /// The time this notification was fired in RFC3339 format
+    pub timestamp: String,
 }
 
 /// The Ok(ReceiveOk) variant of receive_money(...) return result
 struct ReceiveOk {
     fulfill: Fulfill,
     sequence: u64,
+    /// Whether the receiver closed the connection, because it reached a limit
+    connection_closed: bool,
+    /// Data which became deliverable with this packet
+    data: Vec<DataChunk>,
+    /// New totals of the streams the money went to, as `(stream_id, total_received)`
+    received_totals: Vec<(u64, u64)>,
 }
 
 /// The Err(ReceiveErr) variant of receive_money(...) return result
@@ -126,6 +286,9 @@ enum ReceiveErr {
         reject: Reject,
         sequence: u64,
         connection_closed: bool,
+        /// Data which became deliverable with this packet. Data is accepted from
+        /// unfulfillable packets too, so that it can be sent without money.
+        data: Vec<DataChunk>,
     },
 }
 
@@ -146,20 +309,51 @@ pub trait StreamNotificationsStore {
 
     /// Subscribes to the store's node-wide payment notification publisher
     fn all_payment_subscription(&self) -> broadcast::Receiver<PaymentNotification>;
+
+    /// *Synchronously* saves the sending side of a subscription to the data received
+    /// over STREAM by the provided account id
+    fn add_stream_data_subscription(
+        &self,
+        account_id: Uuid,
+        sender: UnboundedSender<StreamDataNotification>,
+    );
+
+    /// Instructs the store to deliver data received over STREAM
+    /// to the subscribers of the provided account id
+    fn publish_stream_data_notification(
+        &self,
+        account_id: Uuid,
+        notification: StreamDataNotification,
+    );
 }
 
 /// An OutgoingService that fulfills incoming STREAM packets.
 ///
-/// Note this does **not** maintain STREAM state, but instead fulfills
+/// Note this does **not** maintain the money related STREAM state, but instead fulfills
 /// all incoming packets to collect the money.
 ///
-/// This does not currently support handling data sent via STREAM.
+/// Data sent via STREAM is reassembled per stream and published to the store's
+/// subscribers for the receiving account (see `StreamDataNotification`). Senders
+/// are limited to a window of buffered data per stream using StreamMaxData frames.
+/// This state is kept in memory, so all packets of a connection must be
+/// received by the same node.
+///
+/// The totals received on the streams of connections which sign receipts are also saved
+/// in the store, so that the receipts keep counting from them after the connection was
+/// closed, idle for a while or the node restarted.
+///
+/// Connections whose tag is the ID of one of the receiving account's invoices pay that
+/// invoice: the receiver advertises the amount left to pay in StreamMaxMoney frames and
+/// adds the amount of each packet to the invoice in the store before fulfilling it. Packets
+/// exceeding what is left to pay are rejected, and the amounts of rejected packets released.
 #[derive(Clone)]
 pub struct StreamReceiverService<S, O: OutgoingService<A>, A: Account> {
     connection_generator: ConnectionGenerator,
     next: O,
     account_type: PhantomData<A>,
     store: S,
+    incoming_data: Arc<IncomingData>,
+    received: Arc<ReceivedAmounts>,
 }
 
 impl<S, O, A> StreamReceiverService<S, O, A>
@@ -175,6 +369,12 @@ where
             next,
             account_type: PhantomData,
             store,
+            incoming_data: Arc::new(IncomingData::new(
+                DEFAULT_STREAM_RECEIVE_WINDOW,
+                DEFAULT_CONNECTION_BUFFER,
+                DEFAULT_MAX_OPEN_STREAMS,
+            )),
+            received: Arc::new(ReceivedAmounts::default()),
         }
     }
 }
@@ -182,13 +382,14 @@ where
 #[async_trait]
 impl<S, O, A> OutgoingService<A> for StreamReceiverService<S, O, A>
 where
-    S: StreamNotificationsStore + Send + Sync + 'static + Clone,
+    S: StreamNotificationsStore + InvoiceStore + ReceiptTotalsStore + Send + Sync + 'static + Clone,
     O: OutgoingService<A> + Send + Sync + Clone,
-    A: Account + Send + Sync + Clone,
+    A: ReceiveLimitsAccount + Send + Sync + Clone,
 {
     /// Try fulfilling the request if it is for this STREAM server or pass it to the next
     /// outgoing handler if not.
     async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        let to_id = request.to.id();
         let to_username = request.to.username().clone();
         let from_username = request.from.username().clone();
         let amount = request.prepare.amount();
@@ -199,26 +400,195 @@ where
 
         // The case where the request is bound for this server
         if dest.starts_with(to_address.as_ref()) {
+            let invoice = match ConnectionGenerator::connection_tag(&destination)
+                .and_then(|tag| Uuid::parse_str(tag).ok())
+            {
+                Some(invoice_id) => match self.store.get_invoice(invoice_id).await {
+                    Ok(invoice) if invoice.account_id == to_id => Some(invoice),
+                    // Tags which are not the account's invoices are only opaque identifiers
+                    Ok(_) | Err(InvoiceStoreError::InvoiceNotFound(_)) => None,
+                    Err(err) => {
+                        error!("Error loading invoice {}: {}", invoice_id, err);
+                        return Err(RejectBuilder {
+                            code: ErrorCode::T00_INTERNAL_ERROR,
+                            message: &[],
+                            triggered_by: Some(to_address),
+                            data: &[],
+                        }
+                        .build());
+                    }
+                },
+                None => None,
+            };
+            let invoice_id = invoice.as_ref().map(|invoice| invoice.id);
             let shared_secret = self.connection_generator.rederive_secret(&destination);
+            let receipt_details = self
+                .connection_generator
+                .rederive_receipt_details(&destination);
+            // Receipts count on from the saved totals of a connection which was forgotten,
+            // so that they never attest to a lower total than before
+            if receipt_details.is_some() && self.incoming_data.needs_restored_received(&destination)
+            {
+                match self.store.get_receipt_totals(&destination).await {
+                    Ok(totals) => self.incoming_data.restore_received(&destination, totals),
+                    Err(err) => {
+                        error!(
+                            "Error loading the receipt totals of {}: {}",
+                            destination, err
+                        );
+                        return Err(RejectBuilder {
+                            code: ErrorCode::T00_INTERNAL_ERROR,
+                            message: &[],
+                            triggered_by: Some(to_address),
+                            data: &[],
+                        }
+                        .build());
+                    }
+                }
+            }
+            let min_packet_amount = request.to.receive_min_per_packet().unwrap_or(0);
+            // Add the amount to the received totals and the invoice before the packet may be
+            // fulfilled, so that concurrent packets cannot exceed the limits. If it does not
+            // fit, the limit it exceeds rejects the packet. Only packets from the sender,
+            // which could be fulfilled otherwise, may reserve anything.
+            let reserve_amount = if accepts_money(
+                &shared_secret,
+                &request.prepare,
+                &self.incoming_data,
+                min_packet_amount,
+            ) {
+                amount
+            } else {
+                0
+            };
+            let (mut limits, reservation) =
+                self.received
+                    .reserve(&request.to, &destination, reserve_amount);
+            let mut reserved = false;
+            let mut invoice_paid = false;
+            if let Some(invoice) = invoice {
+                let limit = if let Some(reservation) = reservation.filter(|_| amount > 0) {
+                    match self.store.reserve_invoice_payment(invoice.id, amount).await {
+                        Ok((true, updated)) => {
+                            reserved = true;
+                            invoice_paid = updated.paid;
+                            // The limit applies to what was received before this packet
+                            MoneyLimit {
+                                received: updated.received.saturating_sub(amount),
+                                max: updated.amount,
+                            }
+                        }
+                        Ok((false, updated)) => MoneyLimit {
+                            received: updated.received,
+                            max: updated.amount,
+                        },
+                        Err(err) => {
+                            error!(
+                                "Error reserving {} of invoice {}: {}",
+                                amount, invoice.id, err
+                            );
+                            self.received
+                                .release(&request.to, &destination, &reservation);
+                            return Err(RejectBuilder {
+                                code: ErrorCode::T00_INTERNAL_ERROR,
+                                message: &[],
+                                triggered_by: Some(to_address),
+                                data: &[],
+                            }
+                            .build());
+                        }
+                    }
+                } else {
+                    MoneyLimit {
+                        received: invoice.received,
+                        max: invoice.amount,
+                    }
+                };
+                limits.push(limit);
+            }
+
             let response = receive_money(
                 &shared_secret,
                 &to_address,
                 request.to.asset_code(),
                 request.to.asset_scale(),
                 &request.prepare,
+                &self.incoming_data,
+                receipt_details.as_ref(),
+                &limits,
+                min_packet_amount,
             );
-            match response {
-                Ok(ReceiveOk { fulfill, sequence }) => {
-                    self.store
-                        .publish_payment_notification(PaymentNotification {
-                            to_username,
-                            from_username,
-                            amount,
-                            destination,
+            let publish_data = |data: Vec<DataChunk>| {
+                for chunk in data {
+                    self.store.publish_stream_data_notification(
+                        to_id,
+                        StreamDataNotification {
+                            to_username: to_username.clone(),
+                            from_username: from_username.clone(),
+                            destination: destination.clone(),
+                            stream_id: chunk.stream_id,
+                            offset: chunk.offset,
+                            data: base64::encode(&chunk.data),
+                            stream_closed: chunk.stream_closed,
                             timestamp: DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
-                            sequence,
-                            connection_closed: false,
-                        });
+                        },
+                    );
+                }
+            };
+            let publish_payment = |amount: u64, sequence: u64, connection_closed: bool| {
+                self.store
+                    .publish_payment_notification(PaymentNotification {
+                        to_username: to_username.clone(),
+                        from_username: from_username.clone(),
+                        amount,
+                        destination: destination.clone(),
+                        timestamp: DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
+                        sequence,
+                        connection_closed,
+                        invoice_id,
+                    });
+            };
+            // Take back the amounts reserved for a packet which is not fulfilled after all
+            if let Some(reservation) = reservation.filter(|_| response.is_err()) {
+                self.received
+                    .release(&request.to, &destination, &reservation);
+            }
+            if let Some(invoice_id) = invoice_id.filter(|_| reserved && response.is_err()) {
+                if let Err(err) = self.store.release_invoice_payment(invoice_id, amount).await {
+                    error!(
+                        "Error releasing {} of invoice {}: {}",
+                        amount, invoice_id, err
+                    );
+                }
+            }
+            match response {
+                Ok(ReceiveOk {
+                    fulfill,
+                    sequence,
+                    connection_closed,
+                    data,
+                    received_totals,
+                }) => {
+                    if receipt_details.is_some() && !received_totals.is_empty() {
+                        if let Err(err) = self
+                            .store
+                            .save_receipt_totals(&destination, &received_totals)
+                            .await
+                        {
+                            error!(
+                                "Error saving the receipt totals of {}: {}",
+                                destination, err
+                            );
+                        }
+                    }
+                    publish_data(data);
+                    if let Some(invoice_id) = invoice_id.filter(|_| invoice_paid) {
+                        debug!("Invoice {} was paid", invoice_id);
+                    }
+                    publish_payment(amount, sequence, false);
+                    if connection_closed {
+                        publish_payment(0, sequence, true);
+                    }
                     Ok(fulfill)
                 }
                 Err(ReceiveErr::InvalidPacket) => {
@@ -237,18 +607,11 @@ where
                     reject,
                     sequence,
                     connection_closed,
+                    data,
                 }) => {
+                    publish_data(data);
                     if connection_closed {
-                        self.store
-                            .publish_payment_notification(PaymentNotification {
-                                to_username,
-                                from_username,
-                                amount: 0,
-                                destination,
-                                timestamp: DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
-                                sequence,
-                                connection_closed: true,
-                            });
+                        publish_payment(0, sequence, true);
                     }
 
                     Err(reject)
@@ -260,8 +623,37 @@ where
     }
 }
 
+/// Checks that the Prepare is from the sender of the connection and that the frames do not
+/// prevent fulfilling it, without changing the connection's state. Amounts are only reserved
+/// for such packets, so that nobody else can use up the receive limits or an invoice.
+fn accepts_money(
+    shared_secret: &[u8; 32],
+    prepare: &Prepare,
+    incoming_data: &IncomingData,
+    min_packet_amount: u64,
+) -> bool {
+    let fulfillment = generate_fulfillment(&shared_secret[..], prepare.data());
+    if hash_sha256(&fulfillment) != prepare.execution_condition() {
+        return false;
+    }
+    let stream_packet =
+        match StreamPacket::from_encrypted(shared_secret, BytesMut::from(prepare.data())) {
+            Ok(stream_packet) => stream_packet,
+            Err(_) => return false,
+        };
+    let max_stream_id = incoming_data.max_stream_id(&prepare.destination());
+    let stream_ids_allowed = stream_packet.frames().all(|frame| match frame {
+        Frame::StreamMoney(frame) => frame.stream_id <= max_stream_id,
+        Frame::StreamData(frame) => frame.stream_id <= max_stream_id,
+        _ => true,
+    });
+    prepare.amount() >= min_packet_amount
+        && prepare.amount() >= stream_packet.prepare_amount()
+        && stream_ids_allowed
+}
+
 // TODO send asset code and scale back to sender also
-#[allow(clippy::cognitive_complexity)]
+#[allow(clippy::cognitive_complexity, clippy::too_many_arguments)]
 fn receive_money(
     shared_secret: &[u8; 32],
     // Our node's ILP Address ( we are the receiver, so we should return that
@@ -270,6 +662,11 @@ fn receive_money(
     asset_code: &str,
     asset_scale: u8,
     prepare: &Prepare,
+    incoming_data: &IncomingData,
+    receipt_details: Option<&ReceiptDetails>,
+    // Limits on the money the connection can still receive
+    limits: &[MoneyLimit],
+    min_packet_amount: u64,
 ) -> Result<ReceiveOk, ReceiveErr> {
     // Generate fulfillment
     let fulfillment = generate_fulfillment(&shared_secret[..], prepare.data());
@@ -287,18 +684,16 @@ fn receive_money(
 
     let mut response_frames: Vec<Frame> = Vec::new();
     let mut connection_closed = false;
+    let mut data_frames = Vec::new();
+    let mut closed_streams = Vec::new();
+    let mut money_streams = Vec::new();
+    let mut flow_control_violated = false;
+    let mut stream_id_blocked = false;
 
     // Handle STREAM frames
-    // TODO reject if they send data?
     for frame in stream_packet.frames() {
-        // Tell the sender the stream can handle lots of money
         if let Frame::StreamMoney(ref frame) = frame {
-            response_frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
-                stream_id: frame.stream_id,
-                // TODO will returning zero here cause problems?
-                total_received: 0,
-                receive_max: u64::max_value(),
-            }));
+            money_streams.push((frame.stream_id, frame.shares));
         }
 
         // If we receive a ConnectionNewAddress frame, then send them our asset
@@ -312,16 +707,188 @@ fn receive_money(
             }));
         }
 
+        // The sender wants to open more streams than we allow, so tell it our limit
+        if let Frame::ConnectionStreamIdBlocked(_) = frame {
+            stream_id_blocked = true;
+        }
+
         // The last packet contains the ConnectionClose frame;
         // if this is the case, return this information to the caller
         // to be included in the payment notification
         if let Frame::ConnectionClose(_) = frame {
             connection_closed = true;
         }
+
+        match frame {
+            Frame::StreamData(frame) => data_frames.push(frame),
+            Frame::StreamClose(frame) => closed_streams.push(frame.stream_id),
+            _ => {}
+        }
+    }
+
+    // Streams above the limit may not be opened
+    let connection = prepare.destination();
+    let max_stream_id = incoming_data.max_stream_id(&connection);
+    let opened_stream_ids = money_streams
+        .iter()
+        .map(|(stream_id, _)| *stream_id)
+        .chain(data_frames.iter().map(|frame| frame.stream_id));
+    if let Some(stream_id) = opened_stream_ids
+        .filter(|stream_id| *stream_id > max_stream_id)
+        .max()
+    {
+        debug!(
+            "Closing connection because stream {} exceeds the max stream ID {}",
+            stream_id, max_stream_id
+        );
+        response_frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+            code: StreamErrorCode::StreamIdError,
+            message: "Exceeded the max stream ID",
+        }));
+        connection_closed = true;
+        flow_control_violated = true;
+        data_frames.clear();
+    } else {
+        incoming_data.open_streams(
+            &connection,
+            money_streams.iter().map(|(stream_id, _)| *stream_id),
+        );
+    }
+
+    // Buffer the received data and tell the sender how much more it may send
+    let mut data = match incoming_data.receive(&connection, &data_frames) {
+        Ok((data, max_data)) => {
+            response_frames.extend(max_data.into_iter().map(Frame::StreamMaxData));
+            data
+        }
+        Err(DataError::FlowControl {
+            stream_id,
+            max_offset,
+        }) => {
+            debug!(
+                "Closing connection because data was sent past the max offset {} of stream {}",
+                max_offset, stream_id
+            );
+            response_frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+                code: StreamErrorCode::FlowControlError,
+                message: "Exceeded the stream's max data",
+            }));
+            connection_closed = true;
+            flow_control_violated = true;
+            Vec::new()
+        }
+        // Reject the packet so that the sender retries the data once the gaps are filled
+        Err(DataError::BufferFull { stream_id }) => {
+            debug!(
+                "Rejecting data for stream {} because the connection buffers too much out-of-order data",
+                stream_id
+            );
+            flow_control_violated = true;
+            Vec::new()
+        }
+    };
+
+    // The limit which leaves the least room for more money
+    let limit = limits.iter().min_by_key(|limit| limit.remaining());
+    let below_minimum = prepare_amount > 0 && prepare_amount < min_packet_amount;
+    let exceeds_limit = match limit {
+        Some(limit) => prepare_amount > limit.remaining(),
+        None => false,
+    };
+    let fulfill = is_fulfillable
+        && !flow_control_violated
+        && !below_minimum
+        && !exceeds_limit
+        && prepare_amount >= stream_packet.prepare_amount();
+    let received = if fulfill { prepare_amount } else { 0 };
+
+    // Tell the sender how much each stream got so far (which lets a resumed payment find out
+    // what was delivered), and that it can handle lots of money or how much the limit leaves
+    let totals: Vec<(u64, u64)> = split_amount(received, &money_streams)
+        .into_iter()
+        .map(|(stream_id, amount)| {
+            (
+                stream_id,
+                incoming_data.add_received(&connection, stream_id, amount),
+            )
+        })
+        .collect();
+    let remaining = limit.map(|limit| limit.remaining().saturating_sub(received));
+    // What the limit leaves is shared between the streams like the money, so that the
+    // streams together are not told they can receive more than the connection can
+    let remaining_per_stream = remaining.map(|remaining| split_amount(remaining, &money_streams));
+    response_frames.extend(
+        totals
+            .iter()
+            .enumerate()
+            .map(|(i, (stream_id, total_received))| {
+                Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                    stream_id: *stream_id,
+                    total_received: *total_received,
+                    receive_max: match remaining_per_stream {
+                        Some(ref remaining) => total_received.saturating_add(remaining[i].1),
+                        None => u64::max_value(),
+                    },
+                })
+            }),
+    );
+
+    // Close the connection once a limit leaves less than the minimum per packet,
+    // since it cannot take any more money
+    let limit_reached = match remaining {
+        Some(remaining) => prepare_amount > 0 && remaining < min_packet_amount.max(1),
+        None => false,
+    };
+    if limit_reached && !connection_closed {
+        debug!("Closing connection because it reached the receive limit");
+        response_frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+            code: StreamErrorCode::NoError,
+            message: "Reached the receive limit",
+        }));
+        connection_closed = true;
+    }
+
+    if connection_closed {
+        data.extend(incoming_data.close_connection(&connection));
+    } else {
+        // Closed streams let the sender open new ones, so tell it the raised limit
+        let streams_closed = !closed_streams.is_empty();
+        data.extend(
+            closed_streams
+                .into_iter()
+                .filter_map(|stream_id| incoming_data.close_stream(&connection, stream_id)),
+        );
+        if stream_id_blocked || streams_closed {
+            response_frames.push(Frame::ConnectionMaxStreamId(ConnectionMaxStreamIdFrame {
+                max_stream_id: incoming_data.max_stream_id(&connection),
+            }));
+        }
     }
 
     // Return Fulfill or Reject Packet
-    if is_fulfillable && prepare_amount >= stream_packet.prepare_amount() {
+    if fulfill {
+        // Sign a receipt for the new total of every stream the money went to
+        let receipts: Vec<(u64, Bytes)> = match receipt_details {
+            Some(details) => totals
+                .iter()
+                .map(|(stream_id, total_received)| {
+                    let receipt = Receipt {
+                        nonce: details.nonce,
+                        stream_id: *stream_id,
+                        total_received: *total_received,
+                    };
+                    (*stream_id, receipt.sign(&details.secret[..]))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        response_frames.extend(receipts.iter().map(|(stream_id, receipt)| {
+            Frame::StreamReceipt(StreamReceiptFrame {
+                stream_id: *stream_id,
+                receipt: &receipt[..],
+            })
+        }));
+
         let response_packet = StreamPacketBuilder {
             sequence: stream_packet.sequence(),
             ilp_packet_type: IlpPacketType::Fulfill,
@@ -344,6 +911,9 @@ fn receive_money(
         Ok(ReceiveOk {
             fulfill,
             sequence: stream_packet.sequence(),
+            connection_closed,
+            data,
+            received_totals: totals,
         })
     } else {
         let response_packet = StreamPacketBuilder {
@@ -355,6 +925,19 @@ fn receive_money(
         .build();
         if !is_fulfillable {
             debug!("Packet is unfulfillable");
+        } else if flow_control_violated {
+            debug!("Packet violated the flow control limits");
+        } else if below_minimum {
+            debug!(
+                "Received {} which is less than the minimum of {} per packet",
+                prepare_amount, min_packet_amount
+            );
+        } else if exceeds_limit {
+            debug!(
+                "Received {} which is more than the {} the receive limits leave",
+                prepare_amount,
+                limit.map(MoneyLimit::remaining).unwrap_or_default()
+            );
         } else if prepare_amount < stream_packet.prepare_amount() {
             debug!(
                 "Received only: {} when we should have received at least: {}",
@@ -378,10 +961,33 @@ fn receive_money(
             reject,
             sequence: stream_packet.sequence(),
             connection_closed,
+            data,
         })
     }
 }
 
+/// Splits the amount between the streams in proportion to their shares,
+/// giving the remainder to the last stream
+pub(crate) fn split_amount(amount: u64, streams: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let total_shares: u128 = streams.iter().map(|(_, shares)| u128::from(*shares)).sum();
+    let mut remaining = amount;
+    streams
+        .iter()
+        .enumerate()
+        .map(|(i, (stream_id, shares))| {
+            let stream_amount = if i == streams.len() - 1 {
+                remaining
+            } else {
+                (u128::from(amount) * u128::from(*shares))
+                    .checked_div(total_shares)
+                    .unwrap_or(0) as u64
+            };
+            remaining -= stream_amount;
+            (*stream_id, stream_amount)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod connection_generator {
     use super::*;
@@ -405,6 +1011,42 @@ mod connection_generator {
             shared_secret
         );
     }
+
+    #[test]
+    fn generates_tagged_address() {
+        let receiver_address = Address::from_str("example.receiver").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[9; 32][..]));
+        let receipt_details = ReceiptDetails {
+            nonce: [1; 16],
+            secret: [2; 32],
+        };
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_tag(
+                &receiver_address,
+                "order-1~a",
+                Some(&receipt_details),
+            )
+            .unwrap();
+
+        assert_eq!(
+            ConnectionGenerator::connection_tag(&destination_account),
+            Some("order-1~a")
+        );
+        assert_eq!(
+            connection_generator.rederive_secret(&destination_account),
+            shared_secret
+        );
+        assert_eq!(
+            connection_generator.rederive_receipt_details(&destination_account),
+            Some(receipt_details)
+        );
+
+        let (untagged, _) = connection_generator.generate_address_and_secret(&receiver_address);
+        assert_eq!(ConnectionGenerator::connection_tag(&untagged), None);
+        assert!(connection_generator
+            .generate_address_and_secret_with_tag(&receiver_address, "order.1", None)
+            .is_err());
+    }
 }
 
 #[cfg(test)]
@@ -452,7 +1094,21 @@ mod receiving_money {
         .build();
 
         let shared_secret = connection_generator.rederive_secret(&prepare.destination());
-        let result = receive_money(&shared_secret, &ilp_address, "ABC", 9, &prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &IncomingData::new(
+                DEFAULT_STREAM_RECEIVE_WINDOW,
+                DEFAULT_CONNECTION_BUFFER,
+                DEFAULT_MAX_OPEN_STREAMS,
+            ),
+            None,
+            &[],
+            0,
+        );
         assert!(result.is_ok());
     }
 
@@ -477,7 +1133,21 @@ mod receiving_money {
         .build();
 
         let shared_secret = connection_generator.rederive_secret(&prepare.destination());
-        let result = receive_money(&shared_secret, &ilp_address, "ABC", 9, &prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &IncomingData::new(
+                DEFAULT_STREAM_RECEIVE_WINDOW,
+                DEFAULT_CONNECTION_BUFFER,
+                DEFAULT_MAX_OPEN_STREAMS,
+            ),
+            None,
+            &[],
+            0,
+        );
         assert!(result.is_ok());
     }
 
@@ -503,7 +1173,21 @@ mod receiving_money {
         .build();
 
         let shared_secret = connection_generator.rederive_secret(&prepare.destination());
-        let result = receive_money(&shared_secret, &ilp_address, "ABC", 9, &prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &IncomingData::new(
+                DEFAULT_STREAM_RECEIVE_WINDOW,
+                DEFAULT_CONNECTION_BUFFER,
+                DEFAULT_MAX_OPEN_STREAMS,
+            ),
+            None,
+            &[],
+            0,
+        );
         assert!(result.is_err());
     }
 
@@ -539,10 +1223,105 @@ mod receiving_money {
         .build();
 
         let shared_secret = connection_generator.rederive_secret(&prepare.destination());
-        let result = receive_money(&shared_secret, &ilp_address, "ABC", 9, &prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &IncomingData::new(
+                DEFAULT_STREAM_RECEIVE_WINDOW,
+                DEFAULT_CONNECTION_BUFFER,
+                DEFAULT_MAX_OPEN_STREAMS,
+            ),
+            None,
+            &[],
+            0,
+        );
         assert!(result.is_err());
     }
 
+    #[test]
+    fn enforces_its_max_stream_id() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        let incoming_data = IncomingData::new(
+            DEFAULT_STREAM_RECEIVE_WINDOW,
+            DEFAULT_CONNECTION_BUFFER,
+            DEFAULT_MAX_OPEN_STREAMS,
+        );
+        let receive = |sequence: u64, frames: &[Frame]| {
+            let data = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence,
+                frames,
+            }
+            .build()
+            .into_encrypted(&shared_secret[..]);
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount: 10,
+                expires_at: UNIX_EPOCH,
+                data: &data[..],
+                execution_condition: &generate_condition(&shared_secret[..], &data),
+            }
+            .build();
+            let (fulfilled, data) = match receive_money(
+                &shared_secret,
+                &ilp_address,
+                "ABC",
+                9,
+                &prepare,
+                &incoming_data,
+                None,
+                &[],
+                0,
+            ) {
+                Ok(ok) => (true, BytesMut::from(ok.fulfill.data())),
+                Err(ReceiveErr::Rejection { reject, .. }) => (false, BytesMut::from(reject.data())),
+                Err(ReceiveErr::InvalidPacket) => panic!("packet should be valid"),
+            };
+            let response = StreamPacket::from_encrypted(&shared_secret, data).unwrap();
+            let max_stream_id = response.frames().find_map(|frame| match frame {
+                Frame::ConnectionMaxStreamId(frame) => Some(frame.max_stream_id),
+                _ => None,
+            });
+            let close_code = response.frames().find_map(|frame| match frame {
+                Frame::ConnectionClose(frame) => Some(frame.code),
+                _ => None,
+            });
+            (fulfilled, max_stream_id, close_code)
+        };
+        let money = |stream_id| {
+            Frame::StreamMoney(StreamMoneyFrame {
+                stream_id,
+                shares: 1,
+            })
+        };
+
+        // The receiver answers with its own limit, not the one the sender asks for
+        let blocked = Frame::ConnectionStreamIdBlocked(ConnectionStreamIdBlockedFrame {
+            max_stream_id: 1001,
+        });
+        assert_eq!(receive(1, &[money(1), blocked]), (true, Some(20), None));
+        // Closing a stream raises the limit
+        let close = Frame::StreamClose(StreamCloseFrame {
+            stream_id: 1,
+            code: StreamErrorCode::NoError,
+            message: "",
+        });
+        assert_eq!(receive(2, &[money(3), close]), (true, Some(22), None));
+        assert_eq!(receive(3, &[money(21)]), (true, None, None));
+        // Streams above the limit close the connection
+        assert_eq!(
+            receive(4, &[money(23)]),
+            (false, None, Some(StreamErrorCode::StreamIdError))
+        );
+    }
+
     #[test]
     fn fulfills_packets_sent_to_javascript_receiver() {
         // This was created by the JS ilp-protocol-stream library
@@ -557,24 +1336,198 @@ mod receiving_money {
             &hex!("b7d09d2e16e6f83c55b60e42fcd7c2b8ed49624a1df73c59b383dbe2e8690309")[..],
             "did not regenerate the same shared secret",
         );
-        let fulfill = receive_money(&shared_secret, &ilp_address, "ABC", 9, &prepare)
-            .expect("Receiver should be able to generate the fulfillment")
-            .fulfill;
+        let fulfill = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &IncomingData::new(
+                DEFAULT_STREAM_RECEIVE_WINDOW,
+                DEFAULT_CONNECTION_BUFFER,
+                DEFAULT_MAX_OPEN_STREAMS,
+            ),
+            None,
+            &[],
+            0,
+        )
+        .expect("Receiver should be able to generate the fulfillment")
+        .fulfill;
         assert_eq!(
             &hash_sha256(fulfill.fulfillment())[..],
             &condition[..],
             "fulfillment generated does not hash to the expected condition"
         );
     }
+
+    #[test]
+    fn enforces_receive_limits() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        let incoming_data = IncomingData::new(
+            DEFAULT_STREAM_RECEIVE_WINDOW,
+            DEFAULT_CONNECTION_BUFFER,
+            DEFAULT_MAX_OPEN_STREAMS,
+        );
+        let receive = |sequence: u64, amount: u64, limits: &[MoneyLimit]| {
+            let data = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence,
+                frames: &[Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 1,
+                    shares: 1,
+                })],
+            }
+            .build()
+            .into_encrypted(&shared_secret[..]);
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount,
+                expires_at: UNIX_EPOCH,
+                data: &data[..],
+                execution_condition: &generate_condition(&shared_secret[..], &data),
+            }
+            .build();
+            let (fulfilled, data) = match receive_money(
+                &shared_secret,
+                &ilp_address,
+                "ABC",
+                9,
+                &prepare,
+                &incoming_data,
+                None,
+                limits,
+                10,
+            ) {
+                Ok(ok) => (true, BytesMut::from(ok.fulfill.data())),
+                Err(ReceiveErr::Rejection { reject, .. }) => (false, BytesMut::from(reject.data())),
+                Err(ReceiveErr::InvalidPacket) => panic!("packet should be valid"),
+            };
+            let response = StreamPacket::from_encrypted(&shared_secret, data).unwrap();
+            let max_money = response.frames().find_map(|frame| match frame {
+                Frame::StreamMaxMoney(frame) => Some((frame.total_received, frame.receive_max)),
+                _ => None,
+            });
+            let closed = response
+                .frames()
+                .any(|frame| matches!(frame, Frame::ConnectionClose(_)));
+            (fulfilled, max_money, closed)
+        };
+        let limits = [
+            MoneyLimit {
+                received: 0,
+                max: 150,
+            },
+            MoneyLimit {
+                received: 900,
+                max: 1000,
+            },
+        ];
+
+        // Below the minimum per packet. The sender is told what the stream received
+        // and how much more the tightest limit leaves on top of it.
+        assert_eq!(receive(1, 5, &limits), (false, Some((0, 100)), false));
+        // Fits within the tightest limit
+        assert_eq!(receive(2, 60, &limits), (true, Some((60, 100)), false));
+        // Exceeds what the tightest limit leaves
+        let limits = [
+            MoneyLimit {
+                received: 60,
+                max: 150,
+            },
+            MoneyLimit {
+                received: 960,
+                max: 1000,
+            },
+        ];
+        assert_eq!(receive(3, 50, &limits), (false, Some((60, 100)), false));
+        // Leaving less than the minimum per packet closes the connection
+        assert_eq!(receive(4, 35, &limits), (true, Some((95, 100)), true));
+    }
+
+    #[test]
+    fn splits_the_remaining_limit_between_streams() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        let incoming_data = IncomingData::new(
+            DEFAULT_STREAM_RECEIVE_WINDOW,
+            DEFAULT_CONNECTION_BUFFER,
+            DEFAULT_MAX_OPEN_STREAMS,
+        );
+        let data = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[
+                Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 1,
+                    shares: 1,
+                }),
+                Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 3,
+                    shares: 3,
+                }),
+            ],
+        }
+        .build()
+        .into_encrypted(&shared_secret[..]);
+        let prepare = PrepareBuilder {
+            destination: destination_account,
+            amount: 40,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &generate_condition(&shared_secret[..], &data),
+        }
+        .build();
+        let fulfill = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &incoming_data,
+            None,
+            &[MoneyLimit {
+                received: 0,
+                max: 140,
+            }],
+            0,
+        )
+        .unwrap()
+        .fulfill;
+
+        let response =
+            StreamPacket::from_encrypted(&shared_secret, BytesMut::from(fulfill.data())).unwrap();
+        let max_money: Vec<(u64, u64, u64)> = response
+            .frames()
+            .filter_map(|frame| match frame {
+                Frame::StreamMaxMoney(frame) => {
+                    Some((frame.stream_id, frame.total_received, frame.receive_max))
+                }
+                _ => None,
+            })
+            .collect();
+        // The 100 the limit leaves is shared like the money, rather than offered on each stream
+        assert_eq!(max_money, vec![(1, 10, 35), (3, 30, 105)]);
+    }
 }
 
 #[cfg(test)]
 mod stream_receiver_service {
     use super::*;
+    use crate::invoice::Invoice;
+    use crate::receipt::verify_receipt;
     use crate::test_helpers::*;
+    use interledger_errors::ReceiptStoreError;
     use interledger_packet::PrepareBuilder;
     use interledger_service::outgoing_service_fn;
 
+    use std::collections::HashMap;
     use std::str::FromStr;
     use std::time::UNIX_EPOCH;
 
@@ -752,4 +1705,558 @@ mod stream_receiver_service {
             Address::from_str("example.other-receiver").unwrap(),
         );
     }
+
+    #[derive(Clone, Default)]
+    struct DataStore {
+        received: Arc<parking_lot::Mutex<Vec<(Uuid, StreamDataNotification)>>>,
+        payments: Arc<parking_lot::Mutex<Vec<PaymentNotification>>>,
+        invoices: Arc<parking_lot::Mutex<HashMap<Uuid, Invoice>>>,
+        reservations: Arc<parking_lot::Mutex<Vec<u64>>>,
+        receipt_totals: Arc<parking_lot::Mutex<HashMap<Address, HashMap<u64, u64>>>>,
+    }
+
+    impl StreamNotificationsStore for DataStore {
+        type Account = TestAccount;
+
+        fn add_payment_notification_subscription(
+            &self,
+            _account_id: Uuid,
+            _sender: UnboundedSender<PaymentNotification>,
+        ) {
+        }
+
+        fn publish_payment_notification(&self, payment: PaymentNotification) {
+            self.payments.lock().push(payment);
+        }
+
+        fn all_payment_subscription(&self) -> broadcast::Receiver<PaymentNotification> {
+            broadcast::channel(1).1
+        }
+
+        fn add_stream_data_subscription(
+            &self,
+            _account_id: Uuid,
+            _sender: UnboundedSender<StreamDataNotification>,
+        ) {
+        }
+
+        fn publish_stream_data_notification(
+            &self,
+            account_id: Uuid,
+            notification: StreamDataNotification,
+        ) {
+            self.received.lock().push((account_id, notification));
+        }
+    }
+
+    #[async_trait]
+    impl InvoiceStore for DataStore {
+        async fn insert_invoice(&self, invoice: Invoice) -> Result<(), InvoiceStoreError> {
+            self.invoices.lock().insert(invoice.id, invoice);
+            Ok(())
+        }
+
+        async fn get_invoice(&self, id: Uuid) -> Result<Invoice, InvoiceStoreError> {
+            self.invoices
+                .lock()
+                .get(&id)
+                .cloned()
+                .ok_or_else(|| InvoiceStoreError::InvoiceNotFound(id.to_string()))
+        }
+
+        async fn reserve_invoice_payment(
+            &self,
+            id: Uuid,
+            amount: u64,
+        ) -> Result<(bool, Invoice), InvoiceStoreError> {
+            let mut invoices = self.invoices.lock();
+            let invoice = invoices
+                .get_mut(&id)
+                .ok_or_else(|| InvoiceStoreError::InvoiceNotFound(id.to_string()))?;
+            if amount > invoice.remaining() {
+                return Ok((false, invoice.clone()));
+            }
+            self.reservations.lock().push(amount);
+            invoice.received += amount;
+            invoice.paid = invoice.received >= invoice.amount;
+            Ok((true, invoice.clone()))
+        }
+
+        async fn release_invoice_payment(
+            &self,
+            id: Uuid,
+            amount: u64,
+        ) -> Result<Invoice, InvoiceStoreError> {
+            let mut invoices = self.invoices.lock();
+            let invoice = invoices
+                .get_mut(&id)
+                .ok_or_else(|| InvoiceStoreError::InvoiceNotFound(id.to_string()))?;
+            invoice.received -= amount;
+            invoice.paid = invoice.received >= invoice.amount;
+            Ok(invoice.clone())
+        }
+    }
+
+    #[async_trait]
+    impl ReceiptTotalsStore for DataStore {
+        async fn get_receipt_totals(
+            &self,
+            connection: &Address,
+        ) -> Result<HashMap<u64, u64>, ReceiptStoreError> {
+            Ok(self
+                .receipt_totals
+                .lock()
+                .get(connection)
+                .cloned()
+                .unwrap_or_default())
+        }
+
+        async fn save_receipt_totals(
+            &self,
+            connection: &Address,
+            totals: &[(u64, u64)],
+        ) -> Result<(), ReceiptStoreError> {
+            let mut receipt_totals = self.receipt_totals.lock();
+            let saved = receipt_totals.entry(connection.clone()).or_default();
+            for (stream_id, total_received) in totals {
+                let total = saved.entry(*stream_id).or_default();
+                *total = (*total).max(*total_received);
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn pays_invoice_of_tagged_connection() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let to = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: ilp_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+        let store = DataStore::default();
+        let invoice = Invoice::new(to.id, 150, serde_json::json!({ "order": 1 }));
+        store.insert_invoice(invoice.clone()).await.unwrap();
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_tag(&ilp_address, &invoice.connection_tag(), None)
+            .unwrap();
+        let service = StreamReceiverService::new(
+            server_secret,
+            store.clone(),
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> IlpResult {
+                panic!("shouldn't get here")
+            }),
+        );
+
+        let send = |sequence: u64, amount: u64, prepare_amount: u64| {
+            let data = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount,
+                sequence,
+                frames: &[Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 1,
+                    shares: 1,
+                })],
+            }
+            .build()
+            .into_encrypted(&shared_secret[..]);
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount,
+                expires_at: UNIX_EPOCH,
+                data: &data[..],
+                execution_condition: &generate_condition(&shared_secret[..], &data),
+            }
+            .build();
+            let request = OutgoingRequest {
+                from: to.clone(),
+                to: to.clone(),
+                original_amount: amount,
+                prepare,
+            };
+            let mut service = service.clone();
+            async move {
+                let (data, fulfilled) = match service.send_request(request).await {
+                    Ok(fulfill) => (BytesMut::from(fulfill.data()), true),
+                    Err(reject) => (BytesMut::from(reject.data()), false),
+                };
+                let response = StreamPacket::from_encrypted(&shared_secret, data).unwrap();
+                let max_money = response
+                    .frames()
+                    .find_map(|frame| match frame {
+                        Frame::StreamMaxMoney(frame) => {
+                            Some((frame.total_received, frame.receive_max))
+                        }
+                        _ => None,
+                    })
+                    .unwrap();
+                (fulfilled, max_money)
+            }
+        };
+
+        assert_eq!(send(1, 100, 0).await, (true, (100, 150)));
+        // More than is left to pay
+        assert_eq!(send(2, 100, 0).await, (false, (100, 150)));
+        // Rejected for delivering less than the sender asked for, which releases the amount
+        assert_eq!(send(3, 50, 60).await, (false, (100, 150)));
+        let invoice_after_reject = store.get_invoice(invoice.id).await.unwrap();
+        assert_eq!(invoice_after_reject.received, 100);
+        assert!(!invoice_after_reject.paid);
+        assert_eq!(send(4, 50, 0).await, (true, (150, 150)));
+
+        let paid = store.get_invoice(invoice.id).await.unwrap();
+        assert_eq!(paid.received, 150);
+        assert!(paid.paid);
+        let payments = store.payments.lock();
+        // Paying the full amount also closes the connection
+        assert_eq!(payments.len(), 3);
+        assert!(payments[2].connection_closed);
+        assert!(payments
+            .iter()
+            .all(|payment| payment.invoice_id == Some(invoice.id)));
+    }
+
+    #[tokio::test]
+    async fn reserves_invoice_only_for_packets_from_the_sender() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let to = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: ilp_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+        let store = DataStore::default();
+        let invoice = Invoice::new(to.id, 150, serde_json::Value::Null);
+        store.insert_invoice(invoice.clone()).await.unwrap();
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_tag(&ilp_address, &invoice.connection_tag(), None)
+            .unwrap();
+        let mut service = StreamReceiverService::new(
+            server_secret,
+            store.clone(),
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> IlpResult {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let request = |data: &[u8], execution_condition: &[u8; 32]| OutgoingRequest {
+            from: to.clone(),
+            to: to.clone(),
+            original_amount: 150,
+            prepare: PrepareBuilder {
+                destination: destination_account.clone(),
+                amount: 150,
+                expires_at: UNIX_EPOCH,
+                data,
+                execution_condition,
+            }
+            .build(),
+        };
+
+        // Anyone on the path knows the destination, but not the shared secret
+        let garbage = [7; 64];
+        let result = service
+            .send_request(request(
+                &garbage,
+                &generate_condition(&shared_secret[..], &garbage),
+            ))
+            .await;
+        assert_eq!(result.unwrap_err().code(), ErrorCode::F02_UNREACHABLE);
+
+        let data = test_stream_packet().into_encrypted(&shared_secret[..]);
+        let result = service.send_request(request(&data, &[0; 32])).await;
+        assert_eq!(result.unwrap_err().code(), ErrorCode::F99_APPLICATION_ERROR);
+
+        assert!(store.reservations.lock().is_empty());
+        let invoice = store.get_invoice(invoice.id).await.unwrap();
+        assert_eq!(invoice.received, 0);
+        assert!(!invoice.paid);
+
+        let result = service
+            .send_request(request(
+                &data,
+                &generate_condition(&shared_secret[..], &data),
+            ))
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(*store.reservations.lock(), vec![150]);
+    }
+
+    #[tokio::test]
+    async fn receipts_keep_their_totals_after_the_connection_closed() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let receipt_details = ReceiptDetails {
+            nonce: [1; 16],
+            secret: [2; 32],
+        };
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_receipts(&ilp_address, &receipt_details);
+        let to = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: ilp_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+        let store = DataStore::default();
+        let receiver = || {
+            StreamReceiverService::new(
+                server_secret.clone(),
+                store.clone(),
+                outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> IlpResult {
+                    panic!("shouldn't get here")
+                }),
+            )
+        };
+
+        let receipt_secret = receipt_details.secret;
+        let send = |mut service: StreamReceiverService<_, _, _>,
+                    sequence: u64,
+                    amount: u64,
+                    close: bool| {
+            let mut frames = vec![Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: 1,
+                shares: 1,
+            })];
+            if close {
+                frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+                    code: StreamErrorCode::NoError,
+                    message: "",
+                }));
+            }
+            let data = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence,
+                frames: &frames,
+            }
+            .build()
+            .into_encrypted(&shared_secret[..]);
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount,
+                expires_at: UNIX_EPOCH,
+                data: &data[..],
+                execution_condition: &generate_condition(&shared_secret[..], &data),
+            }
+            .build();
+            let request = OutgoingRequest {
+                from: to.clone(),
+                to: to.clone(),
+                original_amount: amount,
+                prepare,
+            };
+            async move {
+                let fulfill = service.send_request(request).await.unwrap();
+                let response =
+                    StreamPacket::from_encrypted(&shared_secret, BytesMut::from(fulfill.data()))
+                        .unwrap();
+                let receipt = response
+                    .frames()
+                    .find_map(|frame| match frame {
+                        Frame::StreamReceipt(frame) => Some(Bytes::copy_from_slice(frame.receipt)),
+                        _ => None,
+                    })
+                    .unwrap();
+                verify_receipt(&receipt, &receipt_secret[..])
+                    .unwrap()
+                    .total_received
+            }
+        };
+
+        let service = receiver();
+        assert_eq!(send(service.clone(), 1, 100, false).await, 100);
+        assert_eq!(send(service.clone(), 2, 50, true).await, 150);
+        // The receiver forgot the connection when it was closed
+        assert_eq!(send(service, 3, 30, false).await, 180);
+        // And after a restart
+        assert_eq!(send(receiver(), 4, 20, false).await, 200);
+    }
+
+    #[tokio::test]
+    async fn delivers_stream_data_in_order() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        let to = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: ilp_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+        let store = DataStore::default();
+        let mut service = StreamReceiverService::new(
+            server_secret,
+            store.clone(),
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> IlpResult {
+                panic!("shouldn't get here")
+            }),
+        );
+
+        let request = |sequence: u64, frames: &[Frame], fulfillable: bool| {
+            let data = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence,
+                frames,
+            }
+            .build()
+            .into_encrypted(&shared_secret[..]);
+            let execution_condition = if fulfillable {
+                generate_condition(&shared_secret[..], &data)
+            } else {
+                [0; 32]
+            };
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount: 0,
+                expires_at: UNIX_EPOCH,
+                data: &data[..],
+                execution_condition: &execution_condition,
+            }
+            .build();
+            OutgoingRequest {
+                from: to.clone(),
+                to: to.clone(),
+                original_amount: 0,
+                prepare,
+            }
+        };
+
+        // The second half arrives first, on an unfulfillable packet
+        let reject = service
+            .send_request(request(
+                1,
+                &[Frame::StreamData(StreamDataFrame {
+                    stream_id: 1,
+                    offset: 5,
+                    data: b"world",
+                })],
+                false,
+            ))
+            .await
+            .unwrap_err();
+        assert!(store.received.lock().is_empty());
+        let response =
+            StreamPacket::from_encrypted(&shared_secret, BytesMut::from(reject.data())).unwrap();
+        assert_eq!(
+            response.frames().collect::<Vec<_>>(),
+            vec![Frame::StreamMaxData(StreamMaxDataFrame {
+                stream_id: 1,
+                max_offset: DEFAULT_STREAM_RECEIVE_WINDOW,
+            })]
+        );
+
+        service
+            .send_request(request(
+                2,
+                &[
+                    Frame::StreamData(StreamDataFrame {
+                        stream_id: 1,
+                        offset: 0,
+                        data: b"hello",
+                    }),
+                    Frame::StreamClose(StreamCloseFrame {
+                        stream_id: 1,
+                        code: StreamErrorCode::NoError,
+                        message: "",
+                    }),
+                ],
+                true,
+            ))
+            .await
+            .unwrap();
+
+        let received = store.received.lock();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].0, to.id);
+        assert_eq!(received[0].1.stream_id, 1);
+        assert_eq!(received[0].1.offset, 0);
+        assert_eq!(base64::decode(&received[0].1.data).unwrap(), b"helloworld");
+        assert!(!received[0].1.stream_closed);
+        assert_eq!(received[1].1.offset, 10);
+        assert!(received[1].1.stream_closed);
+    }
+
+    #[tokio::test]
+    async fn rejects_data_exceeding_the_window() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        let data = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[Frame::StreamData(StreamDataFrame {
+                stream_id: 1,
+                offset: DEFAULT_STREAM_RECEIVE_WINDOW,
+                data: b"too far",
+            })],
+        }
+        .build()
+        .into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        let prepare = PrepareBuilder {
+            destination: destination_account,
+            amount: 100,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &execution_condition,
+        }
+        .build();
+
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &IncomingData::new(
+                DEFAULT_STREAM_RECEIVE_WINDOW,
+                DEFAULT_CONNECTION_BUFFER,
+                DEFAULT_MAX_OPEN_STREAMS,
+            ),
+            None,
+            &[],
+            0,
+        );
+        match result {
+            Err(ReceiveErr::Rejection {
+                reject,
+                connection_closed,
+                ..
+            }) => {
+                assert!(connection_closed);
+                let response =
+                    StreamPacket::from_encrypted(&shared_secret, BytesMut::from(reject.data()))
+                        .unwrap();
+                match response.frames().next() {
+                    Some(Frame::ConnectionClose(frame)) => {
+                        assert_eq!(frame.code, StreamErrorCode::FlowControlError)
+                    }
+                    other => panic!("unexpected frame: {:?}", other),
+                }
+            }
+            _ => panic!("the packet should have been rejected"),
+        }
+    }
 }